@@ -0,0 +1,99 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use crate::logging::{escape, rfc3339_now};
+
+// Oldest events are dropped beyond this many.
+const MAX_EVENTS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    Blocked,
+    Banned,
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Action::Blocked => "blocked",
+            Action::Banned => "banned",
+        }
+    }
+}
+
+struct Event {
+    timestamp: String,
+    client_ip: IpAddr,
+    action: Action,
+    target: String,
+    rule: String,
+}
+
+// Recent block and ban events, for abuse reports to the offenders' providers.
+#[derive(Default)]
+pub struct AbuseLog {
+    events: Mutex<VecDeque<Event>>,
+}
+
+impl AbuseLog {
+    pub fn record(&self, client_ip: IpAddr, action: Action, target: &str, rule: &str) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() == MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(Event {
+            timestamp: rfc3339_now(),
+            client_ip,
+            action,
+            target: target.to_string(),
+            rule: rule.to_string(),
+        });
+    }
+
+    pub fn to_json(&self) -> String {
+        let events = self.events.lock().unwrap();
+        let rows: Vec<String> = events
+            .iter()
+            .map(|e| {
+                format!(
+                    "{{\"timestamp\":\"{}\",\"client_ip\":\"{}\",\"action\":\"{}\",\"target\":\"{}\",\"rule\":\"{}\"}}",
+                    e.timestamp,
+                    e.client_ip,
+                    e.action.as_str(),
+                    escape(&e.target),
+                    escape(&e.rule)
+                )
+            })
+            .collect();
+        format!("[{}]", rows.join(","))
+    }
+
+    pub fn to_csv(&self) -> String {
+        let events = self.events.lock().unwrap();
+        let mut out = String::from("timestamp,client_ip,action,target,rule\n");
+        for e in events.iter() {
+            out.push_str(&format!(
+                "{},{},{},{},{}\n",
+                e.timestamp,
+                e.client_ip,
+                e.action.as_str(),
+                csv_field(&e.target),
+                csv_field(&e.rule)
+            ));
+        }
+        out
+    }
+}
+
+// RFC 4180 quoting, plus a leading quote for values a spreadsheet would
+// treat as a formula.
+pub fn csv_field(value: &str) -> String {
+    let formula = value.len() > 1 && value.starts_with(['=', '+', '-', '@']);
+    if formula || value.contains([',', '"', '\n', '\r']) {
+        let prefix = if formula { "'" } else { "" };
+        format!("\"{}{}\"", prefix, value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
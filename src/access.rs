@@ -0,0 +1,335 @@
+use hyper::Method;
+use serde::Deserialize;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::logging::civil_from_days;
+
+// Where access events go besides the `access` log event:
+//
+//   [access_log]
+//   format = '$remote_addr - $remote_user [$time_local] "$request_method $target" $status $bytes_sent'
+//   file = "/var/log/secure-proxy/access.log"
+//   parquet_dir = "/var/log/secure-proxy/access"
+#[derive(Debug, Deserialize)]
+pub struct AccessLogConfig {
+    // nginx-style line template; replaces the `access` log event when set
+    pub format: Option<String>,
+    // File the formatted lines are appended to; stdout if unset
+    pub file: Option<PathBuf>,
+    pub parquet_dir: Option<PathBuf>,
+    // Rows per file; bounds the memory held by the writer
+    #[serde(default = "default_rows_per_file")]
+    pub rows_per_file: usize,
+    // Seconds after which a partly filled file is written anyway
+    #[serde(default = "default_flush_interval")]
+    pub flush_interval: u64,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        AccessLogConfig {
+            format: None,
+            file: None,
+            parquet_dir: None,
+            rows_per_file: default_rows_per_file(),
+            flush_interval: default_flush_interval(),
+        }
+    }
+}
+
+fn default_rows_per_file() -> usize {
+    50_000
+}
+
+fn default_flush_interval() -> u64 {
+    60
+}
+
+impl AccessLogConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.rows_per_file == 0 || self.flush_interval == 0 {
+            return Err("access_log.rows_per_file and flush_interval must be positive".to_string());
+        }
+        if let Some(format) = &self.format {
+            Template::parse(format).map_err(|e| format!("access_log.format: {}", e))?;
+        }
+        if self.file.is_some() && self.format.is_none() {
+            return Err("access_log.file needs access_log.format".to_string());
+        }
+        Ok(())
+    }
+}
+
+// One proxied request, as handed to formatters.
+pub struct AccessEvent<'a> {
+    pub time: SystemTime,
+    pub request_id: &'a str,
+    pub client_addr: SocketAddr,
+    // Name of the listener the request came in on
+    pub listener: &'a str,
+    pub user: &'a str,
+    pub method: &'a Method,
+    pub target: &'a str,
+    pub status: u16,
+    pub bytes: u64,
+}
+
+// Turns an access event into one log line (without the newline). The
+// configurable `Template` is one; a build that embeds the proxy can pass
+// its own to `install`.
+pub trait AccessFormatter: Send + Sync {
+    fn format(&self, event: &AccessEvent) -> String;
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Var {
+    TimeLocal,
+    TimeIso8601,
+    Msec,
+    RequestId,
+    RemoteAddr,
+    RemotePort,
+    RemoteUser,
+    Listener,
+    RequestMethod,
+    Target,
+    Status,
+    BytesSent,
+}
+
+const VARS: &[(&str, Var)] = &[
+    ("time_local", Var::TimeLocal),
+    ("time_iso8601", Var::TimeIso8601),
+    ("msec", Var::Msec),
+    ("request_id", Var::RequestId),
+    ("remote_addr", Var::RemoteAddr),
+    ("remote_port", Var::RemotePort),
+    ("remote_user", Var::RemoteUser),
+    ("listener", Var::Listener),
+    ("request_method", Var::RequestMethod),
+    ("target", Var::Target),
+    ("status", Var::Status),
+    ("bytes_sent", Var::BytesSent),
+];
+
+enum Part {
+    Text(String),
+    Var(Var),
+}
+
+// "$name" or "${name}" variables between literal text; "$$" is a dollar.
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(format: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut rest = format;
+        while let Some(at) = rest.find('$') {
+            text.push_str(&rest[..at]);
+            rest = &rest[at + 1..];
+            if let Some(after) = rest.strip_prefix('$') {
+                text.push('$');
+                rest = after;
+                continue;
+            }
+            let (name, after) = match rest.strip_prefix('{') {
+                Some(braced) => braced.split_once('}').ok_or("unclosed '${'")?,
+                None => {
+                    let end = rest
+                        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                        .unwrap_or(rest.len());
+                    (&rest[..end], &rest[end..])
+                }
+            };
+            let var = VARS
+                .iter()
+                .find(|(known, _)| *known == name)
+                .map(|(_, var)| *var)
+                .ok_or_else(|| format!("unknown variable '${}'", name))?;
+            if !text.is_empty() {
+                parts.push(Part::Text(std::mem::take(&mut text)));
+            }
+            parts.push(Part::Var(var));
+            rest = after;
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Template { parts })
+    }
+}
+
+impl AccessFormatter for Template {
+    fn format(&self, event: &AccessEvent) -> String {
+        let mut line = String::new();
+        for part in &self.parts {
+            let var = match part {
+                Part::Text(text) => {
+                    line.push_str(text);
+                    continue;
+                }
+                Part::Var(var) => var,
+            };
+            let _ = match var {
+                Var::TimeLocal => write!(line, "{}", time_local(event.time)),
+                Var::TimeIso8601 => write!(line, "{}", time_iso8601(event.time)),
+                Var::Msec => {
+                    let since = event.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+                    write!(line, "{}.{:03}", since.as_secs(), since.subsec_millis())
+                }
+                Var::RequestId => write!(line, "{}", escape(event.request_id)),
+                Var::RemoteAddr => write!(line, "{}", event.client_addr.ip()),
+                Var::RemotePort => write!(line, "{}", event.client_addr.port()),
+                Var::RemoteUser => write!(line, "{}", escape(event.user)),
+                Var::Listener => write!(line, "{}", escape(event.listener)),
+                Var::RequestMethod => write!(line, "{}", escape(event.method.as_str())),
+                Var::Target => write!(line, "{}", escape(event.target)),
+                Var::Status => write!(line, "{}", event.status),
+                Var::BytesSent => write!(line, "{}", event.bytes),
+            };
+        }
+        line
+    }
+}
+
+// As nginx does: quotes, backslashes and control bytes become \xHH, so a
+// request cannot forge log lines or break quoted fields.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                let _ = write!(out, "\\x{:02X}", c as u32);
+            }
+            c if c.is_control() => {
+                let mut buf = [0; 4];
+                for byte in c.encode_utf8(&mut buf).bytes() {
+                    let _ = write!(out, "\\x{:02X}", byte);
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn date_time(time: SystemTime) -> (i64, u32, u32, u64) {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    (year, month, day, secs % 86_400)
+}
+
+// 16/Oct/2026:13:55:36 +0000 (always UTC)
+fn time_local(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (year, month, day, rem) = date_time(time);
+    format!(
+        "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+// 2026-10-16T13:55:36+00:00
+fn time_iso8601(time: SystemTime) -> String {
+    let (year, month, day, rem) = date_time(time);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}+00:00",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+enum Output {
+    Stdout,
+    File(PathBuf, File),
+}
+
+struct Sink {
+    formatter: Box<dyn AccessFormatter>,
+    output: Mutex<Output>,
+}
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+// Install the configured template, if any.
+pub fn start(config: &AccessLogConfig) -> io::Result<()> {
+    let Some(format) = &config.format else {
+        return Ok(());
+    };
+    let template = Template::parse(format).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    install(Box::new(template), config.file.clone())?;
+    match &config.file {
+        Some(path) => info!("🪵 Writing formatted access logs to {}", path.display()),
+        None => info!("🪵 Writing formatted access logs to stdout"),
+    }
+    Ok(())
+}
+
+// Send access events through `formatter` to `file` (stdout if None)
+// instead of the `access` log event. Only the first call takes effect.
+pub fn install(formatter: Box<dyn AccessFormatter>, file: Option<PathBuf>) -> io::Result<()> {
+    let output = match file {
+        Some(path) => {
+            let file = open(&path)?;
+            Output::File(path, file)
+        }
+        None => Output::Stdout,
+    };
+    let _ = SINK.set(Sink {
+        formatter,
+        output: Mutex::new(output),
+    });
+    Ok(())
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+// Whether the event was written here; if not, the caller logs it as usual.
+pub fn record(event: &AccessEvent) -> bool {
+    let Some(sink) = SINK.get() else {
+        return false;
+    };
+    let mut line = sink.formatter.format(event);
+    line.push('\n');
+    let result = match &mut *sink.output.lock().unwrap() {
+        Output::Stdout => io::stdout().lock().write_all(line.as_bytes()),
+        Output::File(_, file) => file.write_all(line.as_bytes()),
+    };
+    if let Err(e) = result {
+        warn!("⚠️ Failed to write access log: {}", e);
+    }
+    true
+}
+
+// SIGHUP: start a new file after logrotate has moved the old one.
+pub fn reopen() {
+    let Some(sink) = SINK.get() else { return };
+    if let Output::File(path, file) = &mut *sink.output.lock().unwrap() {
+        match open(path) {
+            Ok(new) => *file = new,
+            Err(e) => warn!("⚠️ Failed to reopen access log {}: {}", path.display(), e),
+        }
+    }
+}
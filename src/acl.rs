@@ -0,0 +1,155 @@
+use hyper::{Body, Method, Request};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::groups::{self, GroupConfig};
+use crate::pattern::host_matches;
+
+// Which destinations each user may reach; "@name" refers to [groups]:
+//
+//   [[acl.policies]]
+//   users = ["ci"]
+//   allow = ["*.github.com:443"]
+//
+//   [[acl.policies]]
+//   users = ["@admins"]
+//
+// The first policy naming the user, one of their groups or "*" applies.
+#[derive(Debug, Default, Deserialize)]
+pub struct AclConfig {
+    // For users no policy names; "allow" (default) or "deny"
+    #[serde(default)]
+    pub default: Decision,
+    #[serde(default)]
+    pub policies: Vec<Policy>,
+    // Refuse loopback, RFC 1918, link-local and other internal addresses,
+    // whatever name leads there
+    #[serde(default)]
+    pub deny_private: bool,
+    // Internal networks that stay reachable, e.g. "10.20.0.0/16"
+    #[serde(default)]
+    pub allow_networks: Vec<String>,
+    // Users ("@group", "*") who may reach any internal address
+    #[serde(default)]
+    pub private_users: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Policy {
+    // User names, "@group" or "*"
+    pub users: Vec<String>,
+    // "host", "host:port" or "host:low-high", host as in [[routes]]. With
+    // an allow list, everything not on it is refused; deny always wins.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl AclConfig {
+    pub fn validate(&self, groups: &HashMap<String, GroupConfig>) -> Result<(), String> {
+        if let Some(network) = self.allow_networks.iter().find(|n| crate::egress::parse_network(n).is_none()) {
+            return Err(format!("acl.allow_networks: invalid network '{}'", network));
+        }
+        groups::check_refs(&self.private_users, groups).map_err(|e| format!("acl.private_users: {}", e))?;
+        for (i, policy) in self.policies.iter().enumerate() {
+            if policy.users.is_empty() {
+                return Err(format!("acl.policies[{}]: users must not be empty", i));
+            }
+            groups::check_refs(&policy.users, groups).map_err(|e| format!("acl.policies[{}]: {}", i, e))?;
+            if let Some(rule) = policy.allow.iter().chain(&policy.deny).find(|r| parse(r).is_none()) {
+                return Err(format!("acl.policies[{}]: invalid rule '{}'", i, rule));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.policies.is_empty() || self.default == Decision::Deny || self.deny_private
+    }
+
+    // Whether `user`, a member of `group`, is kept off internal addresses.
+    pub fn guards(&self, user: &str, group: Option<&str>) -> bool {
+        self.deny_private && !groups::covers(&self.private_users, user, group)
+    }
+
+    fn policy(&self, user: &str, group: Option<&str>) -> Option<(usize, &Policy)> {
+        self.policies
+            .iter()
+            .enumerate()
+            .find(|(_, policy)| groups::covers(&policy.users, user, group))
+    }
+
+    // Err(reason) when `user`, a member of `group`, may not reach host:port.
+    pub fn check(&self, user: &str, group: Option<&str>, host: &str, port: u16) -> Result<(), String> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let Some((i, policy)) = self.policy(user, group) else {
+            return match self.default {
+                Decision::Allow => Ok(()),
+                Decision::Deny => Err("no policy for the user".to_string()),
+            };
+        };
+        let matches = |rule: &String| parse(rule).is_some_and(|(pattern, low, high)| {
+            host_matches(pattern, host) && (low..=high).contains(&port)
+        });
+        if let Some(rule) = policy.deny.iter().find(|r| matches(r)) {
+            return Err(format!("policy {} denies '{}'", i, rule));
+        }
+        if !policy.allow.is_empty() && !policy.allow.iter().any(matches) {
+            return Err(format!("not allowed by policy {}", i));
+        }
+        Ok(())
+    }
+}
+
+// "host[:port[-port]]" -> (host pattern, lowest port, highest port). IPv6
+// addresses go in brackets when a port follows: "[2001:db8::1]:443".
+fn parse(rule: &str) -> Option<(&str, u16, u16)> {
+    let (host, ports) = match rule.strip_prefix('[') {
+        Some(rest) => {
+            let (host, after) = rest.split_once(']')?;
+            match after {
+                "" => (host, None),
+                _ => (host, Some(after.strip_prefix(':')?)),
+            }
+        }
+        None if rule.matches(':').count() > 1 => (rule, None),
+        None => match rule.split_once(':') {
+            Some((host, ports)) => (host, Some(ports)),
+            None => (rule, None),
+        },
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let (low, high) = match ports {
+        None => (0, u16::MAX),
+        Some(ports) => match ports.split_once('-') {
+            Some((low, high)) => (low.parse().ok()?, high.parse().ok()?),
+            None => {
+                let port = ports.parse().ok()?;
+                (port, port)
+            }
+        },
+    };
+    (low <= high).then_some((host, low, high))
+}
+
+// The host and port a request is for; None if it names no host, which the
+// handlers reject anyway.
+pub fn destination(req: &Request<Body>) -> Option<(String, u16)> {
+    let authority = req.uri().authority()?;
+    let default = match (req.method(), req.uri().scheme_str()) {
+        (&Method::CONNECT, _) | (_, Some("https")) => 443,
+        _ => 80,
+    };
+    Some((authority.host().to_string(), authority.port_u16().unwrap_or(default)))
+}
@@ -0,0 +1,434 @@
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use tracing::{info, warn};
+
+use crate::cors::{self, CorsConfig};
+use crate::logging::escape;
+use crate::snapshot;
+use crate::userdb::UserDb;
+use crate::users::{self, UserLimits};
+use crate::AppState;
+
+#[derive(Debug, Deserialize)]
+pub struct AdminConfig {
+    // Serve the admin API on the proxy listener
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_path")]
+    pub path: String,
+    // Required as "Authorization: Bearer <token>"
+    pub token: Option<String>,
+    // Token that can only read, e.g. for support staff
+    pub read_only_token: Option<String>,
+    // Reject changes whatever the token, e.g. on a replica instance
+    #[serde(default)]
+    pub read_only: bool,
+    // HMAC key signing configuration snapshots; shared by instances that
+    // exchange them
+    pub snapshot_key: Option<String>,
+    // Browser dashboards allowed to call the API
+    #[serde(default)]
+    pub cors: CorsConfig,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig {
+            enabled: false,
+            path: default_path(),
+            token: None,
+            read_only_token: None,
+            read_only: false,
+            snapshot_key: None,
+            cors: CorsConfig::default(),
+        }
+    }
+}
+
+fn default_path() -> String {
+    "/admin".to_string()
+}
+
+impl AdminConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let set = |token: &Option<String>| token.as_deref().is_some_and(|t| !t.is_empty());
+        if self.enabled && !set(&self.token) && !set(&self.read_only_token) {
+            return Err("admin API is enabled but admin.token is not set".to_string());
+        }
+        if set(&self.token) && self.token == self.read_only_token {
+            return Err("admin.read_only_token must differ from admin.token".to_string());
+        }
+        if self.snapshot_key.as_deref().is_some_and(|k| k.len() < 32) {
+            return Err("admin.snapshot_key must be at least 32 bytes".to_string());
+        }
+        self.cors.validate("admin")
+    }
+
+    // Whether an origin-form request is addressed to the admin API.
+    pub fn matches(&self, req: &Request<Body>) -> bool {
+        let path = req.uri().path();
+        self.enabled
+            && req.uri().authority().is_none()
+            && path
+                .strip_prefix(self.path.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    // None if the token is missing or wrong, otherwise whether the caller
+    // may change anything.
+    fn access(&self, req: &Request<Body>) -> Option<Access> {
+        if self.is_admin_token(req) {
+            Some(if self.read_only { Access::Read } else { Access::Write })
+        } else if token_matches(req, &self.read_only_token) {
+            Some(Access::Read)
+        } else {
+            None
+        }
+    }
+
+    // Whether the full admin token was given, even on a read_only instance.
+    fn is_admin_token(&self, req: &Request<Body>) -> bool {
+        token_matches(req, &self.token)
+    }
+}
+
+fn token_matches(req: &Request<Body>, token: &Option<String>) -> bool {
+    let given = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+    // Constant time, so the token cannot be guessed a byte at a time
+    match (token.as_deref(), given) {
+        (Some(t), Some(given)) => !t.is_empty() && t.len() == given.len() && openssl::memcmp::eq(t.as_bytes(), given.as_bytes()),
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Access {
+    Read,
+    Write,
+}
+
+pub async fn handle(req: Request<Body>, state: &AppState, client_addr: SocketAddr) -> Response<Body> {
+    let methods = if state.config.admin.read_only { "GET" } else { "GET, PUT, DELETE" };
+    cors::wrap(&state.config.admin.cors, methods, req, |req| respond(req, state, client_addr)).await
+}
+
+async fn respond(req: Request<Body>, state: &AppState, client_addr: SocketAddr) -> Response<Body> {
+    let admin = &state.config.admin;
+    let Some(access) = admin.access(&req) else {
+        warn!("🚫 Unauthorized admin API request from {}", client_addr);
+        return text(StatusCode::UNAUTHORIZED, "Admin token required");
+    };
+    if access == Access::Read && req.method() != Method::GET {
+        warn!(
+            "🚫 Read-only admin API request from {} tried {} {}",
+            client_addr,
+            req.method(),
+            req.uri().path()
+        );
+        return text(StatusCode::FORBIDDEN, "Admin API access is read-only");
+    }
+
+    let method = req.method().clone();
+    let route = req.uri().path()[admin.path.len()..].trim_matches('/').to_string();
+    let segments: Vec<&str> = route.split('/').collect();
+    match (&method, segments.as_slice()) {
+        (&Method::GET, ["flags"]) => json(StatusCode::OK, flags_json(state)),
+        (&Method::GET, ["abuse"]) => {
+            if req.uri().query().is_some_and(|q| q.split('&').any(|p| p == "format=csv")) {
+                Response::builder()
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, "text/csv")
+                    .body(Body::from(state.abuse.to_csv()))
+                    .unwrap()
+            } else {
+                json(StatusCode::OK, state.abuse.to_json())
+            }
+        }
+        (&Method::PUT, ["flags", name]) => {
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap_or_default();
+            let on = match std::str::from_utf8(&body).map(str::trim) {
+                Ok("true" | "on" | "1") => true,
+                Ok("false" | "off" | "0") => false,
+                _ => return text(StatusCode::BAD_REQUEST, "Body must be true or false"),
+            };
+            let actor = format!("admin@{}", client_addr.ip());
+            match state.flags.set(name, on, &actor) {
+                Some(_) => json(StatusCode::OK, flags_json(state)),
+                None => text(StatusCode::NOT_FOUND, "Unknown feature flag"),
+            }
+        }
+        (_, ["snapshot"]) => {
+            let Some(key) = admin.snapshot_key.as_deref() else {
+                return text(StatusCode::CONFLICT, "Snapshots need admin.snapshot_key");
+            };
+            // Snapshots carry secrets, so the read-only token cannot take one
+            if !admin.is_admin_token(&req) {
+                return text(StatusCode::FORBIDDEN, "Snapshots need the admin token");
+            }
+            let actor = format!("admin@{}", client_addr.ip());
+            match method {
+                Method::GET => {
+                    info!(target: "audit", actor = %actor, "configuration snapshot exported");
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .header(CONTENT_TYPE, "application/toml")
+                        .body(Body::from(snapshot::export(state, key)))
+                        .unwrap()
+                }
+                Method::PUT => {
+                    let body = hyper::body::to_bytes(req.into_body()).await.unwrap_or_default();
+                    match snapshot::restore(state, key, &body, &actor) {
+                        Ok(summary) => json(StatusCode::OK, summary),
+                        Err(snapshot::Error::Invalid(message)) => {
+                            warn!("🚫 Snapshot from {} refused: {}", client_addr, message);
+                            json(StatusCode::BAD_REQUEST, format!("{{\"error\":\"{}\"}}", escape(&message)))
+                        }
+                        Err(snapshot::Error::Failed(message)) => {
+                            warn!("⚠️ Snapshot restore failed: {}", message);
+                            json(StatusCode::INTERNAL_SERVER_ERROR, format!("{{\"error\":\"{}\"}}", escape(&message)))
+                        }
+                    }
+                }
+                _ => text(StatusCode::NOT_FOUND, "Not found"),
+            }
+        }
+        (&Method::GET, ["har"]) => {
+            let files: Vec<String> = state.har.files().iter().map(|f| format!("\"{}\"", escape(f))).collect();
+            json(StatusCode::OK, format!("{{\"session\":{},\"files\":[{}]}}", state.har.status(), files.join(",")))
+        }
+        (&Method::PUT, ["har"]) => {
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap_or_default();
+            let Some(fields) = form(&body) else {
+                return text(StatusCode::BAD_REQUEST, "Body must be a form");
+            };
+            let mut user = None;
+            let mut bodies = false;
+            for (name, value) in fields {
+                match name.as_str() {
+                    "user" if !value.is_empty() => user = Some(value),
+                    "bodies" => bodies = matches!(value.as_str(), "true" | "on" | "1"),
+                    _ => return text(StatusCode::BAD_REQUEST, "Unknown field"),
+                }
+            }
+            let actor = format!("admin@{}", client_addr.ip());
+            match state.har.start(user, bodies) {
+                Ok(status) => {
+                    info!(target: "audit", actor = %actor, "HAR recording started");
+                    json(StatusCode::OK, status)
+                }
+                Err(message) => text(StatusCode::CONFLICT, message),
+            }
+        }
+        (&Method::DELETE, ["har"]) => match state.har.stop() {
+            Ok(Some(saved)) => json(StatusCode::OK, saved),
+            Ok(None) => text(StatusCode::NOT_FOUND, "No recording is running"),
+            Err(e) => {
+                warn!("⚠️ Could not save HAR recording: {}", e);
+                text(StatusCode::INTERNAL_SERVER_ERROR, "Could not save the recording")
+            }
+        },
+        (&Method::GET, ["har", file]) => {
+            // Recordings carry cookies and credentials, like snapshots
+            if !admin.is_admin_token(&req) {
+                return text(StatusCode::FORBIDDEN, "Recordings need the admin token");
+            }
+            match state.har.read(file) {
+                Some(document) => Response::builder()
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(document))
+                    .unwrap(),
+                None => text(StatusCode::NOT_FOUND, "No such recording"),
+            }
+        }
+        (_, ["users", ..]) => {
+            let Some(db) = state.users.db() else {
+                return text(StatusCode::CONFLICT, "Users are managed in the config file; set user_store");
+            };
+            let actor = format!("admin@{}", client_addr.ip());
+            let response = users_route(req, db, &segments[1..], &actor).await;
+            // A changed password or removed user must not stay logged in
+            if let Some(name) = segments.get(1) {
+                state.auth_cache.forget(name);
+            }
+            response
+        }
+        _ => text(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+// GET users, PUT users/{name} (form body: password, max_connections,
+// bandwidth, quota_bytes; "none" clears a limit), DELETE users/{name},
+// DELETE users/{name}/usage.
+async fn users_route(req: Request<Body>, db: &UserDb, route: &[&str], actor: &str) -> Response<Body> {
+    let method = req.method().clone();
+    let body = match method {
+        Method::PUT => hyper::body::to_bytes(req.into_body()).await.unwrap_or_default(),
+        _ => Default::default(),
+    };
+    // SQLite and password hashing block, so keep them off the other tasks
+    tokio::task::block_in_place(|| users_change(db, &method, route, &body, actor))
+}
+
+fn users_change(db: &UserDb, method: &Method, route: &[&str], body: &[u8], actor: &str) -> Response<Body> {
+    let result = match (method, route) {
+        (&Method::GET, []) => return json(StatusCode::OK, users_json(db)),
+        (&Method::PUT, [name]) => {
+            let Some(fields) = form(body) else {
+                return text(StatusCode::BAD_REQUEST, "Body must be form-encoded");
+            };
+            let (password, limits) = match user_update(db.limits(name), &fields) {
+                Ok(update) => update,
+                Err(message) => return text(StatusCode::BAD_REQUEST, message),
+            };
+            if password.is_some() && !users::valid_name(name) {
+                return text(StatusCode::BAD_REQUEST, "Invalid user name");
+            }
+            put_user(db, name, password, &limits)
+        }
+        (&Method::DELETE, [name]) => db.remove(name).map(|found| found.then_some("removed")),
+        (&Method::DELETE, [name, "usage"]) => db.reset_usage(name).map(|found| found.then_some("usage reset")),
+        _ => return text(StatusCode::NOT_FOUND, "Not found"),
+    };
+    match result {
+        Ok(Some(change)) => {
+            info!(target: "audit", user = %route[0], actor = %actor, change = change, "user store changed");
+            json(StatusCode::OK, users_json(db))
+        }
+        Ok(None) => text(StatusCode::NOT_FOUND, "No such user"),
+        Err(e) => {
+            warn!("⚠️ User store update failed: {}", e);
+            text(StatusCode::INTERNAL_SERVER_ERROR, "User store update failed")
+        }
+    }
+}
+
+// The password and limits a PUT asks for, starting from the current limits.
+fn user_update(
+    mut limits: UserLimits,
+    fields: &[(String, String)],
+) -> Result<(Option<&str>, UserLimits), &'static str> {
+    let mut password = None;
+    for (key, value) in fields {
+        let number = || match value.as_str() {
+            "none" => Ok(None),
+            _ => value.parse().map(Some).map_err(|_| "Limits must be a number or none"),
+        };
+        match key.as_str() {
+            "password" if !value.is_empty() => password = Some(value.as_str()),
+            "max_connections" => limits.max_connections = number()?.map(|n: u64| n as usize),
+            "bandwidth" => limits.bandwidth = number()?,
+            "quota_bytes" => limits.quota_bytes = number()?,
+            _ => return Err("Unknown field"),
+        }
+    }
+    Ok((password, limits))
+}
+
+// Creates the user if a password is given and the user does not exist yet.
+fn put_user(db: &UserDb, name: &str, password: Option<&str>, limits: &UserLimits) -> Result<Option<&'static str>, String> {
+    let hashed = match password {
+        Some(password) => Some(users::hash(password).map_err(|e| e.to_string())?),
+        None => None,
+    };
+    let change = match (db.password(name).is_some(), hashed) {
+        (true, Some(hashed)) => {
+            db.set_password(name, &hashed)?;
+            "password and limits set"
+        }
+        (true, None) => "limits set",
+        (false, Some(hashed)) => {
+            db.insert(name, &hashed)?;
+            "added"
+        }
+        (false, None) => return Ok(None),
+    };
+    db.set_limits(name, limits)?;
+    Ok(Some(change))
+}
+
+fn users_json(db: &UserDb) -> String {
+    let number = |n: Option<u64>| n.map_or("null".to_string(), |n| n.to_string());
+    let users: Vec<String> = db
+        .list()
+        .into_iter()
+        .map(|u| {
+            format!(
+                "{{\"name\":\"{}\",\"max_connections\":{},\"bandwidth\":{},\"quota_bytes\":{},\"requests\":{},\"bytes\":{}}}",
+                escape(&u.name),
+                number(u.limits.max_connections.map(|n| n as u64)),
+                number(u.limits.bandwidth),
+                number(u.limits.quota_bytes),
+                u.requests,
+                u.bytes
+            )
+        })
+        .collect();
+    format!("[{}]", users.join(","))
+}
+
+// application/x-www-form-urlencoded pairs.
+pub fn form(body: &[u8]) -> Option<Vec<(String, String)>> {
+    let body = std::str::from_utf8(body).ok()?.trim();
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            // '+' is a space in forms, but not elsewhere
+            Some((percent_decode(&key.replace('+', " "))?, percent_decode(&value.replace('+', " "))?))
+        })
+        .collect()
+}
+
+// "%XX" escapes, as in form fields and URL paths; None if one is broken or
+// the result is not UTF-8.
+pub fn percent_decode(s: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                // from_str_radix alone would take "+1"
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            _ => out.push(b),
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn flags_json(state: &AppState) -> String {
+    let fields: Vec<String> = state
+        .flags
+        .snapshot()
+        .into_iter()
+        .map(|(name, on)| format!("\"{}\":{}", escape(name), on))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn json(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn text(status: StatusCode, body: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap()
+}
@@ -0,0 +1,193 @@
+use hyper::Uri;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_RESPONSE: u64 = 4 * 1024 * 1024;
+// Blocklists and the like can be far larger than an API response
+const MAX_DOWNLOAD: u64 = 64 * 1024 * 1024;
+
+// Fire-and-forget JSON POST to an http:// or https:// webhook. Runs on the
+// blocking pool so native-tls can be used without an async TLS stack.
+pub fn send_webhook(url: &str, json: String) {
+    let url = url.to_string();
+    tokio::task::spawn_blocking(move || match post(&url, &json) {
+        Ok(status) if (200..300).contains(&status) => debug!("Webhook {} accepted alert", url),
+        Ok(status) => warn!("⚠️ Webhook {} answered {}", url, status),
+        Err(e) => warn!("⚠️ Webhook {} failed: {}", url, e),
+    });
+}
+
+fn post(url: &str, json: &str) -> io::Result<u16> {
+    request("POST", url, Some(("application/json", json)), None, WEBHOOK_TIMEOUT, MAX_RESPONSE).map(|(status, _)| status)
+}
+
+// Blocking GET of an http:// or https:// URL, e.g. an identity provider's
+// key set. Returns the status and body.
+pub fn get(url: &str, timeout: Duration) -> io::Result<(u16, Vec<u8>)> {
+    request("GET", url, None, None, timeout, MAX_RESPONSE)
+}
+
+// Blocking GET of a large file, e.g. a blocklist. A body over the limit is
+// an error rather than silently cut short.
+pub fn download(url: &str, timeout: Duration) -> io::Result<(u16, Vec<u8>)> {
+    request("GET", url, None, None, timeout, MAX_DOWNLOAD)
+}
+
+// Blocking form POST, e.g. to an OAuth token endpoint, with an optional
+// Authorization header value.
+pub fn post_form(url: &str, form: &str, authorization: Option<&str>, timeout: Duration) -> io::Result<(u16, Vec<u8>)> {
+    request(
+        "POST",
+        url,
+        Some(("application/x-www-form-urlencoded", form)),
+        authorization,
+        timeout,
+        MAX_RESPONSE,
+    )
+}
+
+fn request(
+    method: &str,
+    url: &str,
+    body: Option<(&str, &str)>,
+    authorization: Option<&str>,
+    timeout: Duration,
+    limit: u64,
+) -> io::Result<(u16, Vec<u8>)> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_string());
+    let uri: Uri = url.parse().map_err(|_| invalid("invalid URL"))?;
+    let host = uri.host().ok_or_else(|| invalid("URL has no host"))?;
+    let tls = match uri.scheme_str() {
+        Some("https") => true,
+        Some("http") => false,
+        _ => return Err(invalid("URL must be http or https")),
+    };
+    let default_port = if tls { 443 } else { 80 };
+    let port = uri.port_u16().unwrap_or(default_port);
+    let host_header = match port == default_port {
+        true => host.to_string(),
+        false => format!("{}:{}", host, port),
+    };
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let authorization = authorization.map(|a| format!("Authorization: {}\r\n", a)).unwrap_or_default();
+    let body = match body {
+        Some((content_type, body)) => format!(
+            "Content-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        ),
+        None => "\r\n".to_string(),
+    };
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nAccept: application/json\r\nConnection: close\r\n{}{}",
+        method, path, host_header, authorization, body
+    );
+
+    // `timeout` covers the whole exchange, not each read
+    let deadline = Instant::now() + timeout;
+    let stream = Deadline {
+        stream: connect(host.trim_start_matches('[').trim_end_matches(']'), port, deadline)?,
+        deadline,
+    };
+    let mut response = Vec::new();
+    if tls {
+        let connector = native_tls::TlsConnector::new().map_err(io::Error::other)?;
+        let mut stream = connector.connect(host, stream).map_err(io::Error::other)?;
+        stream.write_all(request.as_bytes())?;
+        stream.take(limit + 1).read_to_end(&mut response)?;
+    } else {
+        let mut stream = stream;
+        stream.write_all(request.as_bytes())?;
+        stream.take(limit + 1).read_to_end(&mut response)?;
+    }
+
+    if response.len() as u64 > limit {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("response larger than {} bytes", limit)));
+    }
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response");
+    let head_end = response.windows(4).position(|w| w == b"\r\n\r\n").ok_or_else(malformed)?;
+    let head = String::from_utf8_lossy(&response[..head_end]).to_string();
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(malformed)?;
+    let body = &response[head_end + 4..];
+    let chunked = head.lines().any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("transfer-encoding") && value.to_ascii_lowercase().contains("chunked")
+        })
+    });
+    let body = if chunked { dechunk(body).ok_or_else(malformed)? } else { body.to_vec() };
+    Ok((status, body))
+}
+
+fn connect(host: &str, port: u16, deadline: Instant) -> io::Result<TcpStream> {
+    let mut last = io::Error::new(io::ErrorKind::NotFound, "host has no addresses");
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, remaining(deadline)?) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+fn remaining(deadline: Instant) -> io::Result<Duration> {
+    match deadline.checked_duration_since(Instant::now()) {
+        Some(left) if !left.is_zero() => Ok(left),
+        _ => Err(io::Error::new(io::ErrorKind::TimedOut, "request timed out")),
+    }
+}
+
+// A socket timeout shows up as WouldBlock on Unix.
+fn timed_out(e: io::Error) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::WouldBlock => io::Error::new(io::ErrorKind::TimedOut, "request timed out"),
+        _ => e,
+    }
+}
+
+// A socket whose reads and writes give up once the deadline has passed.
+#[derive(Debug)]
+struct Deadline {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(remaining(self.deadline)?))?;
+        self.stream.read(buf).map_err(timed_out)
+    }
+}
+
+impl Write for Deadline {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(remaining(self.deadline)?))?;
+        self.stream.write(buf).map_err(timed_out)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(out);
+        }
+        out.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
@@ -0,0 +1,195 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::bandwidth::RateLimiter;
+use crate::AppState;
+
+// Flags users whose traffic jumps well above their own recent normal, e.g.
+// leaked credentials being resold:
+//
+//   [anomaly]
+//   enabled = true
+//   factor = 5.0
+//   throttle = 65536
+#[derive(Debug, Deserialize)]
+pub struct AnomalyConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Seconds per sample
+    #[serde(default = "default_window")]
+    pub window: u64,
+    // Samples the baseline averages over (exponentially weighted)
+    #[serde(default = "default_baseline")]
+    pub baseline: u32,
+    // Samples a user needs before being judged
+    #[serde(default = "default_warmup")]
+    pub warmup: u32,
+    // A sample this many times the baseline is an anomaly
+    #[serde(default = "default_factor")]
+    pub factor: f64,
+    // Samples below these volumes are never anomalies
+    #[serde(default = "default_min_requests")]
+    pub min_requests: u64,
+    #[serde(default = "default_min_bytes")]
+    pub min_bytes: u64,
+    // Bandwidth cap in bytes/s for new connections of a flagged user
+    pub throttle: Option<u64>,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        AnomalyConfig {
+            enabled: false,
+            window: default_window(),
+            baseline: default_baseline(),
+            warmup: default_warmup(),
+            factor: default_factor(),
+            min_requests: default_min_requests(),
+            min_bytes: default_min_bytes(),
+            throttle: None,
+        }
+    }
+}
+
+fn default_window() -> u64 {
+    60
+}
+
+fn default_baseline() -> u32 {
+    60
+}
+
+fn default_warmup() -> u32 {
+    10
+}
+
+fn default_factor() -> f64 {
+    5.0
+}
+
+fn default_min_requests() -> u64 {
+    50
+}
+
+fn default_min_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+impl AnomalyConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.window == 0 || self.baseline == 0 {
+            return Err("anomaly.window and anomaly.baseline must be positive".to_string());
+        }
+        if self.factor.is_nan() || self.factor <= 1.0 {
+            return Err("anomaly.factor must be greater than 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct UserStats {
+    // Current sample
+    requests: u64,
+    bytes: u64,
+    baseline_requests: f64,
+    baseline_bytes: f64,
+    samples: u32,
+    // Set while the user is flagged and throttling is configured
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+#[derive(Default)]
+pub struct Anomalies {
+    users: Mutex<HashMap<String, UserStats>>,
+}
+
+impl Anomalies {
+    pub fn record(&self, config: &AnomalyConfig, user: &str, bytes: u64) {
+        if !config.enabled {
+            return;
+        }
+        let mut users = self.users.lock().unwrap();
+        let stats = users.entry(user.to_string()).or_default();
+        stats.requests += 1;
+        stats.bytes += bytes;
+    }
+
+    pub fn limiter(&self, user: &str) -> Option<Arc<RateLimiter>> {
+        self.users.lock().unwrap().get(user)?.limiter.clone()
+    }
+
+    // Close the current sample: compare it with each user's baseline, then
+    // fold it in. Anomalous samples are folded in too, so a lasting change
+    // in behaviour becomes the new normal after about `baseline` samples.
+    fn evaluate(&self, config: &AnomalyConfig) {
+        let alpha = 2.0 / (config.baseline as f64 + 1.0);
+        let mut users = self.users.lock().unwrap();
+        for (user, stats) in users.iter_mut() {
+            let mut anomalous = false;
+            if stats.samples >= config.warmup {
+                let checks = [
+                    ("requests", stats.requests, stats.baseline_requests, config.min_requests),
+                    ("bytes", stats.bytes, stats.baseline_bytes, config.min_bytes),
+                ];
+                for (metric, current, baseline, minimum) in checks {
+                    if current >= minimum && current as f64 > baseline * config.factor {
+                        anomalous = true;
+                        warn!(
+                            target: "audit",
+                            user = %user,
+                            metric = metric,
+                            current = current,
+                            baseline = baseline.round() as u64,
+                            window = config.window,
+                            "traffic anomaly"
+                        );
+                    }
+                }
+            }
+            match (anomalous, config.throttle, stats.limiter.is_some()) {
+                (true, Some(rate), false) => {
+                    stats.limiter = Some(Arc::new(RateLimiter::new(rate)));
+                    warn!(target: "audit", user = %user, rate = rate, "user throttled after traffic anomaly");
+                }
+                (false, _, true) => {
+                    stats.limiter = None;
+                    info!(target: "audit", user = %user, "anomaly throttle lifted");
+                }
+                _ => {}
+            }
+            if stats.samples == 0 {
+                stats.baseline_requests = stats.requests as f64;
+                stats.baseline_bytes = stats.bytes as f64;
+            } else {
+                stats.baseline_requests += alpha * (stats.requests as f64 - stats.baseline_requests);
+                stats.baseline_bytes += alpha * (stats.bytes as f64 - stats.baseline_bytes);
+            }
+            stats.samples = stats.samples.saturating_add(1);
+            stats.requests = 0;
+            stats.bytes = 0;
+        }
+    }
+}
+
+pub fn spawn(state: Arc<AppState>) {
+    let config = &state.config.anomaly;
+    if !config.enabled {
+        return;
+    }
+    info!(
+        "📈 Anomaly detection enabled ({}s samples, factor {})",
+        config.window, config.factor
+    );
+    tokio::spawn(async move {
+        let window = Duration::from_secs(state.config.anomaly.window);
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + window, window);
+        loop {
+            ticker.tick().await;
+            state.anomalies.evaluate(&state.config.anomaly);
+        }
+    });
+}
@@ -0,0 +1,102 @@
+use hyper::header::{HeaderMap, HeaderName};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// Shorter keys are too easy to guess
+const MIN_KEY_LEN: usize = 16;
+
+// Headers with a meaning of their own, which the proxy or origin still needs
+const RESERVED: &[&str] = &[
+    "proxy-authorization",
+    "authorization",
+    "host",
+    "connection",
+    "cookie",
+    "content-length",
+    "transfer-encoding",
+];
+
+// For clients that cannot send Proxy-Authorization (some SDKs drop it):
+//
+//   [api_keys]
+//   header = "X-Proxy-Key"
+//
+//   [api_keys.keys]
+//   "6f1c0e9a3b7d4c2e8a5f" = "alice"
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyConfig {
+    #[serde(default = "default_header")]
+    pub header: String,
+    // key -> user name; a user may have several keys
+    #[serde(default)]
+    pub keys: HashMap<String, String>,
+}
+
+impl Default for ApiKeyConfig {
+    fn default() -> Self {
+        ApiKeyConfig {
+            header: default_header(),
+            keys: HashMap::new(),
+        }
+    }
+}
+
+fn default_header() -> String {
+    "X-Proxy-Key".to_string()
+}
+
+impl ApiKeyConfig {
+    pub fn enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let name = HeaderName::from_bytes(self.header.as_bytes())
+            .map_err(|_| format!("api_keys.header '{}' is not a valid header name", self.header))?;
+        if RESERVED.contains(&name.as_str()) {
+            return Err(format!("api_keys.header cannot be '{}'", self.header));
+        }
+        for (key, user) in &self.keys {
+            if key.len() < MIN_KEY_LEN || key.chars().any(|c| c.is_whitespace() || c.is_control()) {
+                return Err(format!(
+                    "api_keys: keys must be at least {} characters without spaces (key for '{}')",
+                    MIN_KEY_LEN, user
+                ));
+            }
+            if !crate::users::valid_name(user) {
+                return Err(format!("api_keys: invalid user name '{}'", user));
+            }
+        }
+        Ok(())
+    }
+
+    // The configured header's value, if the request has one.
+    pub fn presented<'a>(&self, headers: &'a HeaderMap) -> Option<&'a [u8]> {
+        match self.enabled() {
+            true => headers.get(self.header.as_str()).map(|v| v.as_bytes()),
+            false => None,
+        }
+    }
+
+    // Every key is compared, in constant time, so timing tells nothing
+    // about which keys exist.
+    pub fn user(&self, presented: &[u8]) -> Option<&str> {
+        let mut found = None;
+        for (key, user) in &self.keys {
+            if key.len() == presented.len() && openssl::memcmp::eq(key.as_bytes(), presented) {
+                found = Some(user.as_str());
+            }
+        }
+        found
+    }
+
+    // Keep the key from reaching the origin or a parent proxy.
+    pub fn strip(&self, headers: &mut HeaderMap) {
+        if self.enabled() {
+            headers.remove(self.header.as_str());
+        }
+    }
+}
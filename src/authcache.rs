@@ -0,0 +1,106 @@
+use openssl::hash::{hash as digest, MessageDigest};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Successful Basic logins, so a client's every request does not pay for
+// the password check (PBKDF2, an LDAP bind, a one-time code):
+//
+//   [auth_cache]
+//   ttl = 30
+//   max_entries = 10000
+#[derive(Debug, Deserialize)]
+pub struct AuthCacheConfig {
+    // Seconds a Proxy-Authorization value stays accepted; 0 turns it off
+    #[serde(default = "default_ttl")]
+    pub ttl: u64,
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+}
+
+impl Default for AuthCacheConfig {
+    fn default() -> Self {
+        AuthCacheConfig {
+            ttl: default_ttl(),
+            max_entries: default_max_entries(),
+        }
+    }
+}
+
+fn default_ttl() -> u64 {
+    30
+}
+
+fn default_max_entries() -> usize {
+    10_000
+}
+
+impl AuthCacheConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.ttl > 0 && self.max_entries == 0 {
+            return Err("auth_cache.max_entries must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+// What a Proxy-Authorization value is remembered by, here and for TOTP
+// sessions.
+pub fn key(header: &[u8]) -> Option<Vec<u8>> {
+    digest(MessageDigest::sha256(), header).ok().map(|key| key.to_vec())
+}
+
+// SHA-256 of the header value -> (user, expiry). Only the digest is kept,
+// so the credentials themselves are not held in memory.
+#[derive(Default)]
+pub struct AuthCache {
+    entries: Mutex<HashMap<Vec<u8>, (String, Instant)>>,
+}
+
+impl AuthCache {
+    pub fn get(&self, config: &AuthCacheConfig, header: &[u8]) -> Option<String> {
+        if config.ttl == 0 {
+            return None;
+        }
+        let key = key(header)?;
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(&key[..]) {
+            Some((user, expires)) if *expires > Instant::now() => Some(user.clone()),
+            Some(_) => {
+                entries.remove(&key[..]);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, config: &AuthCacheConfig, header: &[u8], user: &str) {
+        if config.ttl == 0 {
+            return;
+        }
+        let Some(key) = key(header) else {
+            return;
+        };
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= config.max_entries {
+            entries.retain(|_, (_, expires)| *expires > now);
+            // Still full of live entries: start over rather than grow
+            if entries.len() >= config.max_entries {
+                entries.clear();
+            }
+        }
+        entries.insert(key, (user.to_string(), now + Duration::from_secs(config.ttl)));
+    }
+
+    // A user's password changed or the user was removed.
+    pub fn forget(&self, user: &str) {
+        self.entries.lock().unwrap().retain(|_, (cached, _)| cached != user);
+    }
+
+    // SIGHUP or a restored snapshot, after which any user may be gone.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
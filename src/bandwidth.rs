@@ -0,0 +1,228 @@
+use hyper::body::HttpBody;
+use hyper::Body;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::warn;
+
+use crate::buffers::BufferPool;
+use crate::pattern::host_matches;
+
+// Rates are in bytes per second, counting both directions together.
+#[derive(Debug, Default, Deserialize)]
+pub struct BandwidthConfig {
+    // Cap for each individual tunnel or HTTP request
+    pub per_connection: Option<u64>,
+    // Cap shared by all of a user's connections
+    pub per_user: Option<u64>,
+    // Per-user overrides of `per_user`
+    #[serde(default)]
+    pub users: HashMap<String, u64>,
+    // Per-connection caps for matching destinations
+    #[serde(default)]
+    pub rules: Vec<BandwidthRule>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BandwidthRule {
+    pub host: String,
+    pub rate: u64,
+}
+
+// Token bucket allowing up to one second of burst. Consumers may go into
+// debt and then sleep it off, which keeps the accounting lock-free of awaits.
+pub struct RateLimiter {
+    rate: u64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        RateLimiter {
+            rate: rate.max(1),
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    async fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (tokens, last) = &mut *state;
+            let now = Instant::now();
+            let rate = self.rate as f64;
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * rate).min(rate);
+            *last = now;
+            *tokens -= bytes as f64;
+            if *tokens < 0.0 {
+                Duration::from_secs_f64(-*tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    // Takes `n` tokens only if they are there, for traffic such as UDP
+    // datagrams that is dropped over the rate rather than held back.
+    #[cfg_attr(not(feature = "http3"), allow(dead_code))]
+    pub fn try_consume(&self, n: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        let rate = self.rate as f64;
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * rate).min(rate);
+        *last = now;
+        if *tokens < n as f64 {
+            return false;
+        }
+        *tokens -= n as f64;
+        true
+    }
+}
+
+// The set of limiters that apply to one connection.
+#[derive(Clone, Default)]
+pub struct Throttle {
+    limiters: Vec<Arc<RateLimiter>>,
+}
+
+impl Throttle {
+    pub fn with(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.limiters.push(limiter);
+        self
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.limiters.is_empty()
+    }
+
+    async fn consume(&self, bytes: usize) {
+        for limiter in &self.limiters {
+            limiter.consume(bytes).await;
+        }
+    }
+}
+
+// Long-lived per-user buckets, shared across that user's connections.
+#[derive(Default)]
+pub struct BandwidthRegistry {
+    per_user: Mutex<HashMap<String, Arc<RateLimiter>>>,
+}
+
+impl BandwidthRegistry {
+    // `user_rate` is the user's own rate from the user store, if any;
+    // `group_rate` is their group's.
+    pub fn throttle(
+        &self,
+        config: &BandwidthConfig,
+        user: &str,
+        user_rate: Option<u64>,
+        group_rate: Option<u64>,
+        host: &str,
+    ) -> Throttle {
+        let mut limiters = Vec::new();
+
+        let per_connection = config
+            .rules
+            .iter()
+            .find(|r| host_matches(&r.host, host))
+            .map(|r| r.rate)
+            .into_iter()
+            .chain(config.per_connection)
+            .min();
+        if let Some(rate) = per_connection {
+            limiters.push(Arc::new(RateLimiter::new(rate)));
+        }
+
+        if let Some(rate) = user_rate.or(config.users.get(user).copied()).or(group_rate).or(config.per_user) {
+            let mut per_user = self.per_user.lock().unwrap();
+            let limiter = per_user
+                .entry(user.to_string())
+                .or_insert_with(|| Arc::new(RateLimiter::new(rate)));
+            // The rate was changed in the user store
+            if limiter.rate != rate.max(1) {
+                *limiter = Arc::new(RateLimiter::new(rate));
+            }
+            limiters.push(limiter.clone());
+        }
+
+        Throttle { limiters }
+    }
+}
+
+// Re-stream an HTTP body through the throttle.
+pub fn throttle_body(mut body: Body, throttle: Throttle) -> Body {
+    if throttle.is_unlimited() {
+        return body;
+    }
+    let (mut sender, out) = Body::channel();
+    tokio::spawn(async move {
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    warn!("⚠️ Body error while throttling: {}", e);
+                    sender.abort();
+                    return;
+                }
+            };
+            throttle.consume(chunk.len()).await;
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+        if let Ok(Some(trailers)) = body.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    out
+}
+
+// tokio::io::copy_bidirectional with pooled buffers, paced by `throttle`
+// (which may be unlimited). Returns (bytes a->b, bytes b->a).
+pub async fn copy_bidirectional<A, B>(
+    a: &mut A,
+    b: &mut B,
+    throttle: &Throttle,
+    buffers: &BufferPool,
+) -> std::io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut a_read, mut a_write) = tokio::io::split(a);
+    let (mut b_read, mut b_write) = tokio::io::split(b);
+    tokio::try_join!(
+        copy_one_way(&mut a_read, &mut b_write, throttle, buffers),
+        copy_one_way(&mut b_read, &mut a_write, throttle, buffers),
+    )
+}
+
+async fn copy_one_way<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    throttle: &Throttle,
+    buffers: &BufferPool,
+) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = buffers.take();
+    let mut total = 0u64;
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            writer.shutdown().await?;
+            return Ok(total);
+        }
+        throttle.consume(n).await;
+        writer.write_all(&buf[..n]).await?;
+        writer.flush().await?;
+        total += n as u64;
+    }
+}
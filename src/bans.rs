@@ -0,0 +1,124 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use crate::store::Store;
+
+const STORE_PREFIX: &str = "bans.";
+
+#[derive(Debug, Deserialize)]
+pub struct HoneypotConfig {
+    // Decoy usernames; any attempt to use one bans the client IP
+    #[serde(default)]
+    pub users: Vec<String>,
+    // Seconds a ban lasts; 0 bans for good
+    #[serde(default = "default_ban_duration")]
+    pub ban_duration: u64,
+    // Alert endpoint receiving a JSON POST per hit (http or https)
+    pub webhook: Option<String>,
+}
+
+impl Default for HoneypotConfig {
+    fn default() -> Self {
+        HoneypotConfig {
+            users: Vec::new(),
+            ban_duration: default_ban_duration(),
+            webhook: None,
+        }
+    }
+}
+
+fn default_ban_duration() -> u64 {
+    86_400
+}
+
+impl HoneypotConfig {
+    pub fn is_decoy(&self, user: &str) -> bool {
+        self.users.iter().any(|u| u == user)
+    }
+
+    pub fn validate(&self, real_users: &HashMap<String, String>) -> Result<(), String> {
+        match self.users.iter().find(|u| real_users.contains_key(*u)) {
+            Some(user) => Err(format!("honeypot user '{}' is also a real user", user)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Banned client IPs, with expiry as Unix seconds (0 = never). Persisted in
+// the state store when one is configured.
+pub struct Bans {
+    ips: Mutex<HashMap<IpAddr, u64>>,
+    store: Option<Arc<Store>>,
+}
+
+impl Bans {
+    pub fn new(store: Option<Arc<Store>>) -> Self {
+        let now = unix_now();
+        let ips = store
+            .as_ref()
+            .map(|store| store.scan(STORE_PREFIX))
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(ip, until)| Some((ip.parse().ok()?, until.parse().ok()?)))
+            .filter(|(_, until)| *until == 0 || *until > now)
+            .collect();
+        Bans {
+            ips: Mutex::new(ips),
+            store,
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        match self.ips.lock().unwrap().get(&ip) {
+            Some(0) => true,
+            Some(until) => *until > unix_now(),
+            None => false,
+        }
+    }
+
+    pub fn ban(&self, ip: IpAddr, duration: u64, reason: &str) {
+        let until = if duration == 0 { 0 } else { unix_now() + duration };
+        self.insert(ip, until);
+        warn!(
+            target: "audit",
+            client_ip = %ip,
+            duration = duration,
+            reason = %reason,
+            "client banned"
+        );
+    }
+
+    // Bans in force, with their expiry.
+    pub fn list(&self) -> Vec<(IpAddr, u64)> {
+        let now = unix_now();
+        let mut bans: Vec<_> = self
+            .ips
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, until)| **until == 0 || **until > now)
+            .map(|(ip, until)| (*ip, *until))
+            .collect();
+        bans.sort();
+        bans
+    }
+
+    // Ban until a given time, e.g. when restoring a snapshot.
+    pub fn insert(&self, ip: IpAddr, until: u64) {
+        self.ips.lock().unwrap().insert(ip, until);
+        if let Some(store) = &self.store {
+            store.set(&format!("{}{}", STORE_PREFIX, ip), &until.to_string());
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::abuse::csv_field;
+use crate::logging::civil_from_days;
+use crate::AppState;
+
+const HEADER: &str = "period_start,period_end,user,requests,bytes,labels,listener\n";
+const WRITE_INTERVAL: Duration = Duration::from_secs(10);
+
+// Per-user (and per-listener) usage totals written out as one CSV file per billing period:
+//
+//   [billing]
+//   enabled = true
+//   dir = "/var/lib/secure-proxy/billing"
+//   interval = 3600
+//
+//   [billing.labels.alice]
+//   customer = "acme"
+#[derive(Debug, Deserialize)]
+pub struct BillingConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_dir")]
+    pub dir: PathBuf,
+    // Period length in seconds; periods are aligned to the epoch (UTC)
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    // Extra columns per user, e.g. customer or plan
+    #[serde(default)]
+    pub labels: HashMap<String, BTreeMap<String, String>>,
+}
+
+impl Default for BillingConfig {
+    fn default() -> Self {
+        BillingConfig {
+            enabled: false,
+            dir: default_dir(),
+            interval: default_interval(),
+            labels: HashMap::new(),
+        }
+    }
+}
+
+fn default_dir() -> PathBuf {
+    PathBuf::from("billing")
+}
+
+fn default_interval() -> u64 {
+    3600
+}
+
+impl BillingConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enabled && self.interval < 60 {
+            return Err("billing.interval must be at least 60 seconds".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Usage {
+    requests: u64,
+    bytes: u64,
+}
+
+struct Period {
+    start: u64,
+    // (user, listener) -> usage
+    usage: BTreeMap<(String, String), Usage>,
+}
+
+pub struct Billing {
+    dir: PathBuf,
+    interval: u64,
+    labels: HashMap<String, BTreeMap<String, String>>,
+    // Distinguishes files from processes sharing the directory
+    instance: String,
+    current: Mutex<Period>,
+    // Finished periods not yet on disk
+    pending: Mutex<Vec<Period>>,
+}
+
+impl Billing {
+    pub fn new(config: &BillingConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        Ok(Billing {
+            dir: config.dir.clone(),
+            interval: config.interval,
+            labels: config.labels.clone(),
+            instance: format!("{}-{}", crate::loops::hostname(), std::process::id()),
+            current: Mutex::new(Period {
+                start: period_start(now(), config.interval),
+                usage: BTreeMap::new(),
+            }),
+            pending: Mutex::default(),
+        })
+    }
+
+    pub fn record(&self, user: &str, listener: &str, bytes: u64) {
+        let start = period_start(now(), self.interval);
+        let mut current = self.current.lock().unwrap();
+        if current.start != start {
+            let finished = std::mem::replace(
+                &mut *current,
+                Period {
+                    start,
+                    usage: BTreeMap::new(),
+                },
+            );
+            self.pending.lock().unwrap().push(finished);
+        }
+        let usage = current.usage.entry((user.to_string(), listener.to_string())).or_default();
+        usage.requests += 1;
+        usage.bytes += bytes;
+    }
+
+    // Close the current period if its time is up and write out finished
+    // ones. `all` also writes the unfinished current period (at exit).
+    pub fn flush(&self, all: bool) {
+        let start = period_start(now(), self.interval);
+        {
+            let mut current = self.current.lock().unwrap();
+            if all || current.start != start {
+                let finished = std::mem::replace(
+                    &mut *current,
+                    Period {
+                        start,
+                        usage: BTreeMap::new(),
+                    },
+                );
+                self.pending.lock().unwrap().push(finished);
+            }
+        }
+        let periods = std::mem::take(&mut *self.pending.lock().unwrap());
+        let mut failed = Vec::new();
+        for period in periods {
+            if period.usage.is_empty() {
+                continue;
+            }
+            if let Err(e) = self.write(&period) {
+                warn!("⚠️ Failed to write billing period {}: {}", timestamp(period.start), e);
+                failed.push(period);
+            }
+        }
+        // Retried on the next flush
+        self.pending.lock().unwrap().extend(failed);
+    }
+
+    // One file per period and process, written under a temp name and
+    // renamed, so a file is either complete or absent and writing the same
+    // period again replaces it rather than adding rows.
+    fn write(&self, period: &Period) -> io::Result<()> {
+        let (start, end) = (timestamp(period.start), timestamp(period.start + self.interval));
+        let mut out = String::from(HEADER);
+        for ((user, listener), usage) in &period.usage {
+            let labels: Vec<String> = self
+                .labels
+                .get(user)
+                .into_iter()
+                .flatten()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect();
+            let _ = writeln!(
+                out,
+                "{},{},{},{},{},{},{}",
+                start,
+                end,
+                csv_field(user),
+                usage.requests,
+                usage.bytes,
+                csv_field(&labels.join(";")),
+                csv_field(listener)
+            );
+        }
+        let name = format!("usage-{}-{}.csv", compact(period.start), self.instance);
+        let path = self.dir.join(&name);
+        let tmp = self.dir.join(format!(".{}.tmp", name));
+        fs::write(&tmp, out)?;
+        fs::rename(&tmp, &path)?;
+        info!("🧾 Wrote billing period {} ({} rows) to {}", start, period.usage.len(), path.display());
+        Ok(())
+    }
+}
+
+pub fn spawn(state: Arc<AppState>) {
+    let Some(billing) = &state.billing else {
+        return;
+    };
+    info!("🧾 Billing export to {} every {}s", billing.dir.display(), billing.interval);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(WRITE_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Some(billing) = &state.billing {
+                billing.flush(false);
+            }
+        }
+    });
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn period_start(secs: u64, interval: u64) -> u64 {
+    secs - secs % interval
+}
+
+fn civil(secs: u64) -> (i64, u32, u32, u64, u64, u64) {
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+    (year, month, day, rem / 3600, (rem % 3600) / 60, rem % 60)
+}
+
+fn timestamp(secs: u64) -> String {
+    let (y, mo, d, h, mi, s) = civil(secs);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", y, mo, d, h, mi, s)
+}
+
+// For file names, which sort chronologically
+fn compact(secs: u64) -> String {
+    let (y, mo, d, h, mi, _) = civil(secs);
+    format!("{:04}{:02}{:02}T{:02}{:02}Z", y, mo, d, h, mi)
+}
@@ -0,0 +1,290 @@
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+use crate::AppState;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+// A subscribed list of domains to refuse, e.g. for ads and malware:
+//
+//   [[blocklists]]
+//   name = "stevenblack"
+//   url = "https://raw.githubusercontent.com/StevenBlack/hosts/master/hosts"
+//   format = "hosts"
+//
+// A listed domain blocks its subdomains too. With server.data_dir set, the
+// last good copy of each list is kept there and used until a fetch works.
+#[derive(Debug, Deserialize)]
+pub struct BlocklistConfig {
+    // Shown in logs and metrics, and names the cached copy
+    pub name: String,
+    pub url: String,
+    #[serde(default)]
+    pub format: Format,
+    // Seconds between fetches
+    #[serde(default = "default_refresh")]
+    pub refresh: u64,
+}
+
+fn default_refresh() -> u64 {
+    86_400
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    // "0.0.0.0 ads.example.com", as used by Pi-hole and StevenBlack
+    #[default]
+    Hosts,
+    // One domain per line
+    Domains,
+    // EasyList/AdGuard syntax; only whole-domain rules ("||example.com^")
+    // and their exceptions ("@@||example.com^") can apply to a proxy
+    Adblock,
+}
+
+pub fn validate(lists: &[BlocklistConfig]) -> Result<(), String> {
+    let mut names = HashSet::new();
+    for list in lists {
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if list.name.is_empty() || !list.name.chars().all(valid) {
+            return Err(format!("blocklist name '{}' must be letters, digits, '-' or '_'", list.name));
+        }
+        if !names.insert(list.name.as_str()) {
+            return Err(format!("blocklist '{}' is defined twice", list.name));
+        }
+        if !list.url.starts_with("http://") && !list.url.starts_with("https://") {
+            return Err(format!("blocklist '{}': url must be http or https", list.name));
+        }
+        if list.refresh == 0 {
+            return Err(format!("blocklist '{}': refresh must be positive", list.name));
+        }
+    }
+    Ok(())
+}
+
+// The domains one list blocks and the exceptions it makes.
+#[derive(Default)]
+struct Parsed {
+    blocked: Vec<String>,
+    allowed: Vec<String>,
+}
+
+fn parse(format: Format, text: &str) -> Parsed {
+    let mut parsed = Parsed::default();
+    for line in text.lines() {
+        let line = line.trim();
+        match format {
+            Format::Hosts => {
+                let line = line.split('#').next().unwrap_or_default();
+                let mut fields = line.split_whitespace();
+                if fields.next().and_then(|ip| ip.parse::<IpAddr>().ok()).is_none() {
+                    continue;
+                }
+                parsed.blocked.extend(fields.filter_map(domain));
+            }
+            Format::Domains => {
+                if line.starts_with('#') || line.starts_with('!') {
+                    continue;
+                }
+                let name = line.split('#').next().unwrap_or_default().trim();
+                parsed.blocked.extend(domain(name.strip_prefix("*.").unwrap_or(name)));
+            }
+            Format::Adblock => {
+                let (rule, exception) = match line.strip_prefix("@@") {
+                    Some(rule) => (rule, true),
+                    None => (line, false),
+                };
+                let Some(name) = rule.strip_prefix("||").and_then(whole_domain) else {
+                    continue;
+                };
+                match exception {
+                    true => parsed.allowed.extend(domain(name)),
+                    false => parsed.blocked.extend(domain(name)),
+                }
+            }
+        }
+    }
+    parsed
+}
+
+// "example.com^" or "example.com^$important"; rules limited to a path or
+// to certain request types would block more than they mean to.
+fn whole_domain(rule: &str) -> Option<&str> {
+    let (name, rest) = rule.split_once('^')?;
+    let options = match rest {
+        "" | "|" => return Some(name),
+        rest => rest.strip_prefix('$')?,
+    };
+    let whole = |option: &str| matches!(option, "important" | "all" | "document" | "doc" | "third-party" | "3p");
+    options.split(',').all(whole).then_some(name)
+}
+
+// A lowercased host name, or None for anything else hosts files carry
+// ("localhost", "broadcasthost", addresses).
+fn domain(name: &str) -> Option<String> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    (name.contains('.') && name.chars().all(valid) && name.parse::<IpAddr>().is_err() && !name.starts_with('.'))
+        .then_some(name)
+}
+
+// Every list merged into one lookup: the list (by index) that blocks each
+// domain, and the domains some list makes an exception for.
+#[derive(Default)]
+struct Matcher {
+    blocked: HashMap<Box<str>, usize>,
+    allowed: HashSet<Box<str>>,
+}
+
+impl Matcher {
+    // The host and each parent domain, "a.b.example.com" to "example.com".
+    fn suffixes(host: &str) -> impl Iterator<Item = &str> {
+        std::iter::successors(Some(host), |h| h.split_once('.').map(|(_, parent)| parent)).filter(|h| h.contains('.'))
+    }
+
+    fn find(&self, host: &str) -> Option<usize> {
+        if Self::suffixes(host).any(|h| self.allowed.contains(h)) {
+            return None;
+        }
+        Self::suffixes(host).find_map(|h| self.blocked.get(h).copied())
+    }
+}
+
+pub struct Blocklists {
+    lists: Vec<(String, PathBuf)>,
+    cache_dir: Option<PathBuf>,
+    // Latest good contents of each list, None until one loads
+    parsed: Mutex<Vec<Option<Parsed>>>,
+    matcher: RwLock<Arc<Matcher>>,
+}
+
+impl Blocklists {
+    // Starts from the cached copies, if any; fetching happens in spawn.
+    pub fn new(config: &[BlocklistConfig], data_dir: Option<&std::path::Path>) -> Self {
+        let cache_dir = data_dir.map(|dir| dir.join("blocklists"));
+        let blocklists = Blocklists {
+            lists: config
+                .iter()
+                .map(|list| {
+                    let cached = cache_dir.as_ref().map(|dir| dir.join(format!("{}.txt", list.name)));
+                    (list.name.clone(), cached.unwrap_or_default())
+                })
+                .collect(),
+            parsed: Mutex::new(config.iter().map(|_| None).collect()),
+            cache_dir,
+            matcher: RwLock::default(),
+        };
+        if blocklists.cache_dir.is_some() {
+            for (i, list) in config.iter().enumerate() {
+                if let Ok(text) = std::fs::read_to_string(&blocklists.lists[i].1) {
+                    let parsed = parse(list.format, &text);
+                    info!("🛑 Loaded {} cached blocklist entries for '{}'", parsed.blocked.len(), list.name);
+                    blocklists.parsed.lock().unwrap()[i] = Some(parsed);
+                }
+            }
+            blocklists.rebuild();
+        }
+        blocklists
+    }
+
+    // The name of the list that blocks `host`, if one does.
+    pub fn check(&self, host: &str) -> Option<&str> {
+        if self.lists.is_empty() {
+            return None;
+        }
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let i = self.matcher.read().unwrap().find(&host)?;
+        Some(&self.lists[i].0)
+    }
+
+    // Domains each list blocks, for /metrics.
+    pub fn sizes(&self) -> Vec<(String, usize)> {
+        let parsed = self.parsed.lock().unwrap();
+        self.lists
+            .iter()
+            .zip(parsed.iter())
+            .map(|((name, _), p)| (name.clone(), p.as_ref().map_or(0, |p| p.blocked.len())))
+            .collect()
+    }
+
+    // Age of the cached copy of list `i`, to delay the first fetch.
+    fn cache_age(&self, i: usize) -> Option<Duration> {
+        self.cache_dir.as_ref()?;
+        let modified = std::fs::metadata(&self.lists[i].1).and_then(|m| m.modified()).ok()?;
+        Some(SystemTime::now().duration_since(modified).unwrap_or_default())
+    }
+
+    // A failed fetch keeps whatever the list blocked before.
+    fn fetch(&self, i: usize, config: &BlocklistConfig) {
+        let text = match crate::alert::download(&config.url, FETCH_TIMEOUT) {
+            Ok((200, body)) => String::from_utf8_lossy(&body).into_owned(),
+            Ok((status, _)) => {
+                warn!("⚠️ Blocklist '{}' not updated: {} answered {}", config.name, config.url, status);
+                return;
+            }
+            Err(e) => {
+                warn!("⚠️ Blocklist '{}' not updated: {}", config.name, e);
+                return;
+            }
+        };
+        let parsed = parse(config.format, &text);
+        if parsed.blocked.is_empty() {
+            warn!("⚠️ Blocklist '{}' not updated: {} lists no domains", config.name, config.url);
+            return;
+        }
+        info!("🛑 Blocklist '{}' updated, {} entries", config.name, parsed.blocked.len());
+        if let Some(dir) = &self.cache_dir {
+            let path = &self.lists[i].1;
+            let tmp = path.with_extension("txt.tmp");
+            let saved = std::fs::create_dir_all(dir)
+                .and_then(|_| std::fs::write(&tmp, &text))
+                .and_then(|_| std::fs::rename(&tmp, path));
+            if let Err(e) = saved {
+                warn!("⚠️ Failed to cache blocklist '{}' in {}: {}", config.name, dir.display(), e);
+            }
+        }
+        self.parsed.lock().unwrap()[i] = Some(parsed);
+        self.rebuild();
+    }
+
+    // The first list to name a domain is the one reported for it.
+    fn rebuild(&self) {
+        let mut matcher = Matcher::default();
+        for (i, parsed) in self.parsed.lock().unwrap().iter().enumerate() {
+            let Some(parsed) = parsed else {
+                continue;
+            };
+            for name in &parsed.blocked {
+                matcher.blocked.entry(name.as_str().into()).or_insert(i);
+            }
+            matcher.allowed.extend(parsed.allowed.iter().map(|name| name.as_str().into()));
+        }
+        *self.matcher.write().unwrap() = Arc::new(matcher);
+    }
+}
+
+// Fetches each list now (or when its cached copy goes stale) and then
+// every `refresh` seconds.
+pub fn spawn(state: Arc<AppState>) {
+    for i in 0..state.config.blocklists.len() {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let list = &state.config.blocklists[i];
+            let refresh = Duration::from_secs(list.refresh);
+            let wait = state.blocklists.cache_age(i).map_or(Duration::ZERO, |age| refresh.saturating_sub(age));
+            tokio::time::sleep(wait).await;
+            loop {
+                let state = state.clone();
+                // Parsing a list of a few hundred thousand names takes a while
+                let _ = tokio::task::spawn_blocking(move || state.blocklists.fetch(i, &state.config.blocklists[i])).await;
+                tokio::time::sleep(refresh).await;
+            }
+        });
+    }
+}
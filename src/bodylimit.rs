@@ -0,0 +1,66 @@
+use hyper::body::HttpBody;
+use hyper::Body;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::metrics::Metrics;
+
+// Set once a capped body went over its limit, which tells that apart from
+// the other ways forwarding a body can fail.
+#[derive(Clone, Default)]
+pub struct Overflow(Arc<AtomicBool>);
+
+impl Overflow {
+    pub fn happened(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Whether `headers` announce a body longer than `max`.
+pub fn too_long(headers: &hyper::HeaderMap, max: Option<u64>) -> bool {
+    max.is_some_and(|max| crate::content_length(headers) > max)
+}
+
+// `body`, cut off with an error once more than `max` bytes have gone
+// through it. Bodies with a Content-Length are left alone: hyper carries
+// no more than announced, and too_long() has checked that already.
+pub fn cap(
+    mut body: Body,
+    headers: &hyper::HeaderMap,
+    max: Option<u64>,
+    kind: &'static str,
+    metrics: &Arc<Metrics>,
+) -> (Body, Overflow) {
+    let overflow = Overflow::default();
+    let Some(max) = max.filter(|_| !headers.contains_key(hyper::header::CONTENT_LENGTH)) else {
+        return (body, overflow);
+    };
+    let (mut sender, out) = Body::channel();
+    let metrics = metrics.clone();
+    let flag = overflow.clone();
+    tokio::spawn(async move {
+        let mut total = 0u64;
+        while let Some(chunk) = body.data().await {
+            let Ok(chunk) = chunk else {
+                sender.abort();
+                return;
+            };
+            total += chunk.len() as u64;
+            if total > max {
+                warn!("🚫 Cutting off {} body at {} bytes (limit {})", kind, total, max);
+                flag.0.store(true, Ordering::Relaxed);
+                metrics.count_size_limit(kind);
+                sender.abort();
+                return;
+            }
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+        if let Ok(Some(trailers)) = body.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    (out, overflow)
+}
@@ -0,0 +1,106 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+#[derive(Debug, Deserialize)]
+pub struct CircuitBreakerConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Consecutive failures that open the circuit for a host
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    // Seconds new requests are refused before a trial request is let through
+    #[serde(default = "default_cooldown")]
+    pub cooldown: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            enabled: false,
+            failure_threshold: default_failure_threshold(),
+            cooldown: default_cooldown(),
+        }
+    }
+}
+
+fn default_failure_threshold() -> u32 {
+    5
+}
+
+fn default_cooldown() -> u64 {
+    30
+}
+
+#[derive(Default)]
+struct HostState {
+    failures: u32,
+    open_until: Option<Instant>,
+    // A half-open trial request is in flight
+    probing: bool,
+}
+
+// Per-destination circuit breakers. Closed: requests flow and failures are
+// counted. Open: requests are refused until the cooldown ends. Half-open:
+// one trial request decides whether to close again or re-open.
+#[derive(Default)]
+pub struct CircuitBreakers {
+    hosts: Mutex<HashMap<String, HostState>>,
+}
+
+impl CircuitBreakers {
+    // Err carries the time left before the host may be tried again.
+    pub fn check(&self, config: &CircuitBreakerConfig, host: &str) -> Result<(), Duration> {
+        if !config.enabled {
+            return Ok(());
+        }
+        let mut hosts = self.hosts.lock().unwrap();
+        let Some(state) = hosts.get_mut(host) else {
+            return Ok(());
+        };
+        let Some(open_until) = state.open_until else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if now < open_until {
+            return Err(open_until - now);
+        }
+        if state.probing {
+            // Someone else is running the trial; hold others off briefly
+            return Err(Duration::from_secs(1));
+        }
+        state.probing = true;
+        info!("🔌 Circuit for {} half-open, letting a trial request through", host);
+        Ok(())
+    }
+
+    pub fn record_success(&self, config: &CircuitBreakerConfig, host: &str) {
+        if !config.enabled {
+            return;
+        }
+        if let Some(state) = self.hosts.lock().unwrap().remove(host) {
+            if state.open_until.is_some() {
+                info!("✅ Circuit for {} closed", host);
+            }
+        }
+    }
+
+    pub fn record_failure(&self, config: &CircuitBreakerConfig, host: &str) {
+        if !config.enabled {
+            return;
+        }
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = hosts.entry(host.to_string()).or_default();
+        state.failures += 1;
+        if state.probing || state.failures >= config.failure_threshold {
+            warn!(
+                "🔌 Circuit for {} open for {}s after {} consecutive failure(s)",
+                host, config.cooldown, state.failures
+            );
+            state.open_until = Some(Instant::now() + Duration::from_secs(config.cooldown));
+            state.probing = false;
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use serde::Deserialize;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+const MIN_SIZE: usize = 1024;
+const MAX_SIZE: usize = 16 * 1024 * 1024;
+
+// Buffers for copying tunnel traffic, one per direction:
+//
+//   [tunnel]
+//   buffer_size = 65536
+//   pooled_buffers = 2048
+#[derive(Debug, Deserialize)]
+pub struct TunnelConfig {
+    // Bytes read from one side before they are written to the other
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: usize,
+    // Buffers kept for reuse when tunnels close; 0 allocates every time
+    #[serde(default = "default_pooled_buffers")]
+    pub pooled_buffers: usize,
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        TunnelConfig {
+            buffer_size: default_buffer_size(),
+            pooled_buffers: default_pooled_buffers(),
+        }
+    }
+}
+
+fn default_buffer_size() -> usize {
+    16 * 1024
+}
+
+fn default_pooled_buffers() -> usize {
+    1024
+}
+
+impl TunnelConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(MIN_SIZE..=MAX_SIZE).contains(&self.buffer_size) {
+            return Err(format!("tunnel.buffer_size must be between {} and {} bytes", MIN_SIZE, MAX_SIZE));
+        }
+        Ok(())
+    }
+}
+
+// Spare buffers, so thousands of tunnels opening and closing do not each
+// allocate and free their own.
+pub struct BufferPool {
+    size: usize,
+    keep: usize,
+    free: Mutex<Vec<Box<[u8]>>>,
+}
+
+impl BufferPool {
+    pub fn new(config: &TunnelConfig) -> Self {
+        BufferPool {
+            size: config.buffer_size,
+            keep: config.pooled_buffers,
+            free: Mutex::default(),
+        }
+    }
+
+    pub fn take(&self) -> Buffer<'_> {
+        let spare = self.free.lock().unwrap().pop();
+        Buffer {
+            buf: Some(spare.unwrap_or_else(|| vec![0; self.size].into_boxed_slice())),
+            pool: self,
+        }
+    }
+
+    // Buffers waiting for reuse, for /metrics.
+    pub fn idle(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+}
+
+// Goes back to its pool when dropped, unless the pool is full.
+pub struct Buffer<'a> {
+    buf: Option<Box<[u8]>>,
+    pool: &'a BufferPool,
+}
+
+impl Deref for Buffer<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buf.as_deref().unwrap_or_default()
+    }
+}
+
+impl DerefMut for Buffer<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buf.as_deref_mut().unwrap_or_default()
+    }
+}
+
+impl Drop for Buffer<'_> {
+    fn drop(&mut self) {
+        let mut free = self.pool.free.lock().unwrap();
+        if free.len() < self.pool.keep {
+            free.extend(self.buf.take());
+        }
+    }
+}
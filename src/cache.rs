@@ -0,0 +1,481 @@
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, AGE, CACHE_CONTROL, SET_COOKIE, VARY};
+use hyper::{Body, HeaderMap, Response, StatusCode, Uri};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+use crate::pattern::host_matches;
+
+#[derive(Debug, Deserialize)]
+pub struct CacheConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    // Responses larger than this (or without Content-Length) are never buffered
+    #[serde(default = "default_max_object_size")]
+    pub max_object_size: u64,
+    #[serde(default)]
+    pub rules: Vec<CacheRule>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: false,
+            max_entries: default_max_entries(),
+            max_object_size: default_max_object_size(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+fn default_max_entries() -> usize {
+    1024
+}
+
+fn default_max_object_size() -> u64 {
+    1024 * 1024
+}
+
+// Per-route override of the origin's caching headers.
+#[derive(Debug, Deserialize)]
+pub struct CacheRule {
+    pub host: String,
+    #[serde(default = "default_path_prefix")]
+    pub path_prefix: String,
+    // Force-cache for this many seconds regardless of Cache-Control
+    pub cache_ttl: Option<u64>,
+    // Never cache, regardless of Cache-Control
+    #[serde(default)]
+    pub no_cache: bool,
+    // RFC 5861 windows (seconds), overriding the origin's Cache-Control extensions
+    pub stale_while_revalidate: Option<u64>,
+    pub stale_if_error: Option<u64>,
+    // Cache key composition. By default the key is the full request URI.
+    // Request headers whose values partition the cache (Vary-like)
+    #[serde(default)]
+    pub key_headers: Vec<String>,
+    // Query parameters kept in the key; unset keeps the whole query string,
+    // an empty list ignores it entirely
+    pub key_query: Option<Vec<String>>,
+    // Give each proxy user their own copy
+    #[serde(default)]
+    pub key_user: bool,
+}
+
+fn default_path_prefix() -> String {
+    "/".to_string()
+}
+
+impl CacheRule {
+    fn matches(&self, host: &str, path: &str) -> bool {
+        host_matches(&self.host, host) && path.starts_with(&self.path_prefix)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtlPolicy {
+    // Follow the origin's Cache-Control
+    Origin,
+    Force(Duration),
+    Never,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    pub ttl: TtlPolicy,
+    pub stale_while_revalidate: Option<Duration>,
+    pub stale_if_error: Option<Duration>,
+}
+
+impl Policy {
+    pub fn is_cacheable(&self) -> bool {
+        self.ttl != TtlPolicy::Never
+    }
+}
+
+impl CacheConfig {
+    fn rule(&self, host: &str, path: &str) -> Option<&CacheRule> {
+        self.rules.iter().find(|r| r.matches(host, path))
+    }
+
+    pub fn policy(&self, host: &str, path: &str) -> Policy {
+        let rule = self.rule(host, path);
+        let ttl = match rule {
+            Some(rule) if rule.no_cache => TtlPolicy::Never,
+            Some(CacheRule {
+                cache_ttl: Some(ttl),
+                ..
+            }) => TtlPolicy::Force(Duration::from_secs(*ttl)),
+            _ => TtlPolicy::Origin,
+        };
+        Policy {
+            ttl,
+            stale_while_revalidate: rule
+                .and_then(|r| r.stale_while_revalidate)
+                .map(Duration::from_secs),
+            stale_if_error: rule.and_then(|r| r.stale_if_error).map(Duration::from_secs),
+        }
+    }
+}
+
+impl CacheConfig {
+    // Build the cache key for a request according to the matching rule.
+    pub fn key(&self, uri: &Uri, headers: &HeaderMap, user: &str) -> String {
+        let host = uri.host().unwrap_or_default();
+        let Some(rule) = self.rule(host, uri.path()) else {
+            return uri.to_string();
+        };
+
+        let mut key = format!(
+            "{}://{}{}",
+            uri.scheme_str().unwrap_or("http"),
+            uri.authority().map(|a| a.as_str()).unwrap_or(host),
+            uri.path()
+        );
+        match (&rule.key_query, uri.query()) {
+            (None, Some(query)) => {
+                key.push('?');
+                key.push_str(query);
+            }
+            (Some(keep), Some(query)) => {
+                let mut params: Vec<&str> = query
+                    .split('&')
+                    .filter(|p| {
+                        let name = p.split('=').next().unwrap_or_default();
+                        keep.iter().any(|k| k == name)
+                    })
+                    .collect();
+                params.sort_unstable();
+                if !params.is_empty() {
+                    key.push('?');
+                    key.push_str(&params.join("&"));
+                }
+            }
+            (_, None) => {}
+        }
+        for name in &rule.key_headers {
+            let values: Vec<&str> = headers
+                .get_all(name.as_str())
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .collect();
+            key.push_str(&format!(
+                "|{}={}",
+                name.to_ascii_lowercase(),
+                values.join(",")
+            ));
+        }
+        if rule.key_user {
+            key.push_str(&format!("|user={}", user));
+        }
+        key
+    }
+}
+
+// How long a stored response may be served fresh, and how long past that
+// it may still be served stale (RFC 5861).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Freshness {
+    pub ttl: Duration,
+    pub stale_while_revalidate: Duration,
+    pub stale_if_error: Duration,
+}
+
+pub enum Lookup {
+    Fresh(Response<Body>),
+    // Served stale; `revalidate` is set for the one caller that should refresh it
+    Stale {
+        response: Response<Body>,
+        revalidate: bool,
+    },
+    Miss,
+}
+
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored_at: Instant,
+    freshness: Freshness,
+    revalidating: bool,
+}
+
+impl Entry {
+    fn is_fresh(&self) -> bool {
+        self.stored_at.elapsed() < self.freshness.ttl
+    }
+
+    fn within(&self, stale_window: Duration) -> bool {
+        self.stored_at.elapsed() < self.freshness.ttl + stale_window
+    }
+
+    // Worth keeping around: fresh, or still inside one of the stale windows
+    fn is_usable(&self) -> bool {
+        let f = &self.freshness;
+        self.within(f.stale_while_revalidate.max(f.stale_if_error))
+    }
+
+    fn to_response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        let age = self.stored_at.elapsed().as_secs();
+        response.headers_mut().insert(AGE, HeaderValue::from(age));
+        response
+    }
+}
+
+// In-memory cache of GET responses, keyed by CacheConfig::key plus the
+// request's values of the headers the response varies on.
+pub struct ResponseCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    // CacheConfig::key -> header names in the last stored response's Vary
+    varies: Mutex<HashMap<String, Vec<String>>>,
+    max_entries: usize,
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        ResponseCache {
+            entries: Mutex::new(HashMap::new()),
+            varies: Mutex::default(),
+            max_entries: config.max_entries,
+        }
+    }
+
+    // The key a request's copy is kept under: `key` from CacheConfig::key,
+    // extended by the request headers the stored response varied on.
+    pub fn variant(&self, key: &str, request: &HeaderMap) -> String {
+        match self.varies.lock().unwrap().get(key) {
+            Some(names) => vary_key(key, names, request),
+            None => key.to_string(),
+        }
+    }
+
+    pub fn lookup(&self, key: &str) -> Lookup {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get_mut(key) else {
+            return Lookup::Miss;
+        };
+        if entry.is_fresh() {
+            return Lookup::Fresh(entry.to_response());
+        }
+        if entry.within(entry.freshness.stale_while_revalidate) {
+            let revalidate = !entry.revalidating;
+            entry.revalidating = true;
+            return Lookup::Stale {
+                response: entry.to_response(),
+                revalidate,
+            };
+        }
+        Lookup::Miss
+    }
+
+    // A stale copy to serve when the origin is failing, if the entry allows it.
+    pub fn stale_if_error(&self, key: &str) -> Option<Response<Body>> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .filter(|e| e.within(e.freshness.stale_if_error))
+            .map(Entry::to_response)
+    }
+
+    // Background revalidation failed; let the next stale hit try again.
+    pub fn revalidation_failed(&self, key: &str) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(key) {
+            entry.revalidating = false;
+        }
+    }
+
+    pub fn store(
+        &self,
+        key: String,
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+        freshness: Freshness,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            entries.retain(|_, e| e.is_usable());
+            if entries.len() >= self.max_entries {
+                // Still full: evict the oldest entry
+                if let Some(oldest) = entries
+                    .iter()
+                    .min_by_key(|(_, e)| e.stored_at)
+                    .map(|(k, _)| k.clone())
+                {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        debug!("Caching {} for {}s", key, freshness.ttl.as_secs());
+        entries.insert(
+            key,
+            Entry {
+                status,
+                headers,
+                body,
+                stored_at: Instant::now(),
+                freshness,
+                revalidating: false,
+            },
+        );
+    }
+
+    // Buffer a cacheable response and store it. Responses without a
+    // Content-Length or over the size limit are passed through untouched.
+    pub async fn store_response(
+        &self,
+        key: String,
+        request: &HeaderMap,
+        freshness: Freshness,
+        max_object_size: u64,
+        response: Response<Body>,
+    ) -> Response<Body> {
+        let length = response
+            .headers()
+            .get(hyper::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if !matches!(length, Some(len) if len <= max_object_size) {
+            debug!("Not caching {}: body too large or of unknown length", key);
+            return response;
+        }
+        let names = vary(response.headers());
+        let key = {
+            let mut varies = self.varies.lock().unwrap();
+            if names.is_empty() {
+                varies.remove(&key);
+                key
+            } else {
+                // Forgetting a Vary only makes the variants stored under it miss
+                if varies.len() >= self.max_entries && !varies.contains_key(&key) {
+                    varies.clear();
+                }
+                let variant = vary_key(&key, &names, request);
+                varies.insert(key, names);
+                variant
+            }
+        };
+
+        let (parts, body) = response.into_parts();
+        match hyper::body::to_bytes(body).await {
+            Ok(bytes) => {
+                self.store(
+                    key,
+                    parts.status,
+                    parts.headers.clone(),
+                    bytes.clone(),
+                    freshness,
+                );
+                Response::from_parts(parts, Body::from(bytes))
+            }
+            Err(e) => {
+                warn!("⚠️ Failed to read upstream body for caching: {}", e);
+                Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Body::from(format!("Proxy error: {}", e)))
+                    .unwrap()
+            }
+        }
+    }
+}
+
+// Lowercase header names in a response's Vary, sorted.
+fn vary(headers: &HeaderMap) -> Vec<String> {
+    let mut names: Vec<String> = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+    names
+}
+
+fn vary_key(key: &str, names: &[String], request: &HeaderMap) -> String {
+    let mut key = key.to_string();
+    for name in names {
+        let values: Vec<&str> = request.get_all(name.as_str()).iter().filter_map(|v| v.to_str().ok()).collect();
+        key.push_str(&format!("|vary:{}={}", name, values.join(",")));
+    }
+    key
+}
+
+// How long a response may be cached, or None if it must not be stored.
+pub fn freshness(policy: Policy, status: StatusCode, headers: &HeaderMap) -> Option<Freshness> {
+    // Stored bodies keep no trailers, so responses that announce some are
+    // passed on but not kept
+    if status != StatusCode::OK || headers.contains_key(hyper::header::TRAILER) {
+        return None;
+    }
+    // A cookie set for one user must not reach the next, and "Vary: *"
+    // means no two requests get the same answer
+    if headers.contains_key(SET_COOKIE) || vary(headers).iter().any(|name| name == "*") {
+        return None;
+    }
+    let cc = CacheControl::parse(headers);
+    let ttl = match policy.ttl {
+        TtlPolicy::Never => return None,
+        TtlPolicy::Force(ttl) => ttl,
+        TtlPolicy::Origin if cc.no_store => return None,
+        TtlPolicy::Origin => cc.max_age.filter(|ttl| !ttl.is_zero())?,
+    };
+    Some(Freshness {
+        ttl,
+        stale_while_revalidate: policy
+            .stale_while_revalidate
+            .or(cc.stale_while_revalidate)
+            .unwrap_or_default(),
+        stale_if_error: policy
+            .stale_if_error
+            .or(cc.stale_if_error)
+            .unwrap_or_default(),
+    })
+}
+
+#[derive(Default)]
+struct CacheControl {
+    // no-store, no-cache or private: not storable in a shared cache
+    no_store: bool,
+    max_age: Option<Duration>,
+    stale_while_revalidate: Option<Duration>,
+    stale_if_error: Option<Duration>,
+}
+
+impl CacheControl {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut cc = CacheControl::default();
+        let Some(value) = headers.get(CACHE_CONTROL).and_then(|v| v.to_str().ok()) else {
+            return cc;
+        };
+        let mut s_maxage = None;
+        for directive in value.split(',').map(|d| d.trim().to_ascii_lowercase()) {
+            let (name, arg) = match directive.split_once('=') {
+                Some((name, arg)) => (name, arg.trim_matches('"').parse().ok()),
+                None => (directive.as_str(), None),
+            };
+            let arg = arg.map(Duration::from_secs);
+            match name {
+                "no-store" | "no-cache" | "private" => cc.no_store = true,
+                "max-age" => cc.max_age = arg,
+                "s-maxage" => s_maxage = arg,
+                "stale-while-revalidate" => cc.stale_while_revalidate = arg,
+                "stale-if-error" => cc.stale_if_error = arg,
+                _ => {}
+            }
+        }
+        // s-maxage wins over max-age for shared caches
+        cc.max_age = s_maxage.or(cc.max_age);
+        cc
+    }
+}
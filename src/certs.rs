@@ -0,0 +1,222 @@
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::nid::Nid;
+use openssl::x509::{X509NameRef, X509};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::AppState;
+
+// Expiry of the certificates served by TLS listeners, plus any other PEM
+// files worth watching (a CA, certificates pinned by clients):
+//
+//   [certs]
+//   warn_days = 30
+//   critical_days = 7
+//   files = ["/etc/secure-proxy/ca.pem"]
+//   webhook = "https://hooks.example.com/certs"
+#[derive(Debug, Deserialize)]
+pub struct CertsConfig {
+    #[serde(default = "default_warn_days")]
+    pub warn_days: i64,
+    #[serde(default = "default_critical_days")]
+    pub critical_days: i64,
+    // Seconds between checks
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    // Every certificate in each file counts; the first to expire is reported
+    #[serde(default)]
+    pub files: Vec<PathBuf>,
+    // JSON POST whenever a certificate moves to a worse level
+    pub webhook: Option<String>,
+}
+
+impl Default for CertsConfig {
+    fn default() -> Self {
+        CertsConfig {
+            warn_days: default_warn_days(),
+            critical_days: default_critical_days(),
+            interval: default_interval(),
+            files: Vec::new(),
+            webhook: None,
+        }
+    }
+}
+
+fn default_warn_days() -> i64 {
+    30
+}
+
+fn default_critical_days() -> i64 {
+    7
+}
+
+fn default_interval() -> u64 {
+    3600
+}
+
+impl CertsConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval == 0 {
+            return Err("certs.interval must be positive".to_string());
+        }
+        if self.critical_days < 0 || self.critical_days > self.warn_days {
+            return Err("certs.critical_days must be between 0 and warn_days".to_string());
+        }
+        Ok(())
+    }
+
+    fn level(&self, days: i64) -> Level {
+        if days < 0 {
+            Level::Expired
+        } else if days < self.critical_days {
+            Level::Critical
+        } else if days < self.warn_days {
+            Level::Warning
+        } else {
+            Level::Ok
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Level {
+    Ok,
+    Warning,
+    Critical,
+    Expired,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Ok => "ok",
+            Level::Warning => "warning",
+            Level::Critical => "critical",
+            Level::Expired => "expired",
+        }
+    }
+}
+
+struct CertStatus {
+    // Unix seconds
+    not_after: i64,
+    // Whole days left, negative once expired
+    days: i64,
+    level: Level,
+}
+
+// Latest check per certificate, keyed by source ("listener" or "file") and
+// listener name or path.
+#[derive(Default)]
+pub struct CertMonitor {
+    status: Mutex<BTreeMap<(&'static str, String), CertStatus>>,
+}
+
+impl CertMonitor {
+    // (source, name, days left, expiry in Unix seconds)
+    pub fn snapshot(&self) -> Vec<(&'static str, String, i64, i64)> {
+        self.status
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((source, name), status)| (*source, name.clone(), status.days, status.not_after))
+            .collect()
+    }
+}
+
+fn check(state: &AppState) {
+    let config = &state.config.certs;
+    let mut seen: Vec<(&'static str, String, X509)> = state
+        .listeners()
+        .iter()
+        .filter_map(|l| Some(("listener", l.name.clone(), l.certificate.clone()?)))
+        .collect();
+    for path in &config.files {
+        let certs = std::fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|pem| X509::stack_from_pem(&pem).map_err(|e| e.to_string()));
+        match certs {
+            Ok(certs) => match certs.into_iter().min_by_key(|c| unix(c.not_after()).unwrap_or(i64::MAX)) {
+                Some(cert) => seen.push(("file", path.display().to_string(), cert)),
+                None => warn!("⚠️ No certificate in {}", path.display()),
+            },
+            Err(e) => warn!("⚠️ Cannot read certificate {}: {}", path.display(), e),
+        }
+    }
+
+    let now = Asn1Time::days_from_now(0).ok();
+    let mut previous = std::mem::take(&mut *state.certs.status.lock().unwrap());
+    let mut status = BTreeMap::new();
+    for (source, name, cert) in seen {
+        let left = now.as_ref().and_then(|now| now.diff(cert.not_after()).ok());
+        let (Some(not_after), Some(left)) = (unix(cert.not_after()), left) else {
+            continue;
+        };
+        let days = left.days as i64;
+        let level = config.level(days);
+        let subject = common_name(cert.subject_name());
+        match level {
+            Level::Ok => {}
+            Level::Warning => warn!("⚠️ Certificate '{}' ({} {}) expires in {} days", subject, source, name, days),
+            Level::Critical => error!("🚨 Certificate '{}' ({} {}) expires in {} days", subject, source, name, days),
+            Level::Expired => error!("🚨 Certificate '{}' ({} {}) has expired", subject, source, name),
+        }
+        let was = previous.remove(&(source, name.clone())).map(|s| s.level).unwrap_or(Level::Ok);
+        if let (Some(url), true) = (&config.webhook, level > was) {
+            let json = format!(
+                "{{\"event\":\"certificate_expiry\",\"timestamp\":\"{}\",\"level\":\"{}\",\"source\":\"{}\",\"name\":\"{}\",\"subject\":\"{}\",\"days\":{},\"not_after\":{}}}",
+                crate::logging::rfc3339_now(),
+                level.as_str(),
+                source,
+                crate::logging::escape(&name),
+                crate::logging::escape(&subject),
+                days,
+                not_after
+            );
+            crate::alert::send_webhook(url, json);
+        }
+        status.insert(
+            (source, name),
+            CertStatus {
+                not_after,
+                days,
+                level,
+            },
+        );
+    }
+    *state.certs.status.lock().unwrap() = status;
+}
+
+pub fn unix(time: &Asn1TimeRef) -> Option<i64> {
+    let diff = Asn1Time::from_unix(0).ok()?.diff(time).ok()?;
+    Some(diff.days as i64 * 86_400 + diff.secs as i64)
+}
+
+pub fn common_name(name: &X509NameRef) -> String {
+    name.entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|e| e.data().to_string().ok())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+pub fn spawn(state: Arc<AppState>) {
+    let config = &state.config.certs;
+    // Still started without anything to watch: SIGHUP can add TLS listeners
+    if !config.files.is_empty() || state.listeners().iter().any(|l| l.tls) {
+        info!(
+            "📜 Watching certificate expiry every {}s (warning at {} days, critical at {})",
+            config.interval, config.warn_days, config.critical_days
+        );
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(state.config.certs.interval));
+        loop {
+            ticker.tick().await;
+            check(&state);
+        }
+    });
+}
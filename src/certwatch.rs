@@ -0,0 +1,299 @@
+use openssl::hash::MessageDigest;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::X509NameRef;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+use crate::certs::unix;
+use crate::logging::{escape, rfc3339_now};
+use crate::store::Store;
+use crate::AppState;
+
+const STORE_PREFIX: &str = "cert_watch.";
+
+// Remembers the certificate each watched CONNECT destination presents and
+// flags unexpected changes, a small certificate-transparency-style check
+// against interception and mis-issuance. Tunnels are end-to-end encrypted,
+// so the proxy fetches the certificate itself in a separate handshake:
+//
+//   [cert_watch]
+//   domains = ["*.bank.example.com", "login.example.com"]
+//   log = "/var/log/secure-proxy/origin-certs.log"
+#[derive(Debug, Deserialize)]
+pub struct CertWatchConfig {
+    // Host patterns; nothing is watched when empty
+    #[serde(default)]
+    pub domains: Vec<String>,
+    // Seconds before a destination is checked again
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    // A new certificate from the same issuer this close to the old one's
+    // expiry is a routine renewal rather than a change
+    #[serde(default = "default_renewal_days")]
+    pub renewal_days: i64,
+    // Each new certificate is appended here as a JSON line
+    pub log: Option<PathBuf>,
+    pub webhook: Option<String>,
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for CertWatchConfig {
+    fn default() -> Self {
+        CertWatchConfig {
+            domains: Vec::new(),
+            interval: default_interval(),
+            renewal_days: default_renewal_days(),
+            log: None,
+            webhook: None,
+            timeout: default_timeout(),
+        }
+    }
+}
+
+fn default_interval() -> u64 {
+    3600
+}
+
+fn default_renewal_days() -> i64 {
+    30
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+impl CertWatchConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval == 0 || self.timeout == 0 {
+            return Err("cert_watch.interval and timeout must be positive".to_string());
+        }
+        if self.renewal_days < 0 {
+            return Err("cert_watch.renewal_days must not be negative".to_string());
+        }
+        Ok(())
+    }
+
+    fn watches(&self, host: &str) -> bool {
+        self.domains.iter().any(|pattern| crate::pattern::host_matches(pattern, host))
+    }
+}
+
+#[derive(Clone)]
+struct Seen {
+    // SHA-256 of the DER certificate, lowercase hex
+    fingerprint: String,
+    // Unix seconds
+    not_before: i64,
+    not_after: i64,
+    issuer: String,
+    subject: String,
+}
+
+impl Seen {
+    // Stored as "fingerprint|not_before|not_after|issuer"; the subject is
+    // only needed for reporting
+    fn encode(&self) -> String {
+        format!("{}|{}|{}|{}", self.fingerprint, self.not_before, self.not_after, self.issuer)
+    }
+
+    fn decode(value: &str) -> Option<Self> {
+        let mut parts = value.splitn(4, '|');
+        Some(Seen {
+            fingerprint: parts.next()?.to_string(),
+            not_before: parts.next()?.parse().ok()?,
+            not_after: parts.next()?.parse().ok()?,
+            issuer: parts.next()?.to_string(),
+            subject: String::new(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Verdict {
+    // First certificate seen for the destination
+    New,
+    // Same issuer, old certificate close to expiry
+    Renewed,
+    Changed,
+}
+
+impl Verdict {
+    fn as_str(self) -> &'static str {
+        match self {
+            Verdict::New => "new",
+            Verdict::Renewed => "renewed",
+            Verdict::Changed => "changed",
+        }
+    }
+}
+
+pub struct CertWatch {
+    // "host:port" -> latest certificate
+    seen: Mutex<HashMap<String, Seen>>,
+    // "host:port" -> last check, also set while one is running
+    checked: Mutex<HashMap<String, Instant>>,
+    log: Option<Mutex<File>>,
+    store: Option<Arc<Store>>,
+}
+
+impl CertWatch {
+    pub fn new(config: &CertWatchConfig, store: Option<Arc<Store>>) -> io::Result<Self> {
+        let seen = store
+            .as_ref()
+            .map(|store| store.scan(STORE_PREFIX))
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(target, value)| Some((target, Seen::decode(&value)?)))
+            .collect();
+        let log = match &config.log {
+            Some(path) => Some(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?)),
+            None => None,
+        };
+        Ok(CertWatch {
+            seen: Mutex::new(seen),
+            checked: Mutex::default(),
+            log,
+            store,
+        })
+    }
+
+    fn record(&self, config: &CertWatchConfig, target: &str, cert: Seen) {
+        let previous = self.seen.lock().unwrap().insert(target.to_string(), cert.clone());
+        let verdict = match &previous {
+            Some(old) if old.fingerprint == cert.fingerprint => return,
+            Some(old) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+                match old.issuer == cert.issuer && old.not_after - now < config.renewal_days * 86_400 {
+                    true => Verdict::Renewed,
+                    false => Verdict::Changed,
+                }
+            }
+            None => Verdict::New,
+        };
+        if let Some(store) = &self.store {
+            store.set(&format!("{}{}", STORE_PREFIX, target), &cert.encode());
+        }
+        let previous = previous
+            .map(|old| format!(",\"previous_fingerprint\":\"{}\",\"previous_issuer\":\"{}\"", old.fingerprint, escape(&old.issuer)))
+            .unwrap_or_default();
+        let json = format!(
+            "{{\"event\":\"origin_certificate\",\"timestamp\":\"{}\",\"target\":\"{}\",\"verdict\":\"{}\",\"fingerprint\":\"{}\",\"subject\":\"{}\",\"issuer\":\"{}\",\"not_before\":{},\"not_after\":{}{}}}",
+            rfc3339_now(),
+            escape(target),
+            verdict.as_str(),
+            cert.fingerprint,
+            escape(&cert.subject),
+            escape(&cert.issuer),
+            cert.not_before,
+            cert.not_after,
+            previous
+        );
+        if let Some(log) = &self.log {
+            if let Err(e) = writeln!(log.lock().unwrap(), "{}", json) {
+                warn!("⚠️ Failed to write certificate log: {}", e);
+            }
+        }
+        match verdict {
+            Verdict::Changed => {
+                warn!(
+                    "🔏 Certificate of {} changed unexpectedly: now {} from '{}'",
+                    target, cert.fingerprint, cert.issuer
+                );
+                if let Some(url) = &config.webhook {
+                    crate::alert::send_webhook(url, json);
+                }
+            }
+            _ => info!("🔏 Certificate of {} is {} ({})", target, cert.fingerprint, verdict.as_str()),
+        }
+    }
+}
+
+// Called for every accepted CONNECT; checks watched destinations at most
+// once per interval, in the background.
+pub fn observe(state: &Arc<AppState>, host: &str, port: u16) {
+    let config = &state.config.cert_watch;
+    if !config.watches(host) {
+        return;
+    }
+    let target = format!("{}:{}", host.to_ascii_lowercase(), port);
+    {
+        let mut checked = state.cert_watch.checked.lock().unwrap();
+        if checked.get(&target).is_some_and(|at| at.elapsed() < Duration::from_secs(config.interval)) {
+            return;
+        }
+        checked.insert(target.clone(), Instant::now());
+    }
+    let state = state.clone();
+    let host = host.to_string();
+    tokio::spawn(async move {
+        // The address the tunnel itself would use
+        let addr = match crate::dns::lookup(&host, port).await.map(|addrs| addrs.into_iter().next()) {
+            Ok(Some(addr)) => addr,
+            Ok(None) => return,
+            Err(e) => {
+                debug!("Could not resolve {} to check its certificate: {}", target, e);
+                return;
+            }
+        };
+        let _ = tokio::task::spawn_blocking(move || {
+            let config = &state.config.cert_watch;
+            match fetch(addr, &host, Duration::from_secs(config.timeout)) {
+                Ok(cert) => state.cert_watch.record(config, &target, cert),
+                Err(e) => debug!("Could not fetch the certificate of {}: {}", target, e),
+            }
+        })
+        .await;
+    });
+}
+
+// The leaf certificate the server presents, without verifying it: a
+// certificate that does not verify is exactly what should be recorded.
+fn fetch(addr: SocketAddr, host: &str, timeout: Duration) -> io::Result<Seen> {
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut builder = SslConnector::builder(SslMethod::tls_client()).map_err(io::Error::other)?;
+    builder.set_verify(SslVerifyMode::NONE);
+    let connector = builder.build();
+    let mut ssl = connector
+        .configure()
+        .map_err(io::Error::other)?
+        .verify_hostname(false)
+        .connect(host.trim_start_matches('[').trim_end_matches(']'), stream)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let cert = ssl
+        .ssl()
+        .peer_certificate()
+        .ok_or_else(|| io::Error::other("no certificate presented"))?;
+    let _ = ssl.shutdown();
+    let digest = cert.digest(MessageDigest::sha256()).map_err(io::Error::other)?;
+    Ok(Seen {
+        fingerprint: digest.iter().map(|b| format!("{:02x}", b)).collect(),
+        not_before: unix(cert.not_before()).unwrap_or_default(),
+        not_after: unix(cert.not_after()).unwrap_or_default(),
+        issuer: distinguished_name(cert.issuer_name()),
+        subject: distinguished_name(cert.subject_name()),
+    })
+}
+
+// "CN=R11, O=Let's Encrypt, C=US"
+fn distinguished_name(name: &X509NameRef) -> String {
+    let mut parts: Vec<String> = name
+        .entries()
+        .filter_map(|entry| {
+            let key = entry.object().nid().short_name().ok()?;
+            Some(format!("{}={}", key, entry.data().to_string().ok()?))
+        })
+        .collect();
+    // Most specific first, as usually written
+    parts.reverse();
+    parts.join(", ")
+}
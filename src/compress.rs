@@ -0,0 +1,208 @@
+// gzip and zlib (RFC 1950/1951/1952) for body filters, on top of flate2.
+// Decoding is bounded by `max_output` and refuses truncated streams, which
+// flate2's readers would silently cut short.
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::{Compression, Crc, Decompress, FlushDecompress, Status};
+use std::fmt;
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    // "deflate" in HTTP means zlib-wrapped deflate (RFC 9110 §8.4.1.2)
+    Deflate,
+}
+
+impl Encoding {
+    // Parse a Content-Encoding value we know how to transcode.
+    pub fn from_header(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            _ => None,
+        }
+    }
+
+    pub fn decode(self, data: &[u8], max_output: usize) -> Result<Vec<u8>, DecodeError> {
+        match self {
+            Encoding::Gzip => gunzip(data, max_output),
+            Encoding::Deflate => zlib_decode(data, max_output),
+        }
+    }
+
+    pub fn encode(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Gzip => gzip(data),
+            Encoding::Deflate => zlib_encode(data),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    Truncated,
+    BadHeader,
+    BadBlock,
+    BadChecksum,
+    TooLarge,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            DecodeError::Truncated => "truncated stream",
+            DecodeError::BadHeader => "invalid header",
+            DecodeError::BadBlock => "invalid deflate block",
+            DecodeError::BadChecksum => "checksum mismatch",
+            DecodeError::TooLarge => "decompressed size over limit",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+// ---------------------------------------------------------------------------
+// Containers
+
+pub fn gunzip(data: &[u8], max_output: usize) -> Result<Vec<u8>, DecodeError> {
+    const FEXTRA: u8 = 4;
+    const FNAME: u8 = 8;
+    const FCOMMENT: u8 = 16;
+    const FHCRC: u8 = 2;
+
+    if data.len() < 18 || data[0] != 0x1f || data[1] != 0x8b || data[2] != 8 {
+        return Err(DecodeError::BadHeader);
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let len = *data.get(pos).ok_or(DecodeError::Truncated)? as usize
+            | (*data.get(pos + 1).ok_or(DecodeError::Truncated)? as usize) << 8;
+        pos += 2 + len;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = data
+                .get(pos..)
+                .and_then(|rest| rest.iter().position(|&b| b == 0))
+                .ok_or(DecodeError::Truncated)?;
+            pos += end + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    let body = data.get(pos..).ok_or(DecodeError::Truncated)?;
+
+    let (out, used) = inflate(body, false, max_output)?;
+    let trailer = body.get(used..used + 8).ok_or(DecodeError::Truncated)?;
+    let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+    let size = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+    let mut check = Crc::new();
+    check.update(&out);
+    if crc != check.sum() || size != out.len() as u32 {
+        return Err(DecodeError::BadChecksum);
+    }
+    Ok(out)
+}
+
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    // Writing to a Vec cannot fail
+    let _ = encoder.write_all(data);
+    encoder.finish().unwrap_or_default()
+}
+
+// Some servers send raw deflate for "deflate"; accept both.
+pub fn zlib_decode(data: &[u8], max_output: usize) -> Result<Vec<u8>, DecodeError> {
+    let is_zlib = data.len() >= 2
+        && data[0] & 0x0f == 8
+        && (u16::from(data[0]) << 8 | u16::from(data[1])) % 31 == 0;
+    if is_zlib && data[1] & 0x20 != 0 {
+        // preset dictionary
+        return Err(DecodeError::BadHeader);
+    }
+    // With the zlib wrapper, the Adler-32 trailer is checked as well
+    inflate(data, is_zlib, max_output).map(|(out, _)| out)
+}
+
+pub fn zlib_encode(data: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+    let _ = encoder.write_all(data);
+    encoder.finish().unwrap_or_default()
+}
+
+// Inflate a deflate stream, raw or zlib-wrapped. Returns the output and the
+// number of input bytes consumed (so container trailers can be located).
+pub fn inflate(data: &[u8], zlib: bool, max_output: usize) -> Result<(Vec<u8>, usize), DecodeError> {
+    let mut inflater = Decompress::new(zlib);
+    let mut out = Vec::with_capacity(data.len().saturating_mul(3).min(max_output));
+    loop {
+        if out.len() > max_output {
+            return Err(DecodeError::TooLarge);
+        }
+        if out.len() == out.capacity() {
+            out.reserve((max_output + 1 - out.len()).min(64 * 1024));
+        }
+        let (consumed, produced) = (inflater.total_in() as usize, out.len());
+        let status = inflater
+            .decompress_vec(&data[consumed..], &mut out, FlushDecompress::None)
+            .map_err(|_| DecodeError::BadBlock)?;
+        if status == Status::StreamEnd {
+            return match out.len() > max_output {
+                true => Err(DecodeError::TooLarge),
+                false => Ok((out, inflater.total_in() as usize)),
+            };
+        }
+        // Neither input left to read nor room needed: the stream stops early
+        if inflater.total_in() as usize == consumed && out.len() == produced {
+            return Err(DecodeError::Truncated);
+        }
+    }
+}
+
+// Encoder for bodies that arrive in pieces, so they can be sent on without
+// waiting for the rest. Every piece ends with a sync flush, which brings the
+// stream to a byte boundary so the client can decode all of it so far.
+pub struct StreamEncoder {
+    encoder: Encoder,
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl StreamEncoder {
+    pub fn new(encoding: Encoding) -> Self {
+        let encoder = match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::fast())),
+            Encoding::Deflate => Encoder::Deflate(ZlibEncoder::new(Vec::new(), Compression::fast())),
+        };
+        StreamEncoder { encoder }
+    }
+
+    // Writing to a Vec cannot fail, so neither can these.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<u8> {
+        match &mut self.encoder {
+            Encoder::Gzip(encoder) => {
+                let _ = encoder.write_all(data).and_then(|_| encoder.flush());
+                std::mem::take(encoder.get_mut())
+            }
+            Encoder::Deflate(encoder) => {
+                let _ = encoder.write_all(data).and_then(|_| encoder.flush());
+                std::mem::take(encoder.get_mut())
+            }
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        match self.encoder {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+        }
+        .unwrap_or_default()
+    }
+}
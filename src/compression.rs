@@ -0,0 +1,191 @@
+use hyper::body::HttpBody;
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, VARY,
+};
+use hyper::{Body, Response};
+use serde::Deserialize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+use crate::compress::{Encoding, StreamEncoder};
+use crate::metrics::Metrics;
+use crate::rewrite::weaken_etag;
+
+// Compression of plain HTTP responses the origin sent uncompressed, for
+// clients that accept it:
+//
+//   [compression]
+//   enabled = true
+//   min_size = 1024
+//   content_types = ["text/*", "application/json"]
+#[derive(Debug, Deserialize)]
+pub struct CompressionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Responses with a smaller Content-Length are sent as they are; ones
+    // without a length are always compressed
+    #[serde(default = "default_min_size")]
+    pub min_size: u64,
+    // Media types worth compressing; "text/*" covers a whole type
+    #[serde(default = "default_content_types")]
+    pub content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: false,
+            min_size: default_min_size(),
+            content_types: default_content_types(),
+        }
+    }
+}
+
+fn default_min_size() -> u64 {
+    1024
+}
+
+fn default_content_types() -> Vec<String> {
+    [
+        "text/*",
+        "application/json",
+        "application/javascript",
+        "application/xml",
+        "application/xhtml+xml",
+        "application/rss+xml",
+        "application/atom+xml",
+        "application/manifest+json",
+        "application/wasm",
+        "image/svg+xml",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+impl CompressionConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(bad) = self.content_types.iter().find(|t| !t.contains('/')) {
+            return Err(format!("compression.content_types: '{}' is not a media type", bad));
+        }
+        Ok(())
+    }
+
+    fn compresses(&self, headers: &HeaderMap) -> bool {
+        let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+            return false;
+        };
+        let mime = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        self.content_types.iter().any(|pattern| match pattern.strip_suffix("/*") {
+            Some(kind) => mime.split('/').next() == Some(&kind.to_ascii_lowercase()),
+            None => pattern.eq_ignore_ascii_case(&mime),
+        })
+    }
+}
+
+// The encoding the client prefers out of the ones we can produce, from its
+// Accept-Encoding. gzip wins a tie; "q=0" rules an encoding out.
+pub fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    for value in headers.get_all(ACCEPT_ENCODING).iter().filter_map(|v| v.to_str().ok()) {
+        for item in value.split(',') {
+            let mut params = item.split(';').map(str::trim);
+            let encoding = match params.next().unwrap_or_default().to_ascii_lowercase().as_str() {
+                "gzip" | "x-gzip" => Encoding::Gzip,
+                "deflate" => Encoding::Deflate,
+                _ => continue,
+            };
+            let q = params
+                .filter_map(|p| p.split_once('='))
+                .find(|(k, _)| k.trim().eq_ignore_ascii_case("q"))
+                .and_then(|(_, v)| v.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if q > 0.0 && best.is_none_or(|(_, b)| q > b) {
+                best = Some((encoding, q));
+            }
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+// `response` compressed with `encoding`, if the client asked for it and the
+// body is of a kind and size worth it. Anything already encoded, partial or
+// marked no-transform goes out untouched.
+pub fn apply(
+    config: &CompressionConfig,
+    encoding: Option<Encoding>,
+    metrics: &Arc<Metrics>,
+    response: Response<Body>,
+) -> Response<Body> {
+    let Some(encoding) = encoding.filter(|_| config.enabled) else {
+        return response;
+    };
+    let headers = response.headers();
+    let identity = headers
+        .get(CONTENT_ENCODING)
+        .is_none_or(|v| v.to_str().is_ok_and(|v| v.trim().eq_ignore_ascii_case("identity")));
+    let no_transform = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.split(',').any(|d| d.trim().eq_ignore_ascii_case("no-transform")));
+    let length = headers.get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u64>().ok());
+    if response.status() != hyper::StatusCode::OK
+        || !identity
+        || no_transform
+        || headers.contains_key(CONTENT_RANGE)
+        || length.is_some_and(|len| len < config.min_size)
+        || !config.compresses(headers)
+    {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(match encoding {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }),
+    );
+    parts.headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    weaken_etag(&mut parts.headers);
+    metrics.compressed_responses.fetch_add(1, Ordering::Relaxed);
+    debug!("Compressing response with {:?}", encoding);
+    Response::from_parts(parts, compress_body(body, encoding, metrics.clone()))
+}
+
+// Re-stream `body` compressed, one block per chunk as it arrives.
+fn compress_body(mut body: Body, encoding: Encoding, metrics: Arc<Metrics>) -> Body {
+    let (mut sender, out) = Body::channel();
+    tokio::spawn(async move {
+        let mut encoder = StreamEncoder::new(encoding);
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    warn!("⚠️ Upstream body error while compressing: {}", e);
+                    sender.abort();
+                    return;
+                }
+            };
+            let compressed = encoder.feed(&chunk);
+            metrics.compression_in_bytes.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            metrics.compression_out_bytes.fetch_add(compressed.len() as u64, Ordering::Relaxed);
+            if sender.send_data(compressed.into()).await.is_err() {
+                return;
+            }
+        }
+        let rest = encoder.finish();
+        metrics.compression_out_bytes.fetch_add(rest.len() as u64, Ordering::Relaxed);
+        if sender.send_data(rest.into()).await.is_err() {
+            return;
+        }
+        if let Ok(Some(trailers)) = body.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    out
+}
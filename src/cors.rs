@@ -0,0 +1,127 @@
+use hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use std::future::Future;
+use tracing::debug;
+
+// Cross-origin access for endpoints served to browsers, e.g.
+//
+//   [admin.cors]
+//   allowed_origins = ["https://dashboard.example.com"]
+#[derive(Debug, Clone, Deserialize)]
+pub struct CorsConfig {
+    // Exact origins ("https://host[:port]") or "*"; empty disables CORS
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_allowed_headers")]
+    pub allowed_headers: Vec<String>,
+    // Seconds a browser may cache a preflight result
+    #[serde(default = "default_max_age")]
+    pub max_age: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_headers: default_allowed_headers(),
+            max_age: default_max_age(),
+        }
+    }
+}
+
+fn default_allowed_headers() -> Vec<String> {
+    vec!["authorization".to_string(), "content-type".to_string()]
+}
+
+fn default_max_age() -> u64 {
+    600
+}
+
+impl CorsConfig {
+    pub fn validate(&self, section: &str) -> Result<(), String> {
+        for origin in &self.allowed_origins {
+            let valid = origin == "*"
+                || origin
+                    .split_once("://")
+                    .is_some_and(|(scheme, host)| !scheme.is_empty() && !host.is_empty() && !host.contains('/'));
+            if !valid {
+                return Err(format!(
+                    "{}.cors: '{}' is not an origin (expected scheme://host[:port] or \"*\")",
+                    section, origin
+                ));
+            }
+        }
+        if let Some(header) = self.allowed_headers.iter().find(|h| h.parse::<hyper::header::HeaderName>().is_err()) {
+            return Err(format!("{}.cors: '{}' is not a header name", section, header));
+        }
+        Ok(())
+    }
+
+    fn enabled(&self) -> bool {
+        !self.allowed_origins.is_empty()
+    }
+
+    // Value for Access-Control-Allow-Origin, if the request's origin may
+    // read the response.
+    fn allow_origin(&self, req: &Request<Body>) -> Option<HeaderValue> {
+        let origin = req.headers().get(ORIGIN)?;
+        if self.allowed_origins.iter().any(|o| o == "*") {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let given = origin.to_str().ok()?.trim_end_matches('/');
+        self.allowed_origins
+            .iter()
+            .any(|o| o.trim_end_matches('/').eq_ignore_ascii_case(given))
+            .then(|| origin.clone())
+    }
+}
+
+// Runs `handler` with CORS applied: preflights are answered here, before
+// any authentication (browsers send them without credentials), and other
+// responses get Access-Control-Allow-Origin when the origin is allowed.
+// `methods` lists what the endpoint accepts, e.g. "GET, PUT".
+pub async fn wrap<F, Fut>(config: &CorsConfig, methods: &'static str, req: Request<Body>, handler: F) -> Response<Body>
+where
+    F: FnOnce(Request<Body>) -> Fut,
+    Fut: Future<Output = Response<Body>>,
+{
+    if !config.enabled() {
+        return handler(req).await;
+    }
+    let allowed = config.allow_origin(&req);
+    let preflight = req.method() == Method::OPTIONS && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+    let mut response = if preflight {
+        if allowed.is_none() {
+            debug!("Refusing CORS preflight from origin {:?}", req.headers().get(ORIGIN));
+            return Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::from("Origin not allowed"))
+                .unwrap();
+        }
+        let mut response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(ACCESS_CONTROL_ALLOW_METHODS, format!("{}, OPTIONS", methods))
+            .header(ACCESS_CONTROL_MAX_AGE, config.max_age)
+            .body(Body::empty())
+            .unwrap();
+        if let Ok(headers) = HeaderValue::from_str(&config.allowed_headers.join(", ")) {
+            response.headers_mut().insert(ACCESS_CONTROL_ALLOW_HEADERS, headers);
+        }
+        response
+    } else {
+        handler(req).await
+    };
+    let headers = response.headers_mut();
+    if !config.allowed_origins.iter().any(|o| o == "*") {
+        // The answer depends on who asks
+        headers.append(VARY, HeaderValue::from_static("origin"));
+    }
+    if let Some(origin) = allowed {
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    }
+    response
+}
@@ -0,0 +1,74 @@
+use hyper::header::{HeaderName, HeaderValue, LINK};
+use hyper::HeaderMap;
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use crate::pattern::host_matches;
+
+// Lifecycle headers for managed APIs reached through the proxy.
+// See RFC 9745 (Deprecation) and RFC 8594 (Sunset).
+#[derive(Debug, Deserialize)]
+pub struct DeprecationRule {
+    pub host: String,
+    #[serde(default = "default_path_prefix")]
+    pub path_prefix: String,
+    // e.g. "@1735689600" (unix timestamp) or "true"
+    pub deprecation: Option<String>,
+    // HTTP-date, e.g. "Thu, 31 Dec 2026 23:59:59 GMT"
+    pub sunset: Option<String>,
+    // Documentation for migrating off the deprecated API
+    pub link: Option<String>,
+}
+
+fn default_path_prefix() -> String {
+    "/".to_string()
+}
+
+impl DeprecationRule {
+    fn matches(&self, host: &str, path: &str) -> bool {
+        host_matches(&self.host, host) && path.starts_with(&self.path_prefix)
+    }
+}
+
+// Add Deprecation/Sunset/Link headers from the first matching rule.
+// Headers the origin already set are left untouched.
+pub fn apply(rules: &[DeprecationRule], host: &str, path: &str, headers: &mut HeaderMap) {
+    let Some(rule) = rules.iter().find(|r| r.matches(host, path)) else {
+        return;
+    };
+    debug!(
+        "Applying deprecation rule for {}{}",
+        rule.host, rule.path_prefix
+    );
+
+    let deprecation = HeaderName::from_static("deprecation");
+    let sunset = HeaderName::from_static("sunset");
+
+    if let Some(value) = &rule.deprecation {
+        insert_if_absent(headers, deprecation, value);
+    }
+    if let Some(value) = &rule.sunset {
+        insert_if_absent(headers, sunset, value);
+    }
+    if let Some(link) = &rule.link {
+        let value = format!(r#"<{}>; rel="deprecation""#, link);
+        match HeaderValue::from_str(&value) {
+            Ok(v) => {
+                headers.append(LINK, v);
+            }
+            Err(_) => warn!("⚠️ Invalid deprecation link for {}: {}", rule.host, link),
+        }
+    }
+}
+
+fn insert_if_absent(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    if headers.contains_key(&name) {
+        return;
+    }
+    match HeaderValue::from_str(value) {
+        Ok(v) => {
+            headers.insert(name, v);
+        }
+        Err(_) => warn!("⚠️ Invalid {} header value: {}", name, value),
+    }
+}
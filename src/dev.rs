@@ -0,0 +1,103 @@
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509Name, X509};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::Config;
+
+pub const USER: &str = "dev";
+pub const PASSWORD: &str = "dev";
+pub const ADMIN_TOKEN: &str = "dev";
+
+// `secure-proxy dev`: everything needed to try the proxy locally, without a
+// config.toml. Plain HTTP on `port`, TLS with a throwaway self-signed
+// certificate on `tls_port`, one user, the admin API open to browsers and
+// verbose logs.
+pub fn config(port: u16, tls_port: u16) -> Result<Config, Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join(format!("secure-proxy-dev-{}", std::process::id()));
+    std::fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    let (cert, key) = self_signed_cert(&dir)?;
+    let toml = format!(
+        r#"
+[server]
+port = {port}
+host = "127.0.0.1"
+log_format = "pretty"
+log_level = "debug,hyper=info"
+
+[users]
+{USER} = "{PASSWORD}"
+
+[admin]
+enabled = true
+token = "{ADMIN_TOKEN}"
+
+[metrics]
+enabled = true
+
+[[listeners]]
+address = "127.0.0.1:{port}"
+
+[[listeners]]
+address = "127.0.0.1:{tls_port}"
+tls_cert = {cert:?}
+tls_key = {key:?}
+"#
+    );
+    let mut config: Config = toml::from_str(&toml)?;
+    config.hash = crate::startup::config_hash(&toml);
+    config.admin.cors.allowed_origins = vec!["*".to_string()];
+    Ok(config)
+}
+
+// P-256 key and a one-day certificate for localhost and 127.0.0.1, written
+// as PEM files into `dir`.
+fn self_signed_cert(dir: &Path) -> io::Result<(PathBuf, PathBuf)> {
+    let generate = || -> Result<(Vec<u8>, Vec<u8>), openssl::error::ErrorStack> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+        let mut name = X509Name::builder()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, "localhost")?;
+        let name = name.build();
+        let mut serial = BigNum::new()?;
+        serial.rand(64, MsbOption::MAYBE_ZERO, false)?;
+        let serial = serial.to_asn1_integer()?;
+        let not_before = Asn1Time::days_from_now(0)?;
+        let not_after = Asn1Time::days_from_now(1)?;
+
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        builder.set_serial_number(&serial)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(&name)?;
+        builder.set_pubkey(&key)?;
+        builder.set_not_before(&not_before)?;
+        builder.set_not_after(&not_after)?;
+        let san = SubjectAlternativeName::new()
+            .dns("localhost")
+            .ip("127.0.0.1")
+            .build(&builder.x509v3_context(None, None))?;
+        builder.append_extension(san)?;
+        builder.sign(&key, MessageDigest::sha256())?;
+        Ok((builder.build().to_pem()?, key.private_key_to_pem_pkcs8()?))
+    };
+    let (cert_pem, key_pem) = generate().map_err(io::Error::other)?;
+    let cert = dir.join("cert.pem");
+    let key = dir.join("key.pem");
+    std::fs::write(&cert, cert_pem)?;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&key)?
+        .write_all(&key_pem)?;
+    Ok((cert, key))
+}
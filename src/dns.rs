@@ -0,0 +1,205 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::resolver::Resolver;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize)]
+pub struct DnsConfig {
+    // Nameservers asked instead of the system resolver, in order of
+    // preference: "1.1.1.1", "10.0.0.2:5353" or "[2606:4700::1111]:53"
+    #[serde(default)]
+    pub servers: Vec<String>,
+    // Seconds to wait for one server before asking the next
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    // Fixed addresses for some names, e.g. a staging deployment:
+    //
+    //   [dns.hosts]
+    //   "shop.example.com" = "10.1.2.3"
+    //   "*.staging.example.com" = ["10.1.2.4", "fd00::4"]
+    //
+    // A wildcard covers subdomains only; the most specific entry wins.
+    #[serde(default)]
+    pub hosts: HashMap<String, Addresses>,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        DnsConfig {
+            servers: Vec::new(),
+            timeout: default_timeout(),
+            hosts: HashMap::new(),
+        }
+    }
+}
+
+fn default_timeout() -> u64 {
+    2
+}
+
+// "1.1.1.1" (port 53), "10.0.0.2:5353" or "[2606:4700::1111]:53"
+fn parse_server(server: &str) -> Option<SocketAddr> {
+    server
+        .parse()
+        .ok()
+        .or_else(|| server.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 53)))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Addresses {
+    One(IpAddr),
+    Many(Vec<IpAddr>),
+}
+
+impl Addresses {
+    fn list(&self) -> &[IpAddr] {
+        match self {
+            Addresses::One(ip) => std::slice::from_ref(ip),
+            Addresses::Many(ips) => ips,
+        }
+    }
+}
+
+impl DnsConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(server) = self.servers.iter().find(|s| parse_server(s).is_none()) {
+            return Err(format!("dns.servers: '{}' is not an IP address with an optional port", server));
+        }
+        if self.timeout == 0 {
+            return Err("dns.timeout must be positive".to_string());
+        }
+        for (name, addrs) in &self.hosts {
+            let bare = name.strip_prefix("*.").unwrap_or(name);
+            if bare.is_empty() || bare.contains(['*', ':', '/', ' ']) {
+                return Err(format!("dns.hosts: invalid name '{}'", name));
+            }
+            if addrs.list().is_empty() {
+                return Err(format!("dns.hosts: '{}' needs at least one address", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+// [dns.hosts], lowercased, set once at startup.
+static HOSTS: OnceLock<HashMap<String, Vec<IpAddr>>> = OnceLock::new();
+// dns.servers, when set
+static RESOLVER: OnceLock<Resolver> = OnceLock::new();
+
+pub fn configure(config: &DnsConfig) {
+    let hosts = config
+        .hosts
+        .iter()
+        .map(|(name, addrs)| (name.trim_end_matches('.').to_ascii_lowercase(), addrs.list().to_vec()))
+        .collect();
+    let _ = HOSTS.set(hosts);
+    if !config.servers.is_empty() {
+        let servers = config.servers.iter().filter_map(|s| parse_server(s)).collect();
+        let _ = RESOLVER.set(Resolver::new(servers, Duration::from_secs(config.timeout)));
+    }
+}
+
+// The [dns.hosts] addresses of `host`: its own entry, else the wildcard
+// of its closest parent domain.
+pub fn fixed(host: &str) -> Option<&'static [IpAddr]> {
+    let hosts = HOSTS.get()?;
+    if hosts.is_empty() {
+        return None;
+    }
+    let host = host.trim_start_matches('[').trim_end_matches(']').trim_end_matches('.').to_ascii_lowercase();
+    if let Some(addrs) = hosts.get(&host) {
+        return Some(addrs);
+    }
+    let mut parent = host.as_str();
+    while let Some((_, rest)) = parent.split_once('.') {
+        if let Some(addrs) = hosts.get(&format!("*.{}", rest)) {
+            return Some(addrs);
+        }
+        parent = rest;
+    }
+    None
+}
+
+// Addresses to connect to for host:port: [dns.hosts], then dns.servers or
+// the system resolver. Every outbound name lookup goes through here.
+pub async fn lookup(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    if let Some(addrs) = fixed(host) {
+        debug!("🧭 {} is fixed to {:?} by dns.hosts", host, addrs);
+        return Ok(addrs.iter().map(|ip| SocketAddr::new(*ip, port)).collect());
+    }
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let Some(resolver) = RESOLVER.get() else {
+        return Ok(tokio::net::lookup_host((host, port)).await?.collect());
+    };
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    // Never sent to nameservers (RFC 6761)
+    if host.eq_ignore_ascii_case("localhost") || host.to_ascii_lowercase().ends_with(".localhost") {
+        return Ok(vec![SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), port)]);
+    }
+    let addrs = resolver.lookup(host).await?;
+    Ok(addrs.into_iter().map(|ip| SocketAddr::new(ip, port)).collect())
+}
+
+// Names are resolved through the system resolver on every new upstream
+// connection, with no cache of our own, so a nameserver change (VPN up or
+// down, DHCP renewal) applies to the next connection. This only reports
+// such changes so they can be matched against resolution failures.
+pub fn spawn_watch() {
+    if let Some(resolver) = RESOLVER.get() {
+        let servers: Vec<String> = resolver.servers.iter().map(SocketAddr::to_string).collect();
+        info!("🧭 Nameservers from dns.servers: {}", servers.join(", "));
+        return;
+    }
+    tokio::spawn(async {
+        let path = Path::new(RESOLV_CONF);
+        let mut current = nameservers(path).await;
+        if let Some(servers) = &current {
+            info!("🧭 System nameservers: {}", describe(servers));
+        }
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let servers = nameservers(path).await;
+            if servers == current {
+                continue;
+            }
+            match &servers {
+                Some(list) => info!("🧭 DNS configuration changed, nameservers now: {}", describe(list)),
+                None => warn!("⚠️ {} is no longer readable", RESOLV_CONF),
+            }
+            current = servers;
+        }
+    });
+}
+
+async fn nameservers(path: &Path) -> Option<Vec<String>> {
+    let contents = tokio::fs::read_to_string(path).await.ok()?;
+    Some(
+        contents
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("nameserver"))
+            .filter(|rest| rest.starts_with([' ', '\t']))
+            .map(|rest| rest.trim().to_string())
+            .collect(),
+    )
+}
+
+fn describe(servers: &[String]) -> String {
+    if servers.is_empty() {
+        "none".to_string()
+    } else {
+        servers.join(", ")
+    }
+}
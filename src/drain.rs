@@ -0,0 +1,145 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server};
+use std::convert::Infallible;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{error, info, warn};
+
+use crate::logging::escape;
+use crate::AppState;
+
+// Tunnels named one by one in a drain log report; the rest are counted
+const LOGGED_TUNNELS: usize = 20;
+
+// Set when SIGTERM starts the drain, to the hard stop time.
+pub fn start(state: &AppState, deadline: Instant) {
+    let _ = state.draining.set(deadline);
+}
+
+pub fn is_draining(state: &AppState) -> bool {
+    state.draining.get().is_some()
+}
+
+// The open tunnels and when they will be cut, e.g.
+//   ⏳ Draining 3 connection(s), 2 tunnel(s); hard stop in 17s
+//      alice 10.0.0.5:51234 -> db.internal:5432 via public, open 312s
+pub fn log(state: &AppState, final_report: bool) {
+    let tunnels = state.connections.open();
+    let left = state.draining.get().map_or(0, |d| d.saturating_duration_since(Instant::now()).as_secs());
+    let mut report = match final_report {
+        true => format!(
+            "⏱️ Drain timeout reached, closing {} connection(s), {} tunnel(s)",
+            state.client_slots.active(),
+            tunnels.len()
+        ),
+        false => format!(
+            "⏳ Draining {} connection(s), {} tunnel(s); hard stop in {}s",
+            state.client_slots.active(),
+            tunnels.len(),
+            left
+        ),
+    };
+    for tunnel in tunnels.iter().take(LOGGED_TUNNELS) {
+        let _ = write!(
+            report,
+            "\n   {} {} -> {} via {}, open {}s",
+            tunnel.user,
+            tunnel.client,
+            tunnel.target,
+            tunnel.listener,
+            tunnel.since.elapsed().as_secs()
+        );
+    }
+    if tunnels.len() > LOGGED_TUNNELS {
+        let _ = write!(report, "\n   ... and {} more", tunnels.len() - LOGGED_TUNNELS);
+    }
+    match final_report {
+        true => warn!("{}", report),
+        false => info!("{}", report),
+    }
+}
+
+// GET /readyz: readiness with the reason, and while draining what is
+// still open. 200 only when ready.
+pub fn readyz(state: &AppState) -> Response<Body> {
+    let (status, ready) = match (is_draining(state), state.watchdog.is_ready()) {
+        (true, _) => ("draining", false),
+        (false, true) => ("ready", true),
+        (false, false) => ("not_ready", false),
+    };
+    let mut json = format!(
+        "{{\"status\":\"{}\",\"connections\":{},\"tunnels\":{}",
+        status,
+        state.client_slots.active(),
+        state.tunnel_slots.active()
+    );
+    if let Some(deadline) = state.draining.get() {
+        let tunnels: Vec<String> = state
+            .connections
+            .open()
+            .iter()
+            .map(|t| {
+                format!(
+                    "{{\"user\":\"{}\",\"client\":\"{}\",\"target\":\"{}\",\"listener\":\"{}\",\"open_seconds\":{}}}",
+                    escape(&t.user),
+                    t.client,
+                    escape(&t.target),
+                    escape(&t.listener),
+                    t.since.elapsed().as_secs()
+                )
+            })
+            .collect();
+        let _ = write!(
+            json,
+            ",\"hard_stop_in\":{},\"open_tunnels\":[{}]",
+            deadline.saturating_duration_since(Instant::now()).as_secs(),
+            tunnels.join(",")
+        );
+    }
+    json.push('}');
+    Response::builder()
+        .status(if ready { 200 } else { 503 })
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(json))
+        .unwrap()
+}
+
+// Health endpoints on their own address, which stays open through the
+// drain after the proxy listeners have closed.
+pub fn spawn_status(state: Arc<AppState>, addr: SocketAddr) {
+    let make_svc = make_service_fn(move |_| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(status_response(&state, &req)) }
+            }))
+        }
+    });
+    let server = match Server::try_bind(&addr) {
+        Ok(builder) => builder.serve(make_svc),
+        Err(e) => {
+            error!("❌ Failed to bind status address {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    };
+    info!("🩺 Serving /health, /ready and /readyz on {}", addr);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("❌ Status server error on {}: {}", addr, e);
+        }
+    });
+}
+
+fn status_response(state: &AppState, req: &Request<Body>) -> Response<Body> {
+    let text = |status: u16, body: &'static str| Response::builder().status(status).body(Body::from(body)).unwrap();
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/health") => text(200, "OK"),
+        (&Method::GET, "/ready") if state.watchdog.is_ready() && !is_draining(state) => text(200, "READY"),
+        (&Method::GET, "/ready") => text(503, "NOT READY"),
+        (&Method::GET, "/readyz") => readyz(state),
+        _ => text(404, "Not Found"),
+    }
+}
@@ -0,0 +1,432 @@
+use serde::Deserialize;
+use std::ffi::CStr;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::net::{TcpStream, UdpSocket};
+use tracing::debug;
+
+// A local network path other than the default route, e.g. a VPN tunnel:
+//
+//   [interfaces.vpn]
+//   device = "tun0"
+//   networks = ["10.0.0.0/8", "172.16.0.0/12"]
+#[derive(Debug, Clone, Deserialize)]
+pub struct InterfaceConfig {
+    // Network device to send through, e.g. "tun0" or "wg0"
+    pub device: Option<String>,
+    // Local address to bind; with `device`, picks one of its addresses
+    pub source_ip: Option<IpAddr>,
+    // Destination ranges sent this way automatically while it is up
+    #[serde(default)]
+    pub networks: Vec<String>,
+}
+
+impl InterfaceConfig {
+    pub fn validate(&self, name: &str) -> Result<(), String> {
+        if self.device.is_none() && self.source_ip.is_none() {
+            return Err(format!("interface '{}' needs a device or a source_ip", name));
+        }
+        if let Some(network) = self.networks.iter().find(|n| parse_network(n).is_none()) {
+            return Err(format!("interface '{}' has invalid network '{}'", name, network));
+        }
+        Ok(())
+    }
+}
+
+// Which addresses of a dual-stack destination to connect to, e.g. to
+// avoid IPv6 paths that resolve but never answer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IpFamily {
+    // In the order the resolver returns them
+    #[default]
+    System,
+    PreferIpv6,
+    PreferIpv4,
+    Ipv6Only,
+    Ipv4Only,
+}
+
+impl IpFamily {
+    // Resolve host:port and order (or drop) the addresses accordingly. The
+    // preferred family is tried first, the other one after it fails.
+    async fn lookup(self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let mut addrs = crate::dns::lookup(host, port).await?;
+        match self {
+            IpFamily::System => {}
+            IpFamily::PreferIpv6 => addrs.sort_by_key(|a| a.is_ipv4()),
+            IpFamily::PreferIpv4 => addrs.sort_by_key(|a| a.is_ipv6()),
+            IpFamily::Ipv6Only => addrs.retain(|a| a.is_ipv6()),
+            IpFamily::Ipv4Only => addrs.retain(|a| a.is_ipv4()),
+        }
+        match addrs.is_empty() {
+            true => Err(no_addresses(host, self)),
+            false => Ok(addrs),
+        }
+    }
+}
+
+// Connection Attempt Delay, as RFC 8305 recommends
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+// Loopback, RFC 1918, carrier-grade NAT, link-local, unique local,
+// multicast and other ranges that are not the public internet
+const PRIVATE: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/3",
+    "::/127",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+// Networks direct connections must not reach. The check runs on the very
+// addresses that are then connected to, so a name cannot resolve to a
+// public address for the check and an internal one for the connection
+// (DNS rebinding).
+pub struct Guard {
+    deny: Vec<(IpAddr, u8)>,
+    // Exceptions, e.g. an intranet range some users need
+    allow: Vec<(IpAddr, u8)>,
+}
+
+impl Guard {
+    pub fn private(allow: &[String]) -> Self {
+        Guard {
+            deny: PRIVATE.iter().filter_map(|n| parse_network(n)).collect(),
+            allow: allow.iter().filter_map(|n| parse_network(n)).collect(),
+        }
+    }
+
+    fn denies(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        let within = |networks: &[(IpAddr, u8)]| networks.iter().any(|(network, prefix)| in_network(ip, *network, *prefix));
+        within(&self.deny) && !within(&self.allow)
+    }
+
+    // Any denied address refuses the name, whichever one would be tried.
+    fn check(&self, host: &str, addrs: &[SocketAddr]) -> io::Result<()> {
+        match addrs.iter().find(|a| self.denies(a.ip())) {
+            Some(addr) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                Refused {
+                    host: host.to_string(),
+                    ip: addr.ip(),
+                },
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+// What applies to one user's direct connections.
+#[derive(Clone, Default)]
+pub struct Outbound {
+    pub guard: Option<Arc<Guard>>,
+    // Their dedicated address from [egress_ips]
+    pub source: Option<IpAddr>,
+}
+
+// The error a connection refused by a Guard fails with.
+#[derive(Debug)]
+pub struct Refused {
+    pub host: String,
+    pub ip: IpAddr,
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host.parse::<IpAddr>() {
+            Ok(_) => write!(f, "{} is a private address", self.ip),
+            Err(_) => write!(f, "{} resolves to the private address {}", self.host, self.ip),
+        }
+    }
+}
+
+impl std::error::Error for Refused {}
+
+// The Refused error behind `e`, if a Guard caused it.
+pub fn refused(e: &io::Error) -> Option<&Refused> {
+    e.get_ref()?.downcast_ref()
+}
+
+pub struct Interface {
+    pub name: String,
+    config: InterfaceConfig,
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl Interface {
+    pub fn new(name: &str, config: &InterfaceConfig) -> Self {
+        Interface {
+            name: name.to_string(),
+            networks: config.networks.iter().filter_map(|n| parse_network(n)).collect(),
+            config: config.clone(),
+        }
+    }
+
+    // The device is present and up (and holds `source_ip`, if set).
+    pub fn is_up(&self) -> bool {
+        !self.sources().is_empty()
+    }
+
+    pub fn has_networks(&self) -> bool {
+        !self.networks.is_empty()
+    }
+
+    fn covers(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|(network, prefix)| in_network(ip, *network, *prefix))
+    }
+
+    fn sources(&self) -> Vec<IpAddr> {
+        interface_addrs()
+            .into_iter()
+            .filter(|(device, _)| self.config.device.as_ref().is_none_or(|d| d == device))
+            .map(|(_, ip)| ip)
+            .filter(|ip| self.config.source_ip.is_none_or(|s| s == *ip))
+            .collect()
+    }
+
+    async fn connect_addr(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let source = self
+            .sources()
+            .into_iter()
+            .find(|ip| ip.is_ipv4() == addr.is_ipv4())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    format!("interface '{}' has no usable address for {}", self.name, addr),
+                )
+            })?;
+        let socket = crate::tcp::upstream_socket(addr)?;
+        if let Some(device) = &self.config.device {
+            // Needs CAP_NET_RAW; binding the source address is usually enough
+            if let Err(e) = socket.bind_device(Some(device.as_bytes())) {
+                debug!("Could not bind to device {} ({}), using source address only", device, e);
+            }
+        }
+        socket.bind(SocketAddr::new(source, 0))?;
+        let stream = socket.connect(addr).await?;
+        crate::tcp::config().upstream.apply(&stream);
+        Ok(stream)
+    }
+
+    // Connect to host:port through this interface.
+    pub async fn connect(&self, host: &str, port: u16, family: IpFamily, guard: Option<&Guard>) -> io::Result<TcpStream> {
+        let addrs = family.lookup(host, port).await?;
+        if let Some(guard) = guard {
+            guard.check(host, &addrs)?;
+        }
+        race(addrs, |addr| self.connect_addr(addr)).await
+    }
+}
+
+// Direct connection, except that destinations inside the networks of an
+// interface that is up leave through that interface (split horizon).
+// Otherwise a user with a source address leaves from it, which limits
+// them to destinations of that address's family.
+pub async fn connect_direct(
+    interfaces: &[Arc<Interface>],
+    host: &str,
+    port: u16,
+    family: IpFamily,
+    outbound: &Outbound,
+) -> io::Result<TcpStream> {
+    let mut addrs = family.lookup(host, port).await?;
+    if let Some(guard) = &outbound.guard {
+        guard.check(host, &addrs)?;
+    }
+    if let Some(source) = outbound.source {
+        addrs.retain(|a| a.is_ipv4() == source.is_ipv4());
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no address reachable from {}", host, source),
+            ));
+        }
+    }
+    race(addrs, |addr| async move {
+        match interfaces.iter().find(|i| i.covers(addr.ip()) && i.is_up()) {
+            Some(interface) => {
+                debug!("{} is in the networks of interface '{}'", addr, interface.name);
+                interface.connect_addr(addr).await
+            }
+            None => crate::tcp::connect(addr, outbound.source).await,
+        }
+    })
+    .await
+}
+
+// A UDP socket connected to host:port, for relayed datagrams. The guard and
+// source address apply as for TCP; with nothing to race, the first address
+// in the preferred family's order is used and interfaces are not consulted.
+pub async fn bind_udp(host: &str, port: u16, family: IpFamily, outbound: &Outbound) -> io::Result<UdpSocket> {
+    let mut addrs = family.lookup(host, port).await?;
+    if let Some(guard) = &outbound.guard {
+        guard.check(host, &addrs)?;
+    }
+    if let Some(source) = outbound.source {
+        addrs.retain(|a| a.is_ipv4() == source.is_ipv4());
+    }
+    let Some(addr) = addrs.first() else {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no reachable address", host)));
+    };
+    let local = match (outbound.source, addr) {
+        (Some(source), _) => source,
+        (None, SocketAddr::V4(_)) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        (None, SocketAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+// Happy Eyeballs (RFC 8305): the address families take turns, and a new
+// attempt starts every ATTEMPT_DELAY, or as soon as one fails, while the
+// earlier ones are still pending. The first connection wins and the others
+// are dropped, so a broken IPv6 path costs a quarter second, not a timeout.
+pub async fn race<F, Fut>(addrs: Vec<SocketAddr>, connect: F) -> io::Result<TcpStream>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<TcpStream>>,
+{
+    let mut queue = interleave(addrs).into_iter();
+    let mut attempts: Vec<Pin<Box<Fut>>> = Vec::new();
+    let mut last_error = None;
+    loop {
+        if attempts.is_empty() {
+            match queue.next() {
+                Some(addr) => attempts.push(Box::pin(connect(addr))),
+                None => {
+                    return Err(last_error
+                        .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")))
+                }
+            }
+        }
+        let finished = std::future::poll_fn(|cx| {
+            for (i, attempt) in attempts.iter_mut().enumerate() {
+                if let Poll::Ready(result) = attempt.as_mut().poll(cx) {
+                    return Poll::Ready((i, result));
+                }
+            }
+            Poll::Pending
+        });
+        tokio::select! {
+            (i, result) = finished => {
+                attempts.swap_remove(i);
+                match result {
+                    Ok(stream) => return Ok(stream),
+                    Err(e) => last_error = Some(e),
+                }
+                if let Some(addr) = queue.next() {
+                    attempts.push(Box::pin(connect(addr)));
+                }
+            }
+            _ = tokio::time::sleep(ATTEMPT_DELAY), if queue.len() > 0 => {
+                if let Some(addr) = queue.next() {
+                    debug!("No connection after {:?}, also trying {}", ATTEMPT_DELAY, addr);
+                    attempts.push(Box::pin(connect(addr)));
+                }
+            }
+        }
+    }
+}
+
+// Alternate families, starting with that of the first address.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(ipv4_first) = addrs.first().map(SocketAddr::is_ipv4) else {
+        return addrs;
+    };
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|a| a.is_ipv4() == ipv4_first);
+    let mut other = other.into_iter();
+    let mut ordered = Vec::new();
+    for addr in preferred {
+        ordered.push(addr);
+        ordered.extend(other.next());
+    }
+    ordered.extend(other);
+    ordered
+}
+
+fn no_addresses(host: &str, family: IpFamily) -> io::Error {
+    let kind = match family {
+        IpFamily::Ipv6Only => "IPv6 ",
+        IpFamily::Ipv4Only => "IPv4 ",
+        _ => "",
+    };
+    io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve to any {}address", host, kind))
+}
+
+// "10.0.0.0/8", "fd00::/8", or a single address.
+pub fn parse_network(network: &str) -> Option<(IpAddr, u8)> {
+    let (addr, prefix) = match network.split_once('/') {
+        Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+        None => (network.parse::<IpAddr>().ok()?, None),
+    };
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = prefix.unwrap_or(max);
+    (prefix <= max).then_some((addr, prefix))
+}
+
+pub fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+// Addresses of the interfaces that are currently up, by device name.
+pub fn interface_addrs() -> Vec<(String, IpAddr)> {
+    let mut addrs = Vec::new();
+    let mut head: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut head) } != 0 {
+        return addrs;
+    }
+    let mut cursor = head;
+    while !cursor.is_null() {
+        let ifa = unsafe { &*cursor };
+        cursor = ifa.ifa_next;
+        if ifa.ifa_addr.is_null() || ifa.ifa_flags & libc::IFF_UP as u32 == 0 {
+            continue;
+        }
+        let ip = match unsafe { (*ifa.ifa_addr).sa_family } as i32 {
+            libc::AF_INET => {
+                let sa = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(sa.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let sa = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                IpAddr::V6(Ipv6Addr::from(sa.sin6_addr.s6_addr))
+            }
+            _ => continue,
+        };
+        let name = unsafe { CStr::from_ptr(ifa.ifa_name) }.to_string_lossy().into_owned();
+        addrs.push((name, ip));
+    }
+    unsafe { libc::freeifaddrs(head) };
+    addrs
+}
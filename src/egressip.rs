@@ -0,0 +1,170 @@
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+use crate::groups::{self, GroupConfig};
+use crate::store::Store;
+
+const STORE_PREFIX: &str = "egress_ip.";
+// Largest network a pool entry may expand to
+const MAX_POOL: u128 = 65_536;
+
+// A dedicated source address per user for direct connections, so each
+// customer's traffic leaves from its own IP:
+//
+//   [egress_ips]
+//   pool = ["203.0.113.16/28", "203.0.113.40"]
+//   users = ["@customers"]
+//
+//   [egress_ips.assign]
+//   bigcorp = "203.0.113.99"
+#[derive(Debug, Deserialize)]
+pub struct EgressIpConfig {
+    // Addresses or networks handed out, one address per user
+    #[serde(default)]
+    pub pool: Vec<String>,
+    // Who gets one: names, "@group" or "*"
+    #[serde(default = "everyone")]
+    pub users: Vec<String>,
+    // Fixed addresses by user name, in the pool or not
+    #[serde(default)]
+    pub assign: HashMap<String, IpAddr>,
+}
+
+impl Default for EgressIpConfig {
+    fn default() -> Self {
+        EgressIpConfig {
+            pool: Vec::new(),
+            users: everyone(),
+            assign: HashMap::new(),
+        }
+    }
+}
+
+fn everyone() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+impl EgressIpConfig {
+    pub fn enabled(&self) -> bool {
+        !self.pool.is_empty() || !self.assign.is_empty()
+    }
+
+    pub fn validate(&self, groups: &HashMap<String, GroupConfig>) -> Result<(), String> {
+        if let Some(entry) = self.pool.iter().find(|e| expand(e).is_none()) {
+            return Err(format!(
+                "egress_ips.pool: '{}' is not an address or a network of at most {} addresses",
+                entry, MAX_POOL
+            ));
+        }
+        groups::check_refs(&self.users, groups).map_err(|e| format!("egress_ips.users: {}", e))
+    }
+}
+
+// "203.0.113.40" or "203.0.113.16/28"; a network's addresses all count,
+// as it is up to the operator to have them routed to this host.
+fn expand(entry: &str) -> Option<Vec<IpAddr>> {
+    let (network, prefix) = crate::egress::parse_network(entry)?;
+    let bits = if network.is_ipv4() { 32 } else { 128 };
+    let size = 1u128.checked_shl(bits - prefix as u32).unwrap_or(u128::MAX);
+    if size > MAX_POOL {
+        return None;
+    }
+    Some(match network {
+        IpAddr::V4(v4) => {
+            let start = u32::from(v4) & u32::MAX.checked_shl(bits - prefix as u32).unwrap_or(0);
+            (0..size as u32).map(|i| IpAddr::V4((start + i).into())).collect()
+        }
+        IpAddr::V6(v6) => {
+            let start = u128::from(v6) & u128::MAX.checked_shl(bits - prefix as u32).unwrap_or(0);
+            (0..size).map(|i| IpAddr::V6((start + i).into())).collect()
+        }
+    })
+}
+
+// Which pool address each user has. A user keeps theirs across restarts
+// when the state store is configured.
+pub struct EgressIps {
+    pool: Vec<IpAddr>,
+    assigned: Mutex<HashMap<String, IpAddr>>,
+    store: Option<Arc<Store>>,
+}
+
+impl EgressIps {
+    pub fn new(config: &EgressIpConfig, store: Option<Arc<Store>>) -> Self {
+        let mut pool: Vec<IpAddr> = config.pool.iter().filter_map(|e| expand(e)).flatten().collect();
+        // Fixed addresses are nobody else's
+        pool.retain(|ip| !config.assign.values().any(|fixed| fixed == ip));
+        pool.sort();
+        pool.dedup();
+        // Binding an address the host does not have fails every connection
+        let local: Vec<IpAddr> = crate::egress::interface_addrs().into_iter().map(|(_, ip)| ip).collect();
+        let missing: Vec<&IpAddr> = pool
+            .iter()
+            .chain(config.assign.values())
+            .filter(|ip| !ip.is_loopback() && !local.contains(ip))
+            .collect();
+        if let Some(first) = missing.first() {
+            warn!(
+                "⚠️ {} egress IP(s) are not assigned to this host (e.g. {}); users given them cannot connect",
+                missing.len(),
+                first
+            );
+        }
+        let assigned = store
+            .as_ref()
+            .map(|store| store.scan(STORE_PREFIX))
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(user, ip)| Some((user, ip.parse().ok()?)))
+            .filter(|(_, ip)| pool.contains(ip))
+            .collect();
+        EgressIps {
+            pool,
+            assigned: Mutex::new(assigned),
+            store,
+        }
+    }
+
+    // The source address for `user`, a member of `group`, if they get one.
+    pub fn source(&self, config: &EgressIpConfig, user: &str, group: Option<&str>) -> Option<IpAddr> {
+        if let Some(ip) = config.assign.get(user) {
+            return Some(*ip);
+        }
+        if self.pool.is_empty() || !groups::covers(&config.users, user, group) {
+            return None;
+        }
+        let mut assigned = self.assigned.lock().unwrap();
+        if let Some(ip) = assigned.get(user) {
+            return Some(*ip);
+        }
+        let ip = match self.pool.iter().find(|ip| !assigned.values().any(|taken| taken == *ip)) {
+            Some(free) => {
+                info!("📤 User '{}' now leaves from {}", user, free);
+                *free
+            }
+            None => {
+                // Better a shared address than the proxy's own
+                let mut hasher = DefaultHasher::new();
+                user.hash(&mut hasher);
+                let shared = self.pool[hasher.finish() as usize % self.pool.len()];
+                warn!("⚠️ Egress IP pool exhausted, user '{}' shares {}", user, shared);
+                shared
+            }
+        };
+        assigned.insert(user.to_string(), ip);
+        if let Some(store) = &self.store {
+            store.set(&format!("{}{}", STORE_PREFIX, user), &ip.to_string());
+        }
+        Some(ip)
+    }
+
+    // (users with an address, pool size), for /metrics.
+    pub fn usage(&self) -> (usize, usize) {
+        (self.assigned.lock().unwrap().len(), self.pool.len())
+    }
+}
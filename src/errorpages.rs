@@ -0,0 +1,193 @@
+use hyper::header::{HeaderValue, ACCEPT, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, HeaderMap, Response};
+use serde::Deserialize;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use tracing::info;
+
+// HTML pages shown to browsers instead of the proxy's terse error bodies:
+//
+//   [error_pages]
+//   blocked = "/etc/proxy/pages/blocked.html"
+//   auth = "/etc/proxy/pages/login.html"
+//
+// Templates may use {user}, {target}, {rule}, {status}, {code}, {message},
+// {request_id} and {client_ip}; values are HTML-escaped.
+#[derive(Debug, Default, Deserialize)]
+pub struct ErrorPagesConfig {
+    // 403 for destinations the ACL, a blocklist, a country rule or
+    // deny_private refuses
+    pub blocked: Option<PathBuf>,
+    // 407 asking for proxy credentials
+    pub auth: Option<PathBuf>,
+    // Quota exhausted and 429 for too many tunnels
+    pub limit: Option<PathBuf>,
+    // 502 and 504 when the destination could not be reached or failed
+    pub upstream: Option<PathBuf>,
+}
+
+impl ErrorPagesConfig {
+    pub fn enabled(&self) -> bool {
+        self.blocked.is_some() || self.auth.is_some() || self.limit.is_some() || self.upstream.is_some()
+    }
+}
+
+// What the proxy itself refused or failed, kept on the response so the
+// page can be filled in once the request is done.
+#[derive(Clone)]
+pub struct ProxyError {
+    pub code: String,
+    pub message: String,
+    pub user: Option<String>,
+    pub rule: Option<String>,
+}
+
+impl ProxyError {
+    pub fn new(code: &str, message: &str) -> Self {
+        ProxyError {
+            code: code.to_string(),
+            message: message.to_string(),
+            user: None,
+            rule: None,
+        }
+    }
+}
+
+// Name who was refused, and by which rule, on a response that carries a
+// ProxyError.
+pub fn blame(mut response: Response<Body>, user: &str, rule: Option<&str>) -> Response<Body> {
+    if let Some(error) = response.extensions_mut().get_mut::<ProxyError>() {
+        error.user = Some(user.to_string());
+        error.rule = rule.map(str::to_string);
+    }
+    response
+}
+
+// The request's side of the template variables.
+pub struct Request {
+    pub target: String,
+    pub request_id: String,
+    pub client_ip: IpAddr,
+    html: bool,
+}
+
+impl Request {
+    pub fn new(headers: &HeaderMap, target: String, request_id: String, client_ip: IpAddr) -> Self {
+        // Browsers ask for text/html; API clients keep the JSON bodies
+        let html = headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .any(|v| v.split(',').any(|t| t.trim().starts_with("text/html")));
+        Request {
+            target,
+            request_id,
+            client_ip,
+            html,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ErrorPages {
+    blocked: Option<String>,
+    auth: Option<String>,
+    limit: Option<String>,
+    upstream: Option<String>,
+}
+
+impl ErrorPages {
+    pub fn load(config: &ErrorPagesConfig) -> io::Result<Self> {
+        let read = |path: &Option<PathBuf>| -> io::Result<Option<String>> {
+            let Some(path) = path else {
+                return Ok(None);
+            };
+            let page = std::fs::read_to_string(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("error page {}: {}", path.display(), e)))?;
+            info!("📄 Loaded error page {}", path.display());
+            Ok(Some(page))
+        };
+        Ok(ErrorPages {
+            blocked: read(&config.blocked)?,
+            auth: read(&config.auth)?,
+            limit: read(&config.limit)?,
+            upstream: read(&config.upstream)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocked.is_none() && self.auth.is_none() && self.limit.is_none() && self.upstream.is_none()
+    }
+
+    fn page(&self, status: u16, code: &str) -> Option<&str> {
+        match (status, code) {
+            (_, "quota_exceeded") | (429, _) => self.limit.as_deref(),
+            (403, _) => self.blocked.as_deref(),
+            (407, _) => self.auth.as_deref(),
+            (502 | 504, _) => self.upstream.as_deref(),
+            _ => None,
+        }
+    }
+
+    // `response` with its body replaced by the matching page, if it is a
+    // proxy error that has one and the client reads HTML. Status and other
+    // headers (Proxy-Authenticate, Retry-After) are kept.
+    pub fn render(&self, request: &Request, mut response: Response<Body>) -> Response<Body> {
+        if !request.html {
+            return response;
+        }
+        let Some(error) = response.extensions().get::<ProxyError>() else {
+            return response;
+        };
+        let status = response.status().as_u16();
+        let Some(page) = self.page(status, &error.code) else {
+            return response;
+        };
+        let vars = [
+            ("{user}", error.user.as_deref().unwrap_or("-")),
+            ("{target}", &request.target),
+            ("{rule}", error.rule.as_deref().unwrap_or_default()),
+            ("{status}", &status.to_string()),
+            ("{code}", &error.code),
+            ("{message}", &error.message),
+            ("{request_id}", &request.request_id),
+            ("{client_ip}", &request.client_ip.to_string()),
+        ]
+        .map(|(name, value)| (name, escape(value)));
+        let html = fill(page, &vars);
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        headers.remove(CONTENT_LENGTH);
+        *response.body_mut() = Body::from(html);
+        response
+    }
+}
+
+// One pass over `page`, so a value that happens to contain "{rule}" is
+// not filled in again.
+fn fill(page: &str, vars: &[(&str, String)]) -> String {
+    let mut out = String::with_capacity(page.len());
+    let mut rest = page;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match vars.iter().find(|(name, _)| rest.starts_with(name)) {
+            Some((name, value)) => {
+                out.push_str(value);
+                rest = &rest[name.len()..];
+            }
+            None => {
+                out.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
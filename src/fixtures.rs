@@ -0,0 +1,152 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::Config;
+
+// (file name, what it sets up, contents). Plain text so the files read like
+// hand-written configs; every run checks them against Config, so one that
+// no longer parses or validates fails here rather than for the user.
+const FIXTURES: &[(&str, &str, &str)] = &[
+    (
+        "basic-auth.toml",
+        "Forward proxy with Basic auth for two users and the admin API",
+        r#"[server]
+host = "127.0.0.1"
+port = 8080
+
+[users]
+alice = "change-me"
+bob = "change-me-too"
+
+[admin]
+enabled = true
+token = "change-me-admin-token"
+
+[metrics]
+enabled = true
+"#,
+    ),
+    (
+        "chained.toml",
+        "Proxy that sends internal hosts through a parent proxy and .onion through Tor",
+        r#"[server]
+host = "127.0.0.1"
+port = 8080
+
+[users]
+alice = "change-me"
+
+[upstreams.parent]
+type = "http"
+address = "127.0.0.1:3128"
+username = "svc-proxy"
+password = "change-me"
+
+[upstreams.tor]
+type = "socks5"
+address = "127.0.0.1:9050"
+
+[[routes]]
+host = "*.internal.example"
+via = "parent"
+fallback = "direct"
+
+[[routes]]
+host = "*.onion"
+via = "tor"
+
+[[routes]]
+host = "*"
+via = "direct"
+"#,
+    ),
+    (
+        "filtering-gateway.toml",
+        "Guest-network gateway: knock-to-open gate, decoy users, connection and bandwidth caps, a banner on pages",
+        r#"[server]
+host = "0.0.0.0"
+port = 8080
+
+[users]
+guest = "change-me"
+
+[gate]
+enabled = true
+secret = "change-me-gate-secret"
+path = "/knock"
+ttl = 3600
+
+[honeypot]
+users = ["admin", "root", "proxy"]
+ban_duration = 86400
+
+[limits]
+max_connections_per_user = 50
+max_client_connections = 500
+max_tunnels = 250
+
+[bandwidth]
+per_connection = 1048576
+per_user = 5242880
+
+[[banners]]
+host = "*"
+path_prefix = "/"
+html = '<div style="background:#fc0;padding:4px">You are on the guest network</div>'
+"#,
+    ),
+    (
+        "cert-lab.toml",
+        "TLS lab: records the certificates CONNECT destinations present and flags unexpected changes",
+        r#"[server]
+host = "127.0.0.1"
+port = 8080
+log_level = "debug,hyper=info"
+
+[users]
+lab = "lab"
+
+[cert_watch]
+domains = ["*"]
+interval = 60
+log = "origin-certs.log"
+
+[certs]
+interval = 60
+"#,
+    ),
+];
+
+// `secure-proxy gen-fixtures`: writes the example configs into `dir`
+// (default ~/.config/secure-proxy/examples), replacing earlier copies.
+// The output is the same on every run of the same version.
+pub fn run(dir: Option<PathBuf>) -> Result<(), String> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => default_dir().ok_or("cannot find the home directory; pass --dir")?,
+    };
+    for (name, _, contents) in FIXTURES {
+        Config::parse(contents).map_err(|e| format!("fixture {} is out of date: {}", name, e))?;
+    }
+    fs::create_dir_all(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    for (name, about, contents) in FIXTURES {
+        let path = dir.join(name);
+        let text = format!(
+            "# {}\n# Generated by `secure-proxy gen-fixtures` {}; run it again to refresh.\n\n{}",
+            about,
+            env!("CARGO_PKG_VERSION"),
+            contents
+        );
+        fs::write(&path, text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+fn default_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME").filter(|v| !v.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(&std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("secure-proxy").join("examples"))
+}
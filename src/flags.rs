@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::store::Store;
+
+pub const CACHE: &str = "cache";
+pub const BANNERS: &str = "banners";
+pub const BANDWIDTH: &str = "bandwidth";
+pub const ROUTING: &str = "routing";
+pub const DEPRECATIONS: &str = "deprecations";
+
+// Every feature that can be switched off at runtime. A flag only gates a
+// feature that is also configured; turning it on does not enable anything
+// the config leaves off.
+pub const ALL: &[&str] = &[CACHE, BANNERS, BANDWIDTH, ROUTING, DEPRECATIONS];
+
+const STORE_PREFIX: &str = "flags.";
+
+pub struct FeatureFlags {
+    flags: Vec<(&'static str, AtomicBool)>,
+    store: Option<Arc<Store>>,
+}
+
+impl FeatureFlags {
+    // Flags default to on; `initial` comes from the [features] table. Values
+    // changed at runtime and persisted in `store` take precedence.
+    pub fn new(initial: &HashMap<String, bool>, store: Option<Arc<Store>>) -> Self {
+        let persisted: HashMap<String, bool> = store
+            .as_ref()
+            .map(|store| store.scan(STORE_PREFIX))
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, value.parse().ok()?)))
+            .collect();
+        FeatureFlags {
+            flags: ALL
+                .iter()
+                .map(|&name| {
+                    let on = persisted
+                        .get(name)
+                        .or_else(|| initial.get(name))
+                        .copied()
+                        .unwrap_or(true);
+                    (name, AtomicBool::new(on))
+                })
+                .collect(),
+            store,
+        }
+    }
+
+    pub fn enabled(&self, name: &str) -> bool {
+        self.flags
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, on)| on.load(Ordering::Relaxed))
+            .unwrap_or(false)
+    }
+
+    // Flip a flag and write an audit record. Returns the previous value, or
+    // None for an unknown flag.
+    pub fn set(&self, name: &str, on: bool, actor: &str) -> Option<bool> {
+        let (name, flag) = self.flags.iter().find(|(n, _)| *n == name)?;
+        let previous = flag.swap(on, Ordering::Relaxed);
+        if let Some(store) = &self.store {
+            store.set(&format!("{}{}", STORE_PREFIX, name), &on.to_string());
+        }
+        info!(
+            target: "audit",
+            flag = %name,
+            enabled = on,
+            previous = previous,
+            actor = %actor,
+            "feature flag changed"
+        );
+        Some(previous)
+    }
+
+    pub fn snapshot(&self) -> Vec<(&'static str, bool)> {
+        self.flags
+            .iter()
+            .map(|(name, on)| (*name, on.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+pub fn validate(initial: &HashMap<String, bool>) -> Result<(), String> {
+    match initial.keys().find(|name| !ALL.contains(&name.as_str())) {
+        Some(name) => Err(format!("unknown feature flag '{}'", name)),
+        None => Ok(()),
+    }
+}
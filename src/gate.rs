@@ -0,0 +1,136 @@
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+#[derive(Debug, Deserialize)]
+pub struct GateConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // HMAC-SHA256 key for signed knock URLs
+    #[serde(default)]
+    pub secret: String,
+    #[serde(default = "default_path")]
+    pub path: String,
+    // Seconds a client IP stays allowlisted after a valid knock
+    #[serde(default = "default_ttl")]
+    pub ttl: u64,
+    // Longest a knock URL may be valid for, in seconds from now; URLs that
+    // expire further ahead are refused, so a leaked one cannot work forever
+    #[serde(default = "default_max_lifetime")]
+    pub max_lifetime: u64,
+}
+
+impl Default for GateConfig {
+    fn default() -> Self {
+        GateConfig {
+            enabled: false,
+            secret: String::new(),
+            path: default_path(),
+            ttl: default_ttl(),
+            max_lifetime: default_max_lifetime(),
+        }
+    }
+}
+
+fn default_path() -> String {
+    "/knock".to_string()
+}
+
+fn default_ttl() -> u64 {
+    3600
+}
+
+fn default_max_lifetime() -> u64 {
+    86_400
+}
+
+impl GateConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enabled && self.secret.is_empty() {
+            return Err("gate is enabled but gate.secret is not set".to_string());
+        }
+        if self.enabled && self.max_lifetime == 0 {
+            return Err("gate.max_lifetime must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Pre-authentication gate: until a client IP has visited a valid signed
+// URL, the proxy answers it with a bare 403 and never offers proxy auth.
+#[derive(Default)]
+pub struct Gate {
+    allowed: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl Gate {
+    pub fn is_allowed(&self, config: &GateConfig, ip: IpAddr) -> bool {
+        if !config.enabled {
+            return true;
+        }
+        self.allowed
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .is_some_and(|until| *until > Instant::now())
+    }
+
+    // Handle `<path>?expires=<unix seconds>&sig=<hex HMAC-SHA256(secret, expires)>`.
+    pub fn knock(&self, config: &GateConfig, query: Option<&str>, ip: IpAddr) -> bool {
+        let params: HashMap<&str, &str> = query
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .collect();
+        let (Some(expires), Some(sig)) = (params.get("expires"), params.get("sig")) else {
+            warn!("🚪 Malformed knock from {}", ip);
+            return false;
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let Ok(expires_at) = expires.parse::<u64>() else {
+            warn!("🚪 Malformed knock from {}", ip);
+            return false;
+        };
+        if expires_at < now {
+            warn!("🚪 Expired knock URL used from {}", ip);
+            return false;
+        }
+        if expires_at - now > config.max_lifetime {
+            warn!("🚪 Knock URL valid for longer than gate.max_lifetime used from {}", ip);
+            return false;
+        }
+        let valid = sign(&config.secret, expires).is_some_and(|expected| {
+            expected.len() == sig.len()
+                && openssl::memcmp::eq(expected.as_bytes(), sig.to_ascii_lowercase().as_bytes())
+        });
+        if !valid {
+            warn!("🚪 Bad knock signature from {}", ip);
+            return false;
+        }
+
+        let mut allowed = self.allowed.lock().unwrap();
+        let now = Instant::now();
+        allowed.retain(|_, until| *until > now);
+        allowed.insert(ip, now + Duration::from_secs(config.ttl));
+        info!("🚪 Allowlisted {} for {}s", ip, config.ttl);
+        true
+    }
+}
+
+// Lowercase hex HMAC-SHA256 of `message`; also signs snapshots.
+pub fn sign(secret: &str, message: &str) -> Option<String> {
+    let key = PKey::hmac(secret.as_bytes()).ok()?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).ok()?;
+    signer.update(message.as_bytes()).ok()?;
+    let mac = signer.sign_to_vec().ok()?;
+    Some(mac.iter().map(|b| format!("{:02x}", b)).collect())
+}
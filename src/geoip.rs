@@ -0,0 +1,329 @@
+use serde::Deserialize;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+use crate::mmdb;
+use crate::AppState;
+
+#[derive(Debug, Deserialize)]
+pub struct GeoIpConfig {
+    // Country database: a MaxMind DB (.mmdb, e.g. GeoLite2-Country) or an
+    // IP-range CSV ("start_ip,end_ip,country_code" per line, as in the
+    // DB-IP country lite database)
+    pub database: Option<PathBuf>,
+    // MaxMind DB with autonomous system numbers, e.g. GeoLite2-ASN
+    pub asn_database: Option<PathBuf>,
+    // Seconds between checks for updated database files; 0 = never
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval: u64,
+    // Client countries refused before auth (ISO 3166 alpha-2)
+    #[serde(default)]
+    pub deny_clients: Vec<String>,
+    // If non-empty, only clients from these countries are served
+    #[serde(default)]
+    pub allow_clients: Vec<String>,
+    // Destination countries and networks nobody may reach
+    #[serde(default)]
+    pub deny_destinations: Vec<String>,
+    #[serde(default)]
+    pub deny_asns: Vec<u32>,
+    // Egress paths by destination country or network; tried before
+    // [[routes]], the first match wins
+    #[serde(default)]
+    pub routes: Vec<GeoRoute>,
+}
+
+impl Default for GeoIpConfig {
+    fn default() -> Self {
+        GeoIpConfig {
+            database: None,
+            asn_database: None,
+            reload_interval: default_reload_interval(),
+            deny_clients: Vec::new(),
+            allow_clients: Vec::new(),
+            deny_destinations: Vec::new(),
+            deny_asns: Vec::new(),
+            routes: Vec::new(),
+        }
+    }
+}
+
+fn default_reload_interval() -> u64 {
+    3600
+}
+
+//   [[geoip.routes]]
+//   countries = ["CN"]
+//   via = "cn-exit"
+#[derive(Debug, Deserialize)]
+pub struct GeoRoute {
+    #[serde(default)]
+    pub countries: Vec<String>,
+    #[serde(default)]
+    pub asns: Vec<u32>,
+    // "direct", an upstream, a pool or an interface
+    pub via: String,
+}
+
+impl GeoIpConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let by_country = !self.deny_clients.is_empty()
+            || !self.allow_clients.is_empty()
+            || !self.deny_destinations.is_empty()
+            || self.routes.iter().any(|r| !r.countries.is_empty());
+        if by_country && self.database.is_none() {
+            return Err("geoip country restrictions need geoip.database".to_string());
+        }
+        let by_asn = !self.deny_asns.is_empty() || self.routes.iter().any(|r| !r.asns.is_empty());
+        if by_asn && self.asn_database.is_none() {
+            return Err("geoip ASN rules need geoip.asn_database".to_string());
+        }
+        if let Some(i) = self.routes.iter().position(|r| r.countries.is_empty() && r.asns.is_empty()) {
+            return Err(format!("geoip.routes[{}] needs countries or asns", i));
+        }
+        Ok(())
+    }
+
+    // Whether destinations need looking up at all.
+    pub fn checks_destinations(&self) -> bool {
+        !self.deny_destinations.is_empty() || !self.deny_asns.is_empty() || !self.routes.is_empty()
+    }
+
+    // The client's country if it must be refused ("--" when unknown).
+    pub fn rejects_client(&self, country: Option<&str>) -> Option<String> {
+        let listed = |list: &[String]| country.is_some_and(|c| list.iter().any(|l| l.eq_ignore_ascii_case(c)));
+        let rejected = listed(&self.deny_clients) || (!self.allow_clients.is_empty() && !listed(&self.allow_clients));
+        rejected.then(|| country.unwrap_or("--").to_string())
+    }
+}
+
+// What the destination's addresses mean for a request.
+pub enum Verdict {
+    Allow,
+    // Refused; names the rule that matched, e.g. "KP" or "AS64500"
+    Deny(String, IpAddr),
+    // Sent along the `via` of a [[geoip.routes]] entry
+    Route(String),
+}
+
+// The route picked by [[geoip.routes]], carried in request extensions.
+#[derive(Clone)]
+pub struct Via(pub String);
+
+// Sorted, non-overlapping ranges looked up by binary search.
+#[derive(Default)]
+pub struct Ranges {
+    v4: Vec<(u32, u32, [u8; 2])>,
+    v6: Vec<(u128, u128, [u8; 2])>,
+}
+
+pub enum GeoIp {
+    Csv(Ranges),
+    MaxMind(mmdb::Reader),
+}
+
+impl GeoIp {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = std::fs::read(path)?;
+        if mmdb::Reader::is_mmdb(&data) {
+            let reader = mmdb::Reader::new(data).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}: not a valid MaxMind DB", path.display()))
+            })?;
+            info!("🌍 Loaded GeoIP database {} ({})", path.display(), reader.database_type);
+            return Ok(GeoIp::MaxMind(reader));
+        }
+        let contents = String::from_utf8(data).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: neither a CSV nor a MaxMind DB", path.display()))
+        })?;
+        let bad = |line: usize| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: expected start_ip,end_ip,country", path.display(), line + 1),
+            )
+        };
+        let mut db = Ranges::default();
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(|f| f.trim().trim_matches('"')).collect();
+            let [start, end, country] = fields[..] else {
+                return Err(bad(n));
+            };
+            let country: [u8; 2] = country
+                .to_ascii_uppercase()
+                .as_bytes()
+                .try_into()
+                .map_err(|_| bad(n))?;
+            match (start.parse::<IpAddr>(), end.parse::<IpAddr>()) {
+                (Ok(IpAddr::V4(s)), Ok(IpAddr::V4(e))) => db.v4.push((s.into(), e.into(), country)),
+                (Ok(IpAddr::V6(s)), Ok(IpAddr::V6(e))) => db.v6.push((s.into(), e.into(), country)),
+                _ => return Err(bad(n)),
+            }
+        }
+        db.v4.sort_unstable_by_key(|r| r.0);
+        db.v6.sort_unstable_by_key(|r| r.0);
+        info!(
+            "🌍 Loaded GeoIP database {} ({} IPv4 / {} IPv6 ranges)",
+            path.display(),
+            db.v4.len(),
+            db.v6.len()
+        );
+        Ok(GeoIp::Csv(db))
+    }
+
+    pub fn country(&self, ip: IpAddr) -> Option<&str> {
+        match self {
+            GeoIp::Csv(db) => {
+                let country = match ip {
+                    IpAddr::V4(ip) => find(&db.v4, u32::from(ip)),
+                    IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                        Some(v4) => find(&db.v4, u32::from(v4)),
+                        None => find(&db.v6, u128::from(ip)),
+                    },
+                }?;
+                std::str::from_utf8(country).ok()
+            }
+            // Where the network is registered, for anycast and satellite
+            // ranges without a located country
+            GeoIp::MaxMind(db) => db
+                .string(ip, &["country", "iso_code"])
+                .or_else(|| db.string(ip, &["registered_country", "iso_code"])),
+        }
+    }
+
+    pub fn asn(&self, ip: IpAddr) -> Option<u32> {
+        match self {
+            GeoIp::Csv(_) => None,
+            GeoIp::MaxMind(db) => db.uint(ip, &["autonomous_system_number"]).map(|n| n as u32),
+        }
+    }
+}
+
+fn find<T: Ord + Copy>(ranges: &[(T, T, [u8; 2])], ip: T) -> Option<&[u8; 2]> {
+    let i = ranges.partition_point(|r| r.0 <= ip).checked_sub(1)?;
+    let (_, end, country) = &ranges[i];
+    (ip <= *end).then_some(country)
+}
+
+// A database file, swapped for its new contents when it changes on disk.
+pub struct Database {
+    path: PathBuf,
+    modified: Mutex<Option<SystemTime>>,
+    current: RwLock<Arc<GeoIp>>,
+}
+
+impl Database {
+    fn open(path: &Path) -> io::Result<Self> {
+        Ok(Database {
+            modified: Mutex::new(modified(path)),
+            current: RwLock::new(Arc::new(GeoIp::load(path)?)),
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn get(&self) -> Arc<GeoIp> {
+        self.current.read().unwrap().clone()
+    }
+
+    // A file that fails to load keeps the old data in use.
+    fn reload(&self) {
+        let stamp = modified(&self.path);
+        if stamp.is_none() || *self.modified.lock().unwrap() == stamp {
+            return;
+        }
+        match GeoIp::load(&self.path) {
+            Ok(db) => {
+                *self.current.write().unwrap() = Arc::new(db);
+                *self.modified.lock().unwrap() = stamp;
+            }
+            Err(e) => warn!("⚠️ Keeping the previous GeoIP data, {} failed to load: {}", self.path.display(), e),
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[derive(Default)]
+pub struct Databases {
+    pub country: Option<Database>,
+    pub asn: Option<Database>,
+}
+
+impl Databases {
+    pub fn open(config: &GeoIpConfig) -> io::Result<Self> {
+        Ok(Databases {
+            country: config.database.as_deref().map(Database::open).transpose()?,
+            asn: config.asn_database.as_deref().map(Database::open).transpose()?,
+        })
+    }
+
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        self.country.as_ref()?.get().country(ip).map(str::to_string)
+    }
+
+    pub fn asn(&self, ip: IpAddr) -> Option<u32> {
+        self.asn.as_ref()?.get().asn(ip)
+    }
+
+    // Resolves host:port and checks every address: any denied one refuses
+    // the request, so a name cannot mix allowed and denied addresses.
+    // Names that do not resolve here are let through; connecting fails.
+    pub async fn destination(&self, config: &GeoIpConfig, host: &str, port: u16) -> Verdict {
+        let addrs: Vec<IpAddr> = match crate::dns::lookup(host, port).await {
+            Ok(addrs) => addrs.into_iter().map(|a| a.ip()).collect(),
+            Err(_) => return Verdict::Allow,
+        };
+        let looked_up: Vec<(IpAddr, Option<String>, Option<u32>)> =
+            addrs.into_iter().map(|ip| (ip, self.country(ip), self.asn(ip))).collect();
+        let listed = |list: &[String], country: &Option<String>| {
+            country.as_deref().is_some_and(|c| list.iter().any(|l| l.eq_ignore_ascii_case(c)))
+        };
+        for (ip, country, asn) in &looked_up {
+            if listed(&config.deny_destinations, country) {
+                return Verdict::Deny(country.clone().unwrap_or_default(), *ip);
+            }
+            if let Some(asn) = asn.filter(|asn| config.deny_asns.contains(asn)) {
+                return Verdict::Deny(format!("AS{}", asn), *ip);
+            }
+        }
+        for route in &config.routes {
+            let matched = looked_up.iter().any(|(_, country, asn)| {
+                listed(&route.countries, country) || asn.is_some_and(|asn| route.asns.contains(&asn))
+            });
+            if matched {
+                return Verdict::Route(route.via.clone());
+            }
+        }
+        Verdict::Allow
+    }
+}
+
+pub fn spawn_reload(state: Arc<AppState>) {
+    let interval = state.config.geoip.reload_interval;
+    if interval == 0 || (state.geoip.country.is_none() && state.geoip.asn.is_none()) {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            // Parsing a large CSV takes a while; keep it off the runtime
+            let state = state.clone();
+            let _ = tokio::task::spawn_blocking(move || {
+                for db in state.geoip.country.iter().chain(&state.geoip.asn) {
+                    db.reload();
+                }
+            })
+            .await;
+        }
+    });
+}
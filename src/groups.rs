@@ -0,0 +1,83 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::upstream::RouteRule;
+
+// Settings shared by a set of users, so they need not be repeated per user:
+//
+//   [groups.ci]
+//   members = ["ci-runner-1", "ci-runner-2"]
+//   max_connections = 10
+//   bandwidth = 1048576
+//
+//   [[groups.ci.routes]]
+//   host = "*"
+//   via = "build-egress"
+//
+// A user's own settings (user store, [bandwidth.users]) still win.
+#[derive(Debug, Default, Deserialize)]
+pub struct GroupConfig {
+    #[serde(default)]
+    pub members: Vec<String>,
+    // Concurrent tunnels per member
+    pub max_connections: Option<usize>,
+    // Bytes per second per member
+    pub bandwidth: Option<u64>,
+    // Bytes per member, counted in the user store
+    pub quota_bytes: Option<u64>,
+    // Tried before [[routes]]; the first matching rule of either wins
+    #[serde(default)]
+    pub routes: Vec<RouteRule>,
+}
+
+pub fn validate(groups: &HashMap<String, GroupConfig>) -> Result<(), String> {
+    let mut seen: HashMap<&str, &str> = HashMap::new();
+    for (name, group) in groups {
+        for member in &group.members {
+            if !crate::users::valid_name(member) {
+                return Err(format!("groups.{}: invalid member name '{}'", name, member));
+            }
+            if let Some(other) = seen.insert(member, name) {
+                return Err(format!("user '{}' is in both group '{}' and '{}'", member, other, name));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Whether a "users" list from the config (names, "@group" or "*") covers
+// `user`, a member of `group`.
+pub fn covers(list: &[String], user: &str, group: Option<&str>) -> bool {
+    list.iter().any(|name| match name.strip_prefix('@') {
+        Some(name) => group == Some(name),
+        None => name == "*" || name == user,
+    })
+}
+
+// Every "@group" in a "users" list must exist.
+pub fn check_refs(list: &[String], groups: &HashMap<String, GroupConfig>) -> Result<(), String> {
+    match list.iter().filter_map(|name| name.strip_prefix('@')).find(|g| !groups.contains_key(*g)) {
+        Some(group) => Err(format!("unknown group '{}'", group)),
+        None => Ok(()),
+    }
+}
+
+// Member name -> group name, built once at startup.
+#[derive(Default)]
+pub struct Membership {
+    by_user: HashMap<String, String>,
+}
+
+impl Membership {
+    pub fn new(groups: &HashMap<String, GroupConfig>) -> Self {
+        let by_user = groups
+            .iter()
+            .flat_map(|(name, group)| group.members.iter().map(move |m| (m.clone(), name.clone())))
+            .collect();
+        Membership { by_user }
+    }
+
+    pub fn group_of(&self, user: &str) -> Option<&str> {
+        self.by_user.get(user).map(String::as_str)
+    }
+}
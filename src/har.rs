@@ -0,0 +1,434 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hyper::body::HttpBody;
+use hyper::header::{CONTENT_TYPE, PROXY_AUTHORIZATION};
+use hyper::{Body, HeaderMap, Request, Response};
+use serde::Deserialize;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::{info, warn};
+
+use crate::logging::{escape, rfc3339_now};
+
+// Recordings of plain HTTP traffic as HAR 1.2 files, for debugging client
+// integrations. Started and stopped through the admin API; nothing is
+// recorded until then.
+//
+//   [har]
+//   dir = "/var/lib/proxy/har"
+//   max_body = 65536
+#[derive(Debug, Deserialize)]
+pub struct HarConfig {
+    // Where finished recordings are written; recording is off without it
+    pub dir: Option<PathBuf>,
+    // Bytes kept of each request and response body, when a recording
+    // includes bodies
+    #[serde(default = "default_max_body")]
+    pub max_body: usize,
+    // Entries per recording; later requests are counted but not kept
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+}
+
+impl Default for HarConfig {
+    fn default() -> Self {
+        HarConfig {
+            dir: None,
+            max_body: default_max_body(),
+            max_entries: default_max_entries(),
+        }
+    }
+}
+
+fn default_max_body() -> usize {
+    64 * 1024
+}
+
+fn default_max_entries() -> usize {
+    1000
+}
+
+impl HarConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.dir.is_some() && self.max_entries == 0 {
+            return Err("har.max_entries must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+// The recording in progress, if any.
+struct Session {
+    id: u64,
+    file: String,
+    started: String,
+    // Only this user's requests, or everyone's
+    user: Option<String>,
+    bodies: bool,
+    entries: Vec<String>,
+    dropped: usize,
+}
+
+pub struct Recorder {
+    dir: Option<PathBuf>,
+    max_body: usize,
+    max_entries: usize,
+    session: Arc<Mutex<Option<Session>>>,
+    next_id: AtomicU64,
+}
+
+// One request on its way, recorded once its response is complete.
+pub struct Entry {
+    session: Arc<Mutex<Option<Session>>>,
+    id: u64,
+    started: String,
+    since: Instant,
+    user: String,
+    method: String,
+    url: String,
+    version: String,
+    headers: String,
+    query: String,
+    request_type: String,
+    request_size: i64,
+    // Set when the recording includes bodies
+    request_body: Option<Arc<Mutex<Captured>>>,
+    max_body: usize,
+    max_entries: usize,
+}
+
+#[derive(Default)]
+struct Captured {
+    bytes: Vec<u8>,
+    total: u64,
+}
+
+impl Recorder {
+    pub fn new(config: &HarConfig) -> Self {
+        Recorder {
+            dir: config.dir.clone(),
+            max_body: config.max_body,
+            max_entries: config.max_entries,
+            session: Arc::default(),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    pub fn start(&self, user: Option<String>, bodies: bool) -> Result<String, &'static str> {
+        if self.dir.is_none() {
+            return Err("Recording needs har.dir");
+        }
+        let mut session = self.session.lock().unwrap();
+        if session.is_some() {
+            return Err("A recording is already running");
+        }
+        let started = rfc3339_now();
+        info!(
+            "🎥 HAR recording started for {}{}",
+            user.as_deref().unwrap_or("all users"),
+            if bodies { ", with bodies" } else { "" }
+        );
+        *session = Some(Session {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            file: format!("{}.har", started.replace(':', "-")),
+            started,
+            user,
+            bodies,
+            entries: Vec::new(),
+            dropped: 0,
+        });
+        Ok(status_json(session.as_ref()))
+    }
+
+    // Ends the recording and writes it out; None if nothing was running.
+    pub fn stop(&self) -> io::Result<Option<String>> {
+        let Some(session) = self.session.lock().unwrap().take() else {
+            return Ok(None);
+        };
+        let Some(dir) = &self.dir else {
+            return Ok(None);
+        };
+        std::fs::create_dir_all(dir)?;
+        let document = format!(
+            "{{\"log\":{{\"version\":\"1.2\",\"creator\":{{\"name\":\"secure-proxy\",\"version\":\"{}\"}},\"pages\":[],\"entries\":[{}]}}}}\n",
+            env!("CARGO_PKG_VERSION"),
+            session.entries.join(",")
+        );
+        // Recordings hold cookies and other credentials
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(dir.join(&session.file))?
+            .write_all(document.as_bytes())?;
+        info!(
+            "🎥 HAR recording saved to {} ({} entries, {} not kept)",
+            session.file,
+            session.entries.len(),
+            session.dropped
+        );
+        Ok(Some(format!(
+            "{{\"file\":\"{}\",\"entries\":{},\"dropped\":{}}}",
+            escape(&session.file),
+            session.entries.len(),
+            session.dropped
+        )))
+    }
+
+    pub fn status(&self) -> String {
+        status_json(self.session.lock().unwrap().as_ref())
+    }
+
+    // Finished recordings, oldest first.
+    pub fn files(&self) -> Vec<String> {
+        let Some(Ok(dir)) = self.dir.as_ref().map(std::fs::read_dir) else {
+            return Vec::new();
+        };
+        let mut files: Vec<String> = dir
+            .filter_map(|e| e.ok()?.file_name().into_string().ok())
+            .filter(|name| name.ends_with(".har"))
+            .collect();
+        files.sort();
+        files
+    }
+
+    pub fn read(&self, file: &str) -> Option<Vec<u8>> {
+        // Names only, never paths
+        if !file.ends_with(".har") || file.contains('/') || file.starts_with('.') {
+            return None;
+        }
+        std::fs::read(self.dir.as_ref()?.join(file)).ok()
+    }
+
+    // Starts an entry for `req` if a recording covers `user`.
+    pub fn begin(&self, user: &str, req: &mut Request<Body>) -> Option<Entry> {
+        let (id, bodies) = {
+            let session = self.session.lock().unwrap();
+            let session = session.as_ref()?;
+            if session.user.as_deref().is_some_and(|u| u != user) {
+                return None;
+            }
+            (session.id, session.bodies)
+        };
+        let request_body = bodies.then(|| {
+            let captured = Arc::new(Mutex::new(Captured::default()));
+            let done = captured.clone();
+            let body = tee(std::mem::take(req.body_mut()), self.max_body, move |c| *done.lock().unwrap() = c);
+            *req.body_mut() = body;
+            captured
+        });
+        let query = req
+            .uri()
+            .query()
+            .and_then(|q| crate::admin::form(q.as_bytes()))
+            .unwrap_or_default()
+            .iter()
+            .map(|(name, value)| pair(name, value))
+            .collect::<Vec<_>>()
+            .join(",");
+        Some(Entry {
+            session: self.session.clone(),
+            id,
+            started: rfc3339_now(),
+            since: Instant::now(),
+            user: user.to_string(),
+            method: req.method().to_string(),
+            url: req.uri().to_string(),
+            version: format!("{:?}", req.version()),
+            headers: headers_json(req.headers()),
+            query,
+            request_type: mime_type(req.headers()),
+            request_size: body_size(req.headers()),
+            request_body,
+            max_body: self.max_body,
+            max_entries: self.max_entries,
+        })
+    }
+}
+
+impl Entry {
+    // Records the entry once `response` has been sent on, with its body if
+    // the recording includes bodies.
+    pub fn finish(self, mut response: Response<Body>) -> Response<Body> {
+        let wait = self.since.elapsed();
+        let head = ResponseHead {
+            status: response.status().as_u16(),
+            status_text: response.status().canonical_reason().unwrap_or_default().to_string(),
+            version: format!("{:?}", response.version()),
+            headers: headers_json(response.headers()),
+            mime_type: mime_type(response.headers()),
+            size: body_size(response.headers()),
+        };
+        match self.request_body.is_some() {
+            true => {
+                let max_body = self.max_body;
+                let body = tee(std::mem::take(response.body_mut()), max_body, move |captured| {
+                    let receive = self.since.elapsed().saturating_sub(wait);
+                    self.record(&head, Some(captured), wait.as_millis(), receive.as_millis());
+                });
+                *response.body_mut() = body;
+            }
+            false => self.record(&head, None, wait.as_millis(), 0),
+        }
+        response
+    }
+
+    fn record(&self, response: &ResponseHead, body: Option<Captured>, wait: u128, receive: u128) {
+        let request_body = self.request_body.as_ref().map(|c| std::mem::take(&mut *c.lock().unwrap()));
+        let post_data = match &request_body {
+            Some(captured) if captured.total > 0 => format!(
+                ",\"postData\":{{\"mimeType\":\"{}\",{}}}",
+                escape(&self.request_type),
+                body_text(captured, self.max_body)
+            ),
+            _ => String::new(),
+        };
+        let request_size = request_body.as_ref().map_or(self.request_size, |c| c.total as i64);
+        let response_size = body.as_ref().map_or(response.size, |c| c.total as i64);
+        let content = match &body {
+            Some(captured) if captured.total > 0 => format!(",{}", body_text(captured, self.max_body)),
+            _ => String::new(),
+        };
+        let entry = format!(
+            concat!(
+                "{{\"startedDateTime\":\"{}\",\"time\":{},\"_user\":\"{}\",",
+                "\"request\":{{\"method\":\"{}\",\"url\":\"{}\",\"httpVersion\":\"{}\",\"cookies\":[],\"headers\":[{}],",
+                "\"queryString\":[{}]{},\"headersSize\":-1,\"bodySize\":{}}},",
+                "\"response\":{{\"status\":{},\"statusText\":\"{}\",\"httpVersion\":\"{}\",\"cookies\":[],\"headers\":[{}],",
+                "\"content\":{{\"size\":{},\"mimeType\":\"{}\"{}}},\"redirectURL\":\"\",\"headersSize\":-1,\"bodySize\":{}}},",
+                "\"cache\":{{}},\"timings\":{{\"send\":0,\"wait\":{},\"receive\":{}}}}}"
+            ),
+            self.started,
+            wait + receive,
+            escape(&self.user),
+            escape(&self.method),
+            escape(&self.url),
+            self.version,
+            self.headers,
+            self.query,
+            post_data,
+            request_size,
+            response.status,
+            escape(&response.status_text),
+            response.version,
+            response.headers,
+            response_size.max(0),
+            escape(&response.mime_type),
+            content,
+            response_size,
+            wait,
+            receive,
+        );
+        let mut session = self.session.lock().unwrap();
+        // A recording stopped or replaced since this request began
+        let Some(session) = session.as_mut().filter(|s| s.id == self.id) else {
+            return;
+        };
+        if session.entries.len() < self.max_entries {
+            session.entries.push(entry);
+        } else {
+            session.dropped += 1;
+        }
+    }
+}
+
+struct ResponseHead {
+    status: u16,
+    status_text: String,
+    version: String,
+    headers: String,
+    mime_type: String,
+    size: i64,
+}
+
+fn status_json(session: Option<&Session>) -> String {
+    match session {
+        None => "{\"recording\":false}".to_string(),
+        Some(s) => format!(
+            "{{\"recording\":true,\"file\":\"{}\",\"started\":\"{}\",\"user\":{},\"bodies\":{},\"entries\":{},\"dropped\":{}}}",
+            escape(&s.file),
+            s.started,
+            s.user.as_deref().map_or("null".to_string(), |u| format!("\"{}\"", escape(u))),
+            s.bodies,
+            s.entries.len(),
+            s.dropped
+        ),
+    }
+}
+
+fn pair(name: &str, value: &str) -> String {
+    format!("{{\"name\":\"{}\",\"value\":\"{}\"}}", escape(name), escape(value))
+}
+
+// Headers as HAR name/value pairs; the client's proxy credentials are left out.
+fn headers_json(headers: &HeaderMap) -> String {
+    headers
+        .iter()
+        .filter(|(name, _)| *name != PROXY_AUTHORIZATION)
+        .map(|(name, value)| pair(name.as_str(), &String::from_utf8_lossy(value.as_bytes())))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn mime_type(headers: &HeaderMap) -> String {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+// Content-Length, or -1 (unknown) as HAR has it.
+fn body_size(headers: &HeaderMap) -> i64 {
+    match headers.contains_key(hyper::header::CONTENT_LENGTH) {
+        true => crate::content_length(headers) as i64,
+        false => -1,
+    }
+}
+
+// "text" (and "encoding" for binary bodies) for content and postData.
+fn body_text(captured: &Captured, max_body: usize) -> String {
+    let text = match std::str::from_utf8(&captured.bytes) {
+        Ok(text) => format!("\"text\":\"{}\"", escape(text)),
+        Err(_) => format!("\"text\":\"{}\",\"encoding\":\"base64\"", BASE64.encode(&captured.bytes)),
+    };
+    match captured.total > captured.bytes.len() as u64 {
+        true => format!("{},\"comment\":\"first {} of {} bytes\"", text, max_body, captured.total),
+        false => text,
+    }
+}
+
+// `body`, passed on unchanged while its first `max` bytes are kept for
+// `done`, which runs when it ends or the receiving side goes away.
+fn tee(mut body: Body, max: usize, done: impl FnOnce(Captured) + Send + 'static) -> Body {
+    let (mut sender, out) = Body::channel();
+    tokio::spawn(async move {
+        let mut captured = Captured::default();
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    warn!("⚠️ Body error while recording: {}", e);
+                    sender.abort();
+                    done(captured);
+                    return;
+                }
+            };
+            let keep = max.saturating_sub(captured.bytes.len()).min(chunk.len());
+            captured.bytes.extend_from_slice(&chunk[..keep]);
+            captured.total += chunk.len() as u64;
+            if sender.send_data(chunk).await.is_err() {
+                break;
+            }
+        }
+        if let Ok(Some(trailers)) = body.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+        done(captured);
+    });
+    out
+}
@@ -0,0 +1,110 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use crate::pattern::host_matches;
+use crate::userheaders::RESERVED;
+
+// Headers added, replaced or removed by destination, for every user:
+//
+//   [[headers.request]]
+//   host = "*"
+//   except = ["*.corp.example"]
+//   remove = ["Referer"]
+//
+//   [[headers.request]]
+//   host = "*.corp.example"
+//   set = { "X-Internal-Route" = "intranet" }
+//
+//   [[headers.response]]
+//   host = "legacy.example.com"
+//   remove = ["Server", "X-Powered-By"]
+//
+// Every matching rule applies, in order. Within a rule, removals come
+// first, then `set` (replacing any value) and `add` (kept alongside).
+#[derive(Debug, Default, Deserialize)]
+pub struct HeaderRulesConfig {
+    // Applied to plain HTTP requests on their way to the destination
+    #[serde(default)]
+    pub request: Vec<HeaderRule>,
+    // Applied to responses on their way back to the client
+    #[serde(default)]
+    pub response: Vec<HeaderRule>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HeaderRule {
+    #[serde(default = "any_host")]
+    pub host: String,
+    // Destinations the rule skips even though `host` matches them
+    #[serde(default)]
+    pub except: Vec<String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+    #[serde(default)]
+    pub add: BTreeMap<String, String>,
+}
+
+fn any_host() -> String {
+    "*".to_string()
+}
+
+impl HeaderRulesConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for (direction, rules) in [("request", &self.request), ("response", &self.response)] {
+            for (i, rule) in rules.iter().enumerate() {
+                rule.validate().map_err(|e| format!("headers.{}[{}]: {}", direction, i, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.request.is_empty() && self.response.is_empty()
+    }
+}
+
+impl HeaderRule {
+    fn validate(&self) -> Result<(), String> {
+        if self.remove.is_empty() && self.set.is_empty() && self.add.is_empty() {
+            return Err("nothing to remove, set or add".to_string());
+        }
+        let names = self.remove.iter().chain(self.set.keys()).chain(self.add.keys());
+        for name in names {
+            let header =
+                HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("'{}' is not a valid header name", name))?;
+            if RESERVED.contains(&header.as_str()) {
+                return Err(format!("'{}' is managed by the proxy and cannot be changed", name));
+            }
+        }
+        for (name, value) in self.set.iter().chain(&self.add) {
+            HeaderValue::from_str(value).map_err(|_| format!("value for '{}' is not a valid header value", name))?;
+        }
+        Ok(())
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        host_matches(&self.host, host) && !self.except.iter().any(|p| host_matches(p, host))
+    }
+}
+
+// Applies every rule matching `host` to `headers`.
+pub fn apply(rules: &[HeaderRule], host: &str, headers: &mut HeaderMap) {
+    for rule in rules.iter().filter(|r| r.matches(host)) {
+        for name in &rule.remove {
+            headers.remove(name.as_str());
+        }
+        for (name, value) in &rule.set {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+                headers.insert(name, value);
+            }
+        }
+        for (name, value) in &rule.add {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+                headers.append(name, value);
+            }
+        }
+    }
+}
@@ -0,0 +1,23 @@
+use serde::Deserialize;
+
+use crate::pattern::host_matches;
+
+// Origins known to speak HTTP/2 without TLS (h2c), such as gRPC services:
+//
+//   [http2]
+//   prior_knowledge = ["*.grpc.internal", "10.1.2.3"]
+//
+// Plain http:// requests to them go out over HTTP/2, which carries
+// trailers; HTTP/1.1 as the proxy speaks it drops them. An origin listed
+// here that only speaks HTTP/1.1 fails every request with 502.
+#[derive(Debug, Default, Deserialize)]
+pub struct Http2Config {
+    #[serde(default)]
+    pub prior_knowledge: Vec<String>,
+}
+
+impl Http2Config {
+    pub fn prior_knowledge(&self, host: &str) -> bool {
+        self.prior_knowledge.iter().any(|pattern| host_matches(pattern, host))
+    }
+}
@@ -0,0 +1,505 @@
+use hyper::body::{Buf, Bytes};
+use h3::server::RequestStream;
+use hyper::body::HttpBody;
+use hyper::{Body, Request, Response};
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, warn};
+
+use crate::bandwidth::RateLimiter;
+use crate::listener::{Listener, PendingTunnel};
+use crate::{abuse, errorpages, kerberos, logging, masque, AppState};
+
+// Per direction of a CONNECT tunnel's in-process pipe
+const TUNNEL_BUFFER: usize = 64 * 1024;
+// Datagrams waiting for a CONNECT-UDP session before more are dropped
+const DATAGRAM_QUEUE: usize = 256;
+const MAX_DATAGRAM: usize = 65535;
+// Unparsed capsule bytes a session holds before the client is cut off
+const MAX_CAPSULE_BUFFER: usize = 2 * MAX_DATAGRAM;
+
+type Stream = RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+// Experimental HTTP/3 alongside a TLS listener: QUIC on the same address
+// and port over UDP, with the same certificate. Requests and CONNECT
+// tunnels go through the same authentication, ACLs and limits as those of
+// the TCP listener.
+pub fn bind(addr: SocketAddr, cert: &Path, key: &Path) -> io::Result<quinn::Endpoint> {
+    let chain = rustls_pemfile::certs(&mut io::BufReader::new(std::fs::File::open(cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut io::BufReader::new(std::fs::File::open(key)?))?
+        .ok_or_else(|| io::Error::other(format!("no private key in {}", key.display())))?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(chain, key))
+        .map_err(io::Error::other)?;
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls).map_err(io::Error::other)?;
+    quinn::Endpoint::server(quinn::ServerConfig::with_crypto(Arc::new(crypto)), addr)
+}
+
+// Serve QUIC connections until `shutdown` fires, then let those open finish.
+pub async fn serve(endpoint: quinn::Endpoint, state: Arc<AppState>, listener: Arc<Listener>, shutdown: oneshot::Receiver<()>) {
+    let mut shutdown = std::pin::pin!(shutdown);
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => match incoming {
+                Some(incoming) => incoming,
+                None => break,
+            },
+            _ = &mut shutdown => break,
+        };
+        let state = state.clone();
+        let listener = listener.clone();
+        tokio::spawn(async move {
+            let remote = incoming.remote_address();
+            if let Err(e) = connection(incoming, state, listener).await {
+                debug!("HTTP/3 connection from {} ended: {}", remote, e);
+            }
+        });
+    }
+    endpoint.set_server_config(None);
+    endpoint.wait_idle().await;
+    info!("🔚 Retired HTTP/3 listener {}", listener.addr);
+}
+
+async fn connection(
+    incoming: quinn::Incoming,
+    state: Arc<AppState>,
+    listener: Arc<Listener>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let client_addr = incoming.remote_address();
+    if let Some(country) = state.config.geoip.rejects_client(state.geoip.country(client_addr.ip()).as_deref()) {
+        warn!("🌍 Refusing client {} from country {}", client_addr, country);
+        state.metrics.count_geoip_rejection(&country);
+        let rule = format!("geoip country {}", country);
+        state.abuse.record(client_addr.ip(), abuse::Action::Blocked, "-", &rule);
+        incoming.refuse();
+        return Ok(());
+    }
+    // Released when the connection and its tunnels are done, as for TCP clients
+    let Some(slot) = state.client_slots.try_acquire().map(Arc::new) else {
+        warn!(
+            "🚫 Client connection cap reached ({} active), rejecting {}",
+            state.client_slots.active(),
+            client_addr
+        );
+        incoming.refuse();
+        return Ok(());
+    };
+    let pinned = kerberos::Pinned::default();
+    let conn = incoming.await?;
+    let sessions = Sessions::default();
+    tokio::spawn(read_datagrams(conn.clone(), sessions.clone()));
+    let mut h3 = h3::server::builder()
+        .enable_extended_connect(true)
+        .enable_datagram(true)
+        .build::<_, Bytes>(h3_quinn::Connection::new(conn.clone()))
+        .await?;
+    while let Some(resolver) = h3.accept().await? {
+        let state = state.clone();
+        let listener = listener.clone();
+        let slot = slot.clone();
+        let pinned = pinned.clone();
+        let conn = conn.clone();
+        let sessions = sessions.clone();
+        tokio::spawn(async move {
+            let (head, stream) = match resolver.resolve_request().await {
+                Ok(request) => request,
+                Err(e) => {
+                    debug!("Bad HTTP/3 request from {}: {}", client_addr, e);
+                    return;
+                }
+            };
+            let Some(mut req) = to_hyper(&head) else {
+                let _ = respond(stream, crate::error_response(400, "bad_request", "Malformed request")).await;
+                return;
+            };
+            req.extensions_mut().insert(slot);
+            req.extensions_mut().insert(pinned);
+            let result = match head.extensions().get::<h3::ext::Protocol>() {
+                None => request(req, stream, state, listener, client_addr).await,
+                Some(&h3::ext::Protocol::CONNECT_UDP) => {
+                    connect_udp(req, stream, state, listener, client_addr, conn, sessions).await
+                }
+                Some(protocol) => {
+                    debug!("Refusing extended CONNECT for {} from {}", protocol.as_str(), client_addr);
+                    let response = crate::error_response(501, "not_implemented", "Unsupported CONNECT protocol");
+                    respond(stream, response).await
+                }
+            };
+            if let Err(e) = result {
+                debug!("HTTP/3 request from {} failed: {}", client_addr, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn request(
+    mut req: Request<Body>,
+    stream: Stream,
+    state: Arc<AppState>,
+    listener: Arc<Listener>,
+    client_addr: SocketAddr,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (mut send, recv) = stream.split();
+    if req.method() != hyper::Method::CONNECT {
+        let (sender, body) = Body::channel();
+        *req.body_mut() = body;
+        tokio::spawn(request_body(recv, sender));
+        let response = handle(req, state, listener, client_addr).await;
+        send.send_response(to_h3(&response)).await?;
+        return send_body(&mut send, response.into_body()).await;
+    }
+    // The tunnel gets one end of a pipe once the CONNECT succeeds
+    let (pending, pending_rx) = oneshot::channel();
+    req.extensions_mut().insert(PendingTunnel(pending_rx));
+    let response = handle(req, state, listener, client_addr).await;
+    send.send_response(to_h3(&response)).await?;
+    if !response.status().is_success() {
+        return send_body(&mut send, response.into_body()).await;
+    }
+    let (client, tunnel) = tokio::io::duplex(TUNNEL_BUFFER);
+    let _ = pending.send(tunnel);
+    relay(client, send, recv).await;
+    Ok(())
+}
+
+async fn handle(req: Request<Body>, state: Arc<AppState>, listener: Arc<Listener>, client_addr: SocketAddr) -> Response<Body> {
+    let request_id = logging::request_id();
+    let page = (!state.error_pages.is_empty()).then(|| {
+        errorpages::Request::new(req.headers(), req.uri().to_string(), request_id.clone(), client_addr.ip())
+    });
+    let response = match crate::handle_request(req, state.clone(), listener, client_addr, request_id).await {
+        Ok(response) => response,
+        Err(never) => match never {},
+    };
+    match page {
+        Some(page) => state.error_pages.render(&page, response),
+        None => response,
+    }
+}
+
+// CONNECT-UDP sessions on one connection by quarter stream ID, which
+// QUIC datagrams start with
+#[derive(Clone, Default)]
+struct Sessions(Arc<Mutex<HashMap<u64, mpsc::Sender<Bytes>>>>);
+
+async fn read_datagrams(conn: quinn::Connection, sessions: Sessions) {
+    while let Ok(datagram) = conn.read_datagram().await {
+        let Some((id, len)) = read_varint(&datagram) else {
+            continue;
+        };
+        let session = sessions.0.lock().unwrap().get(&id).cloned();
+        // A full queue drops the datagram, as a congested link would
+        if let Some(session) = session {
+            let _ = session.try_send(datagram.slice(len..));
+        }
+    }
+}
+
+async fn connect_udp(
+    mut req: Request<Body>,
+    mut stream: Stream,
+    state: Arc<AppState>,
+    listener: Arc<Listener>,
+    client_addr: SocketAddr,
+    conn: quinn::Connection,
+    sessions: Sessions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Checked like a CONNECT to the same host and port
+    let authority = target(req.uri().path()).and_then(|(host, port)| format!("{}:{}", host, port).parse().ok());
+    let Some(authority) = authority else {
+        let response = crate::error_response(400, "bad_request", "Expected /.well-known/masque/udp/{host}/{port}/");
+        return respond(stream, response).await;
+    };
+    *req.method_mut() = hyper::Method::CONNECT;
+    *req.uri_mut() = authority;
+    let (pending, pending_rx) = oneshot::channel();
+    req.extensions_mut().insert(masque::UdpRequest(pending));
+    let id = stream.id().into_inner() / 4;
+    let (datagrams, from_client) = mpsc::channel(DATAGRAM_QUEUE);
+    sessions.0.lock().unwrap().insert(id, datagrams);
+    let response = handle(req, state, listener, client_addr).await;
+    let result = match pending_rx.await {
+        Ok(session) if response.status().is_success() => {
+            stream.send_response(to_h3(&response)).await?;
+            relay_udp(session, stream, conn, id, from_client).await;
+            Ok(())
+        }
+        _ => respond(stream, response).await,
+    };
+    sessions.0.lock().unwrap().remove(&id);
+    result
+}
+
+// Moves datagrams between the client and the target's socket until the
+// client ends the stream, the session goes idle or it is reaped. Clients
+// that cannot use QUIC datagrams send and get DATAGRAM capsules on the
+// stream instead.
+async fn relay_udp(
+    session: masque::Session,
+    stream: Stream,
+    conn: quinn::Connection,
+    id: u64,
+    mut from_client: mpsc::Receiver<Bytes>,
+) {
+    let (mut send, mut recv) = stream.split();
+    let masque::Session { socket, activity, limiters: (upstream, downstream), idle_timeout, closed } = session;
+    let allowed = |limiter: &Option<RateLimiter>| limiter.as_ref().is_none_or(|l| l.try_consume(1));
+    let mut prefix = Vec::new();
+    put_varint(&mut prefix, id);
+    put_varint(&mut prefix, 0);
+    let mut capsules = Vec::new();
+    let mut buf = vec![0; MAX_DATAGRAM];
+    let mut dropped = 0u64;
+    loop {
+        let mut payloads = Vec::new();
+        tokio::select! {
+            datagram = from_client.recv() => match datagram {
+                Some(datagram) => payloads.extend(udp_payload(&datagram).map(<[u8]>::to_vec)),
+                None => break,
+            },
+            data = recv.recv_data() => match data {
+                Ok(Some(mut chunk)) if capsules.len() < MAX_CAPSULE_BUFFER => {
+                    capsules.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+                    while let Some(capsule) = next_capsule(&mut capsules) {
+                        payloads.extend(capsule);
+                    }
+                }
+                _ => break,
+            },
+            received = socket.recv(&mut buf) => match received {
+                Ok(n) if allowed(&downstream) => {
+                    activity.record(false, n);
+                    let sent = match conn.max_datagram_size() {
+                        Some(_) => conn.send_datagram(Bytes::from([&prefix[..], &buf[..n]].concat())).is_ok(),
+                        None => send.send_data(Bytes::from(capsule(&buf[..n]))).await.is_ok(),
+                    };
+                    dropped += u64::from(!sent);
+                }
+                Ok(_) => dropped += 1,
+                // e.g. an ICMP port unreachable for an earlier datagram
+                Err(e) => debug!("UDP session {}: {}", id, e),
+            },
+            _ = tokio::time::sleep(idle_timeout) => {
+                debug!("UDP session {} idle for {:?}, closing", id, idle_timeout);
+                break;
+            }
+            _ = activity.closed() => break,
+        }
+        for payload in payloads {
+            match allowed(&upstream) {
+                true => {
+                    activity.record(true, payload.len());
+                    let _ = socket.send(&payload).await;
+                }
+                false => dropped += 1,
+            }
+        }
+    }
+    if dropped > 0 {
+        debug!("UDP session {} dropped {} datagrams over its limits", id, dropped);
+    }
+    let _ = send.finish().await;
+    let (from_client, from_target) = activity.bytes();
+    closed(from_client, from_target);
+}
+
+// The request body, read from the client as the handler asks for it.
+async fn request_body(mut recv: RequestStream<h3_quinn::RecvStream, Bytes>, mut sender: hyper::body::Sender) {
+    loop {
+        match recv.recv_data().await {
+            Ok(Some(mut chunk)) => {
+                if sender.send_data(chunk.copy_to_bytes(chunk.remaining())).await.is_err() {
+                    return;
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                debug!("HTTP/3 request body failed: {}", e);
+                sender.abort();
+                return;
+            }
+        }
+    }
+    if let Ok(Some(trailers)) = recv.recv_trailers().await {
+        let _ = sender.send_trailers(headers_to_hyper(&trailers)).await;
+    }
+}
+
+// Moves bytes between the tunnel's pipe and the QUIC stream; each side's
+// end is passed on to the other.
+async fn relay(
+    pipe: tokio::io::DuplexStream,
+    mut send: RequestStream<h3_quinn::SendStream<Bytes>, Bytes>,
+    mut recv: RequestStream<h3_quinn::RecvStream, Bytes>,
+) {
+    let (mut from_tunnel, mut to_tunnel) = tokio::io::split(pipe);
+    let upstream = async move {
+        while let Ok(Some(mut chunk)) = recv.recv_data().await {
+            if to_tunnel.write_all_buf(&mut chunk).await.is_err() {
+                return;
+            }
+        }
+        let _ = to_tunnel.shutdown().await;
+    };
+    let downstream = async move {
+        let mut buf = vec![0; TUNNEL_BUFFER];
+        while let Ok(n) = from_tunnel.read(&mut buf).await {
+            if n == 0 || send.send_data(Bytes::copy_from_slice(&buf[..n])).await.is_err() {
+                break;
+            }
+        }
+        let _ = send.finish().await;
+    };
+    tokio::join!(upstream, downstream);
+}
+
+async fn respond(mut stream: Stream, response: Response<Body>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    stream.send_response(to_h3(&response)).await?;
+    send_body(&mut stream, response.into_body()).await
+}
+
+async fn send_body<S: h3::quic::SendStream<Bytes>>(
+    send: &mut RequestStream<S, Bytes>,
+    mut body: Body,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    while let Some(chunk) = body.data().await {
+        send.send_data(chunk?).await?;
+    }
+    match body.trailers().await? {
+        Some(trailers) => send.send_trailers(headers_to_h3(&trailers)).await?,
+        None => send.finish().await?,
+    }
+    Ok(())
+}
+
+// The request as the rest of the proxy sees it. Classic CONNECT carries
+// only an authority, like the HTTP/1.1 form.
+fn to_hyper(head: &http::Request<()>) -> Option<Request<Body>> {
+    let mut req = Request::builder()
+        .method(head.method().as_str())
+        .uri(head.uri().to_string())
+        .body(Body::empty())
+        .ok()?;
+    *req.headers_mut() = headers_to_hyper(head.headers());
+    if let (None, Some(authority)) = (req.headers().get(hyper::header::HOST), head.uri().authority()) {
+        req.headers_mut().insert(hyper::header::HOST, authority.as_str().parse().ok()?);
+    }
+    Some(req)
+}
+
+fn to_h3(response: &Response<Body>) -> http::Response<()> {
+    let mut head = http::Response::new(());
+    *head.status_mut() = http::StatusCode::from_u16(response.status().as_u16()).unwrap_or(http::StatusCode::BAD_GATEWAY);
+    *head.headers_mut() = headers_to_h3(response.headers());
+    head
+}
+
+// Connection-specific fields are not allowed in HTTP/3 messages.
+const CONNECTION_SPECIFIC: &[&str] = &["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+fn headers_to_hyper(headers: &http::HeaderMap) -> hyper::HeaderMap {
+    headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let name = hyper::header::HeaderName::from_bytes(name.as_str().as_bytes()).ok()?;
+            Some((name, hyper::header::HeaderValue::from_bytes(value.as_bytes()).ok()?))
+        })
+        .collect()
+}
+
+fn headers_to_h3(headers: &hyper::HeaderMap) -> http::HeaderMap {
+    headers
+        .iter()
+        .filter(|(name, _)| !CONNECTION_SPECIFIC.contains(&name.as_str()))
+        .filter_map(|(name, value)| {
+            let name = http::HeaderName::from_bytes(name.as_str().as_bytes()).ok()?;
+            Some((name, http::HeaderValue::from_bytes(value.as_bytes()).ok()?))
+        })
+        .collect()
+}
+
+// Target of the default URI template,
+// "/.well-known/masque/udp/{target_host}/{target_port}/". IPv6 addresses
+// come percent-encoded and are returned in brackets, ready for an
+// authority.
+fn target(path: &str) -> Option<(String, u16)> {
+    let rest = path.strip_prefix("/.well-known/masque/udp/")?;
+    let mut parts = rest.trim_end_matches('/').split('/');
+    let (host, port) = (parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+    let host = crate::admin::percent_decode(host)?;
+    let port = port.parse().ok().filter(|p| *p != 0)?;
+    match host.parse::<std::net::Ipv6Addr>() {
+        Ok(ip) => Some((format!("[{}]", ip), port)),
+        Err(_) if !host.is_empty() && !host.contains([':', '[', ']', '@']) => Some((host, port)),
+        Err(_) => None,
+    }
+}
+
+// QUIC variable-length integers (RFC 9000, section 16), which HTTP
+// datagrams and capsules are framed with.
+fn read_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let first = *buf.first()?;
+    let len = 1 << (first >> 6);
+    let bytes = buf.get(..len)?;
+    let value = bytes[1..].iter().fold(u64::from(first & 0x3f), |value, b| value << 8 | u64::from(*b));
+    Some((value, len))
+}
+
+fn put_varint(out: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0x3f => out.push(value as u8),
+        0x40..=0x3fff => out.extend_from_slice(&(value as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => out.extend_from_slice(&(value as u32 | 0x8000_0000).to_be_bytes()),
+        _ => out.extend_from_slice(&(value | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+// The UDP payload of an HTTP datagram (context ID 0); other contexts are
+// extensions this proxy has not negotiated.
+fn udp_payload(datagram: &[u8]) -> Option<&[u8]> {
+    match read_varint(datagram)? {
+        (0, len) => Some(&datagram[len..]),
+        _ => None,
+    }
+}
+
+// Takes the next whole capsule (RFC 9297) off the front of `buf`: Some
+// with the UDP payload for a DATAGRAM capsule, Some(None) for other types,
+// which are skipped, and None until more bytes arrive.
+fn next_capsule(buf: &mut Vec<u8>) -> Option<Option<Vec<u8>>> {
+    let (kind, kind_len) = read_varint(buf)?;
+    let (len, len_len) = read_varint(&buf[kind_len..])?;
+    let start = kind_len + len_len;
+    let end = start.checked_add(usize::try_from(len).ok()?)?;
+    if buf.len() < end {
+        return None;
+    }
+    let capsule: Vec<u8> = buf.drain(..end).skip(start).collect();
+    match kind {
+        0 => Some(udp_payload(&capsule).map(|payload| payload.to_vec())),
+        _ => Some(None),
+    }
+}
+
+// A DATAGRAM capsule carrying `payload`, for clients without QUIC datagrams.
+fn capsule(payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 10);
+    put_varint(&mut out, 0);
+    put_varint(&mut out, payload.len() as u64 + 1);
+    out.push(0);
+    out.extend_from_slice(payload);
+    out
+}
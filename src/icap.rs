@@ -0,0 +1,519 @@
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, TRANSFER_ENCODING};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tracing::{debug, info, warn};
+
+use crate::metrics::Metrics;
+use crate::pattern::host_matches;
+
+// Longest encapsulated HTTP head taken from the scanner
+const MAX_HEAD: usize = 64 * 1024;
+
+// Plain HTTP bodies handed to an ICAP server (RFC 3507) for scanning, such
+// as c-icap with ClamAV or a DLP appliance:
+//
+//   [icap]
+//   reqmod = "icap://127.0.0.1:1344/reqmod"
+//   respmod = "icap://127.0.0.1:1344/avscan"
+//   on_error = "block"
+//   except = ["*.windowsupdate.com"]
+//
+// The scanner answers 204 to pass a message on unchanged, or sends back the
+// message to use instead: a modified one, or a block page.
+#[derive(Debug, Deserialize)]
+pub struct IcapConfig {
+    // Service for requests on their way to the destination
+    pub reqmod: Option<String>,
+    // Service for responses on their way back to the client
+    pub respmod: Option<String>,
+    // Seconds a scan may take, connecting included
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    // Bodies over this many bytes are passed on unscanned
+    #[serde(default = "default_max_body")]
+    pub max_body: u64,
+    // What happens to a message when the scanner is down or fails
+    #[serde(default)]
+    pub on_error: OnError,
+    // Destinations that are never scanned
+    #[serde(default)]
+    pub except: Vec<String>,
+    #[serde(skip)]
+    reqmod_service: Option<Service>,
+    #[serde(skip)]
+    respmod_service: Option<Service>,
+}
+
+impl Default for IcapConfig {
+    fn default() -> Self {
+        IcapConfig {
+            reqmod: None,
+            respmod: None,
+            timeout: default_timeout(),
+            max_body: default_max_body(),
+            on_error: OnError::default(),
+            except: Vec::new(),
+            reqmod_service: None,
+            respmod_service: None,
+        }
+    }
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+fn default_max_body() -> u64 {
+    8 * 1024 * 1024
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnError {
+    // Pass the message on unscanned
+    #[default]
+    Allow,
+    // Refuse it with a 503
+    Block,
+}
+
+#[derive(Debug)]
+struct Service {
+    // host:port to connect to, and the Host header
+    authority: String,
+    // icap://host:port/service, the request target
+    uri: String,
+}
+
+impl Service {
+    fn parse(url: &str) -> Result<Self, String> {
+        let rest = url.strip_prefix("icap://").ok_or("must start with icap://")?;
+        let (authority, path) = rest.split_once('/').ok_or("must name a service, e.g. icap://host:1344/avscan")?;
+        if authority.is_empty() || path.is_empty() {
+            return Err("must name a host and a service, e.g. icap://host:1344/avscan".to_string());
+        }
+        let authority = match authority.rsplit_once(':') {
+            Some((_, port)) if !authority.ends_with(']') => {
+                port.parse::<u16>().map_err(|_| format!("bad port '{}'", port))?;
+                authority.to_string()
+            }
+            _ => format!("{}:1344", authority),
+        };
+        Ok(Service {
+            uri: format!("icap://{}/{}", authority, path),
+            authority,
+        })
+    }
+}
+
+impl IcapConfig {
+    pub fn enabled(&self) -> bool {
+        self.reqmod.is_some() || self.respmod.is_some()
+    }
+
+    // Parses the service URLs once at startup.
+    pub fn prepare(&mut self) -> Result<(), String> {
+        if self.timeout == 0 {
+            return Err("icap.timeout must be positive".to_string());
+        }
+        let parse = |name: &str, url: &Option<String>| {
+            url.as_deref()
+                .map(|url| Service::parse(url).map_err(|e| format!("icap.{} '{}': {}", name, url, e)))
+                .transpose()
+        };
+        self.reqmod_service = parse("reqmod", &self.reqmod)?;
+        self.respmod_service = parse("respmod", &self.respmod)?;
+        Ok(())
+    }
+
+    pub fn scans_requests(&self, host: &str) -> bool {
+        self.reqmod_service.is_some() && !self.excepted(host)
+    }
+
+    pub fn scans_responses(&self, host: &str) -> bool {
+        self.respmod_service.is_some() && !self.excepted(host)
+    }
+
+    fn excepted(&self, host: &str) -> bool {
+        self.except.iter().any(|pattern| host_matches(pattern, host))
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+pub enum Scanned {
+    // Forward this, the original or the scanner's version
+    Forward(Request<Body>),
+    // Answer the client with this instead
+    Respond(Response<Body>),
+}
+
+// Runs `req` past the REQMOD service.
+pub async fn reqmod(config: &IcapConfig, req: Request<Body>, metrics: &Metrics) -> Scanned {
+    let Some(service) = &config.reqmod_service else {
+        return Scanned::Forward(req);
+    };
+    let (parts, body) = req.into_parts();
+    let body = match buffer(body, &parts.headers, config.max_body).await {
+        Ok(Ok(body)) => body,
+        Ok(Err(body)) => {
+            debug!("Request body for {} over {} bytes, not scanned", parts.uri, config.max_body);
+            metrics.count_icap("reqmod", "skipped");
+            return Scanned::Forward(Request::from_parts(parts, body));
+        }
+        Err(e) => {
+            warn!("⚠️ Could not read request body for scanning: {}", e);
+            return Scanned::Respond(crate::error_response(400, "bad_request", "Could not read the request body"));
+        }
+    };
+    let head = request_head(&parts.method, &parts.uri, &parts.headers);
+    let reply = match exchange(config, service, "REQMOD", &[("req-hdr", head)], "req-body", &body).await {
+        Ok(reply) => reply,
+        Err(e) => {
+            warn!("⚠️ ICAP REQMOD for {} failed: {}", parts.uri, e);
+            metrics.count_icap("reqmod", "error");
+            return match config.on_error {
+                OnError::Allow => Scanned::Forward(Request::from_parts(parts, Body::from(body))),
+                OnError::Block => Scanned::Respond(unavailable()),
+            };
+        }
+    };
+    match reply {
+        Reply::Unchanged => {
+            metrics.count_icap("reqmod", "clean");
+            Scanned::Forward(Request::from_parts(parts, Body::from(body)))
+        }
+        Reply::Response(response) => {
+            info!("🦠 Scanner refused request to {} with {}", parts.uri, response.status());
+            metrics.count_icap("reqmod", "blocked");
+            Scanned::Respond(response)
+        }
+        // The request line stays ours; only headers and body are taken
+        Reply::Request(headers, body) => {
+            debug!("Scanner modified request to {}", parts.uri);
+            metrics.count_icap("reqmod", "modified");
+            let mut req = Request::from_parts(parts, body);
+            *req.headers_mut() = headers;
+            Scanned::Forward(req)
+        }
+    }
+}
+
+// Runs `response` to `req`, a request already sent, past the RESPMOD
+// service.
+pub async fn respmod(config: &IcapConfig, req: &Request<()>, response: Response<Body>, metrics: &Metrics) -> Response<Body> {
+    let Some(service) = &config.respmod_service else {
+        return response;
+    };
+    let (parts, body) = response.into_parts();
+    let body = match buffer(body, &parts.headers, config.max_body).await {
+        Ok(Ok(body)) => body,
+        Ok(Err(body)) => {
+            debug!("Response body from {} over {} bytes, not scanned", req.uri(), config.max_body);
+            metrics.count_icap("respmod", "skipped");
+            return Response::from_parts(parts, body);
+        }
+        Err(e) => {
+            warn!("⚠️ Could not read response body from {} for scanning: {}", req.uri(), e);
+            return crate::error_response(502, "upstream_error", &e.to_string());
+        }
+    };
+    let sections = [
+        ("req-hdr", request_head(req.method(), req.uri(), req.headers())),
+        ("res-hdr", response_head(parts.status, &parts.headers)),
+    ];
+    let reply = match exchange(config, service, "RESPMOD", &sections, "res-body", &body).await {
+        Ok(reply) => reply,
+        Err(e) => {
+            warn!("⚠️ ICAP RESPMOD for {} failed: {}", req.uri(), e);
+            metrics.count_icap("respmod", "error");
+            return match config.on_error {
+                OnError::Allow => Response::from_parts(parts, Body::from(body)),
+                OnError::Block => unavailable(),
+            };
+        }
+    };
+    match reply {
+        Reply::Response(response) if response.status() != parts.status && !response.status().is_success() => {
+            info!("🦠 Scanner refused response from {} with {}", req.uri(), response.status());
+            metrics.count_icap("respmod", "blocked");
+            response
+        }
+        Reply::Response(response) => {
+            debug!("Scanner modified response from {}", req.uri());
+            metrics.count_icap("respmod", "modified");
+            response
+        }
+        Reply::Unchanged | Reply::Request(..) => {
+            metrics.count_icap("respmod", "clean");
+            Response::from_parts(parts, Body::from(body))
+        }
+    }
+}
+
+// The head of `req` as the RESPMOD req-hdr section wants it.
+pub fn head(req: &Request<Body>) -> Request<()> {
+    let mut head = Request::new(());
+    *head.method_mut() = req.method().clone();
+    *head.uri_mut() = req.uri().clone();
+    *head.headers_mut() = req.headers().clone();
+    head
+}
+
+fn unavailable() -> Response<Body> {
+    crate::error_response(503, "scan_failed", "Content could not be scanned")
+}
+
+// The whole body if it fits in `max` bytes. Otherwise Err with a body that
+// replays what was read and then the rest.
+async fn buffer(mut body: Body, headers: &HeaderMap, max: u64) -> Result<Result<Bytes, Body>, hyper::Error> {
+    if crate::content_length(headers) > max {
+        return Ok(Err(body));
+    }
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        buf.extend_from_slice(&chunk?);
+        if buf.len() as u64 > max {
+            let (mut sender, out) = Body::channel();
+            tokio::spawn(async move {
+                if sender.send_data(buf.into()).await.is_err() {
+                    return;
+                }
+                while let Some(chunk) = body.data().await {
+                    let Ok(chunk) = chunk else {
+                        sender.abort();
+                        return;
+                    };
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                if let Ok(Some(trailers)) = body.trailers().await {
+                    let _ = sender.send_trailers(trailers).await;
+                }
+            });
+            return Ok(Err(out));
+        }
+    }
+    Ok(Ok(buf.into()))
+}
+
+fn request_head(method: &hyper::Method, uri: &hyper::Uri, headers: &HeaderMap) -> Vec<u8> {
+    let mut head = format!("{} {} HTTP/1.1\r\n", method, uri).into_bytes();
+    put_headers(&mut head, headers);
+    head
+}
+
+fn response_head(status: StatusCode, headers: &HeaderMap) -> Vec<u8> {
+    let reason = status.canonical_reason().unwrap_or("");
+    let mut head = format!("HTTP/1.1 {} {}\r\n", status.as_u16(), reason).into_bytes();
+    put_headers(&mut head, headers);
+    head
+}
+
+fn put_headers(out: &mut Vec<u8>, headers: &HeaderMap) {
+    for (name, value) in headers {
+        out.extend_from_slice(name.as_str().as_bytes());
+        out.extend_from_slice(b": ");
+        out.extend_from_slice(value.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"\r\n");
+}
+
+enum Reply {
+    // 204: pass the message on as it was
+    Unchanged,
+    // 200 with a req-hdr: the modified request's headers and body
+    Request(HeaderMap, Body),
+    // 200 with a res-hdr: the response to use
+    Response(Response<Body>),
+}
+
+// One ICAP request on a connection of its own, within the timeout.
+// `sections` are the encapsulated HTTP heads, in order, followed by `body`
+// under `body_name`.
+async fn exchange(
+    config: &IcapConfig,
+    service: &Service,
+    method: &str,
+    sections: &[(&str, Vec<u8>)],
+    body_name: &str,
+    body: &[u8],
+) -> io::Result<Reply> {
+    let exchange = send(service, method, sections, body_name, body, config.max_body);
+    tokio::time::timeout(config.timeout(), exchange)
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")))
+}
+
+async fn send(
+    service: &Service,
+    method: &str,
+    sections: &[(&str, Vec<u8>)],
+    body_name: &str,
+    body: &[u8],
+    max_body: u64,
+) -> io::Result<Reply> {
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    for (name, head) in sections {
+        offsets.push(format!("{}={}", name, offset));
+        offset += head.len();
+    }
+    offsets.push(format!("{}={}", if body.is_empty() { "null-body" } else { body_name }, offset));
+    let mut message = format!(
+        "{} {} ICAP/1.0\r\nHost: {}\r\nAllow: 204\r\nConnection: close\r\nEncapsulated: {}\r\n\r\n",
+        method,
+        service.uri,
+        service.authority,
+        offsets.join(", ")
+    )
+    .into_bytes();
+    for (_, head) in sections {
+        message.extend_from_slice(head);
+    }
+    if !body.is_empty() {
+        message.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+        message.extend_from_slice(body);
+        message.extend_from_slice(b"\r\n0\r\n\r\n");
+    }
+
+    let mut stream = TcpStream::connect(&service.authority).await?;
+    stream.write_all(&message).await?;
+    let mut reader = BufReader::new(stream);
+
+    let status_line = read_line(&mut reader).await?;
+    let status = status_line
+        .strip_prefix("ICAP/1.0 ")
+        .and_then(|rest| rest.get(..3))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| invalid(format!("bad status line '{}'", status_line)))?;
+    let headers = read_headers(&mut reader).await?;
+    match status {
+        204 => return Ok(Reply::Unchanged),
+        200 => {}
+        _ => return Err(invalid(format!("scanner answered '{}'", status_line))),
+    }
+    let encapsulated = headers
+        .get("encapsulated")
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| invalid("200 without Encapsulated".to_string()))?;
+    let entries: Vec<(&str, usize)> = encapsulated
+        .split(',')
+        .filter_map(|entry| entry.trim().split_once('='))
+        .map(|(name, offset)| offset.trim().parse().map(|offset| (name.trim(), offset)))
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid(format!("bad Encapsulated '{}'", encapsulated)))?;
+
+    let mut head = None;
+    let mut body = Bytes::new();
+    for (i, (name, offset)) in entries.iter().enumerate() {
+        match *name {
+            "req-hdr" | "res-hdr" => {
+                let end = entries.get(i + 1).map(|(_, next)| *next).unwrap_or(*offset);
+                let len = end
+                    .checked_sub(*offset)
+                    .filter(|len| *len <= MAX_HEAD)
+                    .ok_or_else(|| invalid(format!("bad Encapsulated '{}'", encapsulated)))?;
+                let mut section = vec![0; len];
+                reader.read_exact(&mut section).await?;
+                head = Some((*name, section));
+            }
+            "req-body" | "res-body" => body = read_chunked(&mut reader, max_body).await?,
+            _ => {}
+        }
+    }
+    let Some((kind, section)) = head else {
+        return Err(invalid("200 without an HTTP head".to_string()));
+    };
+    let (start, mut http_headers) = parse_head(&section)?;
+    http_headers.remove(TRANSFER_ENCODING);
+    http_headers.remove(CONTENT_LENGTH);
+    if !body.is_empty() {
+        http_headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    }
+    if kind == "req-hdr" {
+        return Ok(Reply::Request(http_headers, Body::from(body)));
+    }
+    let status = start
+        .split(' ')
+        .nth(1)
+        .and_then(|code| StatusCode::from_bytes(code.as_bytes()).ok())
+        .ok_or_else(|| invalid(format!("bad status line '{}'", start)))?;
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    *response.headers_mut() = http_headers;
+    Ok(Reply::Response(response))
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "scanner closed the connection"));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// ICAP headers up to the blank line; only Encapsulated is used.
+async fn read_headers<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    loop {
+        let line = read_line(reader).await?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.trim().as_bytes()), HeaderValue::from_str(value.trim())) {
+                headers.append(name, value);
+            }
+        }
+    }
+}
+
+async fn read_chunked<R: AsyncBufRead + Unpin>(reader: &mut R, max_body: u64) -> io::Result<Bytes> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader).await?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid(format!("bad chunk size '{}'", line)))?;
+        if size == 0 {
+            // Trailers, then the blank line
+            while !read_line(reader).await?.is_empty() {}
+            return Ok(body.into());
+        }
+        if (body.len() + size) as u64 > max_body {
+            return Err(invalid(format!("scanner sent a body over {} bytes", max_body)));
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).await?;
+        read_line(reader).await?;
+    }
+}
+
+// Start line and headers of an encapsulated HTTP head.
+fn parse_head(section: &[u8]) -> io::Result<(String, HeaderMap)> {
+    let text = std::str::from_utf8(section).map_err(|_| invalid("HTTP head is not text".to_string()))?;
+    let mut lines = text.split("\r\n");
+    let start = lines.next().unwrap_or_default().to_string();
+    let mut headers = HeaderMap::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid(format!("bad header line '{}'", line)))?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|_| invalid(format!("bad header '{}'", name)))?;
+        let value = HeaderValue::from_str(value.trim()).map_err(|_| invalid(format!("bad value for {}", name)))?;
+        headers.append(name, value);
+    }
+    Ok((start, headers))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,335 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use base64::Engine as _;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Public};
+use openssl::rsa::Rsa;
+use openssl::sign::{Signer, Verifier};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+use crate::json::{self, Json};
+use crate::AppState;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+// Unknown key IDs trigger a key set refresh at most this often
+const MIN_REFRESH: Duration = Duration::from_secs(60);
+
+// `Proxy-Authorization: Bearer <jwt>`, for CI jobs and service meshes with
+// short-lived tokens. One key source:
+//
+//   [jwt]
+//   jwks_url = "https://issuer.example.com/.well-known/jwks.json"
+//   issuer = "https://issuer.example.com"
+//   audience = "secure-proxy"
+#[derive(Debug, Clone, Deserialize)]
+pub struct JwtConfig {
+    // Key set to fetch and refresh (RS*/ES* tokens)
+    pub jwks_url: Option<String>,
+    // PEM public key file (RS*/ES* tokens)
+    pub public_key: Option<std::path::PathBuf>,
+    // Shared secret (HS* tokens)
+    pub secret: Option<String>,
+    // Required "iss" and "aud" values, when set
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    // Claim holding the username
+    #[serde(default = "default_username_claim")]
+    pub username_claim: String,
+    // Seconds of clock skew allowed on exp and nbf
+    #[serde(default = "default_leeway")]
+    pub leeway: u64,
+    // Seconds between key set refreshes
+    #[serde(default = "default_jwks_refresh")]
+    pub jwks_refresh: u64,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        JwtConfig {
+            jwks_url: None,
+            public_key: None,
+            secret: None,
+            issuer: None,
+            audience: None,
+            username_claim: default_username_claim(),
+            leeway: default_leeway(),
+            jwks_refresh: default_jwks_refresh(),
+        }
+    }
+}
+
+fn default_username_claim() -> String {
+    "sub".to_string()
+}
+
+fn default_leeway() -> u64 {
+    60
+}
+
+fn default_jwks_refresh() -> u64 {
+    3600
+}
+
+impl JwtConfig {
+    pub fn enabled(&self) -> bool {
+        self.jwks_url.is_some() || self.public_key.is_some() || self.secret.is_some()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let sources = [self.jwks_url.is_some(), self.public_key.is_some(), self.secret.is_some()];
+        if sources.iter().filter(|set| **set).count() > 1 {
+            return Err("set only one of jwt.jwks_url, jwt.public_key and jwt.secret".to_string());
+        }
+        if self.secret.as_deref().is_some_and(|s| s.len() < 32) {
+            return Err("jwt.secret must be at least 32 bytes".to_string());
+        }
+        if self.jwks_refresh < 60 {
+            return Err("jwt.jwks_refresh must be at least 60 seconds".to_string());
+        }
+        Ok(())
+    }
+}
+
+enum Key {
+    Hmac(Vec<u8>),
+    Public(PKey<Public>),
+}
+
+#[derive(Default)]
+pub struct Jwt {
+    // kid -> key; a static key is stored under ""
+    keys: RwLock<HashMap<String, Arc<Key>>>,
+    last_fetch: Mutex<Option<Instant>>,
+}
+
+impl Jwt {
+    pub fn new(config: &JwtConfig) -> std::io::Result<Self> {
+        let jwt = Jwt::default();
+        let key = match (&config.secret, &config.public_key) {
+            (Some(secret), _) => Key::Hmac(secret.as_bytes().to_vec()),
+            (_, Some(path)) => {
+                let pem = std::fs::read(path)?;
+                Key::Public(PKey::public_key_from_pem(&pem).map_err(std::io::Error::other)?)
+            }
+            _ => return Ok(jwt),
+        };
+        jwt.keys.write().unwrap().insert(String::new(), Arc::new(key));
+        Ok(jwt)
+    }
+
+    // The username from a valid token, or None (logged).
+    pub async fn authenticate(&self, config: &JwtConfig, token: &str) -> Option<String> {
+        match self.verify(config, token).await {
+            Ok(user) => Some(user),
+            Err(e) => {
+                warn!("❌ Proxy auth bearer token rejected: {}", e);
+                None
+            }
+        }
+    }
+
+    pub async fn verify(&self, config: &JwtConfig, token: &str) -> Result<String, String> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err("not a JWT".to_string());
+        };
+        let decode = |part: &str| BASE64URL.decode(part).map_err(|_| "bad base64".to_string());
+        let header_json = json::parse(&String::from_utf8_lossy(&decode(header)?))?;
+        let alg = header_json.get("alg").and_then(Json::as_str).unwrap_or("none");
+        let kid = header_json.get("kid").and_then(Json::as_str).unwrap_or_default();
+
+        let key = match self.key(config, kid) {
+            Some(key) => key,
+            None if config.jwks_url.is_some() && self.refresh_due() => {
+                fetch_keys(self, config).await;
+                self.key(config, kid).ok_or_else(|| format!("unknown key '{}'", kid))?
+            }
+            None => return Err(format!("unknown key '{}'", kid)),
+        };
+        let signed = &token[..header.len() + 1 + payload.len()];
+        if !check_signature(&key, alg, signed.as_bytes(), &decode(signature)?)? {
+            return Err("bad signature".to_string());
+        }
+
+        let claims = json::parse(&String::from_utf8_lossy(&decode(payload)?))?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as f64;
+        let leeway = config.leeway as f64;
+        match claims.get("exp").and_then(Json::as_f64) {
+            Some(exp) if now > exp + leeway => return Err("token expired".to_string()),
+            Some(_) => {}
+            None => return Err("token has no exp".to_string()),
+        }
+        if claims.get("nbf").and_then(Json::as_f64).is_some_and(|nbf| now + leeway < nbf) {
+            return Err("token not yet valid".to_string());
+        }
+        if let Some(issuer) = &config.issuer {
+            if claims.get("iss").and_then(Json::as_str) != Some(issuer) {
+                return Err("wrong issuer".to_string());
+            }
+        }
+        if let Some(audience) = &config.audience {
+            let matches = match claims.get("aud") {
+                Some(Json::String(aud)) => aud == audience,
+                Some(Json::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !matches {
+                return Err("wrong audience".to_string());
+            }
+        }
+        match claims.get(&config.username_claim).and_then(Json::as_str) {
+            Some(user) if crate::users::valid_name(user) => Ok(user.to_string()),
+            _ => Err(format!("no usable '{}' claim", config.username_claim)),
+        }
+    }
+
+    fn key(&self, config: &JwtConfig, kid: &str) -> Option<Arc<Key>> {
+        let keys = self.keys.read().unwrap();
+        let key = match &config.jwks_url {
+            // A token without "kid" is fine if the set has a single key
+            Some(_) if kid.is_empty() && keys.len() == 1 => keys.values().next(),
+            Some(_) => keys.get(kid),
+            None => keys.get(""),
+        };
+        key.cloned()
+    }
+
+    fn refresh_due(&self) -> bool {
+        let mut last = self.last_fetch.lock().unwrap();
+        if last.is_some_and(|at| at.elapsed() < MIN_REFRESH) {
+            return false;
+        }
+        *last = Some(Instant::now());
+        true
+    }
+}
+
+fn check_signature(key: &Key, alg: &str, signed: &[u8], signature: &[u8]) -> Result<bool, String> {
+    let digest = match alg.get(2..) {
+        Some("256") => MessageDigest::sha256(),
+        Some("384") => MessageDigest::sha384(),
+        Some("512") => MessageDigest::sha512(),
+        _ => return Err(format!("unsupported alg '{}'", alg)),
+    };
+    let ssl = |e: openssl::error::ErrorStack| e.to_string();
+    match (key, alg.get(..2).unwrap_or_default()) {
+        (Key::Hmac(secret), "HS") => {
+            let pkey = PKey::hmac(secret).map_err(ssl)?;
+            let mut signer = Signer::new(digest, &pkey).map_err(ssl)?;
+            signer.update(signed).map_err(ssl)?;
+            let expected = signer.sign_to_vec().map_err(ssl)?;
+            Ok(expected.len() == signature.len() && openssl::memcmp::eq(&expected, signature))
+        }
+        (Key::Public(pkey), "RS") if pkey.rsa().is_ok() => {
+            let mut verifier = Verifier::new(digest, pkey).map_err(ssl)?;
+            verifier.update(signed).map_err(ssl)?;
+            Ok(verifier.verify(signature).unwrap_or(false))
+        }
+        (Key::Public(pkey), "ES") if pkey.ec_key().is_ok() => {
+            // JWS signatures are r || s rather than DER
+            if signature.is_empty() || !signature.len().is_multiple_of(2) {
+                return Ok(false);
+            }
+            let (r, s) = signature.split_at(signature.len() / 2);
+            let sig = EcdsaSig::from_private_components(
+                BigNum::from_slice(r).map_err(ssl)?,
+                BigNum::from_slice(s).map_err(ssl)?,
+            )
+            .map_err(ssl)?;
+            let mut verifier = Verifier::new(digest, pkey).map_err(ssl)?;
+            verifier.update(signed).map_err(ssl)?;
+            Ok(verifier.verify(&sig.to_der().map_err(ssl)?).unwrap_or(false))
+        }
+        _ => Err(format!("alg '{}' does not match the configured key", alg)),
+    }
+}
+
+// Replace the key set from jwks_url; the old keys stay if the fetch fails.
+pub async fn fetch_keys(jwt: &Jwt, config: &JwtConfig) {
+    let Some(url) = config.jwks_url.clone() else {
+        return;
+    };
+    let fetched = tokio::task::spawn_blocking(move || crate::alert::get(&url, FETCH_TIMEOUT))
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+    let url = config.jwks_url.as_deref().unwrap_or_default();
+    let set = match fetched {
+        Ok((200, body)) => json::parse(&String::from_utf8_lossy(&body)),
+        Ok((status, _)) => Err(format!("answered {}", status)),
+        Err(e) => Err(e.to_string()),
+    };
+    let set = match set {
+        Ok(set) => set,
+        Err(e) => {
+            warn!("⚠️ JWKS fetch from {} failed: {}", url, e);
+            return;
+        }
+    };
+    let mut keys = HashMap::new();
+    for jwk in set.get("keys").and_then(Json::as_array).unwrap_or_default() {
+        let kid = jwk.get("kid").and_then(Json::as_str).unwrap_or_default().to_string();
+        match parse_jwk(jwk) {
+            Some(key) => {
+                keys.insert(kid, Arc::new(Key::Public(key)));
+            }
+            None => debug!("Skipping unsupported JWK '{}'", kid),
+        }
+    }
+    if keys.is_empty() {
+        warn!("⚠️ JWKS from {} has no usable keys", url);
+        return;
+    }
+    debug!("Loaded {} JWT signing key(s) from {}", keys.len(), url);
+    *jwt.keys.write().unwrap() = keys;
+    *jwt.last_fetch.lock().unwrap() = Some(Instant::now());
+}
+
+// RSA and P-256/P-384 signing keys.
+fn parse_jwk(jwk: &Json) -> Option<PKey<Public>> {
+    if jwk.get("use").and_then(Json::as_str).is_some_and(|u| u != "sig") {
+        return None;
+    }
+    let field = |name: &str| BASE64URL.decode(jwk.get(name)?.as_str()?).ok();
+    let number = |name: &str| BigNum::from_slice(&field(name)?).ok();
+    match jwk.get("kty")?.as_str()? {
+        "RSA" => PKey::from_rsa(Rsa::from_public_components(number("n")?, number("e")?).ok()?).ok(),
+        "EC" => {
+            let curve = match jwk.get("crv")?.as_str()? {
+                "P-256" => Nid::X9_62_PRIME256V1,
+                "P-384" => Nid::SECP384R1,
+                _ => return None,
+            };
+            let group = EcGroup::from_curve_name(curve).ok()?;
+            let (x, y) = (number("x")?, number("y")?);
+            let key = EcKey::from_public_key_affine_coordinates(&group, &x, &y).ok()?;
+            PKey::from_ec_key(key).ok()
+        }
+        _ => None,
+    }
+}
+
+pub fn spawn(state: Arc<AppState>) {
+    let config = &state.config.jwt;
+    let Some(url) = &config.jwks_url else {
+        if config.enabled() {
+            info!("🎫 Accepting bearer tokens signed with the configured key");
+        }
+        return;
+    };
+    info!("🎫 Accepting bearer tokens signed with keys from {}", url);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(state.config.jwt.jwks_refresh));
+        loop {
+            ticker.tick().await;
+            fetch_keys(&state.jwt, &state.config.jwt).await;
+        }
+    });
+}
@@ -0,0 +1,410 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use serde::Deserialize;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::{Arc, OnceLock};
+use tracing::{info, warn};
+
+// Kerberos tickets are a few KiB; PACs from large AD groups can reach ~48K
+const MAX_TOKEN: usize = 64 * 1024;
+
+// `Proxy-Authorization: Negotiate <SPNEGO token>` from domain-joined
+// clients, checked against a keytab:
+//
+//   [kerberos]
+//   keytab = "/etc/secure-proxy/proxy.keytab"
+//   service = "HTTP@proxy.corp.example.com"
+#[derive(Debug, Clone, Deserialize)]
+pub struct KerberosConfig {
+    // Off when unset
+    pub keytab: Option<PathBuf>,
+    // Host-based service name to accept tickets for; any key in the
+    // keytab when unset
+    pub service: Option<String>,
+    // "alice@CORP.EXAMPLE.COM" becomes "alice"
+    #[serde(default = "default_true")]
+    pub strip_realm: bool,
+    // Client realms accepted; any when empty
+    #[serde(default)]
+    pub realms: Vec<String>,
+}
+
+impl Default for KerberosConfig {
+    fn default() -> Self {
+        KerberosConfig {
+            keytab: None,
+            service: None,
+            strip_realm: true,
+            realms: Vec::new(),
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl KerberosConfig {
+    pub fn enabled(&self) -> bool {
+        self.keytab.is_some()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.service.as_deref().is_some_and(|s| !s.contains('@')) {
+            return Err("kerberos.service must look like \"HTTP@proxy.example.com\"".to_string());
+        }
+        Ok(())
+    }
+}
+
+// MIT krb5 GSS-API (gssapi/gssapi.h), loaded when [kerberos] is
+// configured so that the library is only needed by deployments using it.
+#[allow(non_camel_case_types)]
+mod ffi {
+    use super::*;
+
+    pub type OM_uint32 = u32;
+    pub type gss_name_t = *mut c_void;
+    pub type gss_cred_id_t = *mut c_void;
+    pub type gss_ctx_id_t = *mut c_void;
+
+    #[repr(C)]
+    pub struct gss_buffer_desc {
+        pub length: usize,
+        pub value: *mut c_void,
+    }
+
+    #[repr(C)]
+    pub struct gss_OID_desc {
+        pub length: OM_uint32,
+        pub elements: *mut c_void,
+    }
+    pub type gss_OID = *mut gss_OID_desc;
+
+    pub const GSS_S_COMPLETE: OM_uint32 = 0;
+    pub const GSS_S_CONTINUE_NEEDED: OM_uint32 = 1;
+    pub const GSS_C_GSS_CODE: c_int = 1;
+    pub const GSS_C_MECH_CODE: c_int = 2;
+    pub const GSS_C_ACCEPT: c_int = 2;
+    pub const GSS_C_INDEFINITE: OM_uint32 = 0xffff_ffff;
+
+    pub fn is_error(major: OM_uint32) -> bool {
+        major & 0xffff_0000 != 0
+    }
+
+    pub struct Library {
+        pub register_acceptor_identity: unsafe extern "C" fn(*const c_char) -> OM_uint32,
+        pub import_name:
+            unsafe extern "C" fn(*mut OM_uint32, *mut gss_buffer_desc, gss_OID, *mut gss_name_t) -> OM_uint32,
+        pub release_name: unsafe extern "C" fn(*mut OM_uint32, *mut gss_name_t) -> OM_uint32,
+        pub acquire_cred: unsafe extern "C" fn(
+            *mut OM_uint32,
+            gss_name_t,
+            OM_uint32,
+            *mut c_void,
+            c_int,
+            *mut gss_cred_id_t,
+            *mut c_void,
+            *mut OM_uint32,
+        ) -> OM_uint32,
+        pub release_cred: unsafe extern "C" fn(*mut OM_uint32, *mut gss_cred_id_t) -> OM_uint32,
+        pub accept_sec_context: unsafe extern "C" fn(
+            *mut OM_uint32,
+            *mut gss_ctx_id_t,
+            gss_cred_id_t,
+            *mut gss_buffer_desc,
+            *mut c_void,
+            *mut gss_name_t,
+            *mut gss_OID,
+            *mut gss_buffer_desc,
+            *mut OM_uint32,
+            *mut OM_uint32,
+            *mut gss_cred_id_t,
+        ) -> OM_uint32,
+        pub delete_sec_context: unsafe extern "C" fn(*mut OM_uint32, *mut gss_ctx_id_t, *mut gss_buffer_desc) -> OM_uint32,
+        pub display_name:
+            unsafe extern "C" fn(*mut OM_uint32, gss_name_t, *mut gss_buffer_desc, *mut gss_OID) -> OM_uint32,
+        pub display_status: unsafe extern "C" fn(
+            *mut OM_uint32,
+            OM_uint32,
+            c_int,
+            gss_OID,
+            *mut OM_uint32,
+            *mut gss_buffer_desc,
+        ) -> OM_uint32,
+        pub release_buffer: unsafe extern "C" fn(*mut OM_uint32, *mut gss_buffer_desc) -> OM_uint32,
+        // Address of the library's GSS_C_NT_HOSTBASED_SERVICE variable
+        pub nt_hostbased_service: *const gss_OID,
+    }
+
+    // Function pointers and a pointer to an immutable OID constant
+    unsafe impl Send for Library {}
+    unsafe impl Sync for Library {}
+
+    impl Library {
+        pub fn load() -> Result<Self, String> {
+            let name = c"libgssapi_krb5.so.2";
+            // Never closed: the library stays loaded for the process lifetime
+            let handle = unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+            if handle.is_null() {
+                return Err(format!("cannot load libgssapi_krb5.so.2: {}", dl_error()));
+            }
+            let sym = |name: &CStr| {
+                let ptr = unsafe { libc::dlsym(handle, name.as_ptr()) };
+                match ptr.is_null() {
+                    true => Err(format!("libgssapi_krb5 has no {}", name.to_string_lossy())),
+                    false => Ok(ptr),
+                }
+            };
+            // SAFETY: the symbols have the C signatures declared above
+            unsafe {
+                Ok(Library {
+                    register_acceptor_identity: function(sym(c"krb5_gss_register_acceptor_identity")?),
+                    import_name: function(sym(c"gss_import_name")?),
+                    release_name: function(sym(c"gss_release_name")?),
+                    acquire_cred: function(sym(c"gss_acquire_cred")?),
+                    release_cred: function(sym(c"gss_release_cred")?),
+                    accept_sec_context: function(sym(c"gss_accept_sec_context")?),
+                    delete_sec_context: function(sym(c"gss_delete_sec_context")?),
+                    display_name: function(sym(c"gss_display_name")?),
+                    display_status: function(sym(c"gss_display_status")?),
+                    release_buffer: function(sym(c"gss_release_buffer")?),
+                    nt_hostbased_service: sym(c"GSS_C_NT_HOSTBASED_SERVICE")? as *const gss_OID,
+                })
+            }
+        }
+    }
+
+    // SAFETY: T must be the function pointer type of the symbol at `ptr`.
+    unsafe fn function<T: Copy>(ptr: *mut c_void) -> T {
+        assert_eq!(std::mem::size_of::<T>(), std::mem::size_of::<*mut c_void>());
+        std::mem::transmute_copy::<*mut c_void, T>(&ptr)
+    }
+
+    fn dl_error() -> String {
+        let message = unsafe { libc::dlerror() };
+        match message.is_null() {
+            true => "unknown error".to_string(),
+            false => unsafe { CStr::from_ptr(message) }.to_string_lossy().into_owned(),
+        }
+    }
+}
+
+use ffi::*;
+
+pub struct Kerberos {
+    lib: Library,
+}
+
+impl Kerberos {
+    pub fn new(config: &KerberosConfig) -> Result<Self, String> {
+        let lib = Library::load()?;
+        let keytab = config.keytab.as_ref().map(|p| p.to_string_lossy().into_owned()).unwrap_or_default();
+        let path = CString::new(keytab.clone()).map_err(|_| "kerberos.keytab contains a NUL byte".to_string())?;
+        if std::fs::metadata(&keytab).is_err() {
+            return Err(format!("kerberos.keytab {} is not readable", keytab));
+        }
+        // Process-wide; the keytab is read again whenever a ticket is checked
+        if unsafe { (lib.register_acceptor_identity)(path.as_ptr()) } != GSS_S_COMPLETE {
+            return Err(format!("cannot use keytab {}", keytab));
+        }
+        info!("🎟️ Accepting Kerberos (Negotiate) proxy authentication with keytab {}", keytab);
+        Ok(Kerberos { lib })
+    }
+
+    // Checks one SPNEGO/Kerberos token; the username (client principal,
+    // maybe without realm) if it is valid. Blocking: reads the keytab and
+    // the replay cache.
+    pub fn accept(&self, config: &KerberosConfig, token: &str) -> Result<String, String> {
+        let mut input = BASE64.decode(token.trim()).map_err(|_| "token is not base64".to_string())?;
+        if input.is_empty() || input.len() > MAX_TOKEN {
+            return Err("token is empty or too large".to_string());
+        }
+        let lib = &self.lib;
+        let mut minor = 0;
+        let cred = match &config.service {
+            Some(service) => Cred(self.acquire(service)?, lib),
+            None => Cred(ptr::null_mut(), lib),
+        };
+        let mut context = Context(ptr::null_mut(), lib);
+        let mut client = Name(ptr::null_mut(), lib);
+        let mut input_buffer = gss_buffer_desc {
+            length: input.len(),
+            value: input.as_mut_ptr() as *mut c_void,
+        };
+        let mut output = Buffer::new(lib);
+        let major = unsafe {
+            (lib.accept_sec_context)(
+                &mut minor,
+                &mut context.0,
+                cred.0,
+                &mut input_buffer,
+                ptr::null_mut(),
+                &mut client.0,
+                ptr::null_mut(),
+                &mut output.0,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        if major == GSS_S_CONTINUE_NEEDED {
+            // SPNEGO fell back to NTLM or asked for another round
+            return Err("multi-round Negotiate (e.g. NTLM) is not supported".to_string());
+        }
+        if is_error(major) {
+            return Err(self.status(major, minor));
+        }
+
+        let mut display = Buffer::new(lib);
+        let major = unsafe { (lib.display_name)(&mut minor, client.0, &mut display.0, ptr::null_mut()) };
+        if is_error(major) {
+            return Err(self.status(major, minor));
+        }
+        let principal = display.to_string();
+        let (user, realm) = principal.rsplit_once('@').unwrap_or((&principal, ""));
+        if !config.realms.is_empty() && !config.realms.iter().any(|r| r.eq_ignore_ascii_case(realm)) {
+            return Err(format!("realm of '{}' is not accepted", principal));
+        }
+        let user = if config.strip_realm { user.to_string() } else { principal.clone() };
+        match crate::users::valid_name(&user) {
+            true => Ok(user),
+            false => Err(format!("unusable principal name '{}'", principal)),
+        }
+    }
+
+    fn acquire(&self, service: &str) -> Result<gss_cred_id_t, String> {
+        let lib = &self.lib;
+        let mut minor = 0;
+        let mut buffer = gss_buffer_desc {
+            length: service.len(),
+            value: service.as_ptr() as *mut c_void,
+        };
+        let mut name = Name(ptr::null_mut(), lib);
+        let major = unsafe { (lib.import_name)(&mut minor, &mut buffer, *lib.nt_hostbased_service, &mut name.0) };
+        if is_error(major) {
+            return Err(self.status(major, minor));
+        }
+        let mut cred = ptr::null_mut();
+        let major = unsafe {
+            (lib.acquire_cred)(
+                &mut minor,
+                name.0,
+                GSS_C_INDEFINITE,
+                ptr::null_mut(),
+                GSS_C_ACCEPT,
+                &mut cred,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        if is_error(major) {
+            return Err(self.status(major, minor));
+        }
+        Ok(cred)
+    }
+
+    // "major message: minor message", as the library words them.
+    fn status(&self, major: OM_uint32, minor: OM_uint32) -> String {
+        let message = |code, kind| {
+            let (mut ignored, mut context) = (0, 0);
+            let mut buffer = Buffer::new(&self.lib);
+            unsafe {
+                (self.lib.display_status)(&mut ignored, code, kind, ptr::null_mut(), &mut context, &mut buffer.0);
+            }
+            buffer.to_string()
+        };
+        match minor {
+            0 => message(major, GSS_C_GSS_CODE),
+            _ => format!("{}: {}", message(major, GSS_C_GSS_CODE), message(minor, GSS_C_MECH_CODE)),
+        }
+    }
+}
+
+// GSS-API handles released on drop.
+struct Cred<'a>(gss_cred_id_t, &'a Library);
+struct Context<'a>(gss_ctx_id_t, &'a Library);
+struct Name<'a>(gss_name_t, &'a Library);
+struct Buffer<'a>(gss_buffer_desc, &'a Library);
+
+impl Drop for Cred<'_> {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { (self.1.release_cred)(&mut 0, &mut self.0) };
+        }
+    }
+}
+
+impl Drop for Context<'_> {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { (self.1.delete_sec_context)(&mut 0, &mut self.0, ptr::null_mut()) };
+        }
+    }
+}
+
+impl Drop for Name<'_> {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { (self.1.release_name)(&mut 0, &mut self.0) };
+        }
+    }
+}
+
+impl<'a> Buffer<'a> {
+    fn new(lib: &'a Library) -> Self {
+        Buffer(
+            gss_buffer_desc {
+                length: 0,
+                value: ptr::null_mut(),
+            },
+            lib,
+        )
+    }
+}
+
+impl std::fmt::Display for Buffer<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.value.is_null() {
+            return Ok(());
+        }
+        let bytes = unsafe { std::slice::from_raw_parts(self.0.value as *const u8, self.0.length) };
+        f.write_str(&String::from_utf8_lossy(bytes))
+    }
+}
+
+impl Drop for Buffer<'_> {
+    fn drop(&mut self) {
+        if !self.0.value.is_null() {
+            unsafe { (self.1.release_buffer)(&mut 0, &mut self.0) };
+        }
+    }
+}
+
+// Set on a client connection once it authenticated with Negotiate. As
+// with NTLM, browsers authenticate the connection rather than each
+// request, so later requests on it may come without a header.
+#[derive(Clone, Default)]
+pub struct Pinned(Arc<OnceLock<String>>);
+
+impl Pinned {
+    pub fn user(&self) -> Option<&str> {
+        self.0.get().map(String::as_str)
+    }
+
+    pub fn pin(&self, user: &str) {
+        if self.0.set(user.to_string()).is_err() && self.user() != Some(user) {
+            warn!("⚠️ Connection pinned to '{}' authenticated again as '{}'", self.user().unwrap_or_default(), user);
+        }
+    }
+}
+
+pub fn is_negotiate(header: Option<&hyper::header::HeaderValue>) -> bool {
+    header
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split_once(' '))
+        .is_some_and(|(scheme, _)| scheme.eq_ignore_ascii_case("Negotiate"))
+}
@@ -0,0 +1,442 @@
+use openssl::hash::{hash as digest, MessageDigest};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+// Checks proxy credentials with an LDAP simple bind as the user, e.g.
+// against Active Directory:
+//
+//   [ldap]
+//   url = "ldaps://dc1.corp.example.com"
+//   bind_dn = "{user}@corp.example.com"
+//   base_dn = "dc=corp,dc=example,dc=com"
+//   user_filter = "(sAMAccountName={user})"
+//   group_filter = "(memberOf=cn=proxy-users,ou=groups,dc=corp,dc=example,dc=com)"
+#[derive(Debug, Clone, Deserialize)]
+pub struct LdapConfig {
+    // ldap://host[:389] or ldaps://host[:636]; LDAP is off when unset
+    pub url: Option<String>,
+    // DN (or AD user principal name) to bind as; {user} is the proxy username
+    #[serde(default)]
+    pub bind_dn: String,
+    // Where to look the user up for group_filter
+    pub base_dn: Option<String>,
+    #[serde(default = "default_user_filter")]
+    pub user_filter: String,
+    // If set, the user's entry must also match this filter
+    pub group_filter: Option<String>,
+    // Seconds a successful bind is remembered
+    #[serde(default = "default_cache_ttl")]
+    pub cache_ttl: u64,
+    // Seconds for connecting and each reply
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for LdapConfig {
+    fn default() -> Self {
+        LdapConfig {
+            url: None,
+            bind_dn: String::new(),
+            base_dn: None,
+            user_filter: default_user_filter(),
+            group_filter: None,
+            cache_ttl: default_cache_ttl(),
+            timeout: default_timeout(),
+        }
+    }
+}
+
+fn default_user_filter() -> String {
+    "(uid={user})".to_string()
+}
+
+fn default_cache_ttl() -> u64 {
+    60
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+impl LdapConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let Some(url) = &self.url else {
+            return Ok(());
+        };
+        server(url)?;
+        if !self.bind_dn.contains("{user}") {
+            return Err("ldap.bind_dn must contain {user}".to_string());
+        }
+        if self.timeout == 0 {
+            return Err("ldap.timeout must be positive".to_string());
+        }
+        if let Some(group_filter) = &self.group_filter {
+            if self.base_dn.is_none() {
+                return Err("ldap.group_filter needs ldap.base_dn".to_string());
+            }
+            filter(&self.search_filter(group_filter, "user")).map_err(|e| format!("ldap filters: {}", e))?;
+        }
+        Ok(())
+    }
+
+    fn search_filter(&self, group_filter: &str, user: &str) -> String {
+        let user_filter = self.user_filter.replace("{user}", &escape_filter(user));
+        format!("(&{}{})", user_filter, group_filter)
+    }
+}
+
+// (tls, host, port)
+fn server(url: &str) -> Result<(bool, String, u16), String> {
+    let (tls, rest) = match url.split_once("://") {
+        Some(("ldap", rest)) => (false, rest),
+        Some(("ldaps", rest)) => (true, rest),
+        _ => return Err(format!("ldap.url '{}' must start with ldap:// or ldaps://", url)),
+    };
+    let authority = rest.trim_end_matches('/');
+    let (host, port) = match authority.strip_prefix('[') {
+        // [IPv6]:port
+        Some(v6) => match v6.split_once(']') {
+            Some((host, after)) => (host, after.strip_prefix(':')),
+            None => return Err(format!("ldap.url '{}' has an unclosed '['", url)),
+        },
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| format!("ldap.url '{}' has an invalid port", url))?,
+        None if tls => 636,
+        None => 389,
+    };
+    if host.is_empty() || host.contains('/') {
+        return Err(format!("ldap.url '{}' has no host", url));
+    }
+    Ok((tls, host.to_string(), port))
+}
+
+// Binds that recently succeeded: user -> (password digest, expiry). Only
+// successes are kept, so a fixed password works at once.
+#[derive(Default)]
+pub struct Ldap {
+    verified: Mutex<HashMap<String, (Vec<u8>, Instant)>>,
+}
+
+impl Ldap {
+    pub async fn authenticate(&self, config: &LdapConfig, user: &str, password: &str) -> bool {
+        // An empty password would be an anonymous bind, which servers accept
+        if password.is_empty() {
+            return false;
+        }
+        let Ok(given) = digest(MessageDigest::sha256(), password.as_bytes()) else {
+            return false;
+        };
+        if let Some((known, expires)) = self.verified.lock().unwrap().get(user) {
+            if *expires > Instant::now() && openssl::memcmp::eq(known, &given) {
+                return true;
+            }
+        }
+
+        let ttl = Duration::from_secs(config.cache_ttl);
+        let (config, owned_user, owned_password) = (config.clone(), user.to_string(), password.to_string());
+        let result = tokio::task::spawn_blocking(move || check(&config, &owned_user, &owned_password))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)));
+        match result {
+            Ok(true) => {
+                let expires = Instant::now() + ttl;
+                let mut verified = self.verified.lock().unwrap();
+                if verified.len() >= 10_000 {
+                    let now = Instant::now();
+                    verified.retain(|_, (_, expires)| *expires > now);
+                }
+                verified.insert(user.to_string(), (given.to_vec(), expires));
+                true
+            }
+            Ok(false) => false,
+            Err(e) => {
+                warn!("⚠️ LDAP check for '{}' failed: {}", user, e);
+                false
+            }
+        }
+    }
+}
+
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+// Ok(false) when the directory rejects the credentials or the user does
+// not match group_filter; Err when the directory could not be asked.
+fn check(config: &LdapConfig, user: &str, password: &str) -> io::Result<bool> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let (tls, host, port) = server(config.url.as_deref().unwrap_or_default()).map_err(invalid)?;
+    let timeout = Duration::from_secs(config.timeout);
+    let addr = (host.as_str(), port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| invalid(format!("{} did not resolve", host)))?;
+    let tcp = TcpStream::connect_timeout(&addr, timeout)?;
+    tcp.set_read_timeout(Some(timeout))?;
+    tcp.set_write_timeout(Some(timeout))?;
+    let mut stream: Box<dyn Stream> = if tls {
+        let connector = native_tls::TlsConnector::new().map_err(io::Error::other)?;
+        Box::new(connector.connect(&host, tcp).map_err(io::Error::other)?)
+    } else {
+        Box::new(tcp)
+    };
+
+    // BindRequest: version 3, name, simple [0] password
+    let dn = config.bind_dn.replace("{user}", &escape_dn(user));
+    let bind = [integer(0x02, 3), tlv(0x04, dn.as_bytes()), tlv(0x80, password.as_bytes())].concat();
+    stream.write_all(&message(1, tlv(0x60, &bind)))?;
+    let (tag, op) = read_message(&mut stream)?;
+    if tag != 0x61 {
+        return Err(invalid(format!("unexpected bind reply 0x{:02x}", tag)));
+    }
+    match result_code(&op) {
+        Some(0) => {}
+        // invalidCredentials
+        Some(49) => return Ok(false),
+        Some(code) => return Err(invalid(format!("bind failed with result code {}", code))),
+        None => return Err(invalid("malformed bind reply".to_string())),
+    }
+
+    let mut allowed = true;
+    if let (Some(group_filter), Some(base_dn)) = (&config.group_filter, &config.base_dn) {
+        let filter = filter(&config.search_filter(group_filter, user)).map_err(invalid)?;
+        // SearchRequest: base, subtree, never deref, 1 entry, time limit,
+        // no attribute values, filter, attributes "1.1" (none)
+        let search = [
+            tlv(0x04, base_dn.as_bytes()),
+            integer(0x0a, 2),
+            integer(0x0a, 0),
+            integer(0x02, 1),
+            integer(0x02, config.timeout as u32),
+            tlv(0x01, &[0]),
+            filter,
+            tlv(0x30, &tlv(0x04, b"1.1")),
+        ]
+        .concat();
+        stream.write_all(&message(2, tlv(0x63, &search)))?;
+        let mut entries = 0;
+        loop {
+            match read_message(&mut stream)? {
+                // SearchResultEntry
+                (0x64, _) => entries += 1,
+                // SearchResultDone; sizeLimitExceeded (4) still means a match
+                (0x65, op) => match result_code(&op) {
+                    Some(0 | 4) => break,
+                    Some(code) => return Err(invalid(format!("search failed with result code {}", code))),
+                    None => return Err(invalid("malformed search reply".to_string())),
+                },
+                // References to other servers are not followed
+                _ => {}
+            }
+        }
+        allowed = entries > 0;
+        if !allowed {
+            debug!("LDAP user '{}' does not match ldap.group_filter", user);
+        }
+    }
+
+    // UnbindRequest; the server closes the connection
+    let _ = stream.write_all(&message(3, vec![0x42, 0x00]));
+    Ok(allowed)
+}
+
+fn message(id: u32, op: Vec<u8>) -> Vec<u8> {
+    tlv(0x30, &[integer(0x02, id), op].concat())
+}
+
+// Tag and contents of the protocolOp in the next LDAPMessage.
+fn read_message(stream: &mut dyn Stream) -> io::Result<(u8, Vec<u8>)> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed LDAP message");
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head)?;
+    if head[0] != 0x30 {
+        return Err(malformed());
+    }
+    let len = match head[1] {
+        n if n < 0x80 => n as usize,
+        n if (0x81..=0x84).contains(&n) => {
+            let mut bytes = vec![0u8; (n & 0x7f) as usize];
+            stream.read_exact(&mut bytes)?;
+            bytes.iter().fold(0usize, |len, b| len << 8 | *b as usize)
+        }
+        _ => return Err(malformed()),
+    };
+    if len > 1024 * 1024 {
+        return Err(malformed());
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body)?;
+    let (_, _, rest) = parse_tlv(&body).ok_or_else(malformed)?;
+    let (tag, op, _) = parse_tlv(rest).ok_or_else(malformed)?;
+    Ok((tag, op.to_vec()))
+}
+
+// (tag, contents, rest)
+fn parse_tlv(buf: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, buf) = buf.split_first()?;
+    let (&first, mut buf) = buf.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || buf.len() < n {
+            return None;
+        }
+        let len = buf[..n].iter().fold(0usize, |len, b| len << 8 | *b as usize);
+        buf = &buf[n..];
+        len
+    };
+    (buf.len() >= len).then(|| (tag, &buf[..len], &buf[len..]))
+}
+
+// resultCode, the first field of every LDAPResult
+fn result_code(op: &[u8]) -> Option<u32> {
+    let (tag, code, _) = parse_tlv(op)?;
+    (tag == 0x0a && !code.is_empty() && code.len() <= 4).then(|| code.iter().fold(0, |n, b| n << 8 | *b as u32))
+}
+
+fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().into_iter().skip_while(|b| *b == 0).collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+// Non-negative INTEGER or ENUMERATED in the fewest bytes.
+fn integer(tag: u8, n: u32) -> Vec<u8> {
+    let mut bytes: Vec<u8> = n.to_be_bytes().into_iter().skip_while(|b| *b == 0).collect();
+    if bytes.first().is_none_or(|b| b & 0x80 != 0) {
+        bytes.insert(0, 0);
+    }
+    tlv(tag, &bytes)
+}
+
+// RFC 4515 string filter to BER. Supports &, |, !, =, >=, <=, ~=,
+// presence (attr=*) and substrings (attr=a*b*c); not extensible matches.
+fn filter(s: &str) -> Result<Vec<u8>, String> {
+    let (encoded, rest) = parse_filter(s.trim())?;
+    if !rest.is_empty() {
+        return Err(format!("unexpected '{}' after filter", rest));
+    }
+    Ok(encoded)
+}
+
+fn parse_filter(s: &str) -> Result<(Vec<u8>, &str), String> {
+    let inner = s.strip_prefix('(').ok_or_else(|| format!("expected '(' at '{}'", s))?;
+    let (encoded, rest) = match inner.chars().next() {
+        Some(op @ ('&' | '|')) => {
+            let mut rest = &inner[1..];
+            let mut items = Vec::new();
+            while rest.starts_with('(') {
+                let (item, after) = parse_filter(rest)?;
+                items.extend(item);
+                rest = after;
+            }
+            if items.is_empty() {
+                return Err(format!("empty '{}' filter", op));
+            }
+            (tlv(if op == '&' { 0xa0 } else { 0xa1 }, &items), rest)
+        }
+        Some('!') => {
+            let (item, rest) = parse_filter(&inner[1..])?;
+            (tlv(0xa2, &item), rest)
+        }
+        _ => {
+            let end = inner.find(')').ok_or("missing ')'")?;
+            (item(&inner[..end])?, &inner[end..])
+        }
+    };
+    let rest = rest.strip_prefix(')').ok_or_else(|| format!("expected ')' at '{}'", rest))?;
+    Ok((encoded, rest))
+}
+
+fn item(s: &str) -> Result<Vec<u8>, String> {
+    let (attr, value) = s.split_once('=').ok_or_else(|| format!("'{}' has no '='", s))?;
+    let (attr, tag) = match attr.as_bytes().last() {
+        Some(b'>') => (&attr[..attr.len() - 1], 0xa5),
+        Some(b'<') => (&attr[..attr.len() - 1], 0xa6),
+        Some(b'~') => (&attr[..attr.len() - 1], 0xa8),
+        _ => (attr, 0xa3),
+    };
+    if attr.is_empty() || !attr.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == ';') {
+        return Err(format!("invalid attribute in '{}'", s));
+    }
+    let attr = tlv(0x04, attr.as_bytes());
+    if tag == 0xa3 && value == "*" {
+        return Ok(tlv(0x87, &attr[2..]));
+    }
+    if tag == 0xa3 && value.contains('*') {
+        let parts: Vec<&str> = value.split('*').collect();
+        let mut substrings = Vec::new();
+        for (i, part) in parts.iter().enumerate() {
+            if part.is_empty() {
+                continue;
+            }
+            let position = match i {
+                0 => 0x80,
+                _ if i == parts.len() - 1 => 0x82,
+                _ => 0x81,
+            };
+            substrings.extend(tlv(position, &unescape(part)?));
+        }
+        return Ok(tlv(0xa4, &[attr, tlv(0x30, &substrings)].concat()));
+    }
+    Ok(tlv(tag, &[attr, tlv(0x04, &unescape(value)?)].concat()))
+}
+
+// \XX hex escapes in filter values
+fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        let hex = [bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)];
+        let byte = std::str::from_utf8(&hex).ok().and_then(|h| u8::from_str_radix(h, 16).ok());
+        out.push(byte.ok_or_else(|| format!("bad escape in '{}'", s))?);
+    }
+    Ok(out)
+}
+
+// Usernames are untrusted: escape them before putting them in a filter
+// (RFC 4515) or DN (RFC 4514).
+fn escape_filter(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '*' | '(' | ')' | '\\' | '\0' => format!("\\{:02x}", c as u32),
+            _ => c.to_string(),
+        })
+        .collect()
+}
+
+fn escape_dn(s: &str) -> String {
+    let last = s.chars().count().saturating_sub(1);
+    s.chars()
+        .enumerate()
+        .map(|(i, c)| match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => format!("\\{}", c),
+            '\0' => "\\00".to_string(),
+            '#' | ' ' if i == 0 => format!("\\{}", c),
+            ' ' if i == last => "\\ ".to_string(),
+            _ => c.to_string(),
+        })
+        .collect()
+}
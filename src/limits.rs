@@ -0,0 +1,245 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::{info, warn};
+
+#[derive(Debug, Deserialize)]
+pub struct LimitsConfig {
+    // Concurrent CONNECT tunnels a single user may hold open
+    pub max_connections_per_user: Option<usize>,
+    // Concurrent client connections across all users; excess get 503
+    pub max_client_connections: Option<usize>,
+    // Concurrent CONNECT tunnels across all users; excess get 503
+    pub max_tunnels: Option<usize>,
+    // Seconds a client has to finish a request head once it starts sending
+    // it; 0 = no limit
+    #[serde(default = "default_header_timeout")]
+    pub header_timeout: u64,
+    // Seconds a client connection may sit between requests; 0 = no limit
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    // Largest request head in bytes; larger ones get 431
+    pub max_header_size: Option<usize>,
+    // Most header fields per request; more get 431
+    pub max_headers: Option<usize>,
+    // Largest plain HTTP request body in bytes; larger ones get 413
+    pub max_request_body: Option<u64>,
+    // Largest plain HTTP response body in bytes; larger ones get 502, or
+    // are cut off if the origin did not announce their length
+    pub max_response_body: Option<u64>,
+    // Bytes a CONNECT tunnel may carry, both ways together, before it is
+    // closed
+    pub max_tunnel_bytes: Option<u64>,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_connections_per_user: None,
+            max_client_connections: None,
+            max_tunnels: None,
+            header_timeout: default_header_timeout(),
+            idle_timeout: default_idle_timeout(),
+            max_header_size: None,
+            max_headers: None,
+            max_request_body: None,
+            max_response_body: None,
+            max_tunnel_bytes: None,
+        }
+    }
+}
+
+fn default_header_timeout() -> u64 {
+    30
+}
+
+fn default_idle_timeout() -> u64 {
+    120
+}
+
+// hyper's own bounds: a smaller read buffer than this cannot work, and it
+// refuses more header fields than this whatever the setting
+const MIN_HEADER_SIZE: usize = 8192;
+const MAX_HEADERS: usize = 100;
+
+impl LimitsConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_header_size.is_some_and(|size| size < MIN_HEADER_SIZE) {
+            return Err(format!("limits.max_header_size must be at least {}", MIN_HEADER_SIZE));
+        }
+        if self.max_headers.is_some_and(|max| max == 0 || max > MAX_HEADERS) {
+            return Err(format!("limits.max_headers must be between 1 and {}", MAX_HEADERS));
+        }
+        Ok(())
+    }
+}
+
+// A counting semaphore that never waits: acquisition either succeeds
+// immediately or fails because the cap is reached.
+pub struct Slots {
+    active: Arc<AtomicUsize>,
+    max: Option<usize>,
+}
+
+impl Slots {
+    pub fn new(max: Option<usize>) -> Self {
+        Slots {
+            active: Arc::default(),
+            max,
+        }
+    }
+
+    pub fn try_acquire(&self) -> Option<SlotGuard> {
+        try_increment(&self.active, self.max)
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+}
+
+fn try_increment(counter: &Arc<AtomicUsize>, max: Option<usize>) -> Option<SlotGuard> {
+    counter
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| match max {
+            Some(max) if n >= max => None,
+            _ => Some(n + 1),
+        })
+        .ok()
+        .map(|_| SlotGuard {
+            counter: counter.clone(),
+        })
+}
+
+// Who holds a tunnel open, for the drain report and the reaper.
+#[derive(Clone)]
+pub struct OpenTunnel {
+    pub user: String,
+    pub listener: String,
+    pub client: SocketAddr,
+    pub target: String,
+    pub since: Instant,
+    // Bytes and last activity, and the reaper's way to close it
+    pub activity: Arc<crate::reaper::Activity>,
+}
+
+type OpenTunnels = Arc<Mutex<BTreeMap<u64, OpenTunnel>>>;
+
+// Live tunnel counts keyed by username and by the listener they came in on,
+// and the tunnels themselves.
+#[derive(Default)]
+pub struct ConnectionRegistry {
+    per_user: Mutex<HashMap<String, Arc<AtomicUsize>>>,
+    per_listener: Mutex<HashMap<String, Arc<AtomicUsize>>>,
+    open: OpenTunnels,
+    next_id: AtomicU64,
+}
+
+impl ConnectionRegistry {
+    // Reserve a tunnel slot for `tunnel.user`. The slot is released when the
+    // returned guard is dropped, i.e. when the tunnel closes.
+    pub fn acquire(&self, tunnel: OpenTunnel, limit: Option<usize>) -> Option<TunnelGuard> {
+        let counter = |map: &Mutex<HashMap<String, Arc<AtomicUsize>>>, key: &str| {
+            map.lock().unwrap().entry(key.to_string()).or_default().clone()
+        };
+        let user = try_increment(&counter(&self.per_user, &tunnel.user), limit)?;
+        let listener = try_increment(&counter(&self.per_listener, &tunnel.listener), None)?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.open.lock().unwrap().insert(id, tunnel);
+        Some(TunnelGuard {
+            _user: user,
+            _listener: listener,
+            _entry: Entry {
+                open: self.open.clone(),
+                id,
+            },
+        })
+    }
+
+    // Open tunnels, oldest first.
+    pub fn open(&self) -> Vec<OpenTunnel> {
+        self.open.lock().unwrap().values().cloned().collect()
+    }
+
+    // Open tunnels per listener name, sorted by name.
+    pub fn by_listener(&self) -> Vec<(String, usize)> {
+        let mut counts: Vec<_> = self
+            .per_listener
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, c)| (name.clone(), c.load(Ordering::Acquire)))
+            .collect();
+        counts.sort();
+        counts
+    }
+
+    pub fn active(&self, user: &str) -> usize {
+        self.per_user
+            .lock()
+            .unwrap()
+            .get(user)
+            .map(|c| c.load(Ordering::Acquire))
+            .unwrap_or(0)
+    }
+}
+
+// Releases its slot when dropped.
+pub struct SlotGuard {
+    counter: Arc<AtomicUsize>,
+}
+
+// A tunnel's user and listener slots and its entry among the open ones.
+pub struct TunnelGuard {
+    _user: SlotGuard,
+    _listener: SlotGuard,
+    _entry: Entry,
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+struct Entry {
+    open: OpenTunnels,
+    id: u64,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        self.open.lock().unwrap().remove(&self.id);
+    }
+}
+
+// Each client connection holds one descriptor and each tunnel one more for
+// the upstream socket; warn when the caps could exhaust RLIMIT_NOFILE.
+pub fn check_fd_limit(config: &LimitsConfig) {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit only writes into the struct we pass
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } != 0 {
+        warn!("⚠️ Could not read RLIMIT_NOFILE");
+        return;
+    }
+    info!(
+        "📂 File descriptor limit: {} (hard {})",
+        rlim.rlim_cur, rlim.rlim_max
+    );
+
+    let needed = match (config.max_client_connections, config.max_tunnels) {
+        (None, None) => return,
+        (clients, tunnels) => clients.unwrap_or(0) + tunnels.or(clients).unwrap_or(0),
+    };
+    if needed as libc::rlim_t > rlim.rlim_cur {
+        warn!(
+            "⚠️ Connection caps may need {} descriptors but RLIMIT_NOFILE is {}; raise it with `ulimit -n` or lower the caps",
+            needed, rlim.rlim_cur
+        );
+    }
+}
@@ -0,0 +1,449 @@
+use hyper::server::accept::Accept;
+use hyper::server::conn::AddrStream;
+use hyper::upgrade::Upgraded;
+use openssl::ssl::{ErrorCode, Ssl, SslAcceptor, SslFiletype, SslMethod, SslStream};
+use openssl::x509::X509;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tracing::debug;
+
+use crate::proxyproto::Trusted;
+use crate::transparent;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Pause after a failed accept, such as EMFILE when out of file descriptors,
+// so the accept loop does not spin until one is freed
+pub const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ListenerConfig {
+    // Tag for logs, metrics and billing, e.g. "public-tls"; defaults to the address
+    pub name: Option<String>,
+    // "host:port" to bind
+    pub address: String,
+    // Require Proxy-Authorization on this listener
+    #[serde(default = "default_true")]
+    pub auth: bool,
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    // PEM certificate chain and key; both set makes this a TLS listener
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    // "redirect" or "tproxy": take connections the firewall diverts here
+    // rather than ones addressed to the proxy; "sni": take TLS connections
+    // and relay them by server name. There is no proxy authentication on
+    // such a listener.
+    pub transparent: Option<transparent::Mode>,
+    // Users of a transparent listener by client network, e.g.
+    // { "10.0.1.0/24" = "kids" }; other clients are refused
+    #[serde(default)]
+    pub clients: BTreeMap<String, String>,
+    // Connections come from a load balancer that sends a PROXY protocol
+    // header (v1 or v2) naming the real client; without one they are closed
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    // Networks of the balancers allowed to send that header, e.g.
+    // ["10.0.0.0/8"]; connections from anywhere else are closed
+    #[serde(default)]
+    pub proxy_protocol_from: Vec<String>,
+    // Serve the [reverse] sites: requests are for the proxy's own host
+    // names and go to their backends, without proxy authentication
+    #[serde(default)]
+    pub reverse: bool,
+    // Also serve HTTP/3 over QUIC on the same address (UDP), with the TLS
+    // listener's certificate; needs a build with `--features http3`
+    #[serde(default)]
+    pub http3: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+pub fn default_realm() -> String {
+    "Secure Proxy".to_string()
+}
+
+impl ListenerConfig {
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.address.clone())
+    }
+
+    pub fn socket_addr(&self) -> Result<SocketAddr, String> {
+        self.address
+            .parse()
+            .map_err(|e| format!("Failed to parse listener address '{}': {}", self.address, e))
+    }
+}
+
+pub fn validate(listeners: &[ListenerConfig]) -> Result<(), String> {
+    let mut seen = Vec::new();
+    let mut names = Vec::new();
+    for listener in listeners {
+        let addr = listener.socket_addr()?;
+        if seen.contains(&addr) {
+            return Err(format!("listener {} is defined twice", addr));
+        }
+        seen.push(addr);
+        if let Some(name) = &listener.name {
+            let valid = name.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b));
+            if name.is_empty() || !valid {
+                return Err(format!("listener name '{}' may only use letters, digits, '-', '_' and '.'", name));
+            }
+        }
+        let name = listener.name();
+        if names.contains(&name) {
+            return Err(format!("listener name '{}' is used twice", name));
+        }
+        names.push(name);
+        if listener.tls_cert.is_some() != listener.tls_key.is_some() {
+            return Err(format!("listener {} needs both tls_cert and tls_key", addr));
+        }
+        if listener.transparent.is_some() && listener.tls_cert.is_some() {
+            return Err(format!("listener {}: a transparent listener cannot terminate TLS", addr));
+        }
+        if listener.transparent.is_some() && listener.proxy_protocol {
+            return Err(format!("listener {}: a transparent listener cannot take PROXY protocol", addr));
+        }
+        if listener.transparent.is_some() && listener.reverse {
+            return Err(format!("listener {}: a transparent listener cannot be a reverse proxy", addr));
+        }
+        if listener.http3 && listener.tls_cert.is_none() {
+            return Err(format!("listener {}: http3 needs tls_cert and tls_key", addr));
+        }
+        if listener.proxy_protocol && listener.proxy_protocol_from.is_empty() {
+            return Err(format!("listener {}: proxy_protocol needs proxy_protocol_from, the balancers' networks", addr));
+        }
+        if !listener.proxy_protocol && !listener.proxy_protocol_from.is_empty() {
+            return Err(format!("listener {}: proxy_protocol_from only applies with proxy_protocol = true", addr));
+        }
+        if let Some(network) = listener.proxy_protocol_from.iter().find(|n| crate::egress::parse_network(n).is_none()) {
+            return Err(format!("listener {}: invalid proxy_protocol_from network '{}'", addr, network));
+        }
+        if listener.http3 && listener.proxy_protocol {
+            return Err(format!("listener {}: PROXY protocol headers cannot come over HTTP/3", addr));
+        }
+        if listener.http3 && cfg!(not(feature = "http3")) {
+            return Err(format!("listener {}: http3 needs a build with the http3 feature", addr));
+        }
+        if listener.transparent.is_none() && !listener.clients.is_empty() {
+            return Err(format!("listener {}: clients only apply to transparent listeners", addr));
+        }
+        if let Some(network) = listener.clients.keys().find(|n| crate::egress::parse_network(n).is_none()) {
+            return Err(format!("listener {}: invalid client network '{}'", addr, network));
+        }
+    }
+    Ok(())
+}
+
+// A bound listener, as seen by request handling.
+pub struct Listener {
+    pub name: String,
+    pub addr: SocketAddr,
+    pub auth: bool,
+    pub realm: String,
+    pub tls: bool,
+    // The certificate served, for expiry monitoring
+    pub certificate: Option<X509>,
+    // Handed over by systemd; kept across reloads as it cannot be re-bound
+    pub inherited: bool,
+    pub transparent: Option<Arc<transparent::Transparent>>,
+    // Where PROXY protocol headers are accepted from, if they are expected
+    pub proxy_protocol: Option<Trusted>,
+    pub reverse: bool,
+    pub http3: bool,
+}
+
+// Listening socket for `addr`. With `reuse_port` several processes can bind
+// the same address and the kernel spreads new connections between them,
+// which lets a new version start before the old one stops.
+pub fn bind(addr: SocketAddr, reuse_port: bool, mode: Option<transparent::Mode>) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    socket.set_reuseaddr(true)?;
+    if reuse_port {
+        socket.set_reuseport(true)?;
+    }
+    if mode == Some(transparent::Mode::Tproxy) {
+        transparent::set_ip_transparent(&socket, addr.is_ipv6())?;
+    }
+    crate::tcp::config().client.size_buffers(&socket)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+pub fn tls_acceptor(cert: &PathBuf, key: &PathBuf) -> io::Result<SslAcceptor> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()).map_err(io::Error::other)?;
+    builder
+        .set_certificate_chain_file(cert)
+        .and_then(|_| builder.set_private_key_file(key, SslFiletype::PEM))
+        .and_then(|_| builder.check_private_key())
+        .map_err(|e| io::Error::other(format!("{} / {}: {}", cert.display(), key.display(), e)))?;
+    Ok(builder.build())
+}
+
+// Connections hand their peer address to the service factory.
+pub trait RemoteAddr {
+    fn remote_addr(&self) -> SocketAddr;
+
+    // Where a transparently redirected connection was going
+    fn original_dst(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+// The client side of a CONNECT tunnel on connections hyper does not
+// upgrade itself (HTTP/3), handed over once the 2xx has gone out.
+#[cfg_attr(not(feature = "http3"), allow(dead_code))]
+pub struct PendingTunnel(pub oneshot::Receiver<DuplexStream>);
+
+pub enum TunnelClient {
+    Upgraded(Upgraded),
+    #[cfg_attr(not(feature = "http3"), allow(dead_code))]
+    Stream(DuplexStream),
+}
+
+impl AsyncRead for TunnelClient {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TunnelClient::Upgraded(client) => Pin::new(client).poll_read(cx, buf),
+            TunnelClient::Stream(client) => Pin::new(client).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TunnelClient {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            TunnelClient::Upgraded(client) => Pin::new(client).poll_write(cx, buf),
+            TunnelClient::Stream(client) => Pin::new(client).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TunnelClient::Upgraded(client) => Pin::new(client).poll_flush(cx),
+            TunnelClient::Stream(client) => Pin::new(client).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TunnelClient::Upgraded(client) => Pin::new(client).poll_shutdown(cx),
+            TunnelClient::Stream(client) => Pin::new(client).poll_shutdown(cx),
+        }
+    }
+}
+
+impl RemoteAddr for AddrStream {
+    fn remote_addr(&self) -> SocketAddr {
+        AddrStream::remote_addr(self)
+    }
+}
+
+// Accepts TCP connections and yields them once the TLS handshake is done.
+// Handshakes run on their own tasks so a slow client cannot hold up others.
+pub struct TlsIncoming {
+    streams: mpsc::Receiver<TlsStream>,
+}
+
+impl TlsIncoming {
+    // With `proxy_protocol` each connection's PROXY header is read before
+    // the handshake.
+    pub fn new(listener: TcpListener, acceptor: SslAcceptor, proxy_protocol: Option<Trusted>) -> Self {
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                let (mut tcp, peer) = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            debug!("TLS listener accept failed: {}", e);
+                            tokio::time::sleep(ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                    },
+                    // The server is gone; drop the socket
+                    _ = tx.closed() => return,
+                };
+                crate::tcp::config().client.apply(&tcp);
+                let ssl = Ssl::new(acceptor.context());
+                let tx = tx.clone();
+                let proxy_protocol = proxy_protocol.clone();
+                tokio::spawn(async move {
+                    let remote = match &proxy_protocol {
+                        Some(trusted) => match crate::proxyproto::read_header(&mut tcp, peer, trusted).await {
+                            Ok(remote) => remote,
+                            Err(e) => {
+                                debug!("Closing connection from {}: {}", peer, e);
+                                return;
+                            }
+                        },
+                        None => peer,
+                    };
+                    let handshake = async { TlsStream::accept(ssl?, tcp, remote).await };
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send(stream).await;
+                        }
+                        Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", remote, e),
+                        Err(_) => debug!("TLS handshake with {} timed out", remote),
+                    }
+                });
+            }
+        });
+        TlsIncoming { streams: rx }
+    }
+}
+
+impl Accept for TlsIncoming {
+    type Conn = TlsStream;
+    type Error = io::Error;
+
+    fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<TlsStream>>> {
+        self.streams.poll_recv(cx).map(|stream| stream.map(Ok))
+    }
+}
+
+// Blocking-style Read/Write over an async stream, for openssl. The task
+// context is set for the duration of each call; "not ready" surfaces as
+// WouldBlock, which SslStream passes back up.
+struct Bridge {
+    stream: TcpStream,
+    context: *mut (),
+}
+
+// The context pointer is only set while a poll is on the stack.
+unsafe impl Send for Bridge {}
+unsafe impl Sync for Bridge {}
+
+impl Bridge {
+    fn poll<T>(&mut self, f: impl FnOnce(Pin<&mut TcpStream>, &mut Context<'_>) -> Poll<io::Result<T>>) -> io::Result<T> {
+        assert!(!self.context.is_null());
+        let cx = unsafe { &mut *(self.context as *mut Context<'_>) };
+        match f(Pin::new(&mut self.stream), cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl Read for Bridge {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.poll(|stream, cx| {
+            let mut buf = ReadBuf::new(buf);
+            stream.poll_read(cx, &mut buf).map_ok(|()| buf.filled().len())
+        })
+    }
+}
+
+impl Write for Bridge {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.poll(|stream, cx| stream.poll_write(cx, buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.poll(|stream, cx| stream.poll_flush(cx))
+    }
+}
+
+pub struct TlsStream {
+    ssl: SslStream<Bridge>,
+    remote: SocketAddr,
+}
+
+impl TlsStream {
+    async fn accept(ssl: Ssl, stream: TcpStream, remote: SocketAddr) -> io::Result<Self> {
+        Self::handshake(ssl, stream, remote, |ssl| ssl.accept()).await
+    }
+
+    // The client side, for TLS to reverse proxy backends
+    pub async fn connect(ssl: Ssl, stream: TcpStream, remote: SocketAddr) -> io::Result<Self> {
+        Self::handshake(ssl, stream, remote, |ssl| ssl.connect()).await
+    }
+
+    async fn handshake(
+        ssl: Ssl,
+        stream: TcpStream,
+        remote: SocketAddr,
+        mut step: impl FnMut(&mut SslStream<Bridge>) -> Result<(), openssl::ssl::Error>,
+    ) -> io::Result<Self> {
+        let bridge = Bridge {
+            stream,
+            context: std::ptr::null_mut(),
+        };
+        let mut tls = TlsStream {
+            ssl: SslStream::new(ssl, bridge).map_err(io::Error::other)?,
+            remote,
+        };
+        std::future::poll_fn(|cx| {
+            tls.with_context(cx, |ssl| match step(ssl) {
+                Ok(()) => Poll::Ready(Ok(())),
+                Err(e) if matches!(e.code(), ErrorCode::WANT_READ | ErrorCode::WANT_WRITE) => Poll::Pending,
+                Err(e) => Poll::Ready(Err(io::Error::other(e))),
+            })
+        })
+        .await?;
+        Ok(tls)
+    }
+
+    fn with_context<R>(&mut self, cx: &mut Context<'_>, f: impl FnOnce(&mut SslStream<Bridge>) -> R) -> R {
+        self.ssl.get_mut().context = cx as *mut Context<'_> as *mut ();
+        let result = f(&mut self.ssl);
+        self.ssl.get_mut().context = std::ptr::null_mut();
+        result
+    }
+}
+
+impl RemoteAddr for TlsStream {
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote
+    }
+}
+
+fn would_block<T>(result: io::Result<T>) -> Poll<io::Result<T>> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+        result => Poll::Ready(result),
+    }
+}
+
+impl AsyncRead for TlsStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        self.with_context(cx, |ssl| {
+            would_block(ssl.read(buf.initialize_unfilled())).map_ok(|n| buf.advance(n))
+        })
+    }
+}
+
+impl AsyncWrite for TlsStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.with_context(cx, |ssl| would_block(ssl.write(buf)))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.with_context(cx, |ssl| would_block(ssl.flush()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let closed = self.with_context(cx, |ssl| match ssl.shutdown() {
+            Ok(_) => Poll::Ready(Ok(())),
+            Err(e) if matches!(e.code(), ErrorCode::WANT_READ | ErrorCode::WANT_WRITE) => Poll::Pending,
+            // Peer already gone; nothing left to say
+            Err(_) => Poll::Ready(Ok(())),
+        });
+        match closed {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.ssl.get_mut().stream).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
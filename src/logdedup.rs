@@ -0,0 +1,138 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+
+// Our own summaries, which must never be suppressed themselves
+const TARGET: &str = "log_dedup";
+// Distinct messages tracked per window; further ones are always logged
+const MAX_TRACKED: usize = 10_000;
+const MAX_SAMPLE: usize = 300;
+
+// Identical warnings and errors (same call site, same fields) beyond
+// `limit` per window are counted instead of logged, so one client
+// hammering an unreachable target cannot flood the log. Request-scoped
+// span fields such as request_id do not make messages differ.
+struct Repeats {
+    limit: u32,
+    window: Duration,
+    seen: Mutex<HashMap<u64, Seen>>,
+}
+
+struct Seen {
+    count: u32,
+    suppressed: u64,
+    sample: String,
+}
+
+static REPEATS: OnceLock<Repeats> = OnceLock::new();
+static SUPPRESSED: AtomicU64 = AtomicU64::new(0);
+
+// Turns deduplication on; a `limit` of 0 leaves it off.
+pub fn configure(limit: u32, window: Duration) {
+    if limit > 0 {
+        let _ = REPEATS.set(Repeats {
+            limit,
+            window,
+            seen: Mutex::default(),
+        });
+    }
+}
+
+// Messages suppressed since startup, for /metrics.
+pub fn suppressed() -> u64 {
+    SUPPRESSED.load(Ordering::Relaxed)
+}
+
+pub struct Layer;
+
+impl<S: Subscriber> tracing_subscriber::Layer<S> for Layer {
+    fn event_enabled(&self, event: &Event<'_>, _ctx: Context<'_, S>) -> bool {
+        let meta = event.metadata();
+        if *meta.level() > Level::WARN || meta.target() == TARGET {
+            return true;
+        }
+        let Some(repeats) = REPEATS.get() else {
+            return true;
+        };
+        let mut text = String::new();
+        event.record(&mut Render(&mut text));
+        let mut hasher = DefaultHasher::new();
+        meta.callsite().hash(&mut hasher);
+        text.hash(&mut hasher);
+        let key = hasher.finish();
+
+        let mut seen = repeats.seen.lock().unwrap();
+        if seen.len() >= MAX_TRACKED && !seen.contains_key(&key) {
+            return true;
+        }
+        let entry = seen.entry(key).or_insert_with(|| Seen {
+            count: 0,
+            suppressed: 0,
+            sample: truncate(text),
+        });
+        entry.count += 1;
+        if entry.count <= repeats.limit {
+            return true;
+        }
+        entry.suppressed += 1;
+        SUPPRESSED.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+}
+
+fn truncate(mut text: String) -> String {
+    if text.len() > MAX_SAMPLE {
+        let mut end = MAX_SAMPLE;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        text.push('…');
+    }
+    text
+}
+
+// The message, then any other fields as name=value.
+struct Render<'a>(&'a mut String);
+
+impl Visit for Render<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, "{:?}", value);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+// At the end of every window, logs one line per message that was
+// suppressed in it, then starts counting afresh.
+pub fn spawn_report() {
+    let Some(repeats) = REPEATS.get() else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(repeats.window);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let seen = std::mem::take(&mut *repeats.seen.lock().unwrap());
+            for entry in seen.values().filter(|e| e.suppressed > 0) {
+                tracing::warn!(
+                    target: TARGET,
+                    suppressed = entry.suppressed,
+                    "🔇 Suppressed {} more of: {}",
+                    entry.suppressed,
+                    entry.sample
+                );
+            }
+        }
+    });
+}
@@ -0,0 +1,271 @@
+use serde::Deserialize;
+use std::fmt::{self, Write as _};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::logdedup;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+    // Multi-line, for reading by eye during development
+    Pretty,
+}
+
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+
+// Random 128-bit correlation ID, as 32 lowercase hex digits.
+pub fn request_id() -> String {
+    let mut bytes = [0u8; 16];
+    if openssl::rand::rand_bytes(&mut bytes).is_err() {
+        // Unique enough for log correlation if the RNG is unavailable
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        bytes = nanos.to_be_bytes();
+    }
+    bytes.iter().fold(String::with_capacity(32), |mut out, b| {
+        let _ = write!(out, "{:02x}", b);
+        out
+    })
+}
+
+// Install the global subscriber. `directives` uses RUST_LOG-style syntax,
+// e.g. "info,hyper=warn,secure_proxy=debug"; RUST_LOG takes precedence
+// over the configured value when set.
+pub fn init(format: LogFormat, directives: Option<&str>) {
+    let (source, directives) = match std::env::var("RUST_LOG") {
+        Ok(env) if !env.trim().is_empty() => ("RUST_LOG", env),
+        _ => (
+            "config",
+            directives.unwrap_or(DEFAULT_LOG_LEVEL).to_string(),
+        ),
+    };
+    let (filter, parse_error) = match directives.parse::<Targets>() {
+        Ok(filter) => (filter, None),
+        Err(e) => (
+            DEFAULT_LOG_LEVEL
+                .parse::<Targets>()
+                .expect("default log level is valid"),
+            Some(e),
+        ),
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_target(false)
+        .with_thread_ids(true)
+        .with_line_number(true)
+        .with_max_level(tracing::Level::TRACE);
+
+    match format {
+        LogFormat::Text => builder.finish().with(filter).with(logdedup::Layer).init(),
+        LogFormat::Pretty => builder.pretty().finish().with(filter).with(logdedup::Layer).init(),
+        LogFormat::Json => builder
+            .fmt_fields(JsonFields)
+            .event_format(JsonFormat)
+            .finish()
+            .with(filter)
+            .with(logdedup::Layer)
+            .init(),
+    }
+
+    if let Some(e) = parse_error {
+        tracing::warn!(
+            "⚠️ Invalid log filter '{}' from {}: {}; falling back to '{}'",
+            directives,
+            source,
+            e,
+            DEFAULT_LOG_LEVEL
+        );
+    }
+}
+
+// One JSON object per line:
+// {"timestamp":"…","level":"INFO","message":"…",<event fields>,"spans":[{"name":"…",<span fields>}]}
+//
+// Access events carry the stable fields request_id, client_ip, user, method, target, status and bytes.
+pub struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let meta = event.metadata();
+        write!(
+            writer,
+            r#"{{"timestamp":"{}","level":"{}","#,
+            rfc3339_now(),
+            meta.level()
+        )?;
+        ctx.format_fields(writer.by_ref(), event)?;
+
+        if let Some(scope) = ctx.event_scope() {
+            let mut first = true;
+            for span in scope.from_root() {
+                writer.write_str(if first { r#","spans":["# } else { "," })?;
+                first = false;
+                write!(writer, r#"{{"name":"{}""#, span.name())?;
+                let ext = span.extensions();
+                if let Some(fields) = ext.get::<FormattedFields<N>>() {
+                    if !fields.is_empty() {
+                        write!(writer, ",{}", fields)?;
+                    }
+                }
+                writer.write_char('}')?;
+            }
+            if !first {
+                writer.write_char(']')?;
+            }
+        }
+        writeln!(writer, "}}")
+    }
+}
+
+// Formats fields as comma-separated JSON members (without the enclosing braces)
+// so they can be spliced into the event object by JsonFormat.
+pub struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(&self, writer: Writer<'writer>, fields: R) -> fmt::Result {
+        let mut visitor = JsonVisitor {
+            writer,
+            first: true,
+            result: Ok(()),
+        };
+        fields.record(&mut visitor);
+        visitor.result
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &tracing::span::Record<'_>,
+    ) -> fmt::Result {
+        let first = current.fields.is_empty();
+        let mut visitor = JsonVisitor {
+            writer: current.as_writer(),
+            first,
+            result: Ok(()),
+        };
+        fields.record(&mut visitor);
+        visitor.result
+    }
+}
+
+struct JsonVisitor<'a> {
+    writer: Writer<'a>,
+    first: bool,
+    result: fmt::Result,
+}
+
+impl JsonVisitor<'_> {
+    fn member(&mut self, name: &str, raw_value: fmt::Arguments<'_>) {
+        if self.result.is_err() {
+            return;
+        }
+        let sep = if self.first { "" } else { "," };
+        self.first = false;
+        self.result = write!(self.writer, r#"{}"{}":{}"#, sep, escape(name), raw_value);
+    }
+}
+
+impl Visit for JsonVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.member(field.name(), format_args!("\"{}\"", escape(value)));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.member(field.name(), format_args!("{}", value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.member(field.name(), format_args!("{}", value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.member(field.name(), format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let value = format!("{:?}", value);
+        self.member(field.name(), format_args!("\"{}\"", escape(&value)));
+    }
+}
+
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn rfc3339_now() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60,
+        now.subsec_millis()
+    )
+}
+
+// Howard Hinnant's days-since-epoch to proleptic Gregorian date conversion.
+pub fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+// The inverse: proleptic Gregorian date to days since 1970-01-01.
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (i64::from(month) + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
@@ -0,0 +1,77 @@
+use hyper::header::{HeaderValue, MAX_FORWARDS, VIA};
+use hyper::{HeaderMap, Method};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+
+// Our Via received-by token (RFC 9110 section 7.6.3). Defaults to
+// hostname:port so chained instances on different hosts stay distinct.
+pub fn received_by(configured: Option<&str>, listener: SocketAddr) -> String {
+    if let Some(name) = configured {
+        return name.to_string();
+    }
+    format!("{}:{}", hostname(), listener.port())
+}
+
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let len = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if len == 0 {
+        let end = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+        String::from_utf8_lossy(&buf[..end]).to_string()
+    } else {
+        "secure-proxy".to_string()
+    }
+}
+
+// Whether a Via header shows the request already passed through us.
+pub fn seen_before(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get_all(VIA)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|hop| hop.split_whitespace().nth(1) == Some(token))
+}
+
+pub fn add_via(headers: &mut HeaderMap, token: &str) {
+    if let Ok(value) = HeaderValue::from_str(&format!("1.1 {}", token)) {
+        headers.append(VIA, value);
+    }
+}
+
+// Max-Forwards only applies to TRACE and OPTIONS. Returns the remaining
+// count after decrementing it, or None if the header is absent or ignored.
+pub fn max_forwards(method: &Method, headers: &mut HeaderMap) -> Option<u32> {
+    if method != Method::TRACE && method != Method::OPTIONS {
+        return None;
+    }
+    let remaining: u32 = headers.get(MAX_FORWARDS)?.to_str().ok()?.trim().parse().ok()?;
+    if remaining > 0 {
+        headers.insert(MAX_FORWARDS, HeaderValue::from(remaining - 1));
+    }
+    Some(remaining)
+}
+
+// Whether host:port resolves to an address we are listening on.
+pub async fn targets_listener(listeners: &[SocketAddr], host: &str, port: u16) -> bool {
+    let candidates: Vec<&SocketAddr> = listeners.iter().filter(|l| l.port() == port).collect();
+    if candidates.is_empty() {
+        return false;
+    }
+    let Ok(addrs) = crate::dns::lookup(host, port).await else {
+        return false;
+    };
+    candidates.iter().any(|listener| {
+        addrs.iter().any(|addr| {
+            if listener.ip().is_unspecified() {
+                is_local(addr.ip())
+            } else {
+                addr.ip() == listener.ip()
+            }
+        })
+    })
+}
+
+// Binding only succeeds for addresses assigned to this machine.
+fn is_local(ip: IpAddr) -> bool {
+    ip.is_loopback() || ip.is_unspecified() || UdpSocket::bind((ip, 0)).is_ok()
+}
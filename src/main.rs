@@ -1,62 +1,545 @@
+mod abuse;
+mod acl;
+mod access;
+mod admin;
+mod anomaly;
+mod alert;
+mod apikey;
+mod authcache;
+mod bandwidth;
+mod bans;
+mod billing;
+mod blocklist;
+mod bodylimit;
+mod breaker;
+mod buffers;
+mod cache;
+mod certs;
+mod certwatch;
+mod compress;
+mod compression;
+mod cors;
+mod deprecation;
+mod dev;
+mod dns;
+mod drain;
+mod egress;
+mod egressip;
+mod errorpages;
+mod fixtures;
+mod flags;
+mod gate;
+mod groups;
+mod headerrules;
+mod http2;
+#[cfg(feature = "http3")]
+mod http3;
+mod icap;
+mod har;
+mod geoip;
+mod json;
+mod jwt;
+mod kerberos;
+mod ldap;
+mod limits;
+mod listener;
+mod logdedup;
+mod logging;
+mod loops;
+mod masque;
+mod metrics;
+mod mirror;
+mod mmdb;
+mod oidc;
+mod pac;
+mod pacjs;
+mod parquet;
+mod pattern;
+mod privacy;
+mod privileges;
+mod proxyproto;
+mod reaper;
+mod resolver;
+mod retry;
+mod reverse;
+mod snapshot;
+#[cfg(target_os = "linux")]
+mod splice;
+mod rewrite;
+mod runtime;
+mod schedule;
+mod spool;
+mod startup;
+mod store;
+mod systemd;
+mod tcp;
+mod timeouts;
+mod sqlite;
+mod totp;
+mod transparent;
+mod tz;
+mod upstream;
+mod urlfilter;
+mod userdb;
+mod userheaders;
+mod users;
+mod watchdog;
+mod websocket;
+
+use clap::{Parser, Subcommand};
+use hyper::server::accept::Accept;
+use hyper::server::conn::AddrIncoming;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::upgrade::Upgraded;
 use hyper::{Body, Method, Request, Response, Client, Server};
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::Infallible;
 use std::fs;
 use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::net::TcpStream;
-use tracing::{info, warn, error, debug, instrument};
+use tokio::io::AsyncWriteExt;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
+use tracing::{info, warn, error, debug, instrument, Instrument};
 
 use base64::Engine as _;
 use base64::engine::general_purpose::STANDARD as BASE64;
 use hyper::header::{PROXY_AUTHORIZATION, PROXY_AUTHENTICATE};
 
+#[derive(Parser)]
+#[command(version, about = "Authenticating HTTP/HTTPS forward proxy")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    runtime: runtime::RuntimeConfig,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run locally with a self-signed TLS listener, a default user and verbose logs; ignores config.toml
+    Dev {
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+        #[arg(long, default_value_t = 8443)]
+        tls_port: u16,
+    },
+    /// Add, change, remove or list proxy users in the config file
+    User {
+        #[arg(long, default_value = "config.toml")]
+        config: std::path::PathBuf,
+        #[command(subcommand)]
+        action: users::Action,
+    },
+    /// Write example configs for common setups, checked against this version
+    GenFixtures {
+        /// Default: ~/.config/secure-proxy/examples
+        #[arg(long)]
+        dir: Option<std::path::PathBuf>,
+    },
+}
+
 #[derive(Debug, Deserialize)]
 struct Config {
     server: ServerConfig,
+    #[serde(default)]
     users: HashMap<String, String>, // username -> password
+    // "sqlite://path" to keep users, limits and usage in a database instead of [users]
+    user_store: Option<String>,
+    // Directory checked for users not found locally
+    #[serde(default)]
+    ldap: ldap::LdapConfig,
+    // One-time codes required on top of the password for some users
+    #[serde(default)]
+    totp: totp::TotpConfig,
+    // Accept "Proxy-Authorization: Bearer <jwt>"
+    #[serde(default)]
+    jwt: jwt::JwtConfig,
+    // Sign-in through an identity provider; its tokens are sent as Bearer
+    #[serde(default)]
+    oidc: oidc::OidcConfig,
+    // Negotiate (SPNEGO) tickets from domain-joined clients
+    #[serde(default)]
+    kerberos: kerberos::KerberosConfig,
+    // Keys sent in a header of their own instead of Proxy-Authorization
+    #[serde(default)]
+    api_keys: apikey::ApiKeyConfig,
+    // Successful Basic logins remembered for a while
+    #[serde(default)]
+    auth_cache: authcache::AuthCacheConfig,
+    // Limits and routes shared by their members
+    #[serde(default)]
+    groups: HashMap<String, groups::GroupConfig>,
+    // Per-user and per-group destination rules
+    #[serde(default)]
+    acl: acl::AclConfig,
+    // Headers added to some users' HTTP requests
+    #[serde(default)]
+    user_headers: Vec<userheaders::HeaderRule>,
+    // Hours some users may browse at all
+    #[serde(default)]
+    schedules: Vec<schedule::Schedule>,
+    // Subscribed ad and malware domain lists
+    #[serde(default)]
+    blocklists: Vec<blocklist::BlocklistConfig>,
+    // Path and query rules for plain HTTP requests
+    #[serde(default)]
+    url_filters: Vec<urlfilter::UrlFilter>,
+    #[serde(default)]
+    deprecations: Vec<deprecation::DeprecationRule>,
+    // Headers added, replaced or removed by destination
+    #[serde(default)]
+    headers: headerrules::HeaderRulesConfig,
+    // Tracking headers taken out of some users' traffic
+    #[serde(default)]
+    privacy: privacy::PrivacyConfig,
+    // Sites served by reverse proxy listeners
+    #[serde(default)]
+    reverse: reverse::ReverseConfig,
+    // Tunnels that start with a PROXY header naming the client
+    #[serde(default)]
+    proxy_protocol: proxyproto::ProxyProtocolConfig,
+    // UDP relaying for HTTP/3 clients
+    #[serde(default)]
+    masque: masque::MasqueConfig,
+    // Parent selection by a proxy auto-config file
+    #[serde(default)]
+    pac: pac::PacConfig,
+    // Bodies sent to an external scanner before they go on
+    #[serde(default)]
+    icap: icap::IcapConfig,
+    // Recordings of HTTP traffic, started through the admin API
+    #[serde(default)]
+    har: har::HarConfig,
+    // HTML pages in place of the proxy's own error bodies
+    #[serde(default)]
+    error_pages: errorpages::ErrorPagesConfig,
+    // Copies of some requests sent to a shadow destination
+    #[serde(default)]
+    mirror: mirror::MirrorConfig,
+    #[serde(default)]
+    cache: cache::CacheConfig,
+    #[serde(default)]
+    limits: limits::LimitsConfig,
+    #[serde(default)]
+    banners: Vec<rewrite::BannerRule>,
+    #[serde(default)]
+    filters: rewrite::FilterConfig,
+    // Responses compressed for clients the origin sent them plain to
+    #[serde(default)]
+    compression: compression::CompressionConfig,
+    #[serde(default)]
+    bandwidth: bandwidth::BandwidthConfig,
+    #[serde(default)]
+    metrics: metrics::MetricsConfig,
+    #[serde(default)]
+    upstreams: HashMap<String, upstream::ParentProxy>,
+    #[serde(default)]
+    routes: Vec<upstream::RouteRule>,
+    #[serde(default)]
+    pools: HashMap<String, upstream::PoolConfig>,
+    #[serde(default)]
+    interfaces: HashMap<String, egress::InterfaceConfig>,
+    // Dedicated outbound addresses per user
+    #[serde(default)]
+    egress_ips: egressip::EgressIpConfig,
+    #[serde(default)]
+    features: HashMap<String, bool>, // initial feature flag values
+    #[serde(default)]
+    admin: admin::AdminConfig,
+    #[serde(default)]
+    watchdog: watchdog::WatchdogConfig,
+    #[serde(default)]
+    circuit_breaker: breaker::CircuitBreakerConfig,
+    #[serde(default)]
+    gate: gate::GateConfig,
+    #[serde(default)]
+    geoip: geoip::GeoIpConfig,
+    #[serde(default)]
+    dns: dns::DnsConfig,
+    // Origins sent plain HTTP requests over HTTP/2 (h2c)
+    #[serde(default)]
+    http2: http2::Http2Config,
+    // Socket options for client and upstream connections
+    #[serde(default)]
+    tcp: tcp::TcpConfig,
+    #[serde(default)]
+    tunnel: buffers::TunnelConfig,
+    // Async runtime threads; command-line options override these
+    #[serde(default)]
+    runtime: runtime::RuntimeConfig,
+    #[serde(default)]
+    retry: retry::RetryConfig,
+    #[serde(default)]
+    honeypot: bans::HoneypotConfig,
+    #[serde(default)]
+    billing: billing::BillingConfig,
+    #[serde(default)]
+    access_log: access::AccessLogConfig,
+    #[serde(default)]
+    anomaly: anomaly::AnomalyConfig,
+    // Expiry of listener and other certificates
+    #[serde(default)]
+    certs: certs::CertsConfig,
+    // Certificates presented by CONNECT destinations
+    #[serde(default)]
+    cert_watch: certwatch::CertWatchConfig,
+    // Closes idle and half-closed tunnels
+    #[serde(default)]
+    reaper: reaper::ReaperConfig,
+    // Replaces [server] host/port when non-empty
+    #[serde(default)]
+    listeners: Vec<listener::ListenerConfig>,
+    // SHA-256 of the file as loaded, reported at startup
+    #[serde(skip)]
+    hash: String,
 }
 
-#[derive(Debug, Deserialize)]
-struct ServerConfig {
-    port: u16,
-    host: String,
+// Shared runtime state handed to every connection
+struct AppState {
+    config: Config,
+    cache: cache::ResponseCache,
+    connections: limits::ConnectionRegistry,
+    bandwidth: bandwidth::BandwidthRegistry,
+    buffers: buffers::BufferPool,
+    metrics: Arc<metrics::Metrics>,
+    client_slots: limits::Slots,
+    tunnel_slots: limits::Slots,
+    // Mirrored copies on their way
+    mirror_slots: limits::Slots,
+    flags: flags::FeatureFlags,
+    groups: groups::Membership,
+    upstreams: upstream::Upstreams,
+    watchdog: watchdog::Watchdog,
+    breakers: breaker::CircuitBreakers,
+    gate: gate::Gate,
+    geoip: geoip::Databases,
+    // [acl] deny_private, shared by every connection it applies to
+    private_guard: Arc<egress::Guard>,
+    egress_ips: egressip::EgressIps,
+    blocklists: blocklist::Blocklists,
+    bans: bans::Bans,
+    abuse: abuse::AbuseLog,
+    har: har::Recorder,
+    error_pages: errorpages::ErrorPages,
+    billing: Option<billing::Billing>,
+    users: users::Users,
+    ldap: ldap::Ldap,
+    totp: totp::Totp,
+    auth_cache: authcache::AuthCache,
+    jwt: jwt::Jwt,
+    oidc: oidc::Oidc,
+    kerberos: Option<Arc<kerberos::Kerberos>>,
+    pac: Option<pac::Pac>,
+    anomalies: anomaly::Anomalies,
+    certs: certs::CertMonitor,
+    cert_watch: certwatch::CertWatch,
+    // Bound listeners; the set can change on SIGHUP
+    listeners: std::sync::Mutex<Vec<Arc<listener::Listener>>>,
+    // Hard stop time, once SIGTERM has started the drain
+    draining: std::sync::OnceLock<std::time::Instant>,
 }
 
-impl Config {
-    fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        info!("Loading configuration from: {}", path);
-        let contents = fs::read_to_string(path)?;
-        debug!(
-            "Configuration file read successfully, {} bytes",
-            contents.len()
-        );
-        let config: Config = toml::from_str(&contents)?;
-        info!("Configuration parsed successfully");
-        Ok(config)
+impl AppState {
+    fn new(mut config: Config) -> std::io::Result<Self> {
+        let cache = cache::ResponseCache::new(&config.cache);
+        let store = match &config.server.data_dir {
+            Some(dir) => Some(Arc::new(store::Store::open(dir)?)),
+            None => None,
+        };
+        let geoip = geoip::Databases::open(&config.geoip)?;
+        let blocklists = blocklist::Blocklists::new(&config.blocklists, config.server.data_dir.as_deref());
+        let billing = match config.billing.enabled {
+            true => Some(billing::Billing::new(&config.billing)?),
+            false => None,
+        };
+        let kerberos = match config.kerberos.enabled() {
+            true => Some(Arc::new(kerberos::Kerberos::new(&config.kerberos).map_err(std::io::Error::other)?)),
+            false => None,
+        };
+        let pac = match config.pac.enabled() {
+            true => Some(pac::Pac::new(&config.pac).map_err(std::io::Error::other)?),
+            false => None,
+        };
+        let user_db = match &config.user_store {
+            Some(store) => Some(
+                userdb::store_path(store)
+                    .and_then(|path| userdb::UserDb::open(&path))
+                    .map_err(std::io::Error::other)?,
+            ),
+            None => None,
+        };
+        Ok(AppState {
+            client_slots: limits::Slots::new(config.limits.max_client_connections),
+            tunnel_slots: limits::Slots::new(config.limits.max_tunnels),
+            mirror_slots: limits::Slots::new(Some(config.mirror.max_in_flight)),
+            flags: flags::FeatureFlags::new(&config.features, store.clone()),
+            groups: groups::Membership::new(&config.groups),
+            bans: bans::Bans::new(store.clone()),
+            egress_ips: egressip::EgressIps::new(&config.egress_ips, store.clone()),
+            upstreams: upstream::Upstreams::new(
+                &config.upstreams,
+                &config.pools,
+                &config.interfaces,
+                config.server.ip_family,
+            ),
+            watchdog: watchdog::Watchdog::default(),
+            breakers: breaker::CircuitBreakers::default(),
+            gate: gate::Gate::default(),
+            abuse: abuse::AbuseLog::default(),
+            har: har::Recorder::new(&config.har),
+            error_pages: errorpages::ErrorPages::load(&config.error_pages)?,
+            listeners: std::sync::Mutex::default(),
+            draining: std::sync::OnceLock::new(),
+            geoip,
+            private_guard: Arc::new(egress::Guard::private(&config.acl.allow_networks)),
+            blocklists,
+            billing,
+            // Taken out of the config, which lives on, so plain-text
+            // passwords are only kept sealed
+            users: users::Users::new(std::mem::take(&mut config.users), user_db),
+            ldap: ldap::Ldap::default(),
+            totp: totp::Totp::new(&config.totp),
+            auth_cache: authcache::AuthCache::default(),
+            jwt: jwt::Jwt::new(&config.jwt)?,
+            oidc: oidc::Oidc::default(),
+            kerberos,
+            pac,
+            anomalies: anomaly::Anomalies::default(),
+            certs: certs::CertMonitor::default(),
+            cert_watch: certwatch::CertWatch::new(&config.cert_watch, store)?,
+            buffers: buffers::BufferPool::new(&config.tunnel),
+            config,
+            cache,
+            connections: limits::ConnectionRegistry::default(),
+            bandwidth: bandwidth::BandwidthRegistry::default(),
+            metrics: Arc::default(),
+        })
+    }
+
+    // Usage for the billing export, anomaly baselines and user store
+    // quotas; only requests that got a response count
+    fn account(&self, user: &str, listener: &str, bytes: u64) {
+        if let Some(billing) = &self.billing {
+            billing.record(user, listener, bytes);
+        }
+        self.users.record(user, bytes);
+        self.anomalies.record(&self.config.anomaly, user, bytes);
     }
 
-    fn is_valid_basic(&self, header: Option<&hyper::header::HeaderValue>) -> bool {
+    // The password was right; check the one-time code if the user has one.
+    fn second_factor(&self, user: &str, code: Option<&str>, header: &[u8]) -> bool {
+        let Some(code) = code else { return true };
+        match self.totp.verify(&self.config.totp, user, code, header) {
+            Ok(()) => true,
+            Err(reason) => {
+                warn!("❌ Proxy auth {} for user '{}'", reason, user);
+                false
+            }
+        }
+    }
+
+    // A name that is not a user is attacker-chosen text, so it stays out of
+    // warnings unless server.log_failed_usernames is set.
+    fn unknown_user(&self, reason: &str, user: &str) {
+        match self.config.server.log_failed_usernames {
+            true => warn!("❌ Proxy auth {} '{}'", reason, user),
+            false => {
+                warn!("❌ Proxy auth {}", reason);
+                debug!("Proxy auth {}: '{}'", reason, user);
+            }
+        }
+    }
+
+    fn api_key_user(&self, key: &[u8]) -> Option<String> {
+        match self.config.api_keys.user(key) {
+            Some(user) => {
+                info!("✅ Proxy auth successful for API key user '{}'", user);
+                Some(user.to_string())
+            }
+            None => {
+                warn!("❌ Proxy auth unknown key in {}", self.config.api_keys.header);
+                None
+            }
+        }
+    }
+
+    // Returns the authenticated username, if any. Bearer tokens are checked
+    // as JWTs or with the OIDC provider, Negotiate tokens against the
+    // Kerberos keytab; Basic credentials against local users (config or
+    // user store) first, then LDAP if configured.
+    async fn authenticate(&self, header: Option<&hyper::header::HeaderValue>) -> Option<String> {
+        let credentials = header.and_then(|v| v.to_str().ok()).and_then(|v| v.split_once(' '));
+        let bearer = credentials.filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"));
+        let negotiate = credentials.filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Negotiate"));
+        if let (Some((_, token)), Some(kerberos)) = (negotiate, &self.kerberos) {
+            let (kerberos, config, token) = (kerberos.clone(), self.config.kerberos.clone(), token.to_string());
+            return match tokio::task::spawn_blocking(move || kerberos.accept(&config, &token)).await {
+                Ok(Ok(user)) => {
+                    info!("✅ Proxy auth successful for Kerberos user '{}'", user);
+                    Some(user)
+                }
+                Ok(Err(e)) => {
+                    warn!("❌ Proxy auth Negotiate token rejected: {}", e);
+                    None
+                }
+                Err(_) => None,
+            };
+        }
+        if let (Some((_, token)), true) = (bearer, self.config.oidc.enabled()) {
+            let user = self.oidc.authenticate(&self.config.oidc, token.trim()).await?;
+            info!("✅ Proxy auth successful for token user '{}'", user);
+            return Some(user);
+        }
+        if let (Some((_, token)), true) = (bearer, self.config.jwt.enabled()) {
+            let user = self.jwt.authenticate(&self.config.jwt, token.trim()).await?;
+            info!("✅ Proxy auth successful for token user '{}'", user);
+            return Some(user);
+        }
         if let Some(value) = header {
             if let Ok(v) = value.to_str() {
                 let parts: Vec<&str> = v.split_whitespace().collect();
                 if parts.len() == 2 && parts[0].eq_ignore_ascii_case("Basic") {
+                    if let Some(user) = self.auth_cache.get(&self.config.auth_cache, value.as_bytes()) {
+                        debug!("Proxy auth cached for user '{}'", user);
+                        return Some(user);
+                    }
                     if let Ok(decoded) = BASE64.decode(parts[1]) {
                         if let Ok(creds) = String::from_utf8(decoded) {
                             if let Some((user, pass)) = creds.split_once(':') {
-                                if let Some(stored) = self.users.get(user) {
-                                    let ok = stored == pass;
-                                    if ok {
+                                // Enrolled users append ":<code>" to the password
+                                let (pass, code) = match (self.totp.enrolled(user), totp::split(pass)) {
+                                    (false, _) => (pass, None),
+                                    (true, Some((pass, code))) => (pass, Some(code)),
+                                    (true, None) => {
+                                        warn!("❌ Proxy auth one-time code missing for user '{}'", user);
+                                        return None;
+                                    }
+                                };
+                                if let Some(stored) = self.users.password(user) {
+                                    if users::check(stored, pass.to_string()).await {
+                                        if !self.second_factor(user, code, value.as_bytes()) {
+                                            return None;
+                                        }
                                         info!("✅ Proxy auth successful for user '{}'", user);
-                                    } else {
-                                        warn!("❌ Proxy auth wrong password for user '{}'", user);
+                                        self.auth_cache.insert(&self.config.auth_cache, value.as_bytes(), user);
+                                        return Some(user.to_string());
                                     }
-                                    return ok;
+                                    warn!("❌ Proxy auth wrong password for user '{}'", user);
+                                    return None;
+                                } else if self.config.ldap.url.is_some() {
+                                    if self.ldap.authenticate(&self.config.ldap, user, pass).await {
+                                        if !self.second_factor(user, code, value.as_bytes()) {
+                                            return None;
+                                        }
+                                        info!("✅ Proxy auth successful for LDAP user '{}'", user);
+                                        self.auth_cache.insert(&self.config.auth_cache, value.as_bytes(), user);
+                                        return Some(user.to_string());
+                                    }
+                                    self.unknown_user("rejected by LDAP", user);
                                 } else {
-                                    warn!("❌ Proxy auth unknown user '{}'", user);
+                                    self.unknown_user("unknown user", user);
                                 }
                             } else {
                                 warn!("❌ Proxy auth creds missing ':' separator");
@@ -76,26 +559,406 @@ impl Config {
         } else {
             warn!("❌ No Proxy-Authorization header provided");
         }
-        false
+        None
+    }
+
+    fn listeners(&self) -> Vec<Arc<listener::Listener>> {
+        self.listeners.lock().unwrap().clone()
+    }
+
+    fn listen_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners().iter().map(|l| l.addr).collect()
+    }
+
+    // Via token naming this instance, as reached through `listener`
+    fn via_token(&self, listener: &listener::Listener) -> String {
+        loops::received_by(self.config.server.via_name.as_deref(), listener.addr)
+    }
+
+    // Feature lookups below honour the runtime flags.
+
+    // Egress path for a destination host, unless [[geoip.routes]] picked
+    // one by its address (`geo`).
+    fn route(&self, user: &str, host: &str, geo: Option<&geoip::Via>) -> upstream::Route {
+        if !self.flags.enabled(flags::ROUTING) {
+            return self.upstreams.direct();
+        }
+        match geo {
+            Some(geoip::Via(via)) => self.upstreams.via(via, host),
+            None => self.upstreams.route(self.routes(user, host), host),
+        }
+    }
+
+    // Where to retry when `route(user, host)` cannot be reached.
+    fn fallback_route(&self, user: &str, host: &str, geo: Option<&geoip::Via>) -> Option<upstream::Route> {
+        if !self.flags.enabled(flags::ROUTING) || geo.is_some() {
+            return None;
+        }
+        self.upstreams.fallback(self.routes(user, host), host)
+    }
+
+    // How `user` connects directly: kept off internal addresses unless
+    // [acl] lets them in, and from their own address under [egress_ips].
+    fn outbound(&self, user: &str) -> egress::Outbound {
+        let group = self.groups.group_of(user);
+        egress::Outbound {
+            guard: self.config.acl.guards(user, group).then(|| self.private_guard.clone()),
+            source: self.egress_ips.source(&self.config.egress_ips, user, group),
+        }
+    }
+
+    // The user's group rules if one matches `host`, otherwise [[routes]].
+    fn routes(&self, user: &str, host: &str) -> &[upstream::RouteRule] {
+        match self.group(user) {
+            Some(group) if group.routes.iter().any(|r| pattern::host_matches(&r.host, host)) => &group.routes,
+            _ => &self.config.routes,
+        }
+    }
+
+    fn group(&self, user: &str) -> Option<&groups::GroupConfig> {
+        self.config.groups.get(self.groups.group_of(user)?)
+    }
+
+    fn throttle(&self, user: &str, host: &str) -> bandwidth::Throttle {
+        let throttle = if self.flags.enabled(flags::BANDWIDTH) {
+            let rate = self.users.limits(user).bandwidth;
+            let group_rate = self.group(user).and_then(|g| g.bandwidth);
+            self.bandwidth.throttle(&self.config.bandwidth, user, rate, group_rate, host)
+        } else {
+            bandwidth::Throttle::default()
+        };
+        // Independent of the bandwidth flag
+        match self.anomalies.limiter(user) {
+            Some(limiter) => throttle.with(limiter),
+            None => throttle,
+        }
+    }
+
+    fn banners(&self) -> &[rewrite::BannerRule] {
+        if self.flags.enabled(flags::BANNERS) {
+            &self.config.banners
+        } else {
+            &[]
+        }
+    }
+
+    fn deprecations(&self) -> &[deprecation::DeprecationRule] {
+        if self.flags.enabled(flags::DEPRECATIONS) {
+            &self.config.deprecations
+        } else {
+            &[]
+        }
     }
 }
 
-fn unauthorized_response() -> Response<Body> {
-    // 407 with Proxy-Authenticate as required by spec
+#[derive(Debug, Deserialize)]
+struct ServerConfig {
+    port: u16,
+    host: String,
+    #[serde(default)]
+    log_format: logging::LogFormat,
+    // RUST_LOG-style directives, e.g. "info,hyper=warn". RUST_LOG overrides this.
+    log_level: Option<String>,
+    // Dump full request/response headers at debug level
+    #[serde(default = "default_true")]
+    log_headers: bool,
+    // Identical warnings/errors logged per window before the rest are only
+    // counted; 0 logs every one
+    #[serde(default = "default_log_repeat_limit")]
+    log_repeat_limit: u32,
+    // Seconds per window; a summary of what was suppressed follows each
+    #[serde(default = "default_log_repeat_window")]
+    log_repeat_window: u64,
+    // Where runtime state (e.g. feature flags) is persisted; none if unset
+    data_dir: Option<std::path::PathBuf>,
+    // Via header pseudonym; defaults to hostname:port
+    via_name: Option<String>,
+    // Send each request's ID upstream in this header, e.g. "X-Request-Id"
+    request_id_header: Option<String>,
+    // Bind with SO_REUSEPORT so a new process can share the port during upgrades
+    #[serde(default)]
+    reuse_port: bool,
+    // Seconds in-flight requests and tunnels get to finish after SIGTERM
+    #[serde(default = "default_drain_timeout")]
+    drain_timeout: u64,
+    // Seconds between reports of what is still open while draining
+    #[serde(default = "default_drain_report_interval")]
+    drain_report_interval: u64,
+    // Separate address for /health, /ready and /readyz that stays up while draining
+    status_addr: Option<SocketAddr>,
+    // Name unknown users in warnings. Off by default, as anyone can put
+    // whatever they like into the log that way; they are logged at debug.
+    #[serde(default)]
+    log_failed_usernames: bool,
+    // Address family for direct connections to dual-stack destinations:
+    // "system" (default), "prefer-ipv6", "prefer-ipv4", "ipv6-only" or
+    // "ipv4-only"; [[routes]] can override it
+    #[serde(default)]
+    ip_family: egress::IpFamily,
+    // Account to switch to once the listeners are bound, when started as root
+    run_as_user: Option<String>,
+    run_as_group: Option<String>,
+}
+
+fn default_log_repeat_limit() -> u32 {
+    5
+}
+
+fn default_log_repeat_window() -> u64 {
+    60
+}
+
+fn default_drain_timeout() -> u64 {
+    30
+}
+
+fn default_drain_report_interval() -> u64 {
+    5
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Config {
+    // Files and directories written to at runtime, which a proxy that drops
+    // root must hand over to the account it switches to.
+    fn state_paths(&self) -> Vec<std::path::PathBuf> {
+        let mut paths: Vec<std::path::PathBuf> = self.server.data_dir.iter().cloned().collect();
+        if self.billing.enabled {
+            paths.push(self.billing.dir.clone());
+        }
+        if let Some(db) = self.user_store.as_deref().and_then(|store| userdb::store_path(store).ok()) {
+            for suffix in ["-journal", "-wal", "-shm"] {
+                let mut sidecar = db.clone().into_os_string();
+                sidecar.push(suffix);
+                paths.push(sidecar.into());
+            }
+            paths.push(db);
+        }
+        paths
+    }
+
+    // Runs before the tracing subscriber is installed (the log format comes
+    // from the config), so errors are returned rather than logged here.
+    fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    fn parse(contents: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config: Config = toml::from_str(contents)?;
+        config.hash = startup::config_hash(contents);
+        upstream::validate(&config.routes, &config.upstreams, &config.pools, &config.interfaces)?;
+        groups::validate(&config.groups)?;
+        for group in config.groups.values() {
+            upstream::validate(&group.routes, &config.upstreams, &config.pools, &config.interfaces)?;
+        }
+        flags::validate(&config.features)?;
+        listener::validate(&config.listeners)?;
+        config.metrics.validate()?;
+        config.admin.validate()?;
+        config.gate.validate()?;
+        config.geoip.validate()?;
+        blocklist::validate(&config.blocklists)?;
+        urlfilter::prepare(&mut config.url_filters)?;
+        config.dns.validate()?;
+        config.tcp.validate()?;
+        config.tunnel.validate()?;
+        config.limits.validate()?;
+        config.mirror.validate()?;
+        config.har.validate()?;
+        config.runtime.validate()?;
+        if let Some(route) = config.geoip.routes.iter().find(|r| !upstream::known_via(&r.via, &config.upstreams, &config.pools, &config.interfaces)) {
+            return Err(format!("geoip route uses unknown upstream '{}'", route.via).into());
+        }
+        users::validate(&config.users)?;
+        if let Some(store) = &config.user_store {
+            userdb::store_path(store)?;
+        }
+        config.honeypot.validate(&config.users)?;
+        config.billing.validate()?;
+        config.access_log.validate()?;
+        config.anomaly.validate()?;
+        if config.server.drain_report_interval == 0 {
+            return Err("server.drain_report_interval must be positive".into());
+        }
+        if config.server.log_repeat_limit > 0 && config.server.log_repeat_window == 0 {
+            return Err("server.log_repeat_window must be positive".into());
+        }
+        config.certs.validate()?;
+        config.cert_watch.validate()?;
+        config.ldap.validate()?;
+        config.totp.validate()?;
+        config.jwt.validate()?;
+        config.oidc.validate()?;
+        config.kerberos.validate()?;
+        config.api_keys.validate()?;
+        config.auth_cache.validate()?;
+        config.acl.validate(&config.groups)?;
+        config.egress_ips.validate(&config.groups)?;
+        userheaders::validate(&config.user_headers, &config.groups)?;
+        config.headers.validate()?;
+        config.compression.validate()?;
+        config.proxy_protocol.validate()?;
+        config.masque.validate()?;
+        config.pac.validate()?;
+        if !upstream::known_via(&config.pac.fallback, &config.upstreams, &config.pools, &config.interfaces) {
+            return Err(format!("pac.fallback uses unknown upstream '{}'", config.pac.fallback).into());
+        }
+        config.reverse.prepare()?;
+        if config.reverse.is_empty() && config.listeners.iter().any(|l| l.reverse) {
+            return Err("a listener has reverse = true but [reverse] has no sites".into());
+        }
+        config.privacy.validate(&config.groups)?;
+        config.icap.prepare()?;
+        schedule::prepare(&mut config.schedules, &config.groups)?;
+        config.reaper.validate()?;
+        if config.jwt.enabled() && config.oidc.enabled() {
+            return Err("[jwt] and [oidc] both accept bearer tokens; configure only one".into());
+        }
+        Ok(config)
+    }
+}
+
+fn overloaded_response(message: &'static str) -> Response<Body> {
     Response::builder()
-        .status(407)
-        .header(PROXY_AUTHENTICATE, r#"Basic realm="Secure Proxy""#)
-        .body(Body::from("Proxy authentication required"))
+        .status(503)
+        .header(hyper::header::CONNECTION, "close")
+        .header(hyper::header::RETRY_AFTER, "5")
+        .body(Body::from(message))
+        .unwrap()
+}
+
+// Proxy-generated error with a machine-readable body, e.g.
+// {"error":"upstream_timeout","message":"..."}
+fn error_response(status: u16, code: &str, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .extension(errorpages::ProxyError::new(code, message))
+        .body(Body::from(format!(
+            "{{\"error\":\"{}\",\"message\":\"{}\"}}",
+            code,
+            logging::escape(message)
+        )))
+        .unwrap()
+}
+
+// 504 when the upstream (or parent proxy) timed out, 502 for anything else
+// that went wrong talking to it.
+fn upstream_error_status(err: &hyper::Error) -> (u16, &'static str) {
+    let mut source = std::error::Error::source(err);
+    while let Some(e) = source {
+        if let Some(io) = e.downcast_ref::<std::io::Error>() {
+            if io.kind() == std::io::ErrorKind::TimedOut {
+                return (504, "upstream_timeout");
+            }
+        }
+        source = e.source();
+    }
+    if err.is_timeout() {
+        return (504, "upstream_timeout");
+    }
+    if err.is_connect() {
+        return (502, "upstream_unreachable");
+    }
+    (502, "upstream_error")
+}
+
+// Why [acl] deny_private refused the connection, if it did.
+fn refused_by_guard(err: &hyper::Error) -> Option<String> {
+    let mut source = std::error::Error::source(err);
+    while let Some(e) = source {
+        if let Some(refused) = e.downcast_ref::<std::io::Error>().and_then(egress::refused) {
+            return Some(refused.to_string());
+        }
+        source = e.source();
+    }
+    None
+}
+
+fn circuit_open_response(retry_after: std::time::Duration) -> Response<Body> {
+    Response::builder()
+        .status(503)
+        .header(hyper::header::RETRY_AFTER, retry_after.as_secs().max(1).to_string())
+        .body(Body::from("Destination temporarily unavailable"))
+        .unwrap()
+}
+
+fn loop_detected_response() -> Response<Body> {
+    error_response(508, "loop_detected", "Request would loop back through this proxy")
+}
+
+// Max-Forwards reached zero: we are the final recipient of the TRACE or
+// OPTIONS, so answer it here. TRACE echoes the request head minus credentials.
+fn final_recipient_response(req: &Request<Body>) -> Response<Body> {
+    if req.method() == Method::OPTIONS {
+        return Response::builder()
+            .status(200)
+            .header(hyper::header::ALLOW, "GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS, TRACE, CONNECT")
+            .body(Body::empty())
+            .unwrap();
+    }
+    let mut echo = format!("{} {} {:?}\r\n", req.method(), req.uri(), req.version());
+    for (name, value) in req.headers() {
+        if name == PROXY_AUTHORIZATION || name == hyper::header::AUTHORIZATION || name == hyper::header::COOKIE {
+            continue;
+        }
+        echo.push_str(&format!("{}: {}\r\n", name, String::from_utf8_lossy(value.as_bytes())));
+    }
+    echo.push_str("\r\n");
+    Response::builder()
+        .status(200)
+        .header(hyper::header::CONTENT_TYPE, "message/http")
+        .body(Body::from(echo))
         .unwrap()
 }
 
-#[instrument(skip(req, config), fields(method = %req.method(), uri = %req.uri()))]
+fn unauthorized_response(realm: &str, config: &Config) -> Response<Body> {
+    // 407 with Proxy-Authenticate as required by spec
+    let challenge = format!("Basic realm=\"{}\"", realm.replace(['"', '\\'], ""));
+    let oidc = &config.oidc;
+    let body = match oidc.enabled() {
+        true => format!(
+            "Proxy authentication required. Sign in with POST {}/login on this proxy and send the token as Proxy-Authorization: Bearer <token>",
+            oidc.path
+        ),
+        false => "Proxy authentication required".to_string(),
+    };
+    let mut response = Response::builder()
+        .status(407)
+        .extension(errorpages::ProxyError::new("proxy_auth_required", &body));
+    // Listed first: browsers pick the first scheme they support
+    if config.kerberos.enabled() {
+        response = response.header(PROXY_AUTHENTICATE, "Negotiate");
+    }
+    response.header(PROXY_AUTHENTICATE, challenge).body(Body::from(body)).unwrap()
+}
+
+#[instrument(skip(req, state, listener, client_addr), fields(request_id = %request_id, listener = %listener.name, client_ip = %client_addr.ip(), method = %req.method(), uri = %req.uri()))]
 async fn handle_request(
-    req: Request<Body>,
-    config: Arc<Config>,
+    mut req: Request<Body>,
+    state: Arc<AppState>,
+    listener: Arc<listener::Listener>,
+    client_addr: SocketAddr,
+    request_id: String,
 ) -> Result<Response<Body>, Infallible> {
+    let config = &state.config;
+    let client = Peer {
+        addr: client_addr,
+        listener: listener.clone(),
+    };
     info!("📨 Incoming request: {} {}", req.method(), req.uri());
-    debug!("Request headers: {:?}", req.headers());
+    if config.server.log_headers {
+        debug!("Request headers: {:?}", req.headers());
+    }
+
+    // hyper already refuses heads over max_header_size
+    if config.limits.max_headers.is_some_and(|max| req.headers().len() > max) {
+        warn!("🚫 Refusing request from {} with {} header fields", client_addr, req.headers().len());
+        access_log(&request_id, &client, "-", req.method(), &req.uri().to_string(), 431, 0);
+        return Ok(error_response(431, "headers_too_large", "Too many header fields"));
+    }
 
     // Health check endpoint (no auth required)
     if req.method() == Method::GET && req.uri().path() == "/health" {
@@ -105,48 +968,840 @@ async fn handle_request(
             .unwrap());
     }
 
+    // Readiness: flips to 503 while the watchdog sees a problem
+    if req.method() == Method::GET && req.uri().path() == "/ready" {
+        let status = if state.watchdog.is_ready() { 200 } else { 503 };
+        return Ok(Response::builder()
+            .status(status)
+            .body(Body::from(if status == 200 { "READY" } else { "NOT READY" }))
+            .unwrap());
+    }
+    if req.method() == Method::GET && req.uri().path() == "/readyz" {
+        return Ok(drain::readyz(&state));
+    }
+
+    if state.bans.is_banned(client_addr.ip()) {
+        state.abuse.record(client_addr.ip(), abuse::Action::Blocked, &req.uri().to_string(), "banned");
+        access_log(&request_id, &client, "-", req.method(), &req.uri().to_string(), 403, 0);
+        return Ok(Response::builder()
+            .status(403)
+            .header(hyper::header::CONNECTION, "close")
+            .body(Body::from("Forbidden"))
+            .unwrap());
+    }
+
+    // Requests for the proxy's own sites, not to be proxied onward
+    if listener.reverse {
+        return handle_reverse(req, state.clone(), client, request_id).await;
+    }
+
+    // Prometheus metrics, only for requests addressed to the proxy itself
+    if config.metrics.enabled
+        && req.method() == Method::GET
+        && req.uri().authority().is_none()
+        && req.uri().path() == config.metrics.path
+    {
+        return Ok(Response::builder()
+            .status(200)
+            .header(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(metrics::render(&state)))
+            .unwrap());
+    }
+
+    // Admin API, authenticated by its own bearer token
+    if config.admin.matches(&req) {
+        return Ok(admin::handle(req, &state, client_addr).await);
+    }
+
+    // Pre-auth gate: knock URL, then nothing but a bare 403 for unknown IPs
+    if config.gate.enabled {
+        if req.uri().authority().is_none() && req.uri().path() == config.gate.path {
+            let granted = state.gate.knock(&config.gate, req.uri().query(), client_addr.ip());
+            let (status, body) = if granted { (200, "Access granted") } else { (403, "Forbidden") };
+            return Ok(Response::builder()
+                .status(status)
+                .body(Body::from(body))
+                .unwrap());
+        }
+        if !state.gate.is_allowed(&config.gate, client_addr.ip()) {
+            state.abuse.record(client_addr.ip(), abuse::Action::Blocked, &req.uri().to_string(), "gate");
+            access_log(&request_id, &client, "-", req.method(), &req.uri().to_string(), 403, 0);
+            return Ok(Response::builder()
+                .status(403)
+                .body(Body::from("Forbidden"))
+                .unwrap());
+        }
+    }
+
+    // OIDC device flow helper, for clients that have no token yet
+    if config.oidc.matches(&req) {
+        return Ok(oidc::handle(req, &state).await);
+    }
+
     // Require Proxy-Authorization for ALL requests (HTTP + CONNECT)
     let auth_header = req.headers().get(PROXY_AUTHORIZATION);
-    if !config.is_valid_basic(auth_header) {
-        warn!("🚫 Rejecting request due to invalid/missing proxy credentials");
-        return Ok(unauthorized_response());
+    if let Some(decoy) = basic_username(auth_header).filter(|u| config.honeypot.is_decoy(u)) {
+        honeypot_hit(&state, client_addr, &req.uri().to_string(), &decoy);
+        access_log(&request_id, &client, "-", req.method(), &req.uri().to_string(), 407, 0);
+        return Ok(unauthorized_response(&listener.realm, config));
+    }
+    let api_key = config.api_keys.presented(req.headers()).filter(|_| auth_header.is_none());
+    let pinned = req.extensions().get::<kerberos::Pinned>().cloned().unwrap_or_default();
+    let user = match (listener.auth, pinned.user().filter(|_| auth_header.is_none() && api_key.is_none())) {
+        // Transparent clients are known by their address alone
+        (false, _) => listener
+            .transparent
+            .as_ref()
+            .and_then(|t| t.user(client_addr.ip()))
+            .unwrap_or("-")
+            .to_string(),
+        (true, Some(user)) => user.to_string(),
+        (true, None) => {
+            let authenticated = match api_key {
+                Some(key) => state.api_key_user(key),
+                None => state.authenticate(auth_header).await,
+            };
+            match authenticated {
+                Some(user) => {
+                    if kerberos::is_negotiate(auth_header) {
+                        pinned.pin(&user);
+                    }
+                    user
+                }
+                None => {
+                    warn!("🚫 Rejecting request due to invalid/missing proxy credentials");
+                    let response = unauthorized_response(&listener.realm, config);
+                    access_log(&request_id, &client, "-", req.method(), &req.uri().to_string(), 407, 0);
+                    return Ok(response);
+                }
+            }
+        }
+    };
+    // The key is for us, like Proxy-Authorization
+    config.api_keys.strip(req.headers_mut());
+    if state.users.over_quota(&user, state.group(&user).and_then(|g| g.quota_bytes)) {
+        warn!("🚫 User '{}' is over their traffic quota", user);
+        access_log(&request_id, &client, &user, req.method(), &req.uri().to_string(), 403, 0);
+        return Ok(errorpages::blame(error_response(403, "quota_exceeded", "Traffic quota exhausted"), &user, None));
+    }
+    if req.method() != Method::CONNECT && !config.url_filters.is_empty() {
+        let host = req.uri().host().unwrap_or_default().to_string();
+        let path = req.uri().path_and_query().map_or("/", |p| p.as_str()).to_string();
+        match urlfilter::check(&config.url_filters, &host, &path) {
+            urlfilter::Verdict::Pass => {}
+            urlfilter::Verdict::Block(rule) => {
+                let rule = rule.describe();
+                info!("🧹 Refusing {}{} for '{}': URL filter {}", host, path, user, rule);
+                state.metrics.url_filter_denials.fetch_add(1, Ordering::Relaxed);
+                access_log(&request_id, &client, &user, req.method(), &req.uri().to_string(), 403, 0);
+                let response = error_response(403, "blocked", "URL is blocked by a filter");
+                return Ok(errorpages::blame(response, &user, Some(&rule)));
+            }
+            urlfilter::Verdict::Rewrite(rule, to) => match urlfilter::rewritten(req.uri(), &to) {
+                Ok(uri) => {
+                    debug!("🧹 Rewriting {}{} to {} by URL filter {}", host, path, to, rule.describe());
+                    // The origin sees the new host too
+                    if let Some(authority) = uri.authority().filter(|a| Some(*a) != req.uri().authority()) {
+                        if let Ok(value) = hyper::header::HeaderValue::from_str(authority.as_str()) {
+                            req.headers_mut().insert(hyper::header::HOST, value);
+                        }
+                    }
+                    *req.uri_mut() = uri;
+                }
+                Err(e) => warn!("⚠️ URL filter {} made an invalid target for {}{}: {}", rule.describe(), host, path, e),
+            },
+            urlfilter::Verdict::Redirect(rule, location, status) => {
+                match hyper::header::HeaderValue::from_str(&location) {
+                    Ok(value) => {
+                        info!("🧹 Redirecting {}{} to {} by URL filter {}", host, path, location, rule.describe());
+                        access_log(&request_id, &client, &user, req.method(), &req.uri().to_string(), status, 0);
+                        return Ok(Response::builder()
+                            .status(status)
+                            .header(hyper::header::LOCATION, value)
+                            .body(Body::empty())
+                            .unwrap());
+                    }
+                    Err(_) => warn!("⚠️ URL filter {} made an invalid Location for {}{}", rule.describe(), host, path),
+                }
+            }
+        }
+    }
+    if let Some((host, port)) = acl::destination(&req) {
+        if let Err(reason) = config.acl.check(&user, state.groups.group_of(&user), &host, port) {
+            warn!("⛔ User '{}' may not reach {}:{}: {}", user, host, port, reason);
+            state.metrics.acl_denials.fetch_add(1, Ordering::Relaxed);
+            access_log(&request_id, &client, &user, req.method(), &req.uri().to_string(), 403, 0);
+            let response = error_response(403, "forbidden", "Destination not allowed for this user");
+            return Ok(errorpages::blame(response, &user, Some(&reason)));
+        }
+    }
+    if let Some((host, _)) = acl::destination(&req) {
+        if let Some(list) = state.blocklists.check(&host) {
+            info!("🛑 Refusing {} for '{}': on blocklist '{}'", host, user, list);
+            state.metrics.count_blocklist_denial(list);
+            access_log(&request_id, &client, &user, req.method(), &req.uri().to_string(), 403, 0);
+            return Ok(errorpages::blame(error_response(403, "blocked", "Destination is on a blocklist"), &user, Some(list)));
+        }
+    }
+    if config.geoip.checks_destinations() {
+        if let Some((host, port)) = acl::destination(&req) {
+            match state.geoip.destination(&config.geoip, &host, port).await {
+                geoip::Verdict::Allow => {}
+                geoip::Verdict::Route(via) => {
+                    debug!("🌍 {} goes via '{}' by its location", host, via);
+                    req.extensions_mut().insert(geoip::Via(via));
+                }
+                geoip::Verdict::Deny(rule, ip) => {
+                    warn!("🌍 Refusing {} for '{}': {} is in {}", host, user, ip, rule);
+                    state.metrics.count_geoip_destination_denial(&rule);
+                    access_log(&request_id, &client, &user, req.method(), &req.uri().to_string(), 403, 0);
+                    let response = error_response(403, "forbidden", "Destination region is blocked");
+                    return Ok(errorpages::blame(response, &user, Some(&rule)));
+                }
+            }
+        }
+    }
+    if let (Some(pac), Some((host, port))) = (&state.pac, acl::destination(&req)) {
+        let routed = req.extensions().get::<geoip::Via>().is_some()
+            || state.routes(&user, &host).iter().any(|r| pattern::host_matches(&r.host, &host));
+        if state.flags.enabled(flags::ROUTING) && !routed {
+            let url = pac::url(req.method() == Method::CONNECT, req.uri().scheme_str(), &host, port);
+            let via = pac.via(&config.pac, &state.upstreams, url, host).await;
+            req.extensions_mut().insert(geoip::Via(via));
+        }
+    }
+    if let Err(blocked) = schedule::check(&config.schedules, &user, state.groups.group_of(&user), std::time::SystemTime::now()) {
+        warn!(
+            "🕒 User '{}' is outside the hours of schedule {} ({} {})",
+            user, blocked.schedule, blocked.now, blocked.timezone
+        );
+        state.metrics.schedule_denials.fetch_add(1, Ordering::Relaxed);
+        access_log(&request_id, &client, &user, req.method(), &req.uri().to_string(), 403, 0);
+        return Ok(Response::builder()
+            .status(403)
+            .header(hyper::header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .header(hyper::header::CACHE_CONTROL, "no-store")
+            .body(Body::from(schedule::block_page(&blocked)))
+            .unwrap());
     }
 
-    // Handle HTTPS CONNECT method vs normal HTTP
-    if req.method() == Method::CONNECT {
+    let via = state.via_token(&listener);
+    let started = std::time::Instant::now();
+    let domain = req.uri().host().unwrap_or("-").to_ascii_lowercase();
+    let response = if let Some(udp) = req.extensions_mut().remove::<masque::UdpRequest>() {
+        info!("Routing to CONNECT-UDP handler");
+        handle_connect_udp(req, udp, state.clone(), client, user.clone(), request_id).await
+    } else if req.method() == Method::CONNECT {
+        // Handle HTTPS CONNECT method vs normal HTTP
         info!("Routing to HTTPS CONNECT handler");
-        handle_connect(req).await
+        handle_connect(req, state.clone(), client, user.clone(), request_id, via).await
     } else {
         info!("Routing to HTTP proxy handler");
-        handle_http(req).await
+        let entry = state.har.begin(&user, &mut req);
+        let response = handle_http(req, state.clone(), client, user.clone(), request_id, via).await;
+        match entry {
+            Some(entry) => response.map(|response| entry.finish(response)),
+            None => response,
+        }
+    };
+    if config.metrics.enabled {
+        state.metrics.observe_request(&config.metrics, &user, &domain, &listener.name, started.elapsed());
     }
+    response
 }
 
-#[instrument(skip(req), fields(uri = %req.uri()))]
-async fn handle_http(req: Request<Body>) -> Result<Response<Body>, Infallible> {
-    info!("🌐 Forwarding HTTP request to: {}", req.uri());
-    let client = Client::new();
-    match client.request(req).await {
-        Ok(response) => {
+// Username from a Basic Proxy-Authorization header, without checking it.
+fn basic_username(header: Option<&hyper::header::HeaderValue>) -> Option<String> {
+    let value = header?.to_str().ok()?;
+    let (scheme, creds) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(BASE64.decode(creds.trim()).ok()?).ok()?;
+    decoded.split_once(':').map(|(user, _)| user.to_string())
+}
+
+// A decoy username was tried: ban the source and raise the alarm. The
+// client just sees an ordinary auth failure.
+fn honeypot_hit(state: &AppState, client_addr: SocketAddr, target: &str, user: &str) {
+    let honeypot = &state.config.honeypot;
+    let rule = format!("honeypot user '{}'", user);
+    warn!("🍯 Honeypot username '{}' used from {}", user, client_addr.ip());
+    state.bans.ban(client_addr.ip(), honeypot.ban_duration, &rule);
+    state.abuse.record(client_addr.ip(), abuse::Action::Banned, target, &rule);
+    if let Some(url) = &honeypot.webhook {
+        let json = format!(
+            "{{\"event\":\"honeypot\",\"timestamp\":\"{}\",\"client_ip\":\"{}\",\"username\":\"{}\"}}",
+            logging::rfc3339_now(),
+            client_addr.ip(),
+            logging::escape(user)
+        );
+        alert::send_webhook(url, json);
+    }
+}
+
+// Who sent a request and on which listener, for access logs and accounting.
+#[derive(Clone)]
+struct Peer {
+    addr: SocketAddr,
+    listener: Arc<listener::Listener>,
+}
+
+// Access log event with stable field names, shared by the text and JSON log
+// formats, unless [access_log] format sends it elsewhere.
+fn access_log(request_id: &str, client: &Peer, user: &str, method: &Method, target: &str, status: u16, bytes: u64) {
+    if parquet::enabled() {
+        parquet::record(parquet::Row {
+            timestamp_ms: parquet::now_ms(),
+            request_id: request_id.to_string(),
+            client_ip: client.addr.ip().to_string(),
+            user: user.to_string(),
+            method: method.to_string(),
+            target: target.to_string(),
+            status: status.into(),
+            bytes: bytes as i64,
+            listener: client.listener.name.clone(),
+        });
+    }
+    let event = access::AccessEvent {
+        time: std::time::SystemTime::now(),
+        request_id,
+        client_addr: client.addr,
+        listener: &client.listener.name,
+        user,
+        method,
+        target,
+        status,
+        bytes,
+    };
+    if access::record(&event) {
+        return;
+    }
+    info!(
+        request_id = %request_id,
+        client_ip = %client.addr.ip(),
+        listener = %client.listener.name,
+        user = %user,
+        method = %method,
+        target = %target,
+        status = status,
+        bytes = bytes,
+        "access"
+    );
+}
+
+fn content_length(headers: &hyper::HeaderMap) -> u64 {
+    headers
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
+}
+
+#[instrument(skip(req, state, client, user, request_id, via), fields(uri = %req.uri()))]
+async fn handle_http(
+    mut req: Request<Body>,
+    state: Arc<AppState>,
+    client: Peer,
+    user: String,
+    request_id: String,
+    via: String,
+) -> Result<Response<Body>, Infallible> {
+    let config = &state.config;
+    let method = req.method().clone();
+    let target = req.uri().to_string();
+    let host = req.uri().host().unwrap_or_default().to_string();
+    let path = req.uri().path().to_string();
+    if host.is_empty() || req.uri().scheme_str() != Some("http") {
+        warn!("⚠️ Cannot forward {}: expected an absolute http:// URI", target);
+        access_log(&request_id, &client, &user, &method, &target, 400, 0);
+        return Ok(error_response(400, "bad_request", "Expected an absolute http:// URI"));
+    }
+
+    let limits = &config.limits;
+    if bodylimit::too_long(req.headers(), limits.max_request_body) {
+        warn!("🚫 Refusing {}: {}-byte request body is over the limit", target, content_length(req.headers()));
+        state.metrics.count_size_limit("request");
+        access_log(&request_id, &client, &user, &method, &target, 413, 0);
+        return Ok(error_response(413, "payload_too_large", "Request body exceeds the proxy's limit"));
+    }
+
+    let port = req.uri().port_u16().unwrap_or(80);
+    if loops::seen_before(req.headers(), &via) || loops::targets_listener(&state.listen_addrs(), &host, port).await {
+        warn!("🔁 Refusing {}: it would loop back through this proxy", target);
+        access_log(&request_id, &client, &user, &method, &target, 508, 0);
+        return Ok(loop_detected_response());
+    }
+    if loops::max_forwards(&method, req.headers_mut()) == Some(0) {
+        debug!("Max-Forwards exhausted, answering {} {} ourselves", method, target);
+        access_log(&request_id, &client, &user, &method, &target, 200, 0);
+        return Ok(final_recipient_response(&req));
+    }
+    loops::add_via(req.headers_mut(), &via);
+    if let Some(header) = &config.server.request_id_header {
+        if let (Ok(name), Ok(value)) = (
+            hyper::header::HeaderName::from_bytes(header.as_bytes()),
+            hyper::header::HeaderValue::from_str(&request_id),
+        ) {
+            req.headers_mut().insert(name, value);
+        }
+    }
+    let injected = userheaders::apply(&config.user_headers, &user, state.groups.group_of(&user), &host, req.headers_mut());
+    if !injected.is_empty() {
+        debug!("Added header(s) {} for user '{}'", injected.join(", "), user);
+    }
+    // Responses to HEAD have no body to compress
+    let compression = compression::negotiate(req.headers()).filter(|_| method != Method::HEAD);
+    let private = config.privacy.covers(&user, state.groups.group_of(&user));
+    if private {
+        config.privacy.request(&host, req.headers_mut(), &state.metrics);
+    }
+    headerrules::apply(&config.headers.request, &host, req.headers_mut());
+    // Upgraded connections carry no HTTP bodies to scan, cache or rewrite
+    if websocket::requested(req.headers()) {
+        let geo = req.extensions().get::<geoip::Via>().cloned();
+        let route = state.route(&user, &host, geo.as_ref());
+        info!("🔀 Forwarding protocol upgrade for {} via {}", target, route.name());
+        let outbound = state.outbound(&user);
+        let (account, name, who) = (state.clone(), client.listener.name.clone(), user.clone());
+        let closed = move |from_client, from_server| account.account(&who, &name, from_client + from_server);
+        return match websocket::relay(req, |req| forward(req, &route, outbound, false), closed).await {
+            Ok(response) => {
+                access_log(&request_id, &client, &user, &method, &target, response.status().as_u16(), 0);
+                Ok(response)
+            }
+            Err(err) => {
+                let (status, code) = upstream_error_status(&err);
+                error!("❌ HTTP proxy error: {}", err);
+                access_log(&request_id, &client, &user, &method, &target, status, 0);
+                Ok(errorpages::blame(error_response(status, code, &err.to_string()), &user, None))
+            }
+        };
+    }
+    if config.icap.scans_requests(&host) {
+        req = match icap::reqmod(&config.icap, req, &state.metrics).await {
+            icap::Scanned::Forward(req) => req,
+            icap::Scanned::Respond(response) => {
+                access_log(&request_id, &client, &user, &method, &target, response.status().as_u16(), 0);
+                return Ok(response);
+            }
+        };
+    }
+    if let Some(rule) = config.mirror.rule(&host, &path) {
+        match state.mirror_slots.try_acquire() {
+            Some(slot) => match mirror::duplicate(&mut req, rule, config.mirror.max_body).await {
+                Ok(copy) => {
+                    tokio::spawn(mirror_request(state.clone(), copy, slot));
+                }
+                Err(result) => state.metrics.count_mirror(result),
+            },
+            None => state.metrics.count_mirror("busy"),
+        }
+    }
+    let throttle = state.throttle(&user, &host);
+
+    // Only anonymous GETs are shared through the cache; per-user headers
+    // and cookies make a request as personal as Authorization does
+    let cache_policy = (config.cache.enabled
+        && state.flags.enabled(flags::CACHE)
+        && method == Method::GET
+        && !req.headers().contains_key(hyper::header::AUTHORIZATION)
+        && !req.headers().contains_key(hyper::header::COOKIE)
+        && injected.is_empty())
+    .then(|| config.cache.policy(&host, &path))
+    .filter(|policy| policy.is_cacheable());
+
+    let cache_key = cache_policy.map(|_| config.cache.key(req.uri(), req.headers(), &user));
+    // The request as the stored copy will be varied on
+    let cache_request = cache_key.as_ref().map(|_| req.headers().clone());
+    let cache_variant = cache_key.as_ref().map(|key| state.cache.variant(key, req.headers()));
+
+    if let (Some(policy), Some(key), Some(variant)) = (cache_policy, &cache_key, &cache_variant) {
+        let response = match state.cache.lookup(variant) {
+            cache::Lookup::Fresh(response) => {
+                info!("💾 Cache hit for {}", target);
+                Some(response)
+            }
+            cache::Lookup::Stale {
+                response,
+                revalidate,
+            } => {
+                info!("💾 Serving stale {} while revalidating", target);
+                if revalidate {
+                    let mut headers = req.headers().clone();
+                    headers.remove(PROXY_AUTHORIZATION);
+                    tokio::spawn(revalidate_cached(
+                        state.clone(),
+                        target.clone(),
+                        key.clone(),
+                        headers,
+                        policy,
+                    ));
+                }
+                Some(response)
+            }
+            cache::Lookup::Miss => None,
+        };
+        if let Some(mut response) = response {
+            if private {
+                config.privacy.response(&host, response.headers_mut(), &state.metrics);
+            }
+            let response = rewrite::inject_banner(state.banners(), &config.filters, &state.metrics, &host, &path, response);
+            let bytes = content_length(response.headers());
+            let response = compression::apply(&config.compression, compression, &state.metrics, response)
+                .map(|body| bandwidth::throttle_body(body, throttle));
+            access_log(&request_id, &client, &user, &method, &target, response.status().as_u16(), bytes);
+            state.account(&user, &client.listener.name, bytes);
+            return Ok(response);
+        }
+    }
+
+    // Breakers are keyed by host:port, like CONNECT targets
+    let breakers = &config.circuit_breaker;
+    let authority = format!("{}:{}", host, req.uri().port_u16().unwrap_or(80));
+    if let Err(wait) = state.breakers.check(breakers, &authority) {
+        warn!("🔌 Circuit open for {}, refusing {}", host, target);
+        state.metrics.circuit_rejections.fetch_add(1, Ordering::Relaxed);
+        access_log(&request_id, &client, &user, &method, &target, 503, 0);
+        return Ok(circuit_open_response(wait));
+    }
+
+    let geo = req.extensions().get::<geoip::Via>().cloned();
+    let route = state.route(&user, &host, geo.as_ref());
+    info!("🌐 Forwarding HTTP request to: {} via {}", req.uri(), route.name());
+    // Only bodiless requests can be replayed (fallback route, retries)
+    let fallback = match route {
+        upstream::Route::Parent(_) => state.fallback_route(&user, &host, geo.as_ref()),
+        upstream::Route::Direct(..) | upstream::Route::Interface(..) => None,
+    };
+    let replay = (!has_body(req.headers())
+        && (fallback.is_some() || config.retry.applies_to(&method)))
+    .then(|| retry::Replay::new(&req));
+    let (body, request_overflow) =
+        bodylimit::cap(std::mem::take(req.body_mut()), req.headers(), limits.max_request_body, "request", &state.metrics);
+    *req.body_mut() = body;
+    let scan_head = config.icap.scans_responses(&host).then(|| icap::head(&req));
+    let req = req.map(|body| bandwidth::throttle_body(body, throttle.clone()));
+    let outbound = state.outbound(&user);
+    let http2 = config.http2.prior_knowledge(&host);
+    let mut result = forward(req, &route, outbound.clone(), http2).await;
+    if let Some(refused) = result.as_ref().err().and_then(refused_by_guard) {
+        warn!("🛡️ Refusing {} for '{}': {}", target, user, refused);
+        state.metrics.private_denials.fetch_add(1, Ordering::Relaxed);
+        access_log(&request_id, &client, &user, &method, &target, 403, 0);
+        let response = error_response(403, "forbidden", "Destination is an internal address");
+        return Ok(errorpages::blame(response, &user, Some("deny_private")));
+    }
+    if let (Some(fallback), Some(replay)) = (fallback, &replay) {
+        if matches!(&result, Err(e) if e.is_connect()) {
+            warn!(
+                "⚠️ Upstream '{}' unreachable, retrying {} via {}",
+                route.name(),
+                target,
+                fallback.name()
+            );
+            result = forward(replay.request(), &fallback, outbound.clone(), http2).await;
+        }
+    }
+    if let Some(replay) = replay.filter(|_| config.retry.applies_to(&method)) {
+        for attempt in 1..=config.retry.attempts {
+            if !config.retry.should_retry(&result) {
+                break;
+            }
+            let delay = config.retry.backoff(attempt);
+            match &result {
+                Ok(response) => warn!("🔁 {} returned {}, retry {} in {:?}", target, response.status(), attempt, delay),
+                Err(e) => warn!("🔁 {} failed ({}), retry {} in {:?}", target, e, attempt, delay),
+            }
+            tokio::time::sleep(delay).await;
+            result = forward(replay.request(), &state.route(&user, &host, geo.as_ref()), outbound.clone(), http2).await;
+        }
+    }
+    match &result {
+        Ok(response) if !matches!(response.status().as_u16(), 502..=504) => {
+            state.breakers.record_success(breakers, &authority)
+        }
+        // The client's fault, not the origin's
+        Err(_) if request_overflow.happened() => {}
+        _ => state.breakers.record_failure(breakers, &authority),
+    }
+
+    // stale-if-error: origin unreachable or failing, fall back to a stale copy
+    let origin_failed = match &result {
+        Ok(response) => response.status().is_server_error(),
+        Err(_) => true,
+    };
+    if let (Some(variant), true) = (&cache_variant, origin_failed) {
+        if let Some(mut response) = state.cache.stale_if_error(variant) {
+            warn!("⚠️ Origin failing for {}, serving stale copy", target);
+            if private {
+                config.privacy.response(&host, response.headers_mut(), &state.metrics);
+            }
+            let response = rewrite::inject_banner(state.banners(), &config.filters, &state.metrics, &host, &path, response);
+            let bytes = content_length(response.headers());
+            let response = compression::apply(&config.compression, compression, &state.metrics, response)
+                .map(|body| bandwidth::throttle_body(body, throttle));
+            access_log(&request_id, &client, &user, &method, &target, response.status().as_u16(), bytes);
+            state.account(&user, &client.listener.name, bytes);
+            return Ok(response);
+        }
+    }
+
+    match result {
+        Ok(response) if bodylimit::too_long(response.headers(), limits.max_response_body) => {
+            warn!(
+                "🚫 Refusing {}: {}-byte response body is over the limit",
+                target,
+                content_length(response.headers())
+            );
+            state.metrics.count_size_limit("response");
+            access_log(&request_id, &client, &user, &method, &target, 502, 0);
+            Ok(error_response(502, "response_too_large", "Response body exceeds the proxy's limit"))
+        }
+        Ok(mut response) => {
+            let (body, _) = bodylimit::cap(
+                std::mem::take(response.body_mut()),
+                response.headers(),
+                limits.max_response_body,
+                "response",
+                &state.metrics,
+            );
+            *response.body_mut() = body;
+            // Before the cache, so only scanned content is stored
+            if let Some(head) = &scan_head {
+                response = icap::respmod(&config.icap, head, response, &state.metrics).await;
+            }
+            deprecation::apply(state.deprecations(), &host, &path, response.headers_mut());
+            headerrules::apply(&config.headers.response, &host, response.headers_mut());
+            if let (Some(policy), Some(key), Some(request)) = (cache_policy, cache_key, &cache_request) {
+                if let Some(freshness) =
+                    cache::freshness(policy, response.status(), response.headers())
+                {
+                    response = state
+                        .cache
+                        .store_response(key, request, freshness, config.cache.max_object_size, response)
+                        .await;
+                }
+            }
+            // After the cache, which is shared with users outside the profile
+            if private {
+                config.privacy.response(&host, response.headers_mut(), &state.metrics);
+            }
+            response = rewrite::inject_banner(state.banners(), &config.filters, &state.metrics, &host, &path, response);
             info!(
                 "✅ HTTP request forwarded successfully, status: {}",
                 response.status()
             );
-            debug!("Response headers: {:?}", response.headers());
-            Ok(response)
+            if config.server.log_headers {
+                debug!("Response headers: {:?}", response.headers());
+            }
+            let bytes = content_length(response.headers());
+            access_log(&request_id, &client, &user, &method, &target, response.status().as_u16(), bytes);
+            state.account(&user, &client.listener.name, bytes);
+            let response = compression::apply(&config.compression, compression, &state.metrics, response);
+            Ok(response.map(|body| bandwidth::throttle_body(body, throttle)))
+        }
+        Err(_) if request_overflow.happened() => {
+            access_log(&request_id, &client, &user, &method, &target, 413, 0);
+            Ok(error_response(413, "payload_too_large", "Request body exceeds the proxy's limit"))
         }
         Err(err) => {
+            let (status, code) = upstream_error_status(&err);
             error!("❌ HTTP proxy error: {}", err);
-            Ok(Response::builder()
-                .status(500)
-                .body(Body::from(format!("Proxy error: {}", err)))
-                .unwrap())
+            access_log(&request_id, &client, &user, &method, &target, status, 0);
+            Ok(errorpages::blame(error_response(status, code, &err.to_string()), &user, None))
+        }
+    }
+}
+
+// A request to one of the [reverse] sites, sent on to its backend.
+async fn handle_reverse(
+    mut req: Request<Body>,
+    state: Arc<AppState>,
+    client: Peer,
+    request_id: String,
+) -> Result<Response<Body>, Infallible> {
+    let config = &state.config;
+    let method = req.method().clone();
+    let host = req
+        .uri()
+        .host()
+        .or_else(|| req.headers().get(hyper::header::HOST).and_then(|v| v.to_str().ok()))
+        .map(|host| pattern::strip_port(host).to_ascii_lowercase())
+        .unwrap_or_default();
+    let path = req.uri().path().to_string();
+    let scheme = if client.listener.tls { "https" } else { "http" };
+    let target = format!("{}://{}{}", scheme, host, req.uri().path_and_query().map_or("/", |p| p.as_str()));
+    let Some(site) = config.reverse.site(&host, &path) else {
+        info!("No site for {}", target);
+        access_log(&request_id, &client, "-", &method, &target, 404, 0);
+        return Ok(error_response(404, "not_found", "No site is served here for this host and path"));
+    };
+    if bodylimit::too_long(req.headers(), config.limits.max_request_body) {
+        warn!("🚫 Refusing {}: {}-byte request body is over the limit", target, content_length(req.headers()));
+        state.metrics.count_size_limit("request");
+        access_log(&request_id, &client, "-", &method, &target, 413, 0);
+        return Ok(error_response(413, "payload_too_large", "Request body exceeds the proxy's limit"));
+    }
+    site.rewrite(&mut req, client.addr, client.listener.tls);
+    info!("↪️ Reverse proxying {} to {}", target, site.backend());
+    let backend = site.client();
+    let result = match websocket::requested(req.headers()) {
+        true => websocket::relay(req, |req| backend.request(req), |_, _| {}).await,
+        false => backend.request(req).await,
+    };
+    match result {
+        Ok(mut response) => {
+            deprecation::apply(state.deprecations(), &host, &path, response.headers_mut());
+            headerrules::apply(&config.headers.response, &host, response.headers_mut());
+            site.rewrite_response(response.headers_mut());
+            let bytes = content_length(response.headers());
+            access_log(&request_id, &client, "-", &method, &target, response.status().as_u16(), bytes);
+            Ok(response)
+        }
+        Err(err) => {
+            let (status, code) = upstream_error_status(&err);
+            error!("❌ Backend {} failed: {}", site.backend(), err);
+            access_log(&request_id, &client, "-", &method, &target, status, 0);
+            Ok(error_response(status, code, "The site's backend could not be reached"))
         }
     }
 }
 
-#[instrument(skip(req), fields(uri = %req.uri()))]
-async fn handle_connect(mut req: Request<Body>) -> Result<Response<Body>, Infallible> {
+// Send a mirrored copy of a request and throw the response away.
+async fn mirror_request(state: Arc<AppState>, req: Request<Body>, _slot: limits::SlotGuard) {
+    let target = req.uri().to_string();
+    let host = req.uri().host().unwrap_or_default().to_string();
+    let route = state.route("", &host, None);
+    let timeout = std::time::Duration::from_secs(state.config.mirror.timeout);
+    let sent = tokio::time::timeout(timeout, async {
+        let http2 = state.config.http2.prior_knowledge(&host);
+        let response = forward(req, &route, egress::Outbound::default(), http2).await?;
+        let status = response.status();
+        // Drain so the connection can be reused
+        hyper::body::to_bytes(response.into_body()).await?;
+        Ok::<_, hyper::Error>(status)
+    })
+    .await;
+    let result = match sent {
+        Ok(Ok(status)) => {
+            debug!("Mirrored {}: {}", target, status);
+            "sent"
+        }
+        Ok(Err(e)) => {
+            debug!("Mirroring {} failed: {}", target, e);
+            "failed"
+        }
+        Err(_) => {
+            debug!("Mirroring {} timed out", target);
+            "timeout"
+        }
+    };
+    state.metrics.count_mirror(result);
+}
+
+// Send a plain HTTP request to its origin along `route`. With `http2` the
+// origin is spoken to in HTTP/2 directly, which a parent proxy would not
+// understand, so only routes that reach it themselves use it.
+async fn forward(
+    mut req: Request<Body>,
+    route: &upstream::Route,
+    outbound: egress::Outbound,
+    http2: bool,
+) -> hyper::Result<Response<Body>> {
+    set_upstream_auth(req.headers_mut(), route);
+    let http2 = http2 && !matches!(route, upstream::Route::Parent(_));
+    let client = Client::builder().http2_only(http2).build(upstream::RouteConnector::new(route.clone(), outbound));
+    let _lease = route.lease();
+    client.request(req).await
+}
+
+fn has_body(headers: &hyper::HeaderMap) -> bool {
+    headers.contains_key(hyper::header::TRANSFER_ENCODING) || content_length(headers) > 0
+}
+
+// The client's proxy credentials are for us; only an HTTP parent proxy gets
+// Proxy-Authorization, and then with its own credentials.
+fn set_upstream_auth(headers: &mut hyper::HeaderMap, route: &upstream::Route) {
+    headers.remove(PROXY_AUTHORIZATION);
+    if let upstream::Route::Parent(upstream) = route {
+        if upstream.proxy.kind != upstream::UpstreamKind::Http {
+            return;
+        }
+        if let Some(value) = upstream
+            .proxy
+            .proxy_authorization()
+            .and_then(|v| hyper::header::HeaderValue::from_str(&v).ok())
+        {
+            headers.insert(PROXY_AUTHORIZATION, value);
+        }
+    }
+}
+
+// Refresh a stale cache entry in the background (stale-while-revalidate).
+async fn revalidate_cached(
+    state: Arc<AppState>,
+    target: String,
+    key: String,
+    headers: hyper::HeaderMap,
+    policy: cache::Policy,
+) {
+    let config = &state.config;
+    let uri: hyper::Uri = match target.parse() {
+        Ok(uri) => uri,
+        Err(_) => return,
+    };
+    let host = uri.host().unwrap_or_default().to_string();
+    let path = uri.path().to_string();
+    // Cached responses are shared, so no group's routes apply
+    let route = state.route("", &host, None);
+    let variant = state.cache.variant(&key, &headers);
+    let mut req = Request::new(Body::empty());
+    *req.headers_mut() = headers.clone();
+    *req.uri_mut() = uri;
+
+    debug!("Revalidating cached {}", target);
+    let outbound = egress::Outbound {
+        guard: config.acl.deny_private.then(|| state.private_guard.clone()),
+        source: None,
+    };
+    match forward(req, &route, outbound, config.http2.prior_knowledge(&host)).await {
+        Ok(mut response) if response.status().is_success() => {
+            deprecation::apply(state.deprecations(), &host, &path, response.headers_mut());
+            headerrules::apply(&config.headers.response, &host, response.headers_mut());
+            match cache::freshness(policy, response.status(), response.headers()) {
+                Some(freshness) => {
+                    let response = state
+                        .cache
+                        .store_response(key, &headers, freshness, config.cache.max_object_size, response)
+                        .await;
+                    // Drain so the connection can be reused
+                    let _ = hyper::body::to_bytes(response.into_body()).await;
+                    info!("💾 Revalidated {}", target);
+                }
+                None => state.cache.revalidation_failed(&variant),
+            }
+        }
+        Ok(response) => {
+            warn!("⚠️ Revalidation of {} returned {}", target, response.status());
+            state.cache.revalidation_failed(&variant);
+        }
+        Err(e) => {
+            warn!("⚠️ Revalidation of {} failed: {}", target, e);
+            state.cache.revalidation_failed(&variant);
+        }
+    }
+}
+
+#[instrument(skip(req, state, client, user, request_id, via), fields(uri = %req.uri()))]
+async fn handle_connect(
+    mut req: Request<Body>,
+    state: Arc<AppState>,
+    client: Peer,
+    user: String,
+    request_id: String,
+    via: String,
+) -> Result<Response<Body>, Infallible> {
     let uri_str = req.uri().to_string();
 
     // Extract host:port from URI
@@ -172,6 +1827,22 @@ async fn handle_connect(mut req: Request<Body>) -> Result<Response<Body>, Infall
         target = format!("{}:443", target);
     }
 
+    let Some((host, port)) = target
+        .parse::<hyper::http::uri::Authority>()
+        .ok()
+        .and_then(|authority| Some((authority.host().to_string(), authority.port_u16()?)))
+    else {
+        warn!("⚠️ Invalid CONNECT target: {}", target);
+        access_log(&request_id, &client, &user, &Method::CONNECT, &target, 400, 0);
+        return Ok(error_response(400, "bad_request", "CONNECT target must be host:port"));
+    };
+
+    if loops::seen_before(req.headers(), &via) || loops::targets_listener(&state.listen_addrs(), &host, port).await {
+        warn!("🔁 Refusing CONNECT to {}: it would loop back through this proxy", target);
+        access_log(&request_id, &client, &user, &Method::CONNECT, &target, 508, 0);
+        return Ok(loop_detected_response());
+    }
+
     info!("🔐 Handling HTTPS CONNECT request to: {}", target);
     debug!(
         "CONNECT request details - URI: {}, Version: {:?}",
@@ -179,19 +1850,94 @@ async fn handle_connect(mut req: Request<Body>) -> Result<Response<Body>, Infall
         req.version()
     );
 
+    if let Err(wait) = state.breakers.check(&state.config.circuit_breaker, &target) {
+        warn!("🔌 Circuit open for {}, refusing tunnel", target);
+        state.metrics.circuit_rejections.fetch_add(1, Ordering::Relaxed);
+        access_log(&request_id, &client, &user, &Method::CONNECT, &target, 503, 0);
+        return Ok(circuit_open_response(wait));
+    }
+
+    certwatch::observe(&state, &host, port);
+
+    let Some(tunnel_slot) = state.tunnel_slots.try_acquire() else {
+        warn!(
+            "🚫 Tunnel capacity reached ({} active), refusing {}",
+            state.tunnel_slots.active(),
+            target
+        );
+        access_log(&request_id, &client, &user, &Method::CONNECT, &target, 503, 0);
+        return Ok(overloaded_response("Proxy tunnel capacity reached"));
+    };
+
+    let limit = state
+        .users
+        .limits(&user)
+        .max_connections
+        .or(state.group(&user).and_then(|g| g.max_connections))
+        .or(state.config.limits.max_connections_per_user);
+    let open = limits::OpenTunnel {
+        user: user.clone(),
+        listener: client.listener.name.clone(),
+        client: client.addr,
+        target: target.clone(),
+        since: std::time::Instant::now(),
+        activity: Arc::new(reaper::Activity::capped(state.config.limits.max_tunnel_bytes)),
+    };
+    let guard = match state.connections.acquire(open.clone(), limit) {
+        Some(guard) => guard,
+        None => {
+            warn!(
+                "🚫 User '{}' is at the tunnel limit ({} open)",
+                user,
+                state.connections.active(&user)
+            );
+            access_log(&request_id, &client, &user, &Method::CONNECT, &target, 429, 0);
+            return Ok(Response::builder()
+                .status(429)
+                .extension(errorpages::ProxyError::new("too_many_tunnels", "Too many concurrent tunnels"))
+                .body(Body::from("Too many concurrent tunnels"))
+                .unwrap());
+        }
+    };
+
     tokio::task::spawn(async move {
-        match hyper::upgrade::on(&mut req).await {
+        let host = pattern::strip_port(&target);
+        let throttle = state.throttle(&user, host);
+        let geo = req.extensions().get::<geoip::Via>();
+        let route = state.route(&user, host, geo);
+        let fallback = state.fallback_route(&user, host, geo);
+        // Held for the lifetime of the tunnel
+        let client_slot = req.extensions_mut().remove::<Arc<limits::SlotGuard>>();
+        let _guards = (guard, tunnel_slot, client_slot);
+        let client_io = match req.extensions_mut().remove::<listener::PendingTunnel>() {
+            Some(pending) => pending.0.await.map(listener::TunnelClient::Stream).map_err(|_| "client went away".to_string()),
+            None => hyper::upgrade::on(&mut req).await.map(listener::TunnelClient::Upgraded).map_err(|e| e.to_string()),
+        };
+        match client_io {
             Ok(upgraded) => {
                 info!("✅ Connection upgraded for CONNECT tunnel to {}", target);
-                if let Err(e) = tunnel(upgraded, target).await {
-                    error!("❌ Tunnel error: {}", e);
+                match tunnel(upgraded, &state, &open, route, fallback, throttle, &via).await {
+                    Ok((from_client, from_server)) => {
+                        let bytes = from_client + from_server;
+                        access_log(&request_id, &client, &user, &Method::CONNECT, &target, 200, bytes);
+                        state.account(&user, &client.listener.name, bytes);
+                    }
+                    Err(e) if egress::refused(&e).is_some() => {
+                        warn!("🛡️ Refusing CONNECT to {} for '{}': {}", target, user, e);
+                        state.metrics.private_denials.fetch_add(1, Ordering::Relaxed);
+                        access_log(&request_id, &client, &user, &Method::CONNECT, &target, 403, 0);
+                    }
+                    Err(e) => {
+                        error!("❌ Tunnel error: {}", e);
+                        access_log(&request_id, &client, &user, &Method::CONNECT, &target, 502, 0);
+                    }
                 }
             }
             Err(e) => {
                 error!("❌ Upgrade error: {}", e);
             }
         }
-    });
+    }.instrument(tracing::Span::current()));
 
     Ok(Response::builder()
         .status(200)
@@ -199,125 +1945,738 @@ async fn handle_connect(mut req: Request<Body>) -> Result<Response<Body>, Infall
         .unwrap())
 }
 
-// Create a tunnel between client and target server
-async fn tunnel(mut upgraded: Upgraded, target: String) -> std::io::Result<()> {
-    info!("🔗 Establishing tunnel to {}", target);
+// A CONNECT-UDP session from an HTTP/3 client, already through the ACLs
+// as a CONNECT to the same host and port. The socket is connected before
+// answering, so the client learns at once if the target cannot be used.
+async fn handle_connect_udp(
+    mut req: Request<Body>,
+    udp: masque::UdpRequest,
+    state: Arc<AppState>,
+    client: Peer,
+    user: String,
+    request_id: String,
+) -> Result<Response<Body>, Infallible> {
+    let config = &state.config.masque;
+    let Some((host, port)) = acl::destination(&req) else {
+        return Ok(error_response(400, "bad_request", "CONNECT-UDP needs a target host and port"));
+    };
+    let target = format!("udp://{}:{}", host, port);
+    if !config.allows_port(port) {
+        warn!("⛔ Refusing {} for '{}': UDP port not allowed", target, user);
+        state.metrics.acl_denials.fetch_add(1, Ordering::Relaxed);
+        access_log(&request_id, &client, &user, &Method::CONNECT, &target, 403, 0);
+        return Ok(error_response(403, "forbidden", "UDP port not allowed"));
+    }
+    if loops::targets_listener(&state.listen_addrs(), &host, port).await {
+        warn!("🔁 Refusing {}: it would loop back through this proxy", target);
+        access_log(&request_id, &client, &user, &Method::CONNECT, &target, 508, 0);
+        return Ok(loop_detected_response());
+    }
+    let Some(tunnel_slot) = state.tunnel_slots.try_acquire() else {
+        warn!("🚫 Tunnel capacity reached ({} active), refusing {}", state.tunnel_slots.active(), target);
+        access_log(&request_id, &client, &user, &Method::CONNECT, &target, 503, 0);
+        return Ok(overloaded_response("Proxy tunnel capacity reached"));
+    };
+    let limit = state
+        .users
+        .limits(&user)
+        .max_connections
+        .or(state.group(&user).and_then(|g| g.max_connections))
+        .or(state.config.limits.max_connections_per_user);
+    let open = limits::OpenTunnel {
+        user: user.clone(),
+        listener: client.listener.name.clone(),
+        client: client.addr,
+        target: target.clone(),
+        since: std::time::Instant::now(),
+        activity: Arc::new(reaper::Activity::capped(state.config.limits.max_tunnel_bytes)),
+    };
+    let Some(guard) = state.connections.acquire(open.clone(), limit) else {
+        warn!("🚫 User '{}' is at the tunnel limit ({} open)", user, state.connections.active(&user));
+        access_log(&request_id, &client, &user, &Method::CONNECT, &target, 429, 0);
+        return Ok(error_response(429, "too_many_tunnels", "Too many concurrent tunnels"));
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let socket = match egress::bind_udp(host, port, state.config.server.ip_family, &state.outbound(&user)).await {
+        Ok(socket) => socket,
+        Err(e) if egress::refused(&e).is_some() => {
+            warn!("🛡️ Refusing {} for '{}': {}", target, user, e);
+            state.metrics.private_denials.fetch_add(1, Ordering::Relaxed);
+            access_log(&request_id, &client, &user, &Method::CONNECT, &target, 403, 0);
+            return Ok(error_response(403, "forbidden", &e.to_string()));
+        }
+        Err(e) => {
+            error!("❌ Cannot open {}: {}", target, e);
+            access_log(&request_id, &client, &user, &Method::CONNECT, &target, 502, 0);
+            return Ok(error_response(502, "bad_gateway", &e.to_string()));
+        }
+    };
+    info!("📡 Relaying UDP to {} for '{}'", target, user);
+    // Held for the lifetime of the session
+    let guards = (guard, tunnel_slot, req.extensions_mut().remove::<Arc<limits::SlotGuard>>());
+    let account = state.clone();
+    let closed = move |from_client, from_server| {
+        let _guards = guards;
+        let bytes = from_client + from_server;
+        access_log(&request_id, &client, &user, &Method::CONNECT, &target, 200, bytes);
+        account.account(&user, &client.listener.name, bytes);
+    };
+    let session = masque::Session {
+        socket,
+        activity: open.activity.clone(),
+        limiters: (config.limiter(), config.limiter()),
+        idle_timeout: std::time::Duration::from_secs(config.idle_timeout),
+        closed: Box::new(closed),
+    };
+    if udp.0.send(session).is_err() {
+        debug!("CONNECT-UDP client went away before the session started");
+    }
+    Ok(Response::builder()
+        .status(200)
+        .header("capsule-protocol", "?1")
+        .body(Body::empty())
+        .unwrap())
+}
+
+// Create a tunnel between client and target server.
+// Returns (bytes from client, bytes from server) once both sides close,
+// or the reaper closes the tunnel.
+async fn tunnel(
+    upgraded: listener::TunnelClient,
+    state: &AppState,
+    open: &limits::OpenTunnel,
+    route: upstream::Route,
+    fallback: Option<upstream::Route>,
+    throttle: bandwidth::Throttle,
+    via: &str,
+) -> std::io::Result<(u64, u64)> {
+    let target = &open.target;
+    info!("🔗 Establishing tunnel to {} via {}", target, route.name());
 
-    let mut server = TcpStream::connect(&target).await?;
+    let mut lease = route.lease();
+    let mut via_parent = matches!(route, upstream::Route::Parent(_));
+    let outbound = state.outbound(&open.user);
+    let mut connected = upstream::connect(&route, target, via, &outbound).await;
+    // Only failures to reach the parent itself fail over
+    if let (Err(e), Some(fallback)) = (&connected, fallback) {
+        if matches!(&route, upstream::Route::Parent(u) if !u.is_healthy()) {
+            warn!(
+                "⚠️ Upstream '{}' unreachable ({}), tunnelling to {} via {}",
+                route.name(),
+                e,
+                target,
+                fallback.name()
+            );
+            lease = fallback.lease();
+            via_parent = matches!(fallback, upstream::Route::Parent(_));
+            connected = upstream::connect(&fallback, target, via, &outbound).await;
+        }
+    }
+    let breakers = &state.config.circuit_breaker;
+    let mut server = match connected {
+        Ok(server) => {
+            state.breakers.record_success(breakers, target);
+            server
+        }
+        Err(e) if egress::refused(&e).is_some() => return Err(e),
+        Err(e) => {
+            state.breakers.record_failure(breakers, target);
+            return Err(e);
+        }
+    };
+    let _lease = lease;
     info!("✅ Connected to target server: {}", target);
+    // Servers behind the proxy learn who the client was
+    if let Some(version) = state.config.proxy_protocol.version_for(pattern::strip_port(target)).filter(|_| !via_parent) {
+        let header = proxyproto::header(version, open.client, server.peer_addr()?);
+        server.write_all(&header).await?;
+    }
 
-    let (from_client, from_server) =
-        tokio::io::copy_bidirectional(&mut upgraded, &mut server).await?;
+    let activity = &open.activity;
+    let copy = async {
+        // Throttling needs the bytes in hand, so only unthrottled tunnels
+        // from plain listeners are spliced
+        #[cfg(target_os = "linux")]
+        let upgraded = match (throttle.is_unlimited(), upgraded) {
+            (true, listener::TunnelClient::Upgraded(upgraded)) => match splice::client_socket(upgraded) {
+                Ok((client, early)) => {
+                    state.metrics.spliced_tunnels.fetch_add(1, Ordering::Relaxed);
+                    return splice::copy_bidirectional(client, &mut server, early, activity).await;
+                }
+                Err(upgraded) => listener::TunnelClient::Upgraded(upgraded),
+            },
+            (_, upgraded) => upgraded,
+        };
+        let mut client = reaper::Tracked::client(upgraded, activity);
+        let mut server = reaper::Tracked::server(&mut server, activity);
+        bandwidth::copy_bidirectional(&mut client, &mut server, &throttle, &state.buffers).await
+    };
+    let (from_client, from_server) = tokio::select! {
+        copied = copy => copied?,
+        _ = activity.closed() => activity.bytes(),
+    };
+    if activity.over_cap() {
+        warn!("🚫 Closed tunnel to {} for '{}' at its byte limit", target, open.user);
+        state.metrics.count_size_limit("tunnel");
+    }
 
     info!(
         "🔚 Tunnel closed: {} - {} bytes from client, {} bytes from server",
         target, from_client, from_server
     );
 
-    Ok(())
+    Ok((from_client, from_server))
 }
 
-#[tokio::main]
-async fn main() {
-    eprintln!("DEBUG: Rust main() started");
-    println!("DEBUG: Rust main() started");
-    std::io::Write::flush(&mut std::io::stdout()).ok();
-    std::io::Write::flush(&mut std::io::stderr()).ok();
+fn main() {
+    let cli = Cli::parse();
+    let dev_ports = match cli.command {
+        Some(Command::User { config, action }) => {
+            if let Err(e) = users::run(&config, action) {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::GenFixtures { dir }) => {
+            if let Err(e) = fixtures::run(dir) {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Dev { port, tls_port }) => Some((port, tls_port)),
+        None => None,
+    };
+    let config_result = match dev_ports {
+        Some((port, tls_port)) => dev::config(port, tls_port),
+        None => Config::load("config.toml"),
+    };
+    // Built by hand, as its size comes from the config
+    let settings = cli.runtime.or(config_result.as_ref().ok().map(|cfg| &cfg.runtime));
+    match settings.build() {
+        Ok(runtime) => runtime.block_on(run(config_result, dev_ports, settings)),
+        Err(e) => {
+            eprintln!("error: failed to start the async runtime: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
 
-    println!("=== STARTING PROXY SERVER ===");
-    println!("Current directory: {:?}", std::env::current_dir());
-    println!("Checking for config.toml...");
+async fn run(
+    config_result: Result<Config, Box<dyn std::error::Error>>,
+    dev_ports: Option<(u16, u16)>,
+    settings: runtime::RuntimeConfig,
+) {
+    let (log_format, log_level) = match &config_result {
+        Ok(cfg) => (cfg.server.log_format, cfg.server.log_level.as_deref()),
+        Err(_) => (logging::LogFormat::default(), None),
+    };
+    if let Ok(cfg) = &config_result {
+        logdedup::configure(cfg.server.log_repeat_limit, std::time::Duration::from_secs(cfg.server.log_repeat_window));
+    }
+    logging::init(log_format, log_level);
+    settings.log();
 
-    if std::path::Path::new("config.toml").exists() {
-        println!("✓ config.toml found!");
-    } else {
-        eprintln!("✗ config.toml NOT FOUND in current directory!");
-        println!("Files in current directory:");
-        if let Ok(entries) = std::fs::read_dir(".") {
-            for entry in entries.flatten() {
-                println!("  - {}", entry.path().display());
+    let state = match config_result {
+        Ok(cfg) => match AppState::new(cfg) {
+            Ok(state) => Arc::new(state),
+            Err(e) => {
+                error!("❌ Failed to open state store: {}", e);
+                std::process::exit(1);
             }
+        },
+        Err(e) => {
+            error!("❌ Failed to load config.toml: {e:?}");
+            if let Ok(cwd) = std::env::current_dir() {
+                error!("Current directory: {}", cwd.display());
+            }
+            if let Ok(entries) = std::fs::read_dir(".") {
+                for entry in entries.flatten() {
+                    debug!("  - {}", entry.path().display());
+                }
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+            std::process::exit(1);
         }
-    }
+    };
+
+    let config = &state.config;
+
+    let listener_configs = match listener_configs(config) {
+        Ok(configs) => configs,
+        Err(e) => {
+            error!("❌ {}", e);
+            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+            std::process::exit(1);
+        }
+    };
 
-    tracing_subscriber::fmt()
-        .with_target(false)
-        .with_thread_ids(true)
-        .with_line_number(true)
-        .init();
+    limits::check_fd_limit(&config.limits);
 
-    info!("🚀 Secure proxy server starting...");
+    tcp::configure(&state.config.tcp);
+    state.upstreams.spawn_health_checks();
+    dns::configure(&state.config.dns);
+    dns::spawn_watch();
 
-    println!("Loading config from config.toml...");
-    let config = match Config::load("config.toml") {
-        Ok(cfg) => {
-            println!("Config loaded successfully!");
-            Arc::new(cfg)
+    // Sockets from systemd replace binding the same address; any others are
+    // served as extra listeners with default settings
+    let mut inherited = systemd::listen_fds();
+    let mut listener_configs = listener_configs;
+    if config.listeners.is_empty() && !inherited.is_empty() {
+        // Socket activation replaces [server] host/port
+        listener_configs.clear();
+    }
+    for socket in &inherited {
+        let Ok(addr) = socket.local_addr() else { continue };
+        if !listener_configs.iter().any(|c| c.socket_addr() == Ok(addr)) {
+            listener_configs.push(listener::ListenerConfig {
+                name: None,
+                address: addr.to_string(),
+                auth: true,
+                realm: listener::default_realm(),
+                tls_cert: None,
+                tls_key: None,
+                transparent: None,
+                clients: BTreeMap::new(),
+                proxy_protocol: false,
+                proxy_protocol_from: Vec::new(),
+                reverse: false,
+                http3: false,
+            });
+        }
+    }
+    let mut running = Vec::new();
+    for listener_config in &listener_configs {
+        let addr = listener_config.socket_addr().ok();
+        let socket = inherited
+            .iter()
+            .position(|s| s.local_addr().ok() == addr)
+            .map(|i| inherited.swap_remove(i));
+        if socket.is_none() {
+            debug!("Attempting to bind to {}", listener_config.address);
         }
+        match start_listener(&state, listener_config, socket) {
+            Ok(started) => running.push(started),
+            Err(e) => {
+                error!("❌ {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    *state.listeners.lock().unwrap() = running.iter().map(|(l, _)| l.clone()).collect();
+    let state_paths = config.state_paths();
+    if let Err(e) = privileges::drop(config.server.run_as_user.as_deref(), config.server.run_as_group.as_deref(), &state_paths) {
+        error!("❌ Failed to drop privileges: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = parquet::start(&config.access_log) {
+        error!("❌ Failed to start the Parquet access log: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = access::start(&config.access_log) {
+        error!("❌ Failed to open the access log: {}", e);
+        std::process::exit(1);
+    }
+    watchdog::spawn(state.clone());
+    if let Some(addr) = state.config.server.status_addr {
+        drain::spawn_status(state.clone(), addr);
+    }
+    billing::spawn(state.clone());
+    anomaly::spawn(state.clone());
+    certs::spawn(state.clone());
+    reaper::spawn(state.clone());
+    geoip::spawn_reload(state.clone());
+    blocklist::spawn(state.clone());
+    pac::spawn(state.clone());
+    logdedup::spawn_report();
+    userdb::spawn(state.clone());
+    jwt::spawn(state.clone());
+    oidc::spawn(state.clone());
+    systemd::spawn_watchdog(state.clone());
+    systemd::notify("READY=1");
+    startup::announce(&state);
+
+    if let Some((port, tls_port)) = dev_ports {
+        let creds = format!("{}:{}", dev::USER, dev::PASSWORD);
+        info!("🧪 Dev mode, not for production use");
+        info!("   curl -x http://{}@127.0.0.1:{} http://example.com/", creds, port);
+        info!("   curl --proxy-insecure -x https://{}@127.0.0.1:{} https://example.com/", creds, tls_port);
+        info!("   Admin API: http://127.0.0.1:{}{} (token '{}')", port, config.admin.path, dev::ADMIN_TOKEN);
+        // There is no config file to reload
+        std::future::pending::<()>().await;
+    }
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
         Err(e) => {
-            eprintln!("❌ Failed to load config.toml: {e:?}");
-            error!("❌ Failed to load config.toml: {e:?}");
-            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+            error!("❌ Failed to install SIGHUP handler: {}", e);
             std::process::exit(1);
         }
     };
 
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            error!("❌ Failed to install SIGTERM handler: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    loop {
+        tokio::select! {
+            Some(()) = hangup.recv() => {
+                systemd::notify("RELOADING=1");
+                reload_users(&state);
+                access::reopen();
+                reload_listeners(&state, &mut running);
+                systemd::notify("READY=1");
+            }
+            _ = terminate.recv() => break,
+        }
+    }
+    drain(&state, running).await;
+}
+
+// SIGTERM: close the listeners, so that new connections go to a replacement
+// process sharing the port (see `reuse_port`), and give requests and tunnels
+// in progress up to `drain_timeout` to finish before exiting.
+async fn drain(state: &AppState, running: Vec<(Arc<listener::Listener>, oneshot::Sender<()>)>) {
+    systemd::notify("STOPPING=1");
+    let timeout = std::time::Duration::from_secs(state.config.server.drain_timeout);
+    info!(
+        "🛑 SIGTERM received, draining {} connection(s) for up to {:?}",
+        state.client_slots.active(),
+        timeout
+    );
+    // Nothing left for the watchdog to probe
+    state.listeners.lock().unwrap().clear();
+    for (_, shutdown) in running {
+        let _ = shutdown.send(());
+    }
+    let deadline = tokio::time::Instant::now() + timeout;
+    drain::start(state, deadline.into_std());
+    let every = std::time::Duration::from_secs(state.config.server.drain_report_interval);
+    let mut next_report = tokio::time::Instant::now() + every;
+    while state.client_slots.active() > 0 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        if tokio::time::Instant::now() >= next_report && next_report < deadline && state.client_slots.active() > 0 {
+            drain::log(state, false);
+            next_report += every;
+        }
+    }
+    match state.client_slots.active() {
+        0 => info!("👋 All connections drained, exiting"),
+        _ => drain::log(state, true),
+    }
+    if let Some(billing) = &state.billing {
+        billing.flush(true);
+    }
+    state.users.flush();
+    parquet::close();
+}
+
+// SIGHUP: pick up users changed with `secure-proxy user` or by hand. The
+// user store needs no reload; it is re-read with every usage flush.
+fn reload_users(state: &AppState) {
+    match Config::load("config.toml") {
+        Ok(config) => {
+            state.users.replace(config.users);
+            state.totp.replace(&config.totp);
+            state.auth_cache.clear();
+            info!("🔑 Reloaded {} user(s)", state.users.len());
+        }
+        Err(e) => error!("❌ Reload failed, keeping current users: {}", e),
+    }
+}
+
+// [[listeners]] if any are configured, otherwise the single [server] host/port.
+fn listener_configs(config: &Config) -> Result<Vec<listener::ListenerConfig>, String> {
+    if !config.listeners.is_empty() {
+        return Ok(config.listeners.clone());
+    }
+    Ok(vec![listener::ListenerConfig {
+        name: None,
+        address: listen_addr(&config.server)?.to_string(),
+        auth: true,
+        realm: listener::default_realm(),
+        tls_cert: None,
+        tls_key: None,
+        transparent: None,
+        clients: BTreeMap::new(),
+        proxy_protocol: false,
+        proxy_protocol_from: Vec::new(),
+        reverse: false,
+        http3: false,
+    }])
+}
+
+fn listen_addr(server: &ServerConfig) -> Result<SocketAddr, String> {
     let port = std::env::var("PORT")
         .ok()
         .and_then(|p| p.parse::<u16>().ok())
-        .unwrap_or(config.server.port);
+        .unwrap_or(server.port);
+    let addr_str = format!("{}:{}", server.host, port);
+    addr_str
+        .parse()
+        .map_err(|e| format!("Failed to parse server address '{}': {}", addr_str, e))
+}
 
-    info!("📍 Host: {}", config.server.host);
-    info!(
-        "🔌 Port: {} {}",
-        port,
-        if std::env::var("PORT").is_ok() {
-            "(from PORT env var)"
+// SIGHUP: re-read the listener set, bind listeners at new addresses and let
+// those no longer configured drain. Listeners that stay keep their current
+// settings; everything else still needs a restart.
+fn reload_listeners(state: &Arc<AppState>, running: &mut Vec<(Arc<listener::Listener>, oneshot::Sender<()>)>) {
+    info!("🔄 SIGHUP received, re-reading listeners from config.toml");
+    let socket_activated = running.iter().any(|(l, _)| l.inherited);
+    let configs = match Config::load("config.toml").map_err(|e| e.to_string()).and_then(|c| {
+        // Under socket activation [server] host/port is not a listener
+        if c.listeners.is_empty() && socket_activated {
+            Ok(Vec::new())
         } else {
-            "(from config)"
+            listener_configs(&c)
+        }
+    }) {
+        Ok(configs) => configs,
+        Err(e) => {
+            error!("❌ Reload failed, keeping current listeners: {}", e);
+            return;
+        }
+    };
+    let mut next = Vec::new();
+    while let Some(pos) = running.iter().position(|(l, _)| l.inherited) {
+        next.push(running.swap_remove(pos));
+    }
+    for config in &configs {
+        let Ok(addr) = config.socket_addr() else { continue };
+        if next.iter().any(|(l, _)| l.addr == addr) {
+            continue;
+        }
+        if let Some(pos) = running.iter().position(|(l, _)| l.addr == addr) {
+            next.push(running.swap_remove(pos));
+            continue;
+        }
+        match start_listener(state, config, None) {
+            Ok(started) => next.push(started),
+            Err(e) => error!("❌ {}", e),
+        }
+    }
+    if next.is_empty() {
+        error!("❌ No new listener could be bound, keeping the current ones");
+        *running = next.into_iter().chain(running.drain(..)).collect();
+        return;
+    }
+    if running.is_empty() && next.len() == state.listeners().len() {
+        info!("Listener set unchanged");
+    }
+    // Retired listeners finish their in-flight requests and tunnels
+    for (listener, shutdown) in running.drain(..) {
+        info!("Draining listener {}", listener.addr);
+        let _ = shutdown.send(());
+    }
+    *state.listeners.lock().unwrap() = next.iter().map(|(l, _)| l.clone()).collect();
+    *running = next;
+}
+
+// Bind a listener (or adopt one inherited from systemd) and serve it on its
+// own task until its shutdown sender fires (or is dropped).
+fn start_listener(
+    state: &Arc<AppState>,
+    config: &listener::ListenerConfig,
+    inherited: Option<std::net::TcpListener>,
+) -> Result<(Arc<listener::Listener>, oneshot::Sender<()>), String> {
+    let addr = config.socket_addr()?;
+    let acceptor = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(
+            listener::tls_acceptor(cert, key).map_err(|e| format!("TLS setup for {} failed: {}", addr, e))?,
+        ),
+        _ => None,
+    };
+    let listener = Arc::new(listener::Listener {
+        name: config.name(),
+        addr,
+        auth: config.auth && config.transparent.is_none() && !config.reverse,
+        realm: config.realm.clone(),
+        tls: config.tls_cert.is_some(),
+        certificate: acceptor.as_ref().and_then(|a| a.context().certificate()).map(|c| c.to_owned()),
+        inherited: inherited.is_some(),
+        transparent: config.transparent.map(|mode| Arc::new(transparent::Transparent::new(mode, &config.clients))),
+        proxy_protocol: config.proxy_protocol.then(|| proxyproto::Trusted::new(&config.proxy_protocol_from)),
+        reverse: config.reverse,
+        http3: config.http3,
+    });
+    let (shutdown, shutdown_rx) = oneshot::channel();
+    let tcp = match inherited {
+        Some(socket) => socket
+            .set_nonblocking(true)
+            .and_then(|_| tcp::config().client.size_buffers(&socket))
+            .and_then(|_| tokio::net::TcpListener::from_std(socket)),
+        None => listener::bind(addr, state.config.server.reuse_port, config.transparent),
+    }
+    .map_err(|e| format!("Failed to bind {}: {}", addr, e))?;
+    #[cfg(feature = "http3")]
+    let shutdown_rx = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) if config.http3 => {
+            let endpoint =
+                http3::bind(addr, cert, key).map_err(|e| format!("HTTP/3 setup for {} failed: {}", addr, e))?;
+            // One shutdown for both the TCP and the QUIC side
+            let (tcp_shutdown, tcp_shutdown_rx) = oneshot::channel();
+            let (quic_shutdown, quic_shutdown_rx) = oneshot::channel();
+            tokio::spawn(async move {
+                let _ = shutdown_rx.await;
+                let _ = tcp_shutdown.send(());
+                let _ = quic_shutdown.send(());
+            });
+            tokio::spawn(http3::serve(endpoint, state.clone(), listener.clone(), quic_shutdown_rx));
+            tcp_shutdown_rx
+        }
+        _ => shutdown_rx,
+    };
+    let timeouts = timeouts::Timeouts::new(&state.config.limits);
+    match (acceptor, &listener.transparent) {
+        (_, Some(transparent)) => {
+            let incoming = transparent::TransparentIncoming::new(tcp, transparent.clone());
+            let builder = Server::builder(timeouts::TimedIncoming::new(incoming, timeouts, state.metrics.clone()));
+            spawn_server(addr, serve(builder, state.clone(), listener.clone(), shutdown_rx));
+        }
+        (Some(acceptor), None) => {
+            let incoming = listener::TlsIncoming::new(tcp, acceptor, listener.proxy_protocol.clone());
+            let builder = Server::builder(timeouts::TimedIncoming::new(incoming, timeouts, state.metrics.clone()));
+            spawn_server(addr, serve(builder, state.clone(), listener.clone(), shutdown_rx));
+        }
+        (None, None) if listener.proxy_protocol.is_some() => {
+            let trusted = listener.proxy_protocol.clone().unwrap_or_default();
+            let incoming = proxyproto::ProxiedIncoming::new(tcp, trusted);
+            let builder = Server::builder(timeouts::TimedIncoming::new(incoming, timeouts, state.metrics.clone()));
+            spawn_server(addr, serve(builder, state.clone(), listener.clone(), shutdown_rx));
+        }
+        (None, None) => {
+            let mut incoming = AddrIncoming::from_listener(tcp).map_err(|e| format!("Failed to serve {}: {}", addr, e))?;
+            tcp::config().client.apply_incoming(&mut incoming);
+            let builder = Server::builder(timeouts::TimedIncoming::new(incoming, timeouts, state.metrics.clone()));
+            spawn_server(addr, serve(builder, state.clone(), listener.clone(), shutdown_rx));
+        }
+    }
+    info!(
+        "🎯 Proxy server listening on {}://{}{}{}",
+        if listener.tls { "https" } else { "http" },
+        addr,
+        if listener.http3 { " and HTTP/3" } else { "" },
+        match (&config.transparent, listener.auth) {
+            (Some(transparent::Mode::Redirect), _) => " (transparent, redirect)",
+            (Some(transparent::Mode::Tproxy), _) => " (transparent, tproxy)",
+            (Some(transparent::Mode::Sni), _) => " (transparent, sni)",
+            (None, _) if listener.reverse => " (reverse proxy)",
+            (None, true) => "",
+            (None, false) => " (no authentication)",
         }
     );
-    info!("🔑 Loaded {} user(s)", config.users.len());
-    debug!("Users: {:?}", config.users.keys().collect::<Vec<_>>());
-    info!("✅ Configuration loaded successfully");
+    Ok((listener, shutdown))
+}
 
-    let addr_str = format!("{}:{}", config.server.host, port);
-    println!("About to bind to: {}", addr_str);
+fn spawn_server(addr: SocketAddr, server: impl std::future::Future<Output = hyper::Result<()>> + Send + 'static) {
+    tokio::spawn(async move {
+        match server.await {
+            Ok(()) => info!("🔚 Retired listener {}", addr),
+            Err(e) => {
+                error!("❌ Server error on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        }
+    });
+}
 
-    let addr: SocketAddr = match addr_str.parse() {
-        Ok(a) => a,
-        Err(e) => {
-            eprintln!("❌ Failed to parse server address '{}': {}", addr_str, e);
-            error!(
-                "❌ Failed to parse server address '{}': {}",
-                addr_str, e
+// Run one listener until `shutdown` fires, then finish in-flight requests.
+async fn serve<I, C>(
+    builder: hyper::server::Builder<I>,
+    state: Arc<AppState>,
+    listener: Arc<listener::Listener>,
+    shutdown: oneshot::Receiver<()>,
+) -> hyper::Result<()>
+where
+    I: Accept<Conn = timeouts::Timed<C>>,
+    C: listener::RemoteAddr + tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let builder = match state.config.limits.max_header_size {
+        Some(size) => builder.http1_max_buf_size(size),
+        None => builder,
+    };
+    let make_svc = make_service_fn(move |conn: &I::Conn| {
+        let state = state.clone();
+        let listener = listener.clone();
+        let client_addr = listener::RemoteAddr::remote_addr(conn);
+        let original_dst = listener::RemoteAddr::original_dst(conn);
+        let clock = conn.clock();
+        let rejected_country = state.config.geoip.rejects_client(state.geoip.country(client_addr.ip()).as_deref());
+        if let Some(country) = &rejected_country {
+            warn!("🌍 Refusing client {} from country {}", client_addr, country);
+            state.metrics.count_geoip_rejection(country);
+            let rule = format!("geoip country {}", country);
+            state.abuse.record(client_addr.ip(), abuse::Action::Blocked, "-", &rule);
+        }
+        // Released when hyper drops the service, i.e. when the connection closes
+        let slot = match rejected_country {
+            Some(_) => None,
+            None => state.client_slots.try_acquire().map(Arc::new),
+        };
+        let rejected = rejected_country.is_some();
+        let pinned = kerberos::Pinned::default();
+        if slot.is_none() && !rejected {
+            warn!(
+                "🚫 Client connection cap reached ({} active), rejecting {}",
+                state.client_slots.active(),
+                client_addr
             );
-            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
-            std::process::exit(1);
         }
-    };
-
-    let config_clone = config.clone();
-    let make_svc = make_service_fn(move |_conn| {
-        let config = config_clone.clone();
         async move {
-            Ok::<_, Infallible>(service_fn(move |req| {
-                let config = config.clone();
-                handle_request(req, config)
+            Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                let state = state.clone();
+                let listener = listener.clone();
+                let slot = slot.clone();
+                let pinned = pinned.clone();
+                let clock = clock.clone();
+                async move {
+                    if rejected {
+                        return Ok(Response::builder()
+                            .status(403)
+                            .header(hyper::header::CONNECTION, "close")
+                            .body(Body::from("Forbidden"))
+                            .unwrap());
+                    }
+                    let Some(slot) = slot else {
+                        return Ok(overloaded_response("Proxy connection capacity reached"));
+                    };
+                    if let Some(original) = original_dst {
+                        transparent::absolute(&mut req, original);
+                    }
+                    // CONNECT tunnels outlive the service; they keep the slot via the request
+                    req.extensions_mut().insert(slot);
+                    req.extensions_mut().insert(pinned);
+                    let connect = req.method() == Method::CONNECT;
+                    clock.begin();
+                    let request_id = logging::request_id();
+                    let page = (!state.error_pages.is_empty()).then(|| {
+                        errorpages::Request::new(req.headers(), req.uri().to_string(), request_id.clone(), client_addr.ip())
+                    });
+                    let response = handle_request(req, state.clone(), listener, client_addr, request_id).await;
+                    let response = match page {
+                        Some(page) => response.map(|response| state.error_pages.render(&page, response)),
+                        None => response,
+                    };
+                    let upgraded = response.as_ref().is_ok_and(|r| {
+                        r.status() == hyper::StatusCode::SWITCHING_PROTOCOLS || (connect && r.status().is_success())
+                    });
+                    clock.end(upgraded);
+                    response
+                }
             }))
         }
     });
 
-    info!("Attempting to bind to {}", addr);
-    println!("Attempting to bind to {}", addr);
-    let server = Server::bind(&addr).serve(make_svc);
-
-    info!("🎯 Proxy server listening on http://{}", addr);
-    println!("✅ Server successfully bound and listening on http://{}", addr);
-    info!("🌐 Ready to proxy HTTP and HTTPS requests with proxy authentication");
-
-    if let Err(e) = server.await {
-        error!("❌ Server error: {}", e);
-        std::process::exit(1);
-    }
+    builder
+        .serve(make_svc)
+        .with_graceful_shutdown(async {
+            let _ = shutdown.await;
+        })
+        .await
 }
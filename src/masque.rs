@@ -0,0 +1,96 @@
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+use crate::bandwidth::RateLimiter;
+use crate::reaper::Activity;
+
+// UDP proxying for HTTP/3 clients (CONNECT-UDP, RFC 9298). Sessions pass
+// the same authentication, ACLs, blocklists and per-user tunnel limits as
+// CONNECT, with the UDP port in place of the TCP one:
+//
+//   [masque]
+//   ports = ["53", "443", "3478", "49152-65535"]
+//   max_datagrams_per_second = 2000
+//   idle_timeout = 60
+#[derive(Debug, Deserialize)]
+pub struct MasqueConfig {
+    // UDP ports that may be reached, as "port" or "low-high"; any if empty
+    #[serde(default)]
+    pub ports: Vec<String>,
+    // Each way, per session; datagrams over the rate are dropped
+    pub max_datagrams_per_second: Option<u64>,
+    // Seconds without a datagram either way before a session is closed
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+}
+
+impl Default for MasqueConfig {
+    fn default() -> Self {
+        MasqueConfig {
+            ports: Vec::new(),
+            max_datagrams_per_second: None,
+            idle_timeout: default_idle_timeout(),
+        }
+    }
+}
+
+fn default_idle_timeout() -> u64 {
+    60
+}
+
+impl MasqueConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(ports) = self.ports.iter().find(|p| port_range(p).is_none()) {
+            return Err(format!("masque.ports: invalid port range '{}'", ports));
+        }
+        if self.idle_timeout == 0 {
+            return Err("masque.idle_timeout must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn allows_port(&self, port: u16) -> bool {
+        self.ports.is_empty()
+            || self
+                .ports
+                .iter()
+                .filter_map(|p| port_range(p))
+                .any(|(low, high)| (low..=high).contains(&port))
+    }
+
+    // A limiter for one direction of a new session, if there is a rate.
+    pub fn limiter(&self) -> Option<RateLimiter> {
+        self.max_datagrams_per_second.map(RateLimiter::new)
+    }
+}
+
+fn port_range(ports: &str) -> Option<(u16, u16)> {
+    let (low, high) = match ports.split_once('-') {
+        Some((low, high)) => (low.trim().parse().ok()?, high.trim().parse().ok()?),
+        None => {
+            let port = ports.trim().parse().ok()?;
+            (port, port)
+        }
+    };
+    (low <= high).then_some((low, high))
+}
+
+// Put on a CONNECT-UDP request by the HTTP/3 listener; the handler sends
+// the session back once it has been allowed and its socket is connected.
+#[cfg_attr(not(feature = "http3"), allow(dead_code))]
+pub struct UdpRequest(pub oneshot::Sender<Session>);
+
+// An allowed session, relayed by the listener until either side stops.
+#[cfg_attr(not(feature = "http3"), allow(dead_code))]
+pub struct Session {
+    pub socket: UdpSocket,
+    pub activity: Arc<Activity>,
+    // Datagrams from the client and from the target, counted separately
+    pub limiters: (Option<RateLimiter>, Option<RateLimiter>),
+    pub idle_timeout: Duration,
+    // Called with the bytes moved (from client, from target) at the end
+    pub closed: Box<dyn FnOnce(u64, u64) + Send>,
+}
@@ -0,0 +1,506 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::AppState;
+
+#[derive(Debug, Deserialize)]
+pub struct MetricsConfig {
+    // Serve Prometheus metrics on the proxy listener (no auth required)
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_path")]
+    pub path: String,
+    // Upper bounds, in seconds, of the request duration histogram buckets
+    #[serde(default = "default_buckets")]
+    pub buckets: Vec<f64>,
+    // Labels attached to the request duration histogram
+    #[serde(default = "default_labels")]
+    pub labels: Vec<Label>,
+    // Busiest domains given their own series; the rest are labelled "other"
+    #[serde(default = "default_max_domains")]
+    pub max_domains: usize,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: false,
+            path: default_path(),
+            buckets: default_buckets(),
+            labels: default_labels(),
+            max_domains: default_max_domains(),
+        }
+    }
+}
+
+fn default_path() -> String {
+    "/metrics".to_string()
+}
+
+fn default_buckets() -> Vec<f64> {
+    vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]
+}
+
+fn default_labels() -> Vec<Label> {
+    vec![Label::Domain]
+}
+
+fn default_max_domains() -> usize {
+    50
+}
+
+impl MetricsConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.buckets.is_empty() || self.buckets.iter().any(|b| !b.is_finite()) {
+            return Err("metrics.buckets must be a non-empty list of numbers".to_string());
+        }
+        if self.buckets.windows(2).any(|w| w[0] >= w[1]) {
+            return Err("metrics.buckets must be in increasing order".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Label {
+    User,
+    Domain,
+    Listener,
+}
+
+impl Label {
+    fn as_str(self) -> &'static str {
+        match self {
+            Label::User => "user",
+            Label::Domain => "domain",
+            Label::Listener => "listener",
+        }
+    }
+}
+
+// Label value standing in for domains outside the top N
+const OTHER: &str = "other";
+// Bound on the per-domain request counts used for ranking
+const MAX_TRACKED_DOMAINS: usize = 10_000;
+
+struct Histogram {
+    // Cumulative, one per configured bucket
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+// Request duration histograms keyed by label values (in config order).
+#[derive(Default)]
+struct Durations {
+    series: HashMap<Vec<String>, Histogram>,
+    // Domains that currently get their own series
+    top: HashSet<String>,
+    // Recent requests per domain, halved at every scrape
+    hits: HashMap<String, u64>,
+}
+
+impl Durations {
+    // Re-pick the busiest domains. Series of domains that fall out are
+    // dropped rather than folded into "other", so every exported counter
+    // only ever goes up.
+    fn rerank(&mut self, config: &MetricsConfig) {
+        let Some(index) = config.labels.iter().position(|l| *l == Label::Domain) else {
+            return;
+        };
+        if self.hits.is_empty() {
+            return;
+        }
+        let mut ranked: Vec<(&String, &u64)> = self.hits.iter().collect();
+        ranked.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        self.top = ranked
+            .into_iter()
+            .take(config.max_domains)
+            .map(|(domain, _)| domain.clone())
+            .collect();
+        let top = &self.top;
+        self.series.retain(|labels, _| labels[index] == OTHER || top.contains(&labels[index]));
+        self.hits.retain(|_, hits| {
+            *hits /= 2;
+            *hits > 0
+        });
+    }
+}
+
+// Process-wide counters. Gauges are read from AppState at render time.
+#[derive(Default)]
+pub struct Metrics {
+    pub body_spills: AtomicU64,
+    pub body_spill_bytes: AtomicU64,
+    pub circuit_rejections: AtomicU64,
+    // Requests refused by [acl]
+    pub acl_denials: AtomicU64,
+    // Requests refused by [acl] deny_private
+    pub private_denials: AtomicU64,
+    // Requests refused by [[schedules]]
+    pub schedule_denials: AtomicU64,
+    // Requests refused by [[url_filters]]
+    pub url_filter_denials: AtomicU64,
+    // Responses compressed by [compression], and their bytes before and after
+    pub compressed_responses: AtomicU64,
+    pub compression_in_bytes: AtomicU64,
+    pub compression_out_bytes: AtomicU64,
+    // CONNECT tunnels relayed with splice(2) rather than copied
+    pub spliced_tunnels: AtomicU64,
+    // Refused client connections by country code
+    pub geoip_rejections: Mutex<BTreeMap<String, u64>>,
+    // Requests refused by destination country or network ("KP", "AS64500")
+    geoip_destination_denials: Mutex<BTreeMap<String, u64>>,
+    // Client connections closed for sending their request head too slowly
+    // ("header") or for idling between requests ("idle")
+    client_timeouts: Mutex<BTreeMap<String, u64>>,
+    // Requests, responses and tunnels over a [limits] size cap, by kind
+    size_limits: Mutex<BTreeMap<&'static str, u64>>,
+    // Headers taken out or rewritten by [privacy], by header name
+    privacy_scrubs: Mutex<BTreeMap<&'static str, u64>>,
+    // [icap] scans by mode and result
+    icap_scans: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    // Mirrored request copies by outcome
+    mirrored: Mutex<BTreeMap<&'static str, u64>>,
+    // Requests refused by [[blocklists]], by list name
+    blocklist_denials: Mutex<BTreeMap<String, u64>>,
+    // Tunnels closed by the reaper, by reason
+    reaped: Mutex<BTreeMap<&'static str, u64>>,
+    // Requests past authentication by listener name
+    requests: Mutex<BTreeMap<String, u64>>,
+    durations: Mutex<Durations>,
+}
+
+impl Metrics {
+    pub fn count_geoip_destination_denial(&self, rule: &str) {
+        *self
+            .geoip_destination_denials
+            .lock()
+            .unwrap()
+            .entry(rule.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn count_client_timeout(&self, reason: &str) {
+        *self.client_timeouts.lock().unwrap().entry(reason.to_string()).or_default() += 1;
+    }
+
+    pub fn count_size_limit(&self, kind: &'static str) {
+        *self.size_limits.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    pub fn count_privacy_scrub(&self, header: &'static str) {
+        *self.privacy_scrubs.lock().unwrap().entry(header).or_default() += 1;
+    }
+
+    pub fn count_icap(&self, mode: &'static str, result: &'static str) {
+        *self.icap_scans.lock().unwrap().entry((mode, result)).or_default() += 1;
+    }
+
+    pub fn count_mirror(&self, result: &'static str) {
+        *self.mirrored.lock().unwrap().entry(result).or_default() += 1;
+    }
+
+    pub fn count_blocklist_denial(&self, list: &str) {
+        *self.blocklist_denials.lock().unwrap().entry(list.to_string()).or_default() += 1;
+    }
+
+    pub fn count_geoip_rejection(&self, country: &str) {
+        *self
+            .geoip_rejections
+            .lock()
+            .unwrap()
+            .entry(country.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn count_reaped(&self, reason: &'static str) {
+        *self.reaped.lock().unwrap().entry(reason).or_default() += 1;
+    }
+
+    // Time until a proxied request's response head was ready.
+    pub fn observe_request(&self, config: &MetricsConfig, user: &str, domain: &str, listener: &str, elapsed: Duration) {
+        *self.requests.lock().unwrap().entry(listener.to_string()).or_default() += 1;
+        let mut durations = self.durations.lock().unwrap();
+        let d = &mut *durations;
+        if d.hits.len() < MAX_TRACKED_DOMAINS || d.hits.contains_key(domain) {
+            *d.hits.entry(domain.to_string()).or_default() += 1;
+        }
+        if d.top.len() < config.max_domains {
+            d.top.insert(domain.to_string());
+        }
+        let domain = if d.top.contains(domain) { domain } else { OTHER };
+        let labels = config
+            .labels
+            .iter()
+            .map(|label| match label {
+                Label::User => user,
+                Label::Domain => domain,
+                Label::Listener => listener,
+            })
+            .map(str::to_string)
+            .collect();
+        let histogram = d.series.entry(labels).or_insert_with(|| Histogram {
+            buckets: vec![0; config.buckets.len()],
+            sum: 0.0,
+            count: 0,
+        });
+        let secs = elapsed.as_secs_f64();
+        for (count, bound) in histogram.buckets.iter_mut().zip(&config.buckets) {
+            if secs <= *bound {
+                *count += 1;
+            }
+        }
+        histogram.sum += secs;
+        histogram.count += 1;
+    }
+}
+
+// Prometheus text exposition format.
+pub fn render(state: &AppState) -> String {
+    let m = &state.metrics;
+    let mut out = String::new();
+    counter(
+        &mut out,
+        "proxy_body_spills_total",
+        "Inspected bodies spooled to disk",
+        m.body_spills.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "proxy_body_spill_bytes_total",
+        "Bytes written to body spool files",
+        m.body_spill_bytes.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "proxy_circuit_rejections_total",
+        "Requests refused because the destination's circuit was open",
+        m.circuit_rejections.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "proxy_acl_denials_total",
+        "Requests refused because the user may not reach the destination",
+        m.acl_denials.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "proxy_private_denials_total",
+        "Requests refused because the destination is an internal address",
+        m.private_denials.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "proxy_compressed_responses_total",
+        "Responses compressed by the proxy for clients that accept it",
+        m.compressed_responses.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "proxy_compression_in_bytes_total",
+        "Response bytes before compression by the proxy",
+        m.compression_in_bytes.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "proxy_compression_out_bytes_total",
+        "Response bytes after compression by the proxy",
+        m.compression_out_bytes.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "proxy_spliced_tunnels_total",
+        "CONNECT tunnels relayed in the kernel without copying",
+        m.spliced_tunnels.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "proxy_log_suppressed_total",
+        "Repeated warnings and errors counted instead of logged",
+        crate::logdedup::suppressed(),
+    );
+    counter(
+        &mut out,
+        "proxy_schedule_denials_total",
+        "Requests refused because the user is outside their allowed hours",
+        m.schedule_denials.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "proxy_url_filter_denials_total",
+        "Requests refused because their URL matched a blocking filter",
+        m.url_filter_denials.load(Ordering::Relaxed),
+    );
+    let _ = writeln!(
+        out,
+        "# HELP proxy_geoip_rejections_total Client connections refused by country\n# TYPE proxy_geoip_rejections_total counter"
+    );
+    for (country, count) in m.geoip_rejections.lock().unwrap().iter() {
+        let _ = writeln!(out, "proxy_geoip_rejections_total{{country=\"{}\"}} {}", country, count);
+    }
+    let _ = writeln!(
+        out,
+        "# HELP proxy_geoip_destination_denials_total Requests refused by destination country or network\n# TYPE proxy_geoip_destination_denials_total counter"
+    );
+    for (rule, count) in m.geoip_destination_denials.lock().unwrap().iter() {
+        let _ = writeln!(out, "proxy_geoip_destination_denials_total{{rule=\"{}\"}} {}", rule, count);
+    }
+    let _ = writeln!(
+        out,
+        "# HELP proxy_client_timeouts_total Client connections closed for being too slow or idle\n# TYPE proxy_client_timeouts_total counter"
+    );
+    for (reason, count) in m.client_timeouts.lock().unwrap().iter() {
+        let _ = writeln!(out, "proxy_client_timeouts_total{{reason=\"{}\"}} {}", reason, count);
+    }
+    let _ = writeln!(
+        out,
+        "# HELP proxy_size_limit_exceeded_total Requests, responses and tunnels stopped for carrying too many bytes\n# TYPE proxy_size_limit_exceeded_total counter"
+    );
+    for (kind, count) in m.size_limits.lock().unwrap().iter() {
+        let _ = writeln!(out, "proxy_size_limit_exceeded_total{{kind=\"{}\"}} {}", kind, count);
+    }
+    let _ = writeln!(
+        out,
+        "# HELP proxy_privacy_scrubbed_total Tracking headers removed or rewritten by the privacy profile\n# TYPE proxy_privacy_scrubbed_total counter"
+    );
+    for (header, count) in m.privacy_scrubs.lock().unwrap().iter() {
+        let _ = writeln!(out, "proxy_privacy_scrubbed_total{{header=\"{}\"}} {}", header, count);
+    }
+    let _ = writeln!(
+        out,
+        "# HELP proxy_icap_scans_total Requests and responses sent to the ICAP scanner, by result\n# TYPE proxy_icap_scans_total counter"
+    );
+    for ((mode, result), count) in m.icap_scans.lock().unwrap().iter() {
+        let _ = writeln!(out, "proxy_icap_scans_total{{mode=\"{}\",result=\"{}\"}} {}", mode, result, count);
+    }
+    let _ = writeln!(
+        out,
+        "# HELP proxy_mirrored_requests_total Request copies sent to mirror destinations, or dropped\n# TYPE proxy_mirrored_requests_total counter"
+    );
+    for (result, count) in m.mirrored.lock().unwrap().iter() {
+        let _ = writeln!(out, "proxy_mirrored_requests_total{{result=\"{}\"}} {}", result, count);
+    }
+    let _ = writeln!(
+        out,
+        "# HELP proxy_blocklist_denials_total Requests refused because the destination is on a blocklist\n# TYPE proxy_blocklist_denials_total counter"
+    );
+    for (list, count) in m.blocklist_denials.lock().unwrap().iter() {
+        let _ = writeln!(out, "proxy_blocklist_denials_total{{list=\"{}\"}} {}", list, count);
+    }
+    let _ = writeln!(
+        out,
+        "# HELP proxy_blocklist_domains Domains each blocklist currently blocks\n# TYPE proxy_blocklist_domains gauge"
+    );
+    for (list, size) in state.blocklists.sizes() {
+        let _ = writeln!(out, "proxy_blocklist_domains{{list=\"{}\"}} {}", list, size);
+    }
+    gauge(&mut out, "proxy_tunnel_buffers_idle", "Tunnel copy buffers kept for reuse", state.buffers.idle() as u64);
+    let (assigned, pool) = state.egress_ips.usage();
+    gauge(&mut out, "proxy_egress_ips_assigned", "Users leaving from a pool address", assigned as u64);
+    gauge(&mut out, "proxy_egress_ips_pool", "Addresses in the egress IP pool", pool as u64);
+    let _ = writeln!(
+        out,
+        "# HELP proxy_reaped_connections_total Tunnels closed by the reaper, by reason\n# TYPE proxy_reaped_connections_total counter"
+    );
+    for (reason, count) in m.reaped.lock().unwrap().iter() {
+        let _ = writeln!(out, "proxy_reaped_connections_total{{reason=\"{}\"}} {}", reason, count);
+    }
+    let _ = writeln!(
+        out,
+        "# HELP proxy_requests_total Proxied requests by listener\n# TYPE proxy_requests_total counter"
+    );
+    for (listener, count) in m.requests.lock().unwrap().iter() {
+        let _ = writeln!(out, "proxy_requests_total{{listener=\"{}\"}} {}", label_value(listener), count);
+    }
+    durations(&mut out, &state.config.metrics, &mut m.durations.lock().unwrap());
+    gauge(
+        &mut out,
+        "proxy_client_connections",
+        "Open client connections",
+        state.client_slots.active() as u64,
+    );
+    gauge(
+        &mut out,
+        "proxy_tunnels",
+        "Open CONNECT tunnels",
+        state.tunnel_slots.active() as u64,
+    );
+    let _ = writeln!(
+        out,
+        "# HELP proxy_listener_tunnels Open CONNECT tunnels by listener\n# TYPE proxy_listener_tunnels gauge"
+    );
+    for (listener, count) in state.connections.by_listener() {
+        let _ = writeln!(out, "proxy_listener_tunnels{{listener=\"{}\"}} {}", label_value(&listener), count);
+    }
+    let certs = state.certs.snapshot();
+    let _ = writeln!(
+        out,
+        "# HELP proxy_cert_expiry_days Whole days until a monitored certificate expires\n# TYPE proxy_cert_expiry_days gauge"
+    );
+    for (source, name, days, _) in &certs {
+        let _ = writeln!(out, "proxy_cert_expiry_days{{source=\"{}\",name=\"{}\"}} {}", source, label_value(name), days);
+    }
+    let _ = writeln!(
+        out,
+        "# HELP proxy_cert_not_after_seconds Expiry of a monitored certificate as a Unix timestamp\n# TYPE proxy_cert_not_after_seconds gauge"
+    );
+    for (source, name, _, not_after) in &certs {
+        let _ = writeln!(out, "proxy_cert_not_after_seconds{{source=\"{}\",name=\"{}\"}} {}", source, label_value(name), not_after);
+    }
+    out
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(
+        out,
+        "# HELP {} {}\n# TYPE {} counter\n{} {}",
+        name, help, name, name, value
+    );
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(
+        out,
+        "# HELP {} {}\n# TYPE {} gauge\n{} {}",
+        name, help, name, name, value
+    );
+}
+
+fn durations(out: &mut String, config: &MetricsConfig, durations: &mut Durations) {
+    durations.rerank(config);
+    let name = "proxy_request_duration_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {} Time until the response head of a proxied request was ready\n# TYPE {} histogram",
+        name, name
+    );
+    let mut series: Vec<_> = durations.series.iter().collect();
+    series.sort_by(|a, b| a.0.cmp(b.0));
+    for (values, histogram) in series {
+        let labels: Vec<String> = config
+            .labels
+            .iter()
+            .zip(values)
+            .map(|(label, value)| format!("{}=\"{}\"", label.as_str(), label_value(value)))
+            .collect();
+        let labels = labels.join(",");
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (bound, count) in config.buckets.iter().zip(&histogram.buckets) {
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, sep, histogram.count);
+        let braces = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let _ = writeln!(out, "{}_sum{} {}", name, braces, histogram.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braces, histogram.count);
+    }
+}
+
+fn label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,143 @@
+use hyper::body::Bytes;
+use hyper::{Body, Request, Uri};
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::pattern::host_matches;
+
+// Copies of selected plain HTTP requests sent to a second destination, for
+// trying a new backend on real traffic. Their responses are thrown away.
+//
+//   [mirror]
+//   max_in_flight = 100
+//
+//   [[mirror.rules]]
+//   host = "api.example.com"
+//   path_prefix = "/v2/"
+//   to = "http://10.0.0.9:8080"
+//   percent = 10
+#[derive(Debug, Deserialize)]
+pub struct MirrorConfig {
+    #[serde(default)]
+    pub rules: Vec<MirrorRule>,
+    // Requests with a larger body, or one of unknown length, are not copied
+    #[serde(default = "default_max_body")]
+    pub max_body: u64,
+    // Copies on their way at once; more are dropped
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+    // Seconds a copy may take before it is given up
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for MirrorConfig {
+    fn default() -> Self {
+        MirrorConfig {
+            rules: Vec::new(),
+            max_body: default_max_body(),
+            max_in_flight: default_max_in_flight(),
+            timeout: default_timeout(),
+        }
+    }
+}
+
+fn default_max_body() -> u64 {
+    1024 * 1024
+}
+
+fn default_max_in_flight() -> usize {
+    100
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MirrorRule {
+    pub host: String,
+    #[serde(default = "default_path_prefix")]
+    pub path_prefix: String,
+    // "http://host:port"; the request's path and query are appended
+    pub to: String,
+    // Share of matching requests copied
+    #[serde(default = "default_percent")]
+    pub percent: u8,
+    // Matching requests so far, for picking which to copy
+    #[serde(skip)]
+    seen: AtomicU64,
+}
+
+fn default_path_prefix() -> String {
+    "/".to_string()
+}
+
+fn default_percent() -> u8 {
+    100
+}
+
+impl MirrorConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.timeout == 0 {
+            return Err("mirror.timeout must be positive".to_string());
+        }
+        for rule in &self.rules {
+            if rule.percent == 0 || rule.percent > 100 {
+                return Err(format!("mirror rule for '{}': percent must be between 1 and 100", rule.host));
+            }
+            let to: Uri = rule
+                .to
+                .parse()
+                .map_err(|e| format!("mirror rule for '{}': bad 'to' URL '{}': {}", rule.host, rule.to, e))?;
+            if to.scheme_str() != Some("http") || to.host().is_none() || !matches!(to.path(), "" | "/") {
+                return Err(format!(
+                    "mirror rule for '{}': 'to' must be http://host[:port], got '{}'",
+                    rule.host, rule.to
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // The first rule matching `host` and `path`, if this request is among
+    // the share it copies.
+    pub fn rule(&self, host: &str, path: &str) -> Option<&MirrorRule> {
+        let rule = self
+            .rules
+            .iter()
+            .find(|r| host_matches(&r.host, host) && path.starts_with(&r.path_prefix))?;
+        // Spread evenly rather than at random: copy the n-th request when
+        // n * percent / 100 steps up
+        let n = rule.seen.fetch_add(1, Ordering::Relaxed);
+        let percent = rule.percent as u64;
+        (n * percent / 100 != (n + 1) * percent / 100).then_some(rule)
+    }
+}
+
+// A copy of `req` addressed to the rule's destination. The body is read
+// into memory, so `req` gets it back as a buffered body.
+pub async fn duplicate(req: &mut Request<Body>, rule: &MirrorRule, max_body: u64) -> Result<Request<Body>, &'static str> {
+    let headers = req.headers();
+    let body = match (headers.get(hyper::header::CONTENT_LENGTH), headers.contains_key(hyper::header::TRANSFER_ENCODING)) {
+        (_, true) => return Err("skipped_body"),
+        (None, false) => Bytes::new(),
+        (Some(_), false) if crate::content_length(headers) > max_body => return Err("skipped_body"),
+        (Some(_), false) => {
+            let bytes = hyper::body::to_bytes(std::mem::take(req.body_mut()))
+                .await
+                .map_err(|_| "failed")?;
+            *req.body_mut() = Body::from(bytes.clone());
+            bytes
+        }
+    };
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    let uri: Uri = format!("{}{}", rule.to.trim_end_matches('/'), path)
+        .parse()
+        .map_err(|_| "failed")?;
+    let mut copy = Request::new(Body::from(body));
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = uri;
+    *copy.headers_mut() = req.headers().clone();
+    Ok(copy)
+}
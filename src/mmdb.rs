@@ -0,0 +1,240 @@
+use std::net::IpAddr;
+
+// Minimal reader for MaxMind DB files (.mmdb, as used by GeoLite2 and the
+// DB-IP lite downloads): a binary search tree over address bits whose
+// leaves point into a data section of typed values. Only what lookups need
+// is decoded: maps, strings and unsigned integers.
+// https://maxmind.github.io/MaxMind-DB/
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+
+const POINTER: u8 = 1;
+const STRING: u8 = 2;
+const UINT16: u8 = 5;
+const UINT32: u8 = 6;
+const MAP: u8 = 7;
+const UINT64: u8 = 9;
+const ARRAY: u8 = 11;
+const BOOLEAN: u8 = 14;
+
+pub struct Reader {
+    buf: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    // Where the data section starts; pointers count from here
+    data: usize,
+    // Node reached after 96 zero bits, where IPv4 lookups start
+    ipv4_start: usize,
+    pub database_type: String,
+}
+
+// A decoded value; containers are walked lazily.
+enum Value<'a> {
+    Str(&'a str),
+    UInt(u64),
+    Map(usize, usize),
+    Other,
+}
+
+impl Reader {
+    pub fn is_mmdb(buf: &[u8]) -> bool {
+        find_marker(buf).is_some()
+    }
+
+    pub fn new(buf: Vec<u8>) -> Option<Reader> {
+        let metadata = find_marker(&buf)? + METADATA_MARKER.len();
+        let mut reader = Reader {
+            buf,
+            node_count: 0,
+            record_size: 0,
+            ip_version: 0,
+            data: metadata,
+            ipv4_start: 0,
+            database_type: String::new(),
+        };
+        // The metadata is a map in the data section format, at the end
+        let uint = |r: &Reader, key| match r.get(metadata, &[key]) {
+            Some(Value::UInt(n)) => Some(n),
+            _ => None,
+        };
+        reader.node_count = uint(&reader, "node_count")? as usize;
+        reader.record_size = uint(&reader, "record_size")? as usize;
+        reader.ip_version = uint(&reader, "ip_version")?;
+        if let Some(Value::Str(kind)) = reader.get(metadata, &["database_type"]) {
+            reader.database_type = kind.to_string();
+        }
+        if !matches!(reader.record_size, 24 | 28 | 32) || !matches!(reader.ip_version, 4 | 6) {
+            return None;
+        }
+        let tree = reader.node_count * reader.record_size / 4;
+        // 16 zero bytes separate the tree from the data section
+        reader.data = tree + 16;
+        if reader.data > metadata {
+            return None;
+        }
+        let mut node = 0;
+        if reader.ip_version == 6 {
+            for _ in 0..96 {
+                if node >= reader.node_count {
+                    break;
+                }
+                node = reader.record(node, 0)?;
+            }
+        }
+        reader.ipv4_start = node;
+        Some(reader)
+    }
+
+    // A string at `path` in the record for `ip`, e.g. ["country", "iso_code"].
+    pub fn string(&self, ip: IpAddr, path: &[&str]) -> Option<&str> {
+        match self.get(self.find(ip)?, path)? {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    // An unsigned integer at `path`, e.g. ["autonomous_system_number"].
+    pub fn uint(&self, ip: IpAddr, path: &[&str]) -> Option<u64> {
+        match self.get(self.find(ip)?, path)? {
+            Value::UInt(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    // Offset of the record for `ip` in the buffer, if the tree has one.
+    fn find(&self, ip: IpAddr) -> Option<usize> {
+        // Not every file aliases ::ffff:0:0/96 to the IPv4 part of the tree
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        let (bits, count, mut node): (u128, u32, usize) = match ip {
+            IpAddr::V4(ip) => (u32::from(ip) as u128, 32, self.ipv4_start),
+            IpAddr::V6(_) if self.ip_version == 4 => return None,
+            IpAddr::V6(ip) => (u128::from(ip), 128, 0),
+        };
+        for i in (0..count).rev() {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, (bits >> i) as usize & 1)?;
+        }
+        // node_count itself means "no data"; larger values point at data
+        (node > self.node_count).then(|| self.data + node - self.node_count - 16)
+    }
+
+    // The left (0) or right (1) record of a search tree node.
+    fn record(&self, node: usize, side: usize) -> Option<usize> {
+        let size = self.record_size;
+        let at = node * size / 4;
+        let b = self.buf.get(at..at + size / 4)?;
+        let be = |bytes: &[u8]| bytes.iter().fold(0usize, |n, &b| n << 8 | b as usize);
+        Some(match (size, side) {
+            (24, 0) => be(&b[0..3]),
+            (24, _) => be(&b[3..6]),
+            (28, 0) => (b[3] as usize & 0xf0) << 20 | be(&b[0..3]),
+            (28, _) => (b[3] as usize & 0x0f) << 24 | be(&b[4..7]),
+            (_, 0) => be(&b[0..4]),
+            _ => be(&b[4..8]),
+        })
+    }
+
+    // Follows `path` through nested maps from the value at `at`.
+    fn get(&self, mut at: usize, path: &[&str]) -> Option<Value<'_>> {
+        for key in path {
+            let Value::Map(mut pairs, mut entry) = self.decode(at)?.0 else {
+                return None;
+            };
+            loop {
+                if pairs == 0 {
+                    return None;
+                }
+                let (name, value) = self.decode(entry)?;
+                if matches!(name, Value::Str(name) if name == *key) {
+                    at = value;
+                    break;
+                }
+                entry = self.skip(value)?;
+                pairs -= 1;
+            }
+        }
+        Some(self.decode(at)?.0)
+    }
+
+    // Control byte(s) at `at`: (type, size, offset of the payload).
+    fn control(&self, at: usize) -> Option<(u8, usize, usize)> {
+        let ctrl = *self.buf.get(at)?;
+        let mut kind = ctrl >> 5;
+        let mut next = at + 1;
+        if kind == 0 {
+            kind = 7 + *self.buf.get(next)?;
+            next += 1;
+        }
+        if kind == POINTER {
+            return Some((kind, ((ctrl >> 3) & 3) as usize, next));
+        }
+        let extra = |n: usize| self.buf.get(next..next + n).map(|b| b.iter().fold(0usize, |v, &b| v << 8 | b as usize));
+        let size = match ctrl & 0x1f {
+            29 => 29 + extra(1)?,
+            30 => 285 + extra(2)?,
+            31 => 65_821 + extra(3)?,
+            size => size as usize,
+        };
+        let skip = match ctrl & 0x1f {
+            29 => 1,
+            30 => 2,
+            31 => 3,
+            _ => 0,
+        };
+        Some((kind, size, next + skip))
+    }
+
+    // The value at `at` (through a pointer, if it is one) and the offset of
+    // whatever follows it there.
+    fn decode(&self, at: usize) -> Option<(Value<'_>, usize)> {
+        let (kind, size, payload) = self.control(at)?;
+        if kind == POINTER {
+            let ctrl = self.buf[at] as usize & 7;
+            let bytes = self.buf.get(payload..payload + size + 1)?;
+            let raw = bytes.iter().fold(0usize, |v, &b| v << 8 | b as usize);
+            let target = match size {
+                0 => ctrl << 8 | raw,
+                1 => (ctrl << 16 | raw) + 2048,
+                2 => (ctrl << 24 | raw) + 526_336,
+                _ => raw,
+            };
+            // Pointers never point at pointers; a file that does is broken
+            if self.control(self.data + target)?.0 == POINTER {
+                return None;
+            }
+            let (value, _) = self.decode(self.data + target)?;
+            return Some((value, payload + size + 1));
+        }
+        let bytes = self.buf.get(payload..payload + if kind == MAP || kind == ARRAY || kind == BOOLEAN { 0 } else { size })?;
+        let value = match kind {
+            STRING => Value::Str(std::str::from_utf8(bytes).ok()?),
+            UINT16 | UINT32 | UINT64 => Value::UInt(bytes.iter().fold(0u64, |v, &b| v << 8 | b as u64)),
+            MAP => Value::Map(size, payload),
+            _ => Value::Other,
+        };
+        Some((value, payload + bytes.len()))
+    }
+
+    // Offset just past the value at `at`, containers included.
+    fn skip(&self, at: usize) -> Option<usize> {
+        let (kind, size, payload) = self.control(at)?;
+        match kind {
+            POINTER => Some(payload + size + 1),
+            MAP => (0..size * 2).try_fold(payload, |at, _| self.skip(at)),
+            ARRAY => (0..size).try_fold(payload, |at, _| self.skip(at)),
+            BOOLEAN => Some(payload),
+            _ => Some(payload + size),
+        }
+    }
+}
+
+fn find_marker(buf: &[u8]) -> Option<usize> {
+    // The metadata is at most 128 KiB from the end
+    let from = buf.len().saturating_sub(128 * 1024);
+    buf[from..].windows(METADATA_MARKER.len()).rposition(|w| w == METADATA_MARKER).map(|i| from + i)
+}
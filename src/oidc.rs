@@ -0,0 +1,361 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use openssl::hash::{hash as digest, MessageDigest};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::json::{self, Json};
+use crate::jwt::{self, Jwt, JwtConfig};
+use crate::AppState;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_DISCOVERY: Duration = Duration::from_secs(30);
+const DEVICE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+// Single sign-on through an OIDC provider. Clients without credentials get
+// a token with the device code flow via the proxy's helper endpoints, then
+// send it as `Proxy-Authorization: Bearer <token>`:
+//
+//   [oidc]
+//   issuer = "https://login.example.com/realms/corp"
+//   client_id = "secure-proxy"
+#[derive(Debug, Clone, Deserialize)]
+pub struct OidcConfig {
+    // Discovered from <issuer>/.well-known/openid-configuration; off when unset
+    pub issuer: Option<String>,
+    #[serde(default)]
+    pub client_id: String,
+    // For confidential clients; also used for token introspection
+    pub client_secret: Option<String>,
+    #[serde(default = "default_scope")]
+    pub scope: String,
+    // Required "aud" value of JWT access tokens, when set
+    pub audience: Option<String>,
+    // Claim (or introspection field) holding the username
+    #[serde(default = "default_username_claim")]
+    pub username_claim: String,
+    // Where the device flow helper endpoints are served
+    #[serde(default = "default_path")]
+    pub path: String,
+    // Seconds an introspection result is remembered
+    #[serde(default = "default_cache_ttl")]
+    pub cache_ttl: u64,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        OidcConfig {
+            issuer: None,
+            client_id: String::new(),
+            client_secret: None,
+            scope: default_scope(),
+            audience: None,
+            username_claim: default_username_claim(),
+            path: default_path(),
+            cache_ttl: default_cache_ttl(),
+        }
+    }
+}
+
+fn default_scope() -> String {
+    "openid profile".to_string()
+}
+
+fn default_username_claim() -> String {
+    "preferred_username".to_string()
+}
+
+fn default_path() -> String {
+    "/oidc".to_string()
+}
+
+fn default_cache_ttl() -> u64 {
+    60
+}
+
+impl OidcConfig {
+    pub fn enabled(&self) -> bool {
+        self.issuer.is_some()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let Some(issuer) = &self.issuer else {
+            return Ok(());
+        };
+        if !issuer.starts_with("https://") && !issuer.starts_with("http://") {
+            return Err("oidc.issuer must be an http(s) URL".to_string());
+        }
+        if self.client_id.is_empty() {
+            return Err("oidc.client_id is required".to_string());
+        }
+        if !self.path.starts_with('/') {
+            return Err("oidc.path must start with '/'".to_string());
+        }
+        Ok(())
+    }
+
+    // Whether an origin-form request is for the device flow helper.
+    pub fn matches(&self, req: &Request<Body>) -> bool {
+        self.enabled()
+            && req.uri().authority().is_none()
+            && req
+                .uri()
+                .path()
+                .strip_prefix(self.path.as_str())
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    // Client authentication for the token and introspection endpoints
+    // (RFC 6749 2.3.1): Basic with a secret, otherwise client_id in the form.
+    fn client_auth(&self) -> (Option<String>, Vec<(&str, &str)>) {
+        match &self.client_secret {
+            Some(secret) => {
+                let creds = format!("{}:{}", percent_encode(&self.client_id), percent_encode(secret));
+                (Some(format!("Basic {}", BASE64.encode(creds))), Vec::new())
+            }
+            None => (None, vec![("client_id", self.client_id.as_str())]),
+        }
+    }
+}
+
+// Endpoints from the provider's discovery document.
+struct Provider {
+    device_endpoint: Option<String>,
+    token_endpoint: String,
+    introspection_endpoint: Option<String>,
+    // For JWT access tokens, checked against the provider's key set
+    jwt: Option<JwtConfig>,
+}
+
+#[derive(Default)]
+pub struct Oidc {
+    provider: RwLock<Option<Arc<Provider>>>,
+    jwt: Jwt,
+    // Opaque tokens that introspected as active: digest -> (user, expiry)
+    introspected: Mutex<HashMap<Vec<u8>, (String, Instant)>>,
+}
+
+impl Oidc {
+    fn provider(&self) -> Option<Arc<Provider>> {
+        self.provider.read().unwrap().clone()
+    }
+
+    // The username for a valid access token, or None (logged).
+    pub async fn authenticate(&self, config: &OidcConfig, token: &str) -> Option<String> {
+        let Some(provider) = self.provider() else {
+            warn!("❌ Proxy auth bearer token refused: OIDC provider not discovered yet");
+            return None;
+        };
+        let result = match (&provider.jwt, &provider.introspection_endpoint) {
+            (Some(jwt), _) if token.split('.').count() == 3 => self.jwt.verify(jwt, token).await,
+            (_, Some(endpoint)) => self.introspect(config, endpoint, token).await,
+            _ => Err("not a JWT and the provider has no introspection endpoint".to_string()),
+        };
+        match result {
+            Ok(user) => Some(user),
+            Err(e) => {
+                warn!("❌ Proxy auth OIDC token rejected: {}", e);
+                None
+            }
+        }
+    }
+
+    // RFC 7662 token introspection; only active results are cached.
+    async fn introspect(&self, config: &OidcConfig, endpoint: &str, token: &str) -> Result<String, String> {
+        let key = digest(MessageDigest::sha256(), token.as_bytes()).map_err(|e| e.to_string())?.to_vec();
+        if let Some((user, expires)) = self.introspected.lock().unwrap().get(&key) {
+            if *expires > Instant::now() {
+                return Ok(user.clone());
+            }
+        }
+        let (authorization, mut form) = config.client_auth();
+        form.extend([("token", token), ("token_type_hint", "access_token")]);
+        let (status, body) = call(endpoint, &form_encode(&form), authorization).await?;
+        if status != 200 {
+            return Err(format!("introspection answered {}", status));
+        }
+        let result = json::parse(&String::from_utf8_lossy(&body))?;
+        if result.get("active") != Some(&Json::Bool(true)) {
+            return Err("token is not active".to_string());
+        }
+        let user = [config.username_claim.as_str(), "username"]
+            .iter()
+            .find_map(|claim| result.get(claim).and_then(Json::as_str))
+            .filter(|user| crate::users::valid_name(user))
+            .ok_or_else(|| format!("no usable '{}' in introspection result", config.username_claim))?
+            .to_string();
+
+        let mut ttl = Duration::from_secs(config.cache_ttl);
+        if let Some(exp) = result.get("exp").and_then(Json::as_f64) {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+            ttl = ttl.min(Duration::from_secs_f64((exp - now).max(0.0)));
+        }
+        let mut introspected = self.introspected.lock().unwrap();
+        if introspected.len() >= 10_000 {
+            let now = Instant::now();
+            introspected.retain(|_, (_, expires)| *expires > now);
+        }
+        introspected.insert(key, (user.clone(), Instant::now() + ttl));
+        Ok(user)
+    }
+}
+
+async fn call(url: &str, form: &str, authorization: Option<String>) -> Result<(u16, Vec<u8>), String> {
+    let (url, form) = (url.to_string(), form.to_string());
+    tokio::task::spawn_blocking(move || crate::alert::post_form(&url, &form, authorization.as_deref(), FETCH_TIMEOUT))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+async fn discover(config: &OidcConfig) -> Result<Provider, String> {
+    let issuer = config.issuer.as_deref().unwrap_or_default().trim_end_matches('/');
+    let url = format!("{}/.well-known/openid-configuration", issuer);
+    let fetch_url = url.clone();
+    let (status, body) = tokio::task::spawn_blocking(move || crate::alert::get(&fetch_url, FETCH_TIMEOUT))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+    if status != 200 {
+        return Err(format!("{} answered {}", url, status));
+    }
+    let doc = json::parse(&String::from_utf8_lossy(&body))?;
+    let field = |name: &str| doc.get(name).and_then(Json::as_str).map(str::to_string);
+    // The document must be for the configured issuer (OIDC Discovery 4.3)
+    let doc_issuer = field("issuer").unwrap_or_default();
+    if doc_issuer.trim_end_matches('/') != issuer {
+        return Err(format!("{} is for issuer '{}'", url, doc_issuer));
+    }
+    let jwt = field("jwks_uri").map(|jwks_url| JwtConfig {
+        jwks_url: Some(jwks_url),
+        issuer: Some(doc_issuer.clone()),
+        audience: config.audience.clone(),
+        username_claim: config.username_claim.clone(),
+        ..JwtConfig::default()
+    });
+    Ok(Provider {
+        device_endpoint: field("device_authorization_endpoint"),
+        token_endpoint: field("token_endpoint").ok_or_else(|| format!("{} has no token_endpoint", url))?,
+        introspection_endpoint: field("introspection_endpoint"),
+        jwt,
+    })
+}
+
+// Discover the provider, then again (with fresh keys) every jwks_refresh.
+pub fn spawn(state: Arc<AppState>) {
+    if !state.config.oidc.enabled() {
+        return;
+    }
+    tokio::spawn(async move {
+        let config = &state.config.oidc;
+        loop {
+            let wait = match discover(config).await {
+                Ok(provider) => {
+                    if let Some(jwt) = &provider.jwt {
+                        jwt::fetch_keys(&state.oidc.jwt, jwt).await;
+                    }
+                    if provider.device_endpoint.is_none() {
+                        warn!("⚠️ OIDC provider has no device authorization endpoint; only tokens obtained elsewhere work");
+                    }
+                    let wait = provider.jwt.as_ref().map_or(JwtConfig::default().jwks_refresh, |jwt| jwt.jwks_refresh);
+                    if state.oidc.provider().is_none() {
+                        info!("🪪 OIDC sign-in via {}", config.issuer.as_deref().unwrap_or_default());
+                    }
+                    *state.oidc.provider.write().unwrap() = Some(Arc::new(provider));
+                    Duration::from_secs(wait)
+                }
+                Err(e) => {
+                    warn!("⚠️ OIDC discovery failed: {}", e);
+                    RETRY_DISCOVERY
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    });
+}
+
+// Device code flow helper, without proxy credentials:
+//   POST {path}/login  -> the provider's device authorization response
+//   POST {path}/token  -> polls the token endpoint; form field device_code
+// Provider responses are passed through as they are.
+pub async fn handle(req: Request<Body>, state: &AppState) -> Response<Body> {
+    let config = &state.config.oidc;
+    let route = req.uri().path()[config.path.len()..].trim_matches('/').to_string();
+    let Some(provider) = state.oidc.provider() else {
+        return text(StatusCode::SERVICE_UNAVAILABLE, "OIDC provider not available yet");
+    };
+    let (authorization, mut form) = config.client_auth();
+    let (endpoint, device_code) = match (req.method(), route.as_str()) {
+        (&Method::GET | &Method::POST, "login") => match &provider.device_endpoint {
+            Some(endpoint) => (endpoint.clone(), None),
+            None => return text(StatusCode::NOT_IMPLEMENTED, "The OIDC provider does not support the device flow"),
+        },
+        (&Method::POST, "token") => {
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap_or_default();
+            let device_code = crate::admin::form(&body)
+                .unwrap_or_default()
+                .into_iter()
+                .find(|(key, _)| key == "device_code")
+                .map(|(_, value)| value);
+            match device_code {
+                Some(code) => (provider.token_endpoint.clone(), Some(code)),
+                None => return text(StatusCode::BAD_REQUEST, "Form field device_code is required"),
+            }
+        }
+        _ => return text(StatusCode::NOT_FOUND, "Not found"),
+    };
+    match &device_code {
+        Some(code) => form.extend([("grant_type", DEVICE_GRANT), ("device_code", code.as_str())]),
+        None => {
+            // Public clients send client_id in the form anyway
+            if authorization.is_some() {
+                form.push(("client_id", config.client_id.as_str()));
+            }
+            form.push(("scope", config.scope.as_str()));
+        }
+    }
+    match call(&endpoint, &form_encode(&form), authorization).await {
+        Ok((status, body)) => Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .header(hyper::header::CACHE_CONTROL, "no-store")
+            .body(Body::from(body))
+            .unwrap(),
+        Err(e) => {
+            warn!("⚠️ OIDC provider request to {} failed: {}", endpoint, e);
+            text(StatusCode::BAD_GATEWAY, "OIDC provider unreachable")
+        }
+    }
+}
+
+fn text(status: StatusCode, body: &'static str) -> Response<Body> {
+    Response::builder().status(status).body(Body::from(body)).unwrap()
+}
+
+pub fn percent_encode(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(b as char),
+            _ => {
+                let _ = write!(out, "%{:02X}", b);
+            }
+        }
+    }
+    out
+}
+
+// application/x-www-form-urlencoded
+fn form_encode(pairs: &[(&str, &str)]) -> String {
+    pairs
+        .iter()
+        .map(|(key, value)| format!("{}={}", percent_encode(key), percent_encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
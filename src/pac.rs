@@ -0,0 +1,498 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::runtime::Handle;
+use tracing::{debug, info, warn};
+
+use crate::logging::civil_from_days;
+use crate::pacjs::{Builtins, Script, Value};
+use crate::pattern::glob_matches;
+use crate::tz::Zone;
+use crate::upstream::{Upstreams, DIRECT};
+use crate::AppState;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+const ENTRY: &str = "FindProxyForURL";
+const DAYS: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+const MONTHS: [&str; 12] = ["JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC"];
+
+// Parent selection by an existing proxy auto-config file, for destinations
+// no [[routes]] rule or [[geoip.routes]] entry covers:
+//
+//   [pac]
+//   source = "http://wpad.corp.example/proxy.pac"   # or a file path
+//   refresh = 3600
+//   fallback = "direct"
+//
+// Proxies the file returns are used when an [upstreams] entry has the same
+// address; entries naming other proxies are skipped.
+#[derive(Debug, Deserialize)]
+pub struct PacConfig {
+    pub source: Option<String>,
+    // Seconds between re-reads of the file
+    #[serde(default = "default_refresh")]
+    pub refresh: u64,
+    // Seconds an answer is reused for the same scheme, host and port
+    #[serde(default = "default_cache_ttl")]
+    pub cache_ttl: u64,
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    // "direct", an upstream or a pool, for when the file names nothing usable
+    // or fails to run
+    #[serde(default = "default_fallback")]
+    pub fallback: String,
+    // For weekdayRange, dateRange and timeRange; IANA name or "UTC"
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+}
+
+impl Default for PacConfig {
+    fn default() -> Self {
+        PacConfig {
+            source: None,
+            refresh: default_refresh(),
+            cache_ttl: default_cache_ttl(),
+            max_entries: default_max_entries(),
+            fallback: default_fallback(),
+            timezone: default_timezone(),
+        }
+    }
+}
+
+fn default_refresh() -> u64 {
+    3600
+}
+
+fn default_cache_ttl() -> u64 {
+    300
+}
+
+fn default_max_entries() -> usize {
+    10_000
+}
+
+fn default_fallback() -> String {
+    DIRECT.to_string()
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+impl PacConfig {
+    pub fn enabled(&self) -> bool {
+        self.source.is_some()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.source.as_deref() == Some("") {
+            return Err("pac.source must not be empty".to_string());
+        }
+        if self.refresh == 0 {
+            return Err("pac.refresh must be positive".to_string());
+        }
+        if self.cache_ttl > 0 && self.max_entries == 0 {
+            return Err("pac.max_entries must be positive".to_string());
+        }
+        Zone::load(&self.timezone).map_err(|e| format!("pac.timezone: {}", e))?;
+        Ok(())
+    }
+
+    fn is_url(&self) -> bool {
+        self.source
+            .as_deref()
+            .is_some_and(|s| s.starts_with("http://") || s.starts_with("https://"))
+    }
+}
+
+pub struct Pac {
+    // None until a file has loaded
+    script: RwLock<Option<Arc<Script>>>,
+    zone: Arc<Zone>,
+    // Truncated URL -> (what the file returned, expiry); None if it failed
+    answers: Mutex<HashMap<String, (Option<String>, Instant)>>,
+}
+
+impl Pac {
+    // A file source is read now, so a broken one stops startup; a URL is
+    // fetched by spawn.
+    pub fn new(config: &PacConfig) -> Result<Self, String> {
+        let pac = Pac {
+            script: RwLock::default(),
+            zone: Arc::new(Zone::load(&config.timezone)?),
+            answers: Mutex::default(),
+        };
+        if let (Some(path), false) = (&config.source, config.is_url()) {
+            let text = std::fs::read_to_string(path).map_err(|e| format!("pac.source {}: {}", path, e))?;
+            pac.load(&text).map_err(|e| format!("pac.source {}: {}", path, e))?;
+        }
+        Ok(pac)
+    }
+
+    fn load(&self, text: &str) -> Result<(), String> {
+        let script = Script::parse(text)?;
+        if !script.has_function(ENTRY) {
+            return Err(format!("no {} function", ENTRY));
+        }
+        *self.script.write().unwrap() = Some(Arc::new(script));
+        self.answers.lock().unwrap().clear();
+        Ok(())
+    }
+
+    // A failed read keeps the file loaded before.
+    fn reload(&self, source: &str, is_url: bool) {
+        let text = match is_url {
+            true => match crate::alert::download(source, FETCH_TIMEOUT) {
+                Ok((200, body)) => String::from_utf8_lossy(&body).into_owned(),
+                Ok((status, _)) => {
+                    warn!("⚠️ PAC file not updated: {} answered {}", source, status);
+                    return;
+                }
+                Err(e) => {
+                    warn!("⚠️ PAC file not updated: {}: {}", source, e);
+                    return;
+                }
+            },
+            false => match std::fs::read_to_string(source) {
+                Ok(text) => text,
+                Err(e) => {
+                    warn!("⚠️ PAC file not updated: {}: {}", source, e);
+                    return;
+                }
+            },
+        };
+        match self.load(&text) {
+            Ok(()) => info!("📜 PAC file loaded from {}", source),
+            Err(e) => warn!("⚠️ PAC file not updated: {}: {}", source, e),
+        }
+    }
+
+    // The route name ("direct", an upstream, a pool) for a destination.
+    // `url` is cut to scheme://host[:port]/ by the caller, so paths and
+    // queries neither reach the file nor split the cache.
+    pub async fn via(&self, config: &PacConfig, upstreams: &Upstreams, url: String, host: String) -> String {
+        if self.script.read().unwrap().is_none() {
+            return config.fallback.clone();
+        }
+        let answer = match self.cached(config, &url) {
+            Some(answer) => answer,
+            None => {
+                let answer = self.evaluate(&url, &host).await;
+                self.remember(config, url, answer.clone());
+                answer
+            }
+        };
+        match answer.as_deref().and_then(|answer| select(answer, upstreams)) {
+            Some(via) => {
+                debug!("📜 {} goes via '{}' by the PAC file", host, via);
+                via
+            }
+            None => config.fallback.clone(),
+        }
+    }
+
+    fn cached(&self, config: &PacConfig, url: &str) -> Option<Option<String>> {
+        if config.cache_ttl == 0 {
+            return None;
+        }
+        let mut answers = self.answers.lock().unwrap();
+        match answers.get(url) {
+            Some((answer, expires)) if *expires > Instant::now() => Some(answer.clone()),
+            Some(_) => {
+                answers.remove(url);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn remember(&self, config: &PacConfig, url: String, answer: Option<String>) {
+        if config.cache_ttl == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut answers = self.answers.lock().unwrap();
+        if answers.len() >= config.max_entries {
+            answers.retain(|_, (_, expires)| *expires > now);
+            if answers.len() >= config.max_entries {
+                answers.clear();
+            }
+        }
+        answers.insert(url, (answer, now + Duration::from_secs(config.cache_ttl)));
+    }
+
+    // Runs on the blocking pool, since the DNS functions wait for lookups.
+    async fn evaluate(&self, url: &str, host: &str) -> Option<String> {
+        let script = self.script.read().unwrap().clone()?;
+        let mut builtins = PacBuiltins {
+            zone: self.zone.clone(),
+            runtime: Handle::current(),
+        };
+        let args = vec![Value::Str(url.to_string()), Value::Str(host.to_string())];
+        let result = tokio::task::spawn_blocking(move || script.call(ENTRY, args, &mut builtins)).await;
+        match result {
+            Ok(Ok(Value::Str(answer))) => Some(answer),
+            Ok(Ok(other)) => {
+                warn!("⚠️ PAC file returned {} for {}", other.to_str(), url);
+                None
+            }
+            Ok(Err(e)) => {
+                warn!("⚠️ PAC file failed for {}: {}", url, e);
+                None
+            }
+            Err(e) => {
+                warn!("⚠️ PAC file failed for {}: {}", url, e);
+                None
+            }
+        }
+    }
+}
+
+// "PROXY a:3128; SOCKS5 b:1080; DIRECT": the first entry that is DIRECT or
+// a healthy upstream, else the first known upstream, so its fallback and
+// error handling apply as for a rule naming it.
+fn select(answer: &str, upstreams: &Upstreams) -> Option<String> {
+    let mut known = None;
+    for entry in answer.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let mut fields = entry.split_whitespace();
+        let kind = fields.next().unwrap_or_default().to_ascii_uppercase();
+        if kind == "DIRECT" {
+            return Some(DIRECT.to_string());
+        }
+        let proxy = matches!(kind.as_str(), "PROXY" | "HTTP" | "HTTPS" | "SOCKS" | "SOCKS5");
+        let Some(upstream) = fields.next().filter(|_| proxy).and_then(|address| upstreams.by_address(address)) else {
+            debug!("PAC entry '{}' names no configured upstream", entry);
+            continue;
+        };
+        if upstream.is_healthy() {
+            return Some(upstream.name.clone());
+        }
+        known.get_or_insert_with(|| upstream.name.clone());
+    }
+    known
+}
+
+// Fetches a URL source now and every `refresh` seconds after; a file
+// source, read at startup, is re-read on the same schedule.
+pub fn spawn(state: Arc<AppState>) {
+    let Some(pac) = &state.pac else {
+        return;
+    };
+    let config = &state.config.pac;
+    let (Some(source), is_url) = (config.source.clone(), config.is_url()) else {
+        return;
+    };
+    let refresh = Duration::from_secs(config.refresh);
+    let loaded = pac.script.read().unwrap().is_some();
+    tokio::spawn(async move {
+        if loaded {
+            tokio::time::sleep(refresh).await;
+        }
+        loop {
+            let state = state.clone();
+            let source = source.clone();
+            let _ = tokio::task::spawn_blocking(move || {
+                if let Some(pac) = &state.pac {
+                    pac.reload(&source, is_url);
+                }
+            })
+            .await;
+            tokio::time::sleep(refresh).await;
+        }
+    });
+}
+
+// The functions PAC files may call (as defined by Netscape and kept by
+// browsers since). DNS goes through the proxy's own resolver.
+struct PacBuiltins {
+    zone: Arc<Zone>,
+    runtime: Handle,
+}
+
+impl PacBuiltins {
+    fn resolve(&self, host: &str) -> Option<Ipv4Addr> {
+        if let Ok(ip) = host.parse::<Ipv4Addr>() {
+            return Some(ip);
+        }
+        let addrs = self.runtime.block_on(crate::dns::lookup(host, 0)).ok()?;
+        addrs.iter().find_map(|addr| match addr.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        })
+    }
+
+    // Local (weekday, year, month, day, seconds since midnight), in UTC
+    // if the last argument is "GMT".
+    fn now(&self, args: &[Value]) -> (usize, i64, u32, u32, u32) {
+        let utc = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        let gmt = matches!(args.last(), Some(Value::Str(s)) if s.eq_ignore_ascii_case("GMT"));
+        let local = match gmt {
+            true => utc,
+            false => utc + self.zone.offset(utc) as i64,
+        };
+        let days = local.div_euclid(86_400);
+        let (year, month, day) = civil_from_days(days);
+        let weekday = (days + 4).rem_euclid(7) as usize;
+        (weekday, year, month, day, local.rem_euclid(86_400) as u32)
+    }
+}
+
+impl Builtins for PacBuiltins {
+    fn call(&mut self, name: &str, args: &[Value]) -> Option<Result<Value, String>> {
+        let arg = |i: usize| args.get(i).map(Value::to_str).unwrap_or_default();
+        let value = match name {
+            "isPlainHostName" => Value::Bool(!arg(0).contains('.')),
+            "dnsDomainIs" => Value::Bool(arg(0).to_ascii_lowercase().ends_with(&arg(1).to_ascii_lowercase())),
+            "localHostOrDomainIs" => {
+                let (host, full) = (arg(0).to_ascii_lowercase(), arg(1).to_ascii_lowercase());
+                Value::Bool(host == full || (!host.contains('.') && full.split('.').next() == Some(host.as_str())))
+            }
+            "dnsDomainLevels" => Value::Num(arg(0).matches('.').count() as f64),
+            "shExpMatch" => Value::Bool(glob_matches(&arg(1), &arg(0))),
+            "isResolvable" => Value::Bool(self.resolve(&arg(0)).is_some()),
+            "dnsResolve" => match self.resolve(&arg(0)) {
+                Some(ip) => Value::Str(ip.to_string()),
+                None => Value::Null,
+            },
+            "isInNet" => {
+                let (Ok(pattern), Ok(mask)) = (arg(1).parse::<Ipv4Addr>(), arg(2).parse::<Ipv4Addr>()) else {
+                    return Some(Ok(Value::Bool(false)));
+                };
+                let mask = u32::from(mask);
+                let inside = self.resolve(&arg(0)).is_some_and(|ip| u32::from(ip) & mask == u32::from(pattern) & mask);
+                Value::Bool(inside)
+            }
+            "convert_addr" => Value::Num(arg(0).parse::<Ipv4Addr>().map_or(0.0, |ip| u32::from(ip) as f64)),
+            "myIpAddress" => Value::Str(my_ip_address().to_string()),
+            "weekdayRange" => {
+                let (weekday, ..) = self.now(args);
+                let day = |i: usize| DAYS.iter().position(|d| d.eq_ignore_ascii_case(&arg(i)));
+                let Some(first) = day(0) else {
+                    return Some(Ok(Value::Bool(false)));
+                };
+                let last = day(1).unwrap_or(first);
+                Value::Bool(within(weekday, first, last))
+            }
+            "timeRange" => Value::Bool(self.time_range(args)),
+            "dateRange" => Value::Bool(self.date_range(args)),
+            "alert" => Value::Undefined,
+            _ => return None,
+        };
+        Some(Ok(value))
+    }
+}
+
+impl PacBuiltins {
+    // timeRange(hour), (h1, h2), (h1, m1, h2, m2) or (h1, m1, s1, h2, m2, s2);
+    // the end is exclusive except in the single-hour form.
+    fn time_range(&self, args: &[Value]) -> bool {
+        let (.., now) = self.now(args);
+        let numbers: Vec<u32> = args
+            .iter()
+            .take_while(|a| !matches!(a, Value::Str(s) if s.eq_ignore_ascii_case("GMT")))
+            .map(|a| a.to_num() as u32)
+            .collect();
+        let (start, end) = match numbers[..] {
+            [hour] => return now / 3600 == hour,
+            [h1, h2] => (h1 * 3600, h2 * 3600),
+            [h1, m1, h2, m2] => (h1 * 3600 + m1 * 60, h2 * 3600 + m2 * 60),
+            [h1, m1, s1, h2, m2, s2] => (h1 * 3600 + m1 * 60 + s1, h2 * 3600 + m2 * 60 + s2),
+            _ => return false,
+        };
+        match start <= end {
+            true => (start..end).contains(&now),
+            false => now >= start || now < end,
+        }
+    }
+
+    // dateRange(day), (month), (year), or two of the same shape, e.g.
+    // (1, "JAN", 15, "MAR") or ("DEC", "JAN"); ranges may wrap around.
+    fn date_range(&self, args: &[Value]) -> bool {
+        let (_, year, month, day, _) = self.now(args);
+        // (field, value); fields are 0 = year, 1 = month, 2 = day so that a
+        // date's fields sort from most to least significant
+        let fields: Vec<(u8, i64)> = args
+            .iter()
+            .filter(|a| !matches!(a, Value::Str(s) if s.eq_ignore_ascii_case("GMT")))
+            .map(|a| match a {
+                Value::Str(s) => match MONTHS.iter().position(|m| m.eq_ignore_ascii_case(s)) {
+                    Some(m) => (1, m as i64 + 1),
+                    None => (3, 0),
+                },
+                a if a.to_num() > 31.0 => (0, a.to_num() as i64),
+                a => (2, a.to_num() as i64),
+            })
+            .collect();
+        let current = |field: u8| match field {
+            0 => year,
+            1 => month as i64,
+            _ => day as i64,
+        };
+        let key = |fields: &[(u8, i64)]| {
+            let mut fields = fields.to_vec();
+            fields.sort();
+            fields
+        };
+        let (first, last) = match fields.len() {
+            1 => (&fields[..], &fields[..]),
+            n if n % 2 == 0 && n <= 6 => fields.split_at(n / 2),
+            _ => return false,
+        };
+        let (first, last) = (key(first), key(last));
+        let shape: Vec<u8> = first.iter().map(|(f, _)| *f).collect();
+        let same_shape = shape == last.iter().map(|(f, _)| *f).collect::<Vec<_>>();
+        if shape.contains(&3) || !same_shape || shape.windows(2).any(|w| w[0] == w[1]) {
+            return false;
+        }
+        let today: Vec<i64> = shape.iter().map(|f| current(*f)).collect();
+        let first: Vec<i64> = first.iter().map(|(_, v)| *v).collect();
+        let last: Vec<i64> = last.iter().map(|(_, v)| *v).collect();
+        match first <= last {
+            true => first <= today && today <= last,
+            false => today >= first || today <= last,
+        }
+    }
+}
+
+// Whether `value` is in first..=last, counting round past the end.
+fn within(value: usize, first: usize, last: usize) -> bool {
+    match first <= last {
+        true => (first..=last).contains(&value),
+        false => value >= first || value <= last,
+    }
+}
+
+// The address outbound IPv4 traffic leaves from; connecting a UDP socket
+// sends nothing.
+fn my_ip_address() -> Ipv4Addr {
+    std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| {
+            socket.connect((Ipv4Addr::new(192, 0, 2, 1), 53))?;
+            socket.local_addr()
+        })
+        .ok()
+        .and_then(|addr| match addr.ip() {
+            IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+            _ => None,
+        })
+        .unwrap_or(Ipv4Addr::LOCALHOST)
+}
+
+// The URL handed to the file for a request to host:port.
+pub fn url(connect: bool, scheme: Option<&str>, host: &str, port: u16) -> String {
+    let scheme = match connect {
+        true => "https",
+        false => scheme.unwrap_or("http"),
+    };
+    let default = matches!((scheme, port), ("http", 80) | ("https", 443));
+    let host = match host.contains(':') && !host.starts_with('[') {
+        true => format!("[{}]", host),
+        false => host.to_string(),
+    };
+    match default {
+        true => format!("{}://{}/", scheme, host),
+        false => format!("{}://{}:{}/", scheme, host, port),
+    }
+}
@@ -0,0 +1,799 @@
+use std::collections::HashMap;
+
+// The part of JavaScript that proxy auto-config files are written in:
+// functions, `var`, `if`/`else`, `return`, the usual operators, string and
+// number literals, and the common string methods (`substring`, `indexOf`,
+// `toLowerCase`, `length`, ...). Loops, objects, arrays and regular
+// expressions are not supported; a file using them fails to load. Calls
+// to anything but the file's own functions go to `Builtins`, which
+// provides the PAC functions (`shExpMatch`, `isInNet`, ...).
+
+// Statements run per evaluation, so a runaway script ends with an error
+const MAX_STEPS: usize = 100_000;
+const MAX_DEPTH: usize = 64;
+// Statements and expressions within one another, checked as the file is
+// parsed; with MAX_DEPTH this bounds how deep evaluation recurses
+const MAX_NESTING: usize = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Undefined,
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+}
+
+impl Value {
+    pub fn truthy(&self) -> bool {
+        match self {
+            Value::Undefined | Value::Null => false,
+            Value::Bool(b) => *b,
+            Value::Num(n) => *n != 0.0 && !n.is_nan(),
+            Value::Str(s) => !s.is_empty(),
+        }
+    }
+
+    pub fn to_str(&self) -> String {
+        match self {
+            Value::Undefined => "undefined".to_string(),
+            Value::Null => "null".to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Num(n) if n.fract() == 0.0 && n.abs() < 1e15 => format!("{}", *n as i64),
+            Value::Num(n) => n.to_string(),
+            Value::Str(s) => s.clone(),
+        }
+    }
+
+    pub fn to_num(&self) -> f64 {
+        match self {
+            Value::Undefined => f64::NAN,
+            Value::Null => 0.0,
+            Value::Bool(b) => f64::from(u8::from(*b)),
+            Value::Num(n) => *n,
+            Value::Str(s) if s.trim().is_empty() => 0.0,
+            Value::Str(s) => s.trim().parse().unwrap_or(f64::NAN),
+        }
+    }
+
+    fn loose_eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Undefined | Value::Null, Value::Undefined | Value::Null) => true,
+            (Value::Undefined | Value::Null, _) | (_, Value::Undefined | Value::Null) => false,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (a, b) => a.to_num() == b.to_num(),
+        }
+    }
+}
+
+pub trait Builtins {
+    // None when there is no such function.
+    fn call(&mut self, name: &str, args: &[Value]) -> Option<Result<Value, String>>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(f64),
+    Str(String),
+    Ident(String),
+    Punct(&'static str),
+}
+
+// Longest first, so "===" is not read as "==" and "="
+const PUNCTUATORS: &[&str] = &[
+    "===", "!==", "==", "!=", "<=", ">=", "&&", "||", "+=", "(", ")", "{", "}", ";", ",", ".", "+", "-", "*", "/", "%",
+    "!", "<", ">", "=", "?", ":",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '/' && next == Some('/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && next == Some('*') {
+            let end = (i + 2..chars.len().saturating_sub(1)).find(|&j| chars[j] == '*' && chars[j + 1] == '/');
+            i = end.ok_or("unterminated comment")? + 2;
+        } else if c == '"' || c == '\'' {
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None | Some('\n') => return Err("unterminated string".to_string()),
+                    Some(&q) if q == c => break,
+                    Some('\\') => {
+                        i += 1;
+                        match chars.get(i) {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some(&other) => s.push(other),
+                            None => return Err("unterminated string".to_string()),
+                        }
+                    }
+                    Some(&other) => s.push(other),
+                }
+                i += 1;
+            }
+            i += 1;
+            tokens.push(Token::Str(s));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let n = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+                Some(hex) => u64::from_str_radix(hex, 16).map(|n| n as f64).ok(),
+                None => text.parse().ok(),
+            };
+            tokens.push(Token::Num(n.ok_or_else(|| format!("invalid number '{}'", text))?));
+        } else if c.is_alphabetic() || c == '_' || c == '$' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '$') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 3)].iter().collect();
+            let punct = PUNCTUATORS
+                .iter()
+                .find(|p| rest.starts_with(**p))
+                .ok_or_else(|| format!("unexpected character '{}'", c))?;
+            i += punct.chars().count();
+            tokens.push(Token::Punct(punct));
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+enum Expr {
+    Lit(Value),
+    Var(String),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    // Operands of a whole && or || chain, which long PAC conditions are
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+    Method(Box<Expr>, String, Vec<Expr>),
+    Prop(Box<Expr>, String),
+    Assign(String, Box<Expr>),
+}
+
+#[derive(Debug)]
+enum Stmt {
+    Var(Vec<(String, Option<Expr>)>),
+    Expr(Expr),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+    Return(Option<Expr>),
+    Block(Vec<Stmt>),
+}
+
+#[derive(Debug)]
+struct Function {
+    params: Vec<String>,
+    body: Vec<Stmt>,
+}
+
+// A parsed file: its functions and the top-level statements that set up
+// its global variables.
+#[derive(Debug)]
+pub struct Script {
+    functions: HashMap<String, Function>,
+    globals: Vec<Stmt>,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+    // Statements and expressions being parsed inside one another
+    nesting: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self.tokens.get(self.at).cloned().ok_or("unexpected end of file")?;
+        self.at += 1;
+        Ok(token)
+    }
+
+    fn is(&self, punct: &str) -> bool {
+        matches!(self.peek(), Some(Token::Punct(p)) if *p == punct)
+    }
+
+    fn is_word(&self, word: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(w)) if w == word)
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        let found = self.is(punct);
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), String> {
+        match self.eat(punct) {
+            true => Ok(()),
+            false => Err(format!("expected '{}' near {}", punct, self.position())),
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, String> {
+        match self.next()? {
+            Token::Ident(name) => Ok(name),
+            other => Err(format!("expected a name, found {:?}", other)),
+        }
+    }
+
+    fn position(&self) -> String {
+        match self.peek() {
+            Some(token) => format!("{:?}", token),
+            None => "end of file".to_string(),
+        }
+    }
+
+    fn script(&mut self) -> Result<Script, String> {
+        let mut functions = HashMap::new();
+        let mut globals = Vec::new();
+        while self.peek().is_some() {
+            if self.is_word("function") {
+                self.at += 1;
+                let name = self.ident()?;
+                functions.insert(name, self.function()?);
+            } else {
+                globals.push(self.statement()?);
+            }
+        }
+        Ok(Script { functions, globals })
+    }
+
+    fn function(&mut self) -> Result<Function, String> {
+        self.expect("(")?;
+        let mut params = Vec::new();
+        while !self.eat(")") {
+            params.push(self.ident()?);
+            if !self.is(")") {
+                self.expect(",")?;
+            }
+        }
+        self.expect("{")?;
+        Ok(Function {
+            params,
+            body: self.block()?,
+        })
+    }
+
+    // Statements up to and including the closing brace.
+    fn block(&mut self) -> Result<Vec<Stmt>, String> {
+        let mut body = Vec::new();
+        while !self.eat("}") {
+            body.push(self.statement()?);
+        }
+        Ok(body)
+    }
+
+    fn statement(&mut self) -> Result<Stmt, String> {
+        self.enter()?;
+        let stmt = self.nested_statement();
+        self.nesting -= 1;
+        stmt
+    }
+
+    fn nested_statement(&mut self) -> Result<Stmt, String> {
+        if self.eat("{") {
+            return Ok(Stmt::Block(self.block()?));
+        }
+        if self.eat(";") {
+            return Ok(Stmt::Block(Vec::new()));
+        }
+        let stmt = match self.peek() {
+            Some(Token::Ident(word)) if word == "var" || word == "let" || word == "const" => {
+                self.at += 1;
+                let mut vars = Vec::new();
+                loop {
+                    let name = self.ident()?;
+                    let init = match self.eat("=") {
+                        true => Some(self.expr()?),
+                        false => None,
+                    };
+                    vars.push((name, init));
+                    if !self.eat(",") {
+                        break;
+                    }
+                }
+                Stmt::Var(vars)
+            }
+            Some(Token::Ident(word)) if word == "if" => {
+                self.at += 1;
+                self.expect("(")?;
+                let cond = self.expr()?;
+                self.expect(")")?;
+                let then = Box::new(self.statement()?);
+                let otherwise = match self.is_word("else") {
+                    true => {
+                        self.at += 1;
+                        Some(Box::new(self.statement()?))
+                    }
+                    false => None,
+                };
+                return Ok(Stmt::If(cond, then, otherwise));
+            }
+            Some(Token::Ident(word)) if word == "return" => {
+                self.at += 1;
+                match self.is(";") || self.is("}") {
+                    true => Stmt::Return(None),
+                    false => Stmt::Return(Some(self.expr()?)),
+                }
+            }
+            Some(Token::Ident(word)) if ["for", "while", "do", "switch", "try", "function"].contains(&word.as_str()) => {
+                return Err(format!("'{}' is not supported", word));
+            }
+            _ => Stmt::Expr(self.expr()?),
+        };
+        // Semicolons may be left out before a closing brace or the end
+        if !self.eat(";") && !self.is("}") && self.peek().is_some() && !matches!(self.peek(), Some(Token::Ident(_))) {
+            return Err(format!("expected ';' near {}", self.position()));
+        }
+        Ok(stmt)
+    }
+
+    // An expression of a statement.
+    fn expr(&mut self) -> Result<Expr, String> {
+        Ok(self.expression()?.0)
+    }
+
+    // Each parsing function returns the expression with the depth of its
+    // tree, which evaluating it recurses as deep as.
+    fn expression(&mut self) -> Result<(Expr, usize), String> {
+        self.enter()?;
+        let result = self.assignment();
+        self.nesting -= 1;
+        result
+    }
+
+    // Parsing recurses for each nested statement and expression, so a file
+    // of many nested brackets could otherwise exhaust the stack.
+    fn enter(&mut self) -> Result<(), String> {
+        if self.nesting >= MAX_NESTING {
+            return Err(format!("nested too deeply near {}", self.position()));
+        }
+        self.nesting += 1;
+        Ok(())
+    }
+
+    fn node(&self, expr: Expr, depth: usize) -> Result<(Expr, usize), String> {
+        match depth > MAX_NESTING {
+            true => Err(format!("expression nested too deeply near {}", self.position())),
+            false => Ok((expr, depth)),
+        }
+    }
+
+    fn assignment(&mut self) -> Result<(Expr, usize), String> {
+        let (target, depth) = self.conditional()?;
+        for (op, binary) in [("=", None), ("+=", Some("+"))] {
+            if self.eat(op) {
+                let Expr::Var(name) = target else {
+                    return Err("can only assign to a variable".to_string());
+                };
+                let (value, depth) = self.expression()?;
+                let (value, depth) = match binary {
+                    Some(op) => (Expr::Binary(op, Box::new(Expr::Var(name.clone())), Box::new(value)), depth + 1),
+                    None => (value, depth),
+                };
+                return self.node(Expr::Assign(name, Box::new(value)), depth + 1);
+            }
+        }
+        Ok((target, depth))
+    }
+
+    fn conditional(&mut self) -> Result<(Expr, usize), String> {
+        let (cond, depth) = self.or()?;
+        if !self.eat("?") {
+            return Ok((cond, depth));
+        }
+        let (then, then_depth) = self.expression()?;
+        self.expect(":")?;
+        let (otherwise, otherwise_depth) = self.expression()?;
+        let depth = depth.max(then_depth).max(otherwise_depth) + 1;
+        self.node(Expr::Cond(Box::new(cond), Box::new(then), Box::new(otherwise)), depth)
+    }
+
+    fn or(&mut self) -> Result<(Expr, usize), String> {
+        let (first, mut depth) = self.and()?;
+        let mut operands = vec![first];
+        while self.eat("||") {
+            let (operand, operand_depth) = self.and()?;
+            operands.push(operand);
+            depth = depth.max(operand_depth);
+        }
+        match operands.len() {
+            1 => Ok((operands.remove(0), depth)),
+            _ => self.node(Expr::Or(operands), depth + 1),
+        }
+    }
+
+    fn and(&mut self) -> Result<(Expr, usize), String> {
+        let (first, mut depth) = self.binary(0)?;
+        let mut operands = vec![first];
+        while self.eat("&&") {
+            let (operand, operand_depth) = self.binary(0)?;
+            operands.push(operand);
+            depth = depth.max(operand_depth);
+        }
+        match operands.len() {
+            1 => Ok((operands.remove(0), depth)),
+            _ => self.node(Expr::And(operands), depth + 1),
+        }
+    }
+
+    // Equality, then relational, additive and multiplicative operators
+    fn binary(&mut self, level: usize) -> Result<(Expr, usize), String> {
+        const LEVELS: &[&[&str]] = &[&["===", "!==", "==", "!="], &["<=", ">=", "<", ">"], &["+", "-"], &["*", "/", "%"]];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let (mut left, mut depth) = self.binary(level + 1)?;
+        while let Some(op) = LEVELS[level].iter().find(|op| self.is(op)) {
+            self.at += 1;
+            let (right, right_depth) = self.binary(level + 1)?;
+            (left, depth) = self.node(Expr::Binary(op, Box::new(left), Box::new(right)), depth.max(right_depth) + 1)?;
+        }
+        Ok((left, depth))
+    }
+
+    fn unary(&mut self) -> Result<(Expr, usize), String> {
+        for (op, wrap) in [("!", Expr::Not as fn(Box<Expr>) -> Expr), ("-", Expr::Neg)] {
+            if self.eat(op) {
+                self.enter()?;
+                let operand = self.unary();
+                self.nesting -= 1;
+                let (operand, depth) = operand?;
+                return self.node(wrap(Box::new(operand)), depth + 1);
+            }
+        }
+        self.postfix()
+    }
+
+    fn postfix(&mut self) -> Result<(Expr, usize), String> {
+        let (mut expr, mut depth) = self.primary()?;
+        while self.eat(".") {
+            let name = self.ident()?;
+            (expr, depth) = match self.eat("(") {
+                true => {
+                    let (args, args_depth) = self.arguments()?;
+                    self.node(Expr::Method(Box::new(expr), name, args), depth.max(args_depth) + 1)?
+                }
+                false => self.node(Expr::Prop(Box::new(expr), name), depth + 1)?,
+            };
+        }
+        Ok((expr, depth))
+    }
+
+    // After the opening parenthesis, up to and including the closing one;
+    // with the depth of the deepest.
+    fn arguments(&mut self) -> Result<(Vec<Expr>, usize), String> {
+        let (mut args, mut depth) = (Vec::new(), 0);
+        while !self.eat(")") {
+            let (arg, arg_depth) = self.expression()?;
+            args.push(arg);
+            depth = depth.max(arg_depth);
+            if !self.is(")") {
+                self.expect(",")?;
+            }
+        }
+        Ok((args, depth))
+    }
+
+    fn primary(&mut self) -> Result<(Expr, usize), String> {
+        let expr = match self.next()? {
+            Token::Num(n) => Expr::Lit(Value::Num(n)),
+            Token::Str(s) => Expr::Lit(Value::Str(s)),
+            Token::Punct("(") => {
+                let expr = self.expression()?;
+                self.expect(")")?;
+                return Ok(expr);
+            }
+            Token::Ident(name) => match name.as_str() {
+                "true" => Expr::Lit(Value::Bool(true)),
+                "false" => Expr::Lit(Value::Bool(false)),
+                "null" => Expr::Lit(Value::Null),
+                "undefined" => Expr::Lit(Value::Undefined),
+                _ if self.eat("(") => {
+                    let (args, depth) = self.arguments()?;
+                    return self.node(Expr::Call(name, args), depth + 1);
+                }
+                _ => Expr::Var(name),
+            },
+            other => return Err(format!("unexpected {:?}", other)),
+        };
+        Ok((expr, 1))
+    }
+}
+
+enum Flow {
+    Normal,
+    Return(Value),
+}
+
+struct Eval<'a> {
+    script: &'a Script,
+    builtins: &'a mut dyn Builtins,
+    globals: HashMap<String, Value>,
+    steps: usize,
+    depth: usize,
+}
+
+impl Script {
+    pub fn parse(source: &str) -> Result<Script, String> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            at: 0,
+            nesting: 0,
+        };
+        parser.script()
+    }
+
+    pub fn has_function(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
+
+    // Runs the top-level statements, then calls `name`.
+    pub fn call(&self, name: &str, args: Vec<Value>, builtins: &mut dyn Builtins) -> Result<Value, String> {
+        let mut eval = Eval {
+            script: self,
+            builtins,
+            globals: HashMap::new(),
+            steps: 0,
+            depth: 0,
+        };
+        let mut globals = HashMap::new();
+        for stmt in &self.globals {
+            eval.exec(stmt, &mut globals)?;
+        }
+        eval.globals = globals;
+        eval.call(name, args)
+    }
+}
+
+impl Eval<'_> {
+    fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, String> {
+        let Some(function) = self.script.functions.get(name) else {
+            return self
+                .builtins
+                .call(name, &args)
+                .unwrap_or_else(|| Err(format!("{} is not defined", name)));
+        };
+        if self.depth >= MAX_DEPTH {
+            return Err("too much recursion".to_string());
+        }
+        let args = args.into_iter().chain(std::iter::repeat(Value::Undefined));
+        let mut locals: HashMap<String, Value> = function.params.iter().cloned().zip(args).collect();
+        self.depth += 1;
+        let mut result = Ok(Value::Undefined);
+        for stmt in &function.body {
+            match self.exec(stmt, &mut locals) {
+                Ok(Flow::Normal) => {}
+                Ok(Flow::Return(value)) => {
+                    result = Ok(value);
+                    break;
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.depth -= 1;
+        result
+    }
+
+    fn exec(&mut self, stmt: &Stmt, locals: &mut HashMap<String, Value>) -> Result<Flow, String> {
+        self.steps += 1;
+        if self.steps > MAX_STEPS {
+            return Err("script ran too long".to_string());
+        }
+        match stmt {
+            Stmt::Var(vars) => {
+                for (name, init) in vars {
+                    let value = match init {
+                        Some(init) => self.eval(init, locals)?,
+                        None => Value::Undefined,
+                    };
+                    locals.insert(name.clone(), value);
+                }
+            }
+            Stmt::Expr(expr) => {
+                self.eval(expr, locals)?;
+            }
+            Stmt::If(cond, then, otherwise) => {
+                if self.eval(cond, locals)?.truthy() {
+                    return self.exec(then, locals);
+                }
+                if let Some(otherwise) = otherwise {
+                    return self.exec(otherwise, locals);
+                }
+            }
+            Stmt::Return(value) => {
+                let value = match value {
+                    Some(value) => self.eval(value, locals)?,
+                    None => Value::Undefined,
+                };
+                return Ok(Flow::Return(value));
+            }
+            Stmt::Block(body) => {
+                for stmt in body {
+                    if let Flow::Return(value) = self.exec(stmt, locals)? {
+                        return Ok(Flow::Return(value));
+                    }
+                }
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn eval(&mut self, expr: &Expr, locals: &mut HashMap<String, Value>) -> Result<Value, String> {
+        self.steps += 1;
+        if self.steps > MAX_STEPS {
+            return Err("script ran too long".to_string());
+        }
+        Ok(match expr {
+            Expr::Lit(value) => value.clone(),
+            Expr::Var(name) => match locals.get(name).or_else(|| self.globals.get(name)) {
+                Some(value) => value.clone(),
+                None => return Err(format!("{} is not defined", name)),
+            },
+            Expr::Not(inner) => Value::Bool(!self.eval(inner, locals)?.truthy()),
+            Expr::Neg(inner) => Value::Num(-self.eval(inner, locals)?.to_num()),
+            // The first operand that decides the chain, or the last
+            Expr::And(operands) => {
+                let mut value = Value::Undefined;
+                for operand in operands {
+                    value = self.eval(operand, locals)?;
+                    if !value.truthy() {
+                        break;
+                    }
+                }
+                value
+            }
+            Expr::Or(operands) => {
+                let mut value = Value::Undefined;
+                for operand in operands {
+                    value = self.eval(operand, locals)?;
+                    if value.truthy() {
+                        break;
+                    }
+                }
+                value
+            }
+            Expr::Cond(cond, then, otherwise) => match self.eval(cond, locals)?.truthy() {
+                true => self.eval(then, locals)?,
+                false => self.eval(otherwise, locals)?,
+            },
+            Expr::Binary(op, left, right) => {
+                let (left, right) = (self.eval(left, locals)?, self.eval(right, locals)?);
+                binary(op, left, right)
+            }
+            Expr::Assign(name, value) => {
+                let value = self.eval(value, locals)?;
+                // Undeclared names become globals, as in sloppy-mode JavaScript
+                match locals.get_mut(name) {
+                    Some(slot) => *slot = value.clone(),
+                    None => {
+                        self.globals.insert(name.clone(), value.clone());
+                    }
+                }
+                value
+            }
+            Expr::Call(name, args) => {
+                let args = args.iter().map(|arg| self.eval(arg, locals)).collect::<Result<Vec<_>, _>>()?;
+                self.call(name, args)?
+            }
+            Expr::Prop(target, name) => match (self.eval(target, locals)?, name.as_str()) {
+                (Value::Str(s), "length") => Value::Num(s.chars().count() as f64),
+                (_, name) => return Err(format!("property '{}' is not supported", name)),
+            },
+            Expr::Method(target, name, args) => {
+                let target = self.eval(target, locals)?;
+                let args = args.iter().map(|arg| self.eval(arg, locals)).collect::<Result<Vec<_>, _>>()?;
+                method(&target.to_str(), name, &args)?
+            }
+        })
+    }
+}
+
+fn binary(op: &str, left: Value, right: Value) -> Value {
+    match op {
+        "+" => match (&left, &right) {
+            (Value::Str(_), _) | (_, Value::Str(_)) => Value::Str(left.to_str() + &right.to_str()),
+            _ => Value::Num(left.to_num() + right.to_num()),
+        },
+        "-" => Value::Num(left.to_num() - right.to_num()),
+        "*" => Value::Num(left.to_num() * right.to_num()),
+        "/" => Value::Num(left.to_num() / right.to_num()),
+        "%" => Value::Num(left.to_num() % right.to_num()),
+        "==" => Value::Bool(left.loose_eq(&right)),
+        "!=" => Value::Bool(!left.loose_eq(&right)),
+        "===" => Value::Bool(left == right),
+        "!==" => Value::Bool(left != right),
+        _ => {
+            let ordering = match (&left, &right) {
+                (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+                _ => left.to_num().partial_cmp(&right.to_num()),
+            };
+            Value::Bool(match (op, ordering) {
+                (_, None) => false,
+                ("<", Some(o)) => o.is_lt(),
+                (">", Some(o)) => o.is_gt(),
+                ("<=", Some(o)) => o.is_le(),
+                (_, Some(o)) => o.is_ge(),
+            })
+        }
+    }
+}
+
+// String methods, with JavaScript's clamping of out-of-range indices.
+fn method(s: &str, name: &str, args: &[Value]) -> Result<Value, String> {
+    let chars: Vec<char> = s.chars().collect();
+    let len = chars.len() as f64;
+    let arg = |i: usize| args.get(i).cloned().unwrap_or(Value::Undefined);
+    let index = |i: usize, default: f64| match arg(i) {
+        Value::Undefined => default,
+        value => value.to_num().max(0.0).min(len),
+    };
+    let find = |needle: &str, from: usize| {
+        let rest: String = chars[from..].iter().collect();
+        rest.find(needle).map(|byte| from + rest[..byte].chars().count())
+    };
+    Ok(match name {
+        "toLowerCase" => Value::Str(s.to_lowercase()),
+        "toUpperCase" => Value::Str(s.to_uppercase()),
+        "trim" => Value::Str(s.trim().to_string()),
+        "substring" => {
+            let (a, b) = (index(0, 0.0) as usize, index(1, len) as usize);
+            Value::Str(chars[a.min(b)..a.max(b)].iter().collect())
+        }
+        "substr" => {
+            let start = match arg(0).to_num() {
+                n if n < 0.0 => (len + n).max(0.0),
+                n => n.min(len),
+            } as usize;
+            let count = match arg(1) {
+                Value::Undefined => len as usize,
+                value => value.to_num().max(0.0) as usize,
+            };
+            Value::Str(chars[start..].iter().take(count).collect())
+        }
+        "charAt" => Value::Str(chars.get(arg(0).to_num().max(0.0) as usize).map(|c| c.to_string()).unwrap_or_default()),
+        "indexOf" => {
+            let from = index(1, 0.0) as usize;
+            Value::Num(find(&arg(0).to_str(), from).map_or(-1.0, |at| at as f64))
+        }
+        "lastIndexOf" => {
+            let needle = arg(0).to_str();
+            let at = s.rfind(&needle).map(|byte| s[..byte].chars().count());
+            Value::Num(at.map_or(-1.0, |at| at as f64))
+        }
+        "startsWith" => Value::Bool(s.starts_with(&arg(0).to_str())),
+        "endsWith" => Value::Bool(s.ends_with(&arg(0).to_str())),
+        "includes" => Value::Bool(s.contains(&arg(0).to_str())),
+        "replace" => Value::Str(s.replacen(&arg(0).to_str(), &arg(1).to_str(), 1)),
+        "toString" => Value::Str(s.to_string()),
+        _ => return Err(format!("method '{}' is not supported", name)),
+    })
+}
@@ -0,0 +1,382 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Mutex, OnceLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::access::AccessLogConfig;
+use crate::logging::civil_from_days;
+
+// Access log rows written as Parquet files, for loading straight into
+// DuckDB, Athena and the like. Configured under [access_log].
+
+// Rows waiting for the writer thread; beyond this they are dropped.
+const QUEUE_ROWS: usize = 10_000;
+
+// One access event. The column set and order are the file schema; add
+// columns at the end so existing queries keep working.
+pub struct Row {
+    pub timestamp_ms: i64,
+    pub request_id: String,
+    pub client_ip: String,
+    pub user: String,
+    pub method: String,
+    pub target: String,
+    pub status: i32,
+    pub bytes: i64,
+    pub listener: String,
+}
+
+enum Message {
+    Row(Row),
+    Close,
+}
+
+struct Sink {
+    tx: SyncSender<Message>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+    dropped: AtomicU64,
+}
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+// Start the writer thread if `parquet_dir` is set.
+pub fn start(config: &AccessLogConfig) -> io::Result<()> {
+    let Some(dir) = config.parquet_dir.clone() else {
+        return Ok(());
+    };
+    fs::create_dir_all(&dir)?;
+    info!("🪵 Writing access logs as Parquet to {}", dir.display());
+    let (tx, rx) = mpsc::sync_channel(QUEUE_ROWS);
+    let rows_per_file = config.rows_per_file;
+    let interval = Duration::from_secs(config.flush_interval);
+    let thread = std::thread::Builder::new()
+        .name("parquet-log".to_string())
+        .spawn(move || {
+            let mut batch = Vec::new();
+            let mut deadline = Instant::now() + interval;
+            loop {
+                let full = match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(Message::Row(row)) => {
+                        batch.push(row);
+                        batch.len() >= rows_per_file
+                    }
+                    Err(RecvTimeoutError::Timeout) => true,
+                    Ok(Message::Close) | Err(RecvTimeoutError::Disconnected) => {
+                        write_batch(&dir, &mut batch);
+                        return;
+                    }
+                };
+                if full {
+                    write_batch(&dir, &mut batch);
+                    deadline = Instant::now() + interval;
+                }
+            }
+        })?;
+    let _ = SINK.set(Sink {
+        tx,
+        thread: Mutex::new(Some(thread)),
+        dropped: AtomicU64::new(0),
+    });
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    SINK.get().is_some()
+}
+
+pub fn record(row: Row) {
+    let Some(sink) = SINK.get() else { return };
+    if let Err(TrySendError::Full(_)) = sink.tx.try_send(Message::Row(row)) {
+        sink.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Write out buffered rows and stop the writer; used at exit.
+pub fn close() {
+    let Some(sink) = SINK.get() else { return };
+    let _ = sink.tx.send(Message::Close);
+    if let Some(thread) = sink.thread.lock().unwrap().take() {
+        let _ = thread.join();
+    }
+}
+
+pub fn now_ms() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
+
+// Files go into Hive-style date partitions, e.g.
+// date=2026-10-16/access-1760572800000-1234.parquet, written under a
+// temporary name and renamed so readers never see a partial file.
+fn write_batch(dir: &Path, batch: &mut Vec<Row>) {
+    if let Some(sink) = SINK.get() {
+        let dropped = sink.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("⚠️ Parquet access log queue full, dropped {} row(s)", dropped);
+        }
+    }
+    if batch.is_empty() {
+        return;
+    }
+    let first = batch[0].timestamp_ms;
+    let (year, month, day) = civil_from_days(first.div_euclid(86_400_000));
+    let partition = dir.join(format!("date={:04}-{:02}-{:02}", year, month, day));
+    let name = format!("access-{}-{}.parquet", first, std::process::id());
+    let result = fs::create_dir_all(&partition).and_then(|_| {
+        let tmp = partition.join(format!(".{}.tmp", name));
+        fs::write(&tmp, encode(batch))?;
+        fs::rename(&tmp, partition.join(&name))
+    });
+    match result {
+        Ok(()) => info!("🪵 Wrote {} access log row(s) to {}", batch.len(), partition.join(&name).display()),
+        Err(e) => warn!("⚠️ Failed to write Parquet access log {}: {}", name, e),
+    }
+    batch.clear();
+}
+
+// Parquet physical types and the converted types used here
+const INT32: i32 = 1;
+const INT64: i32 = 2;
+const BYTE_ARRAY: i32 = 6;
+const UTF8: i32 = 0;
+const TIMESTAMP_MILLIS: i32 = 9;
+const REQUIRED: i32 = 0;
+const PLAIN: i32 = 0;
+const RLE: i32 = 3;
+const UNCOMPRESSED: i32 = 0;
+const DATA_PAGE: i32 = 0;
+
+struct Column {
+    name: &'static str,
+    physical: i32,
+    converted: Option<i32>,
+    values: Vec<u8>,
+}
+
+impl Column {
+    fn new(name: &'static str, physical: i32, converted: Option<i32>) -> Self {
+        Column {
+            name,
+            physical,
+            converted,
+            values: Vec::new(),
+        }
+    }
+
+    fn push_i32(&mut self, value: i32) {
+        self.values.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn push_i64(&mut self, value: i64) {
+        self.values.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn push_str(&mut self, value: &str) {
+        self.values.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        self.values.extend_from_slice(value.as_bytes());
+    }
+}
+
+// A single-row-group file: one uncompressed, PLAIN-encoded data page per
+// column, all columns required (so no definition or repetition levels).
+fn encode(rows: &[Row]) -> Vec<u8> {
+    let mut columns = [
+        Column::new("timestamp", INT64, Some(TIMESTAMP_MILLIS)),
+        Column::new("request_id", BYTE_ARRAY, Some(UTF8)),
+        Column::new("client_ip", BYTE_ARRAY, Some(UTF8)),
+        Column::new("user", BYTE_ARRAY, Some(UTF8)),
+        Column::new("method", BYTE_ARRAY, Some(UTF8)),
+        Column::new("target", BYTE_ARRAY, Some(UTF8)),
+        Column::new("status", INT32, None),
+        Column::new("bytes", INT64, None),
+        Column::new("listener", BYTE_ARRAY, Some(UTF8)),
+    ];
+    for row in rows {
+        columns[0].push_i64(row.timestamp_ms);
+        columns[1].push_str(&row.request_id);
+        columns[2].push_str(&row.client_ip);
+        columns[3].push_str(&row.user);
+        columns[4].push_str(&row.method);
+        columns[5].push_str(&row.target);
+        columns[6].push_i32(row.status);
+        columns[7].push_i64(row.bytes);
+        columns[8].push_str(&row.listener);
+    }
+    let num_rows = rows.len() as i64;
+
+    let mut out = b"PAR1".to_vec();
+    // (data page offset, chunk size) per column
+    let mut chunks = Vec::new();
+    for column in &columns {
+        let mut header = Compact::new();
+        header.i32(1, DATA_PAGE);
+        header.i32(2, column.values.len() as i32);
+        header.i32(3, column.values.len() as i32);
+        header.structure(5, |page| {
+            page.i32(1, rows.len() as i32);
+            page.i32(2, PLAIN);
+            page.i32(3, RLE);
+            page.i32(4, RLE);
+        });
+        let header = header.finish();
+        chunks.push((out.len() as i64, (header.len() + column.values.len()) as i64));
+        out.extend_from_slice(&header);
+        out.extend_from_slice(&column.values);
+    }
+
+    let mut meta = Compact::new();
+    meta.i32(1, 1);
+    meta.list_struct(2, columns.len() + 1, |schema, i| {
+        if i == 0 {
+            schema.string(4, "schema");
+            schema.i32(5, columns.len() as i32);
+            return;
+        }
+        let column = &columns[i - 1];
+        schema.i32(1, column.physical);
+        schema.i32(3, REQUIRED);
+        schema.string(4, column.name);
+        if let Some(converted) = column.converted {
+            schema.i32(6, converted);
+        }
+    });
+    meta.i64(3, num_rows);
+    meta.list_struct(4, 1, |group, _| {
+        group.list_struct(1, columns.len(), |chunk, i| {
+            let (offset, size) = chunks[i];
+            chunk.i64(2, offset);
+            chunk.structure(3, |meta| {
+                meta.i32(1, columns[i].physical);
+                meta.list_i32(2, &[PLAIN, RLE]);
+                meta.list_string(3, &[columns[i].name]);
+                meta.i32(4, UNCOMPRESSED);
+                meta.i64(5, num_rows);
+                meta.i64(6, size);
+                meta.i64(7, size);
+                meta.i64(9, offset);
+            });
+        });
+        group.i64(2, chunks.iter().map(|(_, size)| size).sum());
+        group.i64(3, num_rows);
+    });
+    meta.string(6, concat!("secure-proxy version ", env!("CARGO_PKG_VERSION")));
+    let meta = meta.finish();
+
+    out.extend_from_slice(&meta);
+    out.extend_from_slice(&(meta.len() as u32).to_le_bytes());
+    out.extend_from_slice(b"PAR1");
+    out
+}
+
+// Just enough of the Thrift compact protocol for Parquet metadata.
+struct Compact {
+    buf: Vec<u8>,
+    // Last field id written, per open struct
+    last: Vec<i16>,
+}
+
+const T_I32: u8 = 5;
+const T_I64: u8 = 6;
+const T_BINARY: u8 = 8;
+const T_LIST: u8 = 9;
+const T_STRUCT: u8 = 12;
+
+impl Compact {
+    fn new() -> Self {
+        Compact {
+            buf: Vec::new(),
+            last: vec![0],
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.buf.push(0);
+        self.buf
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn field(&mut self, id: i16, kind: u8) {
+        let last = self.last.last_mut().expect("inside a struct");
+        let delta = id - *last;
+        *last = id;
+        if (1..=15).contains(&delta) {
+            self.buf.push((delta as u8) << 4 | kind);
+        } else {
+            self.buf.push(kind);
+            self.varint(((id << 1) ^ (id >> 15)) as u16 as u64);
+        }
+    }
+
+    fn list_header(&mut self, len: usize, kind: u8) {
+        if len < 15 {
+            self.buf.push((len as u8) << 4 | kind);
+        } else {
+            self.buf.push(0xf0 | kind);
+            self.varint(len as u64);
+        }
+    }
+
+    fn i32(&mut self, id: i16, value: i32) {
+        self.field(id, T_I32);
+        self.varint(((value << 1) ^ (value >> 31)) as u32 as u64);
+    }
+
+    fn i64(&mut self, id: i16, value: i64) {
+        self.field(id, T_I64);
+        self.varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn string(&mut self, id: i16, value: &str) {
+        self.field(id, T_BINARY);
+        self.varint(value.len() as u64);
+        self.buf.extend_from_slice(value.as_bytes());
+    }
+
+    fn structure(&mut self, id: i16, fields: impl FnOnce(&mut Self)) {
+        self.field(id, T_STRUCT);
+        self.nested(fields);
+    }
+
+    fn nested(&mut self, fields: impl FnOnce(&mut Self)) {
+        self.last.push(0);
+        fields(self);
+        self.last.pop();
+        self.buf.push(0);
+    }
+
+    fn list_i32(&mut self, id: i16, values: &[i32]) {
+        self.field(id, T_LIST);
+        self.list_header(values.len(), T_I32);
+        for value in values {
+            self.varint(((value << 1) ^ (value >> 31)) as u32 as u64);
+        }
+    }
+
+    fn list_string(&mut self, id: i16, values: &[&str]) {
+        self.field(id, T_LIST);
+        self.list_header(values.len(), T_BINARY);
+        for value in values {
+            self.varint(value.len() as u64);
+            self.buf.extend_from_slice(value.as_bytes());
+        }
+    }
+
+    fn list_struct(&mut self, id: i16, len: usize, mut element: impl FnMut(&mut Self, usize)) {
+        self.field(id, T_LIST);
+        self.list_header(len, T_STRUCT);
+        for i in 0..len {
+            self.nested(|c| element(c, i));
+        }
+    }
+}
@@ -0,0 +1,62 @@
+// Host pattern matching shared by the rule tables in config.
+//
+// Supported forms:
+//   "example.com"     exact match (case-insensitive)
+//   "*.example.com"   any subdomain of example.com, but not example.com itself
+//   "*"               everything
+
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    if pattern == "*" {
+        return true;
+    }
+    if let Some(suffix) = pattern.strip_prefix("*.") {
+        let host = host.to_ascii_lowercase();
+        let suffix = suffix.to_ascii_lowercase();
+        return host.len() > suffix.len()
+            && host.ends_with(&suffix)
+            && host.as_bytes()[host.len() - suffix.len() - 1] == b'.';
+    }
+    pattern.eq_ignore_ascii_case(host)
+}
+
+// Strip an optional ":port" from an authority, handling bracketed IPv6.
+pub fn strip_port(authority: &str) -> &str {
+    if let Some(rest) = authority.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => authority,
+    }
+}
+
+// Shell-style match of the whole of `text`: "*" is any run of characters
+// and "?" any one. Case-sensitive.
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    // Where the last "*" was, and the text position it is currently covering up to
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((at, covered)) => {
+                    p = at + 1;
+                    t = covered + 1;
+                    star = Some((at, covered + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -0,0 +1,184 @@
+use hyper::header::{HeaderMap, HeaderValue, COOKIE, ETAG, IF_NONE_MATCH, REFERER, SET_COOKIE, USER_AGENT};
+use hyper::Uri;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::groups::{self, GroupConfig};
+use crate::metrics::Metrics;
+use crate::pattern::host_matches;
+
+// An opt-in profile that takes tracking signals out of plain HTTP traffic:
+//
+//   [privacy]
+//   enabled = true
+//   users = ["@kids"]
+//   referer = "origin"
+//   third_parties = ["*.doubleclick.net", "*.facebook.com"]
+//   user_agent = "reduce"
+//   gpc = true
+#[derive(Debug, Deserialize)]
+pub struct PrivacyConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // User names, "@group" or "*"
+    #[serde(default = "everyone")]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub referer: RefererPolicy,
+    // Destinations that get no cookies and set none
+    #[serde(default)]
+    pub third_parties: Vec<String>,
+    // Also drop ETag and If-None-Match with third parties, which can carry
+    // an identifier as well as a cookie can
+    #[serde(default = "default_true")]
+    pub etags: bool,
+    // "keep", "reduce" (minor versions zeroed) or a replacement string
+    #[serde(default = "default_user_agent")]
+    pub user_agent: String,
+    // Send "Sec-GPC: 1" (Global Privacy Control)
+    #[serde(default)]
+    pub gpc: bool,
+}
+
+impl Default for PrivacyConfig {
+    fn default() -> Self {
+        PrivacyConfig {
+            enabled: false,
+            users: everyone(),
+            referer: RefererPolicy::default(),
+            third_parties: Vec::new(),
+            etags: true,
+            user_agent: default_user_agent(),
+            gpc: false,
+        }
+    }
+}
+
+fn everyone() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_user_agent() -> String {
+    "reduce".to_string()
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RefererPolicy {
+    Keep,
+    // Only scheme and host when the request goes to another host
+    #[default]
+    Origin,
+    Strip,
+}
+
+impl PrivacyConfig {
+    pub fn validate(&self, groups: &HashMap<String, GroupConfig>) -> Result<(), String> {
+        groups::check_refs(&self.users, groups).map_err(|e| format!("privacy.users: {}", e))?;
+        if !matches!(self.user_agent.as_str(), "keep" | "reduce") && HeaderValue::from_str(&self.user_agent).is_err() {
+            return Err("privacy.user_agent is not a valid header value".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn covers(&self, user: &str, group: Option<&str>) -> bool {
+        self.enabled && groups::covers(&self.users, user, group)
+    }
+
+    fn third_party(&self, host: &str) -> bool {
+        self.third_parties.iter().any(|p| host_matches(p, host))
+    }
+
+    // Scrubs a request on its way to `host`.
+    pub fn request(&self, host: &str, headers: &mut HeaderMap, metrics: &Metrics) {
+        if let Some(referer) = headers.get(REFERER).and_then(|v| v.to_str().ok()) {
+            let scrubbed = match self.referer {
+                RefererPolicy::Keep => Some(referer.to_string()),
+                RefererPolicy::Strip => None,
+                RefererPolicy::Origin => origin_unless_same_host(referer, host),
+            };
+            if scrubbed.as_deref() != Some(referer) {
+                match scrubbed.and_then(|v| HeaderValue::from_str(&v).ok()) {
+                    Some(value) => headers.insert(REFERER, value),
+                    None => headers.remove(REFERER),
+                };
+                metrics.count_privacy_scrub("referer");
+            }
+        }
+        if self.third_party(host) {
+            if headers.remove(COOKIE).is_some() {
+                metrics.count_privacy_scrub("cookie");
+            }
+            if self.etags && headers.remove(IF_NONE_MATCH).is_some() {
+                metrics.count_privacy_scrub("etag");
+            }
+        }
+        if let Some(agent) = headers.get(USER_AGENT).and_then(|v| v.to_str().ok()) {
+            let scrubbed = match self.user_agent.as_str() {
+                "keep" => agent.to_string(),
+                "reduce" => reduce_user_agent(agent),
+                replacement => replacement.to_string(),
+            };
+            if scrubbed != agent {
+                if let Ok(value) = HeaderValue::from_str(&scrubbed) {
+                    headers.insert(USER_AGENT, value);
+                    metrics.count_privacy_scrub("user-agent");
+                }
+            }
+        }
+        if self.gpc {
+            headers.insert("sec-gpc", HeaderValue::from_static("1"));
+        }
+    }
+
+    // Scrubs a response from `host`.
+    pub fn response(&self, host: &str, headers: &mut HeaderMap, metrics: &Metrics) {
+        if !self.third_party(host) {
+            return;
+        }
+        if headers.remove(SET_COOKIE).is_some() {
+            metrics.count_privacy_scrub("set-cookie");
+        }
+        if self.etags && headers.remove(ETAG).is_some() {
+            metrics.count_privacy_scrub("etag");
+        }
+    }
+}
+
+// `referer` as is if it points at `host`, otherwise just its origin.
+fn origin_unless_same_host(referer: &str, host: &str) -> Option<String> {
+    let uri: Uri = referer.parse().ok()?;
+    let from = uri.host()?;
+    if from.eq_ignore_ascii_case(host) {
+        return Some(referer.to_string());
+    }
+    let authority = uri.authority()?.as_str();
+    // Credentials in a referer are dropped along with the path
+    let authority = authority.rsplit('@').next().unwrap_or(authority);
+    Some(format!("{}://{}/", uri.scheme_str()?, authority))
+}
+
+// "Chrome/120.0.6099.109" becomes "Chrome/120.0.0.0", as browsers do in
+// their own reduced User-Agent. Shorter versions such as the frozen
+// "AppleWebKit/537.36" say little and are kept, like everything else.
+fn reduce_user_agent(agent: &str) -> String {
+    agent
+        .split(' ')
+        .map(|token| match token.split_once('/') {
+            Some((product, version))
+                if version.starts_with(|c: char| c.is_ascii_digit()) && version.split('.').count() > 2 =>
+            {
+                let mut parts = version.split('.');
+                let major = parts.next().unwrap_or_default();
+                let minor: Vec<&str> = parts.map(|_| "0").collect();
+                format!("{}/{}.{}", product, major, minor.join("."))
+            }
+            _ => token.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
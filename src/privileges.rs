@@ -0,0 +1,112 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+// Switch to `run_as_user`/`run_as_group` once the listeners are bound, so a
+// proxy started as root for a low port does not keep serving as root.
+// Names or numeric IDs; with only a user, its primary group is used.
+// `state` is what the proxy writes to later (data_dir, the user store,
+// billing output), created while still root: it is handed to the account
+// first, and startup fails if the account could not write it.
+pub fn drop(user: Option<&str>, group: Option<&str>, state: &[PathBuf]) -> Result<(), String> {
+    if user.is_none() && group.is_none() {
+        return Ok(());
+    }
+    let (uid, primary_gid) = match user {
+        Some(name) => {
+            let (uid, gid) = lookup_user(name)?;
+            (Some(uid), Some(gid))
+        }
+        None => (None, None),
+    };
+    let gid = match group {
+        Some(name) => Some(lookup_group(name)?),
+        None => primary_gid,
+    };
+    if unsafe { libc::geteuid() } != 0 {
+        return Err("run_as_user/run_as_group need the proxy to be started as root".to_string());
+    }
+    for path in state.iter().filter(|p| p.exists()) {
+        hand_over(path, uid, gid).map_err(|e| format!("chown {} failed: {}", path.display(), e))?;
+    }
+    // glibc applies these to every thread of the process
+    if let Some(gid) = gid {
+        if unsafe { libc::setgroups(1, &gid) } != 0 || unsafe { libc::setgid(gid) } != 0 {
+            return Err(format!("setgid({}) failed: {}", gid, std::io::Error::last_os_error()));
+        }
+    }
+    if let Some(uid) = uid {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(format!("setuid({}) failed: {}", uid, std::io::Error::last_os_error()));
+        }
+        if unsafe { libc::setuid(0) } == 0 {
+            return Err("root privileges could be regained after setuid".to_string());
+        }
+    }
+    for path in state {
+        // Files are created, or replaced through a temp file, beside them
+        let dir = path.parent().filter(|_| !path.is_dir()).map(|d| if d.as_os_str().is_empty() { Path::new(".") } else { d });
+        for path in path.exists().then_some(path.as_path()).into_iter().chain(dir) {
+            if !writable(path) {
+                return Err(format!("{} is not writable by the account the proxy runs as", path.display()));
+            }
+        }
+    }
+    info!(
+        "🔒 Dropped privileges to uid {} gid {}",
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() }
+    );
+    Ok(())
+}
+
+// chown, through directories; symlinks are left alone rather than followed.
+fn hand_over(path: &Path, uid: Option<libc::uid_t>, gid: Option<libc::gid_t>) -> std::io::Result<()> {
+    std::os::unix::fs::lchown(path, uid, gid)?;
+    if path.symlink_metadata()?.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            hand_over(&entry?.path(), uid, gid)?;
+        }
+    }
+    Ok(())
+}
+
+fn writable(path: &Path) -> bool {
+    CString::new(path.as_os_str().as_bytes()).is_ok_and(|c_path| unsafe { libc::access(c_path.as_ptr(), libc::W_OK) } == 0)
+}
+
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t), String> {
+    let c_name = CString::new(name).map_err(|_| format!("invalid user name '{}'", name))?;
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let found = unsafe { libc::getpwnam_r(c_name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) } == 0
+        && !result.is_null();
+    if found {
+        return Ok((pwd.pw_uid, pwd.pw_gid));
+    }
+    // A bare uid has no passwd entry to take a group from
+    match name.parse::<libc::uid_t>() {
+        Ok(uid) => {
+            let mut result: *mut libc::passwd = std::ptr::null_mut();
+            let found = unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) } == 0
+                && !result.is_null();
+            Ok((uid, if found { pwd.pw_gid } else { uid }))
+        }
+        Err(_) => Err(format!("unknown user '{}'", name)),
+    }
+}
+
+fn lookup_group(name: &str) -> Result<libc::gid_t, String> {
+    let c_name = CString::new(name).map_err(|_| format!("invalid group name '{}'", name))?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::group = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let found = unsafe { libc::getgrnam_r(c_name.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) } == 0
+        && !result.is_null();
+    if found {
+        return Ok(grp.gr_gid);
+    }
+    name.parse().map_err(|_| format!("unknown group '{}'", name))
+}
@@ -0,0 +1,290 @@
+use hyper::server::accept::Accept;
+use serde::Deserialize;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+// The load balancer sends the header right after connecting
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+// Longest v1 line, "\r\n" included
+const V1_MAX: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// Destinations that are sent a PROXY header naming the proxy's client at
+// the start of each tunnel, so servers behind the proxy still learn who
+// connected:
+//
+//   [[proxy_protocol.send]]
+//   host = "*.backend.internal"
+//   version = 2
+//
+// Only tunnels the proxy opens itself get the header; through a parent
+// proxy it would reach the parent instead.
+#[derive(Debug, Default, Deserialize)]
+pub struct ProxyProtocolConfig {
+    #[serde(default)]
+    pub send: Vec<SendRule>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SendRule {
+    pub host: String,
+    // 1 (text) or 2 (binary)
+    #[serde(default = "default_version")]
+    pub version: u8,
+}
+
+fn default_version() -> u8 {
+    1
+}
+
+impl ProxyProtocolConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        match self.send.iter().find(|rule| !matches!(rule.version, 1 | 2)) {
+            Some(rule) => Err(format!("proxy_protocol.send '{}': version must be 1 or 2", rule.host)),
+            None => Ok(()),
+        }
+    }
+
+    // Header version for tunnels to `host`, if they get one; the first
+    // matching rule wins.
+    pub fn version_for(&self, host: &str) -> Option<u8> {
+        self.send
+            .iter()
+            .find(|rule| crate::pattern::host_matches(&rule.host, host))
+            .map(|rule| rule.version)
+    }
+}
+
+// The PROXY header for a connection from `source` to `destination`. Mixed
+// families are sent as IPv6, with the IPv4 side mapped.
+pub fn header(version: u8, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let (source, destination) = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => (source, destination),
+        _ => (mapped(source), mapped(destination)),
+    };
+    if version == 1 {
+        let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+        return format!(
+            "PROXY {} {} {} {} {}\r\n",
+            family,
+            source.ip(),
+            destination.ip(),
+            source.port(),
+            destination.port()
+        )
+        .into_bytes();
+    }
+    let mut header = V2_SIGNATURE.to_vec();
+    // Version 2, PROXY command
+    header.push(0x21);
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            header.extend_from_slice(&[0x11, 0, 12]);
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            header.extend_from_slice(&[0x21, 0, 36]);
+            header.extend_from_slice(&v6(src).octets());
+            header.extend_from_slice(&v6(dst).octets());
+        }
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+fn mapped(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(v6(addr.ip())), addr.port())
+}
+
+fn v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+// Networks a listener takes PROXY headers from: its load balancers. Anyone
+// else could name any client address they liked.
+#[derive(Clone, Default)]
+pub struct Trusted(Arc<[(IpAddr, u8)]>);
+
+impl Trusted {
+    // Networks are checked by listener::validate.
+    pub fn new(networks: &[String]) -> Self {
+        Trusted(networks.iter().filter_map(|n| crate::egress::parse_network(n)).collect())
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0.iter().any(|&(network, prefix)| crate::egress::in_network(ip, network, prefix))
+    }
+}
+
+// Reads the PROXY protocol header (v1 or v2) that a load balancer puts in
+// front of each connection and returns the client address it names. Only
+// the header is consumed; the connection carries on as the client sent it.
+// `peer` is the balancer itself, used for its own health checks (v2 LOCAL,
+// v1 UNKNOWN). Connections without a valid header, or from outside
+// `trusted`, are an error.
+pub async fn read_header(stream: &mut TcpStream, peer: SocketAddr, trusted: &Trusted) -> io::Result<SocketAddr> {
+    if !trusted.contains(peer.ip()) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "not a trusted PROXY protocol source"));
+    }
+    tokio::time::timeout(HEADER_TIMEOUT, read(stream, peer))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no PROXY protocol header"))?
+}
+
+async fn read(stream: &mut TcpStream, peer: SocketAddr) -> io::Result<SocketAddr> {
+    let mut head = [0; 16];
+    stream.read_exact(&mut head[..5]).await?;
+    if &head[..5] == b"PROXY" {
+        let mut line = head[..5].to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX {
+                return Err(invalid("PROXY v1 header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        return parse_v1(&line, peer).ok_or_else(|| invalid("malformed PROXY v1 header"));
+    }
+    stream.read_exact(&mut head[5..]).await?;
+    if &head[..12] != V2_SIGNATURE || head[12] >> 4 != 2 {
+        return Err(invalid("no PROXY protocol header"));
+    }
+    let mut body = vec![0; usize::from(u16::from_be_bytes([head[14], head[15]]))];
+    stream.read_exact(&mut body).await?;
+    match head[12] & 0x0f {
+        // LOCAL: the balancer's own connection
+        0 => Ok(peer),
+        1 => parse_v2(head[13], &body, peer).ok_or_else(|| invalid("malformed PROXY v2 header")),
+        _ => Err(invalid("unknown PROXY v2 command")),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// "PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n"
+fn parse_v1(line: &[u8], peer: SocketAddr) -> Option<SocketAddr> {
+    let line = std::str::from_utf8(line).ok()?.strip_suffix("\r\n")?;
+    let mut fields = line.split(' ').skip(1);
+    let (ip, port) = match fields.next()? {
+        "UNKNOWN" => return Some(peer),
+        "TCP4" => (IpAddr::V4(fields.next()?.parse().ok()?), fields.nth(1)?),
+        "TCP6" => (IpAddr::V6(fields.next()?.parse().ok()?), fields.nth(1)?),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port.parse().ok()?))
+}
+
+// Source address of a v2 PROXY header; other families (UDP, unix) leave
+// the balancer as the client.
+fn parse_v2(family: u8, body: &[u8], peer: SocketAddr) -> Option<SocketAddr> {
+    match family {
+        // TCP over IPv4: source, destination, source port, destination port
+        0x11 => {
+            let ip: [u8; 4] = body.get(..4)?.try_into().ok()?;
+            let port = u16::from_be_bytes(body.get(8..10)?.try_into().ok()?);
+            Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port))
+        }
+        0x21 => {
+            let ip: [u8; 16] = body.get(..16)?.try_into().ok()?;
+            let port = u16::from_be_bytes(body.get(32..34)?.try_into().ok()?);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port))
+        }
+        _ => Some(peer),
+    }
+}
+
+// Accepts plain connections from a load balancer and yields each once its
+// PROXY header has been read. Headers are read on their own tasks so a slow
+// connection cannot hold up others.
+pub struct ProxiedIncoming {
+    streams: mpsc::Receiver<ProxiedStream>,
+}
+
+impl ProxiedIncoming {
+    pub fn new(listener: TcpListener, trusted: Trusted) -> Self {
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                let (mut tcp, peer) = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            debug!("PROXY protocol listener accept failed: {}", e);
+                            tokio::time::sleep(crate::listener::ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                    },
+                    _ = tx.closed() => return,
+                };
+                crate::tcp::config().client.apply(&tcp);
+                let tx = tx.clone();
+                let trusted = trusted.clone();
+                tokio::spawn(async move {
+                    match read_header(&mut tcp, peer, &trusted).await {
+                        Ok(remote) => {
+                            let _ = tx.send(ProxiedStream { stream: tcp, remote }).await;
+                        }
+                        Err(e) => debug!("Closing connection from {}: {}", peer, e),
+                    }
+                });
+            }
+        });
+        ProxiedIncoming { streams: rx }
+    }
+}
+
+impl Accept for ProxiedIncoming {
+    type Conn = ProxiedStream;
+    type Error = io::Error;
+
+    fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<ProxiedStream>>> {
+        self.streams.poll_recv(cx).map(|stream| stream.map(Ok))
+    }
+}
+
+// A connection whose client address came from its PROXY header.
+pub struct ProxiedStream {
+    stream: TcpStream,
+    remote: SocketAddr,
+}
+
+impl crate::listener::RemoteAddr for ProxiedStream {
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote
+    }
+}
+
+impl AsyncRead for ProxiedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxiedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
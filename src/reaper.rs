@@ -0,0 +1,265 @@
+use serde::Deserialize;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+use tracing::info;
+
+use crate::AppState;
+
+// Closes tunnels that are no longer doing anything useful:
+//
+//   [reaper]
+//   interval = 30
+//   idle_timeout = 900          # no bytes either way
+//   half_closed_timeout = 60    # one side sent EOF, the other never did
+//   max_lifetime = 86400
+//
+// Each timeout is in seconds; 0 (the default) leaves that check off.
+#[derive(Debug, Deserialize)]
+pub struct ReaperConfig {
+    // Seconds between scans of the open tunnels
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    #[serde(default)]
+    pub idle_timeout: u64,
+    #[serde(default)]
+    pub half_closed_timeout: u64,
+    #[serde(default)]
+    pub max_lifetime: u64,
+}
+
+impl Default for ReaperConfig {
+    fn default() -> Self {
+        ReaperConfig {
+            interval: default_interval(),
+            idle_timeout: 0,
+            half_closed_timeout: 0,
+            max_lifetime: 0,
+        }
+    }
+}
+
+fn default_interval() -> u64 {
+    30
+}
+
+impl ReaperConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enabled() && self.interval == 0 {
+            return Err("reaper.interval must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.idle_timeout > 0 || self.half_closed_timeout > 0 || self.max_lifetime > 0
+    }
+
+    fn reason(&self, activity: &Activity, age: Duration) -> Option<&'static str> {
+        let now = millis();
+        let past = |at: u64, limit: u64| limit > 0 && at > 0 && now.saturating_sub(at) >= limit * 1000;
+        let half_closed = activity.client_eof.load(Ordering::Relaxed).max(activity.server_eof.load(Ordering::Relaxed));
+        if past(half_closed, self.half_closed_timeout) {
+            Some("half_closed")
+        } else if past(activity.last.load(Ordering::Relaxed), self.idle_timeout) {
+            Some("idle")
+        } else if self.max_lifetime > 0 && age.as_secs() >= self.max_lifetime {
+            Some("max_lifetime")
+        } else {
+            None
+        }
+    }
+}
+
+// Milliseconds since the first call, never 0, so 0 can mean "not yet".
+fn millis() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64 + 1
+}
+
+// What a tunnel has done, shared between its copy loop and the reaper.
+pub struct Activity {
+    pub from_client: AtomicU64,
+    pub from_server: AtomicU64,
+    // millis() of the last bytes read from either side
+    last: AtomicU64,
+    // millis() when that side sent EOF, 0 while it is open
+    client_eof: AtomicU64,
+    server_eof: AtomicU64,
+    reaped: AtomicBool,
+    close: Notify,
+    // Bytes both ways together after which the tunnel closes itself
+    cap: Option<u64>,
+    over_cap: AtomicBool,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Activity {
+            from_client: AtomicU64::new(0),
+            from_server: AtomicU64::new(0),
+            last: AtomicU64::new(millis()),
+            client_eof: AtomicU64::new(0),
+            server_eof: AtomicU64::new(0),
+            reaped: AtomicBool::new(false),
+            close: Notify::new(),
+            cap: None,
+            over_cap: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Activity {
+    pub fn capped(cap: Option<u64>) -> Self {
+        Activity {
+            cap,
+            ..Activity::default()
+        }
+    }
+
+    // Whether the tunnel is being closed; its copy loop stops reading then,
+    // rather than moving more bytes until it is dropped.
+    pub fn closing(&self) -> bool {
+        self.reaped.load(Ordering::Relaxed)
+    }
+
+    // Whether the tunnel was closed for going over its byte cap.
+    pub fn over_cap(&self) -> bool {
+        self.over_cap.load(Ordering::Relaxed)
+    }
+
+    // Completes once the reaper has closed the tunnel.
+    pub async fn closed(&self) {
+        self.close.notified().await
+    }
+
+    pub fn bytes(&self) -> (u64, u64) {
+        (self.from_client.load(Ordering::Relaxed), self.from_server.load(Ordering::Relaxed))
+    }
+
+    // `n` bytes read from the client or the server; 0 is that side's EOF.
+    pub fn record(&self, client: bool, n: usize) {
+        let (bytes, eof) = match client {
+            true => (&self.from_client, &self.client_eof),
+            false => (&self.from_server, &self.server_eof),
+        };
+        match n {
+            0 => {
+                let _ = eof.compare_exchange(0, millis(), Ordering::Relaxed, Ordering::Relaxed);
+            }
+            n => {
+                bytes.fetch_add(n as u64, Ordering::Relaxed);
+                self.last.store(millis(), Ordering::Relaxed);
+                let (from_client, from_server) = self.bytes();
+                if self.cap.is_some_and(|cap| from_client + from_server > cap) && !self.reaped.swap(true, Ordering::Relaxed) {
+                    self.over_cap.store(true, Ordering::Relaxed);
+                    self.close.notify_one();
+                }
+            }
+        }
+    }
+}
+
+// One side of a tunnel, recording what is read from it into `activity`.
+pub struct Tracked<'a, S> {
+    inner: S,
+    activity: &'a Activity,
+    client: bool,
+}
+
+impl<'a, S> Tracked<'a, S> {
+    pub fn client(inner: S, activity: &'a Activity) -> Self {
+        Tracked {
+            inner,
+            activity,
+            client: true,
+        }
+    }
+
+    pub fn server(inner: S, activity: &'a Activity) -> Self {
+        Tracked {
+            inner,
+            activity,
+            client: false,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<'_, S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // The close notification wakes the task that drops this
+        if this.activity.closing() {
+            return Poll::Pending;
+        }
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let n = buf.filled().len() - before;
+            // Nothing read into a full buffer is not an EOF
+            if n > 0 || buf.remaining() > 0 {
+                this.activity.record(this.client, n);
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<'_, S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+fn scan(state: &AppState) {
+    let config = &state.config.reaper;
+    for tunnel in state.connections.open() {
+        let Some(reason) = config.reason(&tunnel.activity, tunnel.since.elapsed()) else {
+            continue;
+        };
+        if tunnel.activity.reaped.swap(true, Ordering::Relaxed) {
+            continue;
+        }
+        info!(
+            "🧹 Closing {} tunnel {} -> {} for '{}' after {}s",
+            reason,
+            tunnel.client,
+            tunnel.target,
+            tunnel.user,
+            tunnel.since.elapsed().as_secs()
+        );
+        state.metrics.count_reaped(reason);
+        tunnel.activity.close.notify_one();
+    }
+}
+
+pub fn spawn(state: Arc<AppState>) {
+    let config = &state.config.reaper;
+    if !config.enabled() {
+        return;
+    }
+    info!(
+        "🧹 Reaping tunnels every {}s (idle {}s, half-closed {}s, lifetime {}s; 0 = off)",
+        config.interval, config.idle_timeout, config.half_closed_timeout, config.max_lifetime
+    );
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(state.config.reaper.interval));
+        loop {
+            ticker.tick().await;
+            scan(&state);
+        }
+    });
+}
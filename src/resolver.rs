@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tracing::debug;
+
+const A: u16 = 1;
+const AAAA: u16 = 28;
+const NXDOMAIN: u16 = 3;
+const MAX_CACHED: usize = 10_000;
+// Answers are reused for their TTL, but never longer than this
+const MAX_TTL: u32 = 300;
+
+// Stub resolver asking configured nameservers directly (RFC 1035), for
+// when the host's /etc/resolv.conf is not the DNS the proxy should use.
+// Servers are tried in order; the next one is asked when a server does not
+// answer or fails, but "no such name" from any of them is final.
+pub struct Resolver {
+    pub servers: Vec<SocketAddr>,
+    timeout: Duration,
+    cache: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>,
+}
+
+enum Answer {
+    // Addresses (possibly none) and the smallest TTL among them, u32::MAX
+    // when there are none
+    Found(Vec<IpAddr>, u32),
+    NoSuchName,
+}
+
+impl Resolver {
+    pub fn new(servers: Vec<SocketAddr>, timeout: Duration) -> Self {
+        Resolver {
+            servers,
+            timeout,
+            cache: Mutex::default(),
+        }
+    }
+
+    // IPv4 addresses first, then IPv6; `ip_family` reorders them.
+    pub async fn lookup(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if let Some((addrs, expires)) = self.cache.lock().unwrap().get(&name) {
+            if *expires > Instant::now() {
+                return Ok(addrs.clone());
+            }
+        }
+        let mut last_error = None;
+        // Addresses of one family, from a server that failed the other query
+        let mut partial = Vec::new();
+        for server in &self.servers {
+            let (v4, v6) = tokio::join!(self.query(*server, &name, A), self.query(*server, &name, AAAA));
+            match (v4, v6) {
+                (Ok(Answer::NoSuchName), _) | (_, Ok(Answer::NoSuchName)) => {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", name)));
+                }
+                (Ok(Answer::Found(mut addrs, ttl4)), Ok(Answer::Found(v6, ttl6))) => {
+                    addrs.extend(v6);
+                    if addrs.is_empty() {
+                        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", name)));
+                    }
+                    self.remember(name, &addrs, ttl4.min(ttl6));
+                    return Ok(addrs);
+                }
+                (Ok(Answer::Found(addrs, _)), Err(e)) | (Err(e), Ok(Answer::Found(addrs, _))) => {
+                    debug!("🧭 Nameserver {} failed for {}: {}", server, name, e);
+                    if partial.is_empty() {
+                        partial = addrs;
+                    }
+                    last_error = Some(e);
+                }
+                (Err(e), Err(_)) => {
+                    debug!("🧭 Nameserver {} failed for {}: {}", server, name, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        if !partial.is_empty() {
+            return Ok(partial);
+        }
+        Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no nameservers")))
+    }
+
+    fn remember(&self, name: String, addrs: &[IpAddr], ttl: u32) {
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED {
+            cache.retain(|_, (_, expires)| *expires > now);
+        }
+        if cache.len() < MAX_CACHED && ttl > 0 {
+            let expires = now + Duration::from_secs(ttl.min(MAX_TTL) as u64);
+            cache.insert(name, (addrs.to_vec(), expires));
+        }
+    }
+
+    // One question to one server: over UDP, and again over TCP if the
+    // answer did not fit.
+    async fn query(&self, server: SocketAddr, name: &str, qtype: u16) -> io::Result<Answer> {
+        let mut id = [0u8; 2];
+        let _ = openssl::rand::rand_bytes(&mut id);
+        let packet = question(u16::from_be_bytes(id), name, qtype)?;
+        let exchange = async {
+            let mut response = udp(server, &packet).await?;
+            // TC: truncated
+            if response[2] & 0x02 != 0 {
+                response = tcp(server, &packet).await?;
+            }
+            Ok::<_, io::Error>(response)
+        };
+        let response = tokio::time::timeout(self.timeout, exchange)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("nameserver {} did not answer", server)))??;
+        parse(&response, &packet[..2], qtype)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("malformed answer from {}", server)))?
+    }
+}
+
+fn question(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid host name '{}'", name));
+    if name.is_empty() || name.len() > 253 {
+        return Err(invalid());
+    }
+    let mut packet = Vec::with_capacity(name.len() + 18);
+    packet.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired; one question
+    packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(invalid());
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&1u16.to_be_bytes());
+    Ok(packet)
+}
+
+async fn udp(server: SocketAddr, packet: &[u8]) -> io::Result<Vec<u8>> {
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    socket.send(packet).await?;
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let n = socket.recv(&mut buf).await?;
+        // Anything not answering our ID is a stray or a spoofing attempt
+        if n >= 12 && buf[..2] == packet[..2] {
+            buf.truncate(n);
+            return Ok(buf);
+        }
+    }
+}
+
+// TCP messages carry a two-byte length prefix.
+async fn tcp(server: SocketAddr, packet: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(server).await?;
+    let mut request = (packet.len() as u16).to_be_bytes().to_vec();
+    request.extend_from_slice(packet);
+    stream.write_all(&request).await?;
+    let len = stream.read_u16().await? as usize;
+    let mut response = vec![0u8; len];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+// The addresses of type `qtype` in a response; CNAME chains need no
+// following, as recursive servers include the final records.
+fn parse(msg: &[u8], id: &[u8], qtype: u16) -> Option<io::Result<Answer>> {
+    let u16_at = |at: usize| msg.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+    if msg.len() < 12 || &msg[..2] != id {
+        return None;
+    }
+    let flags = u16_at(2)?;
+    // QR: this is a response
+    if flags & 0x8000 == 0 {
+        return None;
+    }
+    match flags & 0x000f {
+        0 => {}
+        NXDOMAIN => return Some(Ok(Answer::NoSuchName)),
+        2 => return Some(Err(io::Error::other("nameserver failed (SERVFAIL)"))),
+        5 => return Some(Err(io::Error::other("nameserver refused the query"))),
+        rcode => return Some(Err(io::Error::other(format!("nameserver answered with error code {}", rcode)))),
+    }
+    let (questions, answers) = (u16_at(4)?, u16_at(6)?);
+    let mut at = 12;
+    for _ in 0..questions {
+        at = skip_name(msg, at)? + 4;
+    }
+    let mut addrs = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..answers {
+        at = skip_name(msg, at)?;
+        let (kind, class) = (u16_at(at)?, u16_at(at + 2)?);
+        let record_ttl = u32::from_be_bytes(msg.get(at + 4..at + 8)?.try_into().ok()?);
+        let len = u16_at(at + 8)? as usize;
+        let data = msg.get(at + 10..at + 10 + len)?;
+        at += 10 + len;
+        if kind != qtype || class != 1 {
+            continue;
+        }
+        let ip = match (kind, len) {
+            (A, 4) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(data).ok()?)),
+            (AAAA, 16) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(data).ok()?)),
+            _ => return None,
+        };
+        addrs.push(ip);
+        ttl = ttl.min(record_ttl);
+    }
+    Some(Ok(Answer::Found(addrs, ttl)))
+}
+
+// Offset just past the (possibly compressed) name at `at`.
+fn skip_name(msg: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(at)? as usize;
+        match len {
+            0 => return Some(at + 1),
+            _ if len & 0xc0 == 0xc0 => return Some(at + 2),
+            _ => at += len + 1,
+        }
+    }
+}
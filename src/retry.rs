@@ -0,0 +1,98 @@
+use hyper::{Body, HeaderMap, Method, Request, Response, Uri, Version};
+use serde::Deserialize;
+use std::time::Duration;
+
+#[derive(Debug, Deserialize)]
+pub struct RetryConfig {
+    // Extra attempts after the first; 0 disables retries
+    #[serde(default)]
+    pub attempts: u32,
+    // Delay before the first retry, doubled for each further one
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    #[serde(default = "default_methods")]
+    pub methods: Vec<String>,
+    // Upstream statuses worth another try; connection errors always are
+    #[serde(default = "default_status_codes")]
+    pub status_codes: Vec<u16>,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            attempts: 0,
+            backoff_ms: default_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+            methods: default_methods(),
+            status_codes: default_status_codes(),
+        }
+    }
+}
+
+fn default_backoff_ms() -> u64 {
+    100
+}
+
+fn default_max_backoff_ms() -> u64 {
+    2000
+}
+
+fn default_methods() -> Vec<String> {
+    ["GET", "HEAD", "OPTIONS", "TRACE", "PUT", "DELETE"]
+        .iter()
+        .map(|m| m.to_string())
+        .collect()
+}
+
+fn default_status_codes() -> Vec<u16> {
+    vec![502, 503, 504]
+}
+
+impl RetryConfig {
+    pub fn applies_to(&self, method: &Method) -> bool {
+        self.attempts > 0 && self.methods.iter().any(|m| m.eq_ignore_ascii_case(method.as_str()))
+    }
+
+    pub fn should_retry(&self, result: &hyper::Result<Response<Body>>) -> bool {
+        match result {
+            Ok(response) => self.status_codes.contains(&response.status().as_u16()),
+            Err(_) => true,
+        }
+    }
+
+    // Delay before retry number `attempt` (1-based).
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u64.checked_shl(attempt.saturating_sub(1)).unwrap_or(u64::MAX);
+        Duration::from_millis(self.backoff_ms.saturating_mul(factor).min(self.max_backoff_ms))
+    }
+}
+
+// Copy of a bodiless request's head, so it can be sent more than once.
+pub struct Replay {
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+}
+
+impl Replay {
+    pub fn new(req: &Request<Body>) -> Self {
+        Replay {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            version: req.version(),
+            headers: req.headers().clone(),
+        }
+    }
+
+    pub fn request(&self) -> Request<Body> {
+        let mut req = Request::new(Body::empty());
+        *req.method_mut() = self.method.clone();
+        *req.uri_mut() = self.uri.clone();
+        *req.version_mut() = self.version;
+        *req.headers_mut() = self.headers.clone();
+        req
+    }
+}
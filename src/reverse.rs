@@ -0,0 +1,325 @@
+use hyper::client::connect::{Connected, Connection};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, HOST};
+use hyper::service::Service;
+use hyper::{Body, Client, Request, Uri};
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::egress::{self, IpFamily, Outbound};
+use crate::listener::TlsStream;
+use crate::pattern::host_matches;
+use crate::userheaders::RESERVED;
+
+// Sites served by listeners with `reverse = true`, routed by the request's
+// Host and path:
+//
+//   [[reverse.sites]]
+//   host = "app.example.com"
+//   path = "/api"
+//   backend = "https://10.0.0.5:8443"
+//   strip_prefix = true
+//   request_headers = { "X-Site" = "app" }
+//   response_headers = { "Server" = "" }
+//
+// Of the sites whose host matches, the one with the longest matching path
+// wins; among equal paths, the first listed.
+#[derive(Debug, Default, Deserialize)]
+pub struct ReverseConfig {
+    #[serde(default)]
+    pub sites: Vec<Site>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Site {
+    // Host header pattern, as in the other rule tables
+    #[serde(default = "any_host")]
+    pub host: String,
+    // Path prefix, matched on whole segments
+    #[serde(default = "root")]
+    pub path: String,
+    // "http://host:port" or "https://host:port", optionally with a base path
+    pub backend: String,
+    // Take `path` off before appending the rest to the backend's base path
+    #[serde(default)]
+    pub strip_prefix: bool,
+    // Send the client's Host rather than the backend's
+    #[serde(default)]
+    pub preserve_host: bool,
+    // Set on requests to the backend
+    #[serde(default)]
+    pub request_headers: BTreeMap<String, String>,
+    // Set on responses to the client; an empty value removes the header
+    #[serde(default)]
+    pub response_headers: BTreeMap<String, String>,
+    // For https backends: check the certificate, against `ca_file` if set
+    #[serde(default = "default_true")]
+    pub tls_verify: bool,
+    pub ca_file: Option<PathBuf>,
+    #[serde(skip)]
+    backend_uri: Option<Uri>,
+    #[serde(skip)]
+    tls: Option<SslConnector>,
+    // Built with the site so backend connections are pooled across requests
+    #[serde(skip)]
+    client: Option<Client<BackendConnector>>,
+}
+
+fn any_host() -> String {
+    "*".to_string()
+}
+
+fn root() -> String {
+    "/".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl ReverseConfig {
+    // Parses the backends and sets up their TLS once at startup.
+    pub fn prepare(&mut self) -> Result<(), String> {
+        for (i, site) in self.sites.iter_mut().enumerate() {
+            site.prepare().map_err(|e| format!("reverse.sites[{}]: {}", i, e))?;
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sites.is_empty()
+    }
+
+    // The site for a request to `host` (without port) and `path`.
+    pub fn site(&self, host: &str, path: &str) -> Option<&Site> {
+        self.sites
+            .iter()
+            .filter(|site| host_matches(&site.host, host) && site.prefix_of(path))
+            .fold(None, |best: Option<&Site>, site| match best {
+                Some(best) if best.path.len() >= site.path.len() => Some(best),
+                _ => Some(site),
+            })
+    }
+}
+
+impl Site {
+    fn prepare(&mut self) -> Result<(), String> {
+        if !self.path.starts_with('/') {
+            return Err(format!("path '{}' must start with '/'", self.path));
+        }
+        let uri: Uri = self.backend.parse().map_err(|_| format!("backend '{}' is not a URL", self.backend))?;
+        let tls = match uri.scheme_str() {
+            Some("http") => false,
+            Some("https") => true,
+            _ => return Err(format!("backend '{}' must be http:// or https://", self.backend)),
+        };
+        if uri.host().is_none() || uri.query().is_some() {
+            return Err(format!("backend '{}' needs a host and no query", self.backend));
+        }
+        let names = self.request_headers.keys().chain(self.response_headers.keys());
+        for name in names {
+            let header =
+                HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("'{}' is not a valid header name", name))?;
+            if RESERVED.contains(&header.as_str()) {
+                return Err(format!("'{}' is managed by the proxy and cannot be changed", name));
+            }
+        }
+        if let Some((name, _)) = self
+            .request_headers
+            .iter()
+            .chain(&self.response_headers)
+            .find(|(_, value)| HeaderValue::from_str(value).is_err())
+        {
+            return Err(format!("value of '{}' is not a valid header value", name));
+        }
+        if tls {
+            let mut builder = SslConnector::builder(SslMethod::tls_client()).map_err(|e| e.to_string())?;
+            if let Some(ca) = &self.ca_file {
+                builder.set_ca_file(ca).map_err(|e| format!("ca_file {}: {}", ca.display(), e))?;
+            }
+            if !self.tls_verify {
+                builder.set_verify(SslVerifyMode::NONE);
+            }
+            self.tls = Some(builder.build());
+        }
+        self.backend_uri = Some(uri);
+        self.client = Some(Client::builder().build(self.connector()));
+        Ok(())
+    }
+
+    fn prefix_of(&self, path: &str) -> bool {
+        let prefix = self.path.trim_end_matches('/');
+        match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+
+    pub fn backend(&self) -> &str {
+        &self.backend
+    }
+
+    // Points `req` at the backend: the URI becomes absolute, Host names the
+    // backend (unless preserved) and the X-Forwarded-* headers say what the
+    // client asked for.
+    pub fn rewrite(&self, req: &mut Request<Body>, client: SocketAddr, tls: bool) {
+        let Some(backend) = &self.backend_uri else {
+            return;
+        };
+        let path = req.uri().path();
+        let rest = match self.strip_prefix {
+            true => path.strip_prefix(self.path.trim_end_matches('/')).unwrap_or(path),
+            false => path,
+        };
+        let base = backend.path().trim_end_matches('/');
+        let mut target = format!("{}{}", base, if rest.starts_with('/') { rest.to_string() } else { format!("/{}", rest) });
+        if let Some(query) = req.uri().query() {
+            target.push('?');
+            target.push_str(query);
+        }
+        let authority = backend.authority().map(|a| a.as_str()).unwrap_or_default();
+        let original_host = req.headers().get(HOST).cloned();
+        if let Ok(uri) = format!("{}://{}{}", backend.scheme_str().unwrap_or("http"), authority, target).parse() {
+            *req.uri_mut() = uri;
+        }
+        let headers = req.headers_mut();
+        if !self.preserve_host {
+            if let Ok(value) = HeaderValue::from_str(authority) {
+                headers.insert(HOST, value);
+            }
+        }
+        forwarded(headers, client, original_host, tls);
+        set(headers, &self.request_headers);
+    }
+
+    pub fn rewrite_response(&self, headers: &mut HeaderMap) {
+        set(headers, &self.response_headers);
+    }
+
+    // Connects to this site's backend, over TLS for https ones.
+    fn connector(&self) -> BackendConnector {
+        BackendConnector { tls: self.tls.clone() }
+    }
+
+    // The site's client; clones share its connection pool.
+    pub fn client(&self) -> Client<BackendConnector> {
+        self.client.clone().unwrap_or_else(|| Client::builder().build(self.connector()))
+    }
+}
+
+fn forwarded(headers: &mut HeaderMap, client: SocketAddr, host: Option<HeaderValue>, tls: bool) {
+    let chain = match headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+        Some(earlier) => format!("{}, {}", earlier, client.ip()),
+        None => client.ip().to_string(),
+    };
+    if let Ok(value) = HeaderValue::from_str(&chain) {
+        headers.insert("x-forwarded-for", value);
+    }
+    headers.insert("x-forwarded-proto", HeaderValue::from_static(if tls { "https" } else { "http" }));
+    if let Some(host) = host {
+        headers.insert("x-forwarded-host", host);
+    }
+}
+
+fn set(headers: &mut HeaderMap, values: &BTreeMap<String, String>) {
+    for (name, value) in values {
+        let Ok(name) = HeaderName::from_bytes(name.as_bytes()) else {
+            continue;
+        };
+        match HeaderValue::from_str(value) {
+            Ok(_) if value.is_empty() => {
+                headers.remove(name);
+            }
+            Ok(value) => {
+                headers.insert(name, value);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BackendConnector {
+    tls: Option<SslConnector>,
+}
+
+impl Service<Uri> for BackendConnector {
+    type Response = BackendStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<BackendStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let tls = self.tls.clone();
+        Box::pin(async move {
+            let host = uri
+                .host()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "URI has no host"))?
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string();
+            let port = uri.port_u16().unwrap_or(if tls.is_some() { 443 } else { 80 });
+            let stream = egress::connect_direct(&[], &host, port, IpFamily::default(), &Outbound::default()).await?;
+            let Some(tls) = tls else {
+                return Ok(BackendStream::Plain(stream));
+            };
+            let remote = stream.peer_addr()?;
+            let ssl = tls.configure().and_then(|c| c.into_ssl(&host)).map_err(io::Error::other)?;
+            Ok(BackendStream::Tls(Box::new(TlsStream::connect(ssl, stream, remote).await?)))
+        })
+    }
+}
+
+pub enum BackendStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream>),
+}
+
+impl Connection for BackendStream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl AsyncRead for BackendStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            BackendStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            BackendStream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for BackendStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            BackendStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            BackendStream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            BackendStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            BackendStream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            BackendStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            BackendStream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG};
+use hyper::{Body, Response};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+use crate::compress::Encoding;
+use crate::metrics::Metrics;
+use crate::pattern::host_matches;
+use crate::spool::Spool;
+
+// Give up looking for the end of a `<body ...>` tag after this many bytes.
+const MAX_TAG_LEN: usize = 8 * 1024;
+
+// Settings shared by body filters that need to see decoded content.
+#[derive(Debug, Deserialize)]
+pub struct FilterConfig {
+    // Transparently gunzip/inflate bodies for filtering, re-compressing
+    // them afterwards. Brotli and other encodings pass through unfiltered.
+    #[serde(default = "default_decompress")]
+    pub decompress: bool,
+    // Compressed bodies are buffered for filtering up to this size (and
+    // decoded up to this size); larger ones pass through unfiltered.
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+    // Bodies being buffered for inspection move from memory to a temp file
+    // in `spill_dir` (default: system temp dir) past this many bytes
+    #[serde(default = "default_spill_threshold")]
+    pub spill_threshold: usize,
+    pub spill_dir: Option<PathBuf>,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            decompress: default_decompress(),
+            max_body_size: default_max_body_size(),
+            spill_threshold: default_spill_threshold(),
+            spill_dir: None,
+        }
+    }
+}
+
+fn default_spill_threshold() -> usize {
+    1024 * 1024
+}
+
+fn default_decompress() -> bool {
+    true
+}
+
+fn default_max_body_size() -> usize {
+    8 * 1024 * 1024
+}
+
+// HTML snippet injected right after the opening <body> tag of matching pages.
+#[derive(Debug, Deserialize)]
+pub struct BannerRule {
+    pub host: String,
+    #[serde(default = "default_path_prefix")]
+    pub path_prefix: String,
+    pub html: String,
+}
+
+fn default_path_prefix() -> String {
+    "/".to_string()
+}
+
+impl BannerRule {
+    fn matches(&self, host: &str, path: &str) -> bool {
+        host_matches(&self.host, host) && path.starts_with(&self.path_prefix)
+    }
+}
+
+pub fn inject_banner(
+    rules: &[BannerRule],
+    filters: &FilterConfig,
+    metrics: &Arc<Metrics>,
+    host: &str,
+    path: &str,
+    response: Response<Body>,
+) -> Response<Body> {
+    let Some(rule) = rules.iter().find(|r| r.matches(host, path)) else {
+        return response;
+    };
+    if !response.status().is_success() || !is_rewritable_html(response.headers(), &rule.html) {
+        return response;
+    }
+    let encoding = match response.headers().get(CONTENT_ENCODING) {
+        None => None,
+        Some(value) => match value.to_str().ok().and_then(Encoding::from_header) {
+            Some(encoding) if filters.decompress => Some(encoding),
+            _ => {
+                debug!("Skipping banner: unsupported content encoding {:?}", value);
+                return response;
+            }
+        },
+    };
+    debug!("Injecting banner into {}{}", host, path);
+
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    weaken_etag(&mut parts.headers);
+    let snippet = Bytes::from(rule.html.clone());
+    let body = match encoding {
+        None => stream_with_banner(body, snippet),
+        Some(encoding) => {
+            let spool = Spool::new(filters.spill_threshold, filters.spill_dir.as_deref());
+            transcode_with_banner(
+                body,
+                snippet,
+                encoding,
+                filters.max_body_size,
+                spool,
+                metrics.clone(),
+            )
+        }
+    };
+    Response::from_parts(parts, body)
+}
+
+// The filtered body is no longer byte-identical to what the origin tagged,
+// but is semantically equivalent: downgrade a strong validator to weak.
+pub fn weaken_etag(headers: &mut HeaderMap) {
+    let Some(etag) = headers.get(ETAG).and_then(|v| v.to_str().ok()) else {
+        return;
+    };
+    if etag.starts_with("W/") {
+        return;
+    }
+    if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+        headers.insert(ETAG, weak);
+    }
+}
+
+// Only HTML in an ASCII-compatible charset can be rewritten byte-wise;
+// a non-ASCII snippet additionally requires UTF-8.
+fn is_rewritable_html(headers: &HeaderMap, snippet: &str) -> bool {
+    let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let mut params = content_type.split(';').map(str::trim);
+    let mime = params.next().unwrap_or_default();
+    if !mime.eq_ignore_ascii_case("text/html") {
+        return false;
+    }
+    let charset = params
+        .filter_map(|p| p.split_once('='))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("charset"))
+        .map(|(_, v)| v.trim_matches('"').to_ascii_lowercase());
+    match charset.as_deref() {
+        None | Some("utf-8") | Some("utf8") => true,
+        Some("us-ascii") | Some("iso-8859-1") | Some("latin1") | Some("windows-1252") => {
+            if snippet.is_ascii() {
+                true
+            } else {
+                debug!("Skipping banner: non-ASCII snippet for {:?} page", charset);
+                false
+            }
+        }
+        Some(other) => {
+            debug!("Skipping banner: unsupported charset {}", other);
+            false
+        }
+    }
+}
+
+// Re-stream `body`, inserting `snippet` after the first `<body ...>` tag.
+// Only the bytes of a partially received tag are held back between chunks.
+fn stream_with_banner(mut body: Body, snippet: Bytes) -> Body {
+    let (mut sender, out) = Body::channel();
+    tokio::spawn(async move {
+        let mut injector = Injector::new(snippet);
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    warn!("⚠️ Upstream body error while injecting banner: {}", e);
+                    sender.abort();
+                    return;
+                }
+            };
+            for piece in injector.feed(&chunk).into_iter().filter(|p| !p.is_empty()) {
+                if sender.send_data(piece).await.is_err() {
+                    return;
+                }
+            }
+        }
+        if let Some(rest) = injector.finish() {
+            let _ = sender.send_data(rest).await;
+        }
+        if let Ok(Some(trailers)) = body.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    out
+}
+
+// Compressed variant: buffer the body (up to `max_size`), decode it, inject,
+// and re-encode with the same encoding. Bodies that are too large or fail to
+// decode are forwarded unmodified.
+fn transcode_with_banner(
+    mut body: Body,
+    snippet: Bytes,
+    encoding: Encoding,
+    max_size: usize,
+    mut spool: Spool,
+    metrics: Arc<Metrics>,
+) -> Body {
+    let (mut sender, out) = Body::channel();
+    tokio::spawn(async move {
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    warn!("⚠️ Upstream body error while injecting banner: {}", e);
+                    sender.abort();
+                    return;
+                }
+            };
+            if let Err(e) = spool.write(&chunk, &metrics).await {
+                warn!("⚠️ Failed to spool body for filtering: {}", e);
+                sender.abort();
+                return;
+            }
+            if spool.len() > max_size {
+                debug!("Body over {} bytes, forwarding without banner", max_size);
+                if spool.send_to(&mut sender).await.is_err() {
+                    return;
+                }
+                while let Some(Ok(chunk)) = body.data().await {
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                if let Ok(Some(trailers)) = body.trailers().await {
+                    let _ = sender.send_trailers(trailers).await;
+                }
+                return;
+            }
+        }
+
+        let buffered = match spool.into_bytes().await {
+            Ok(buffered) => buffered,
+            Err(e) => {
+                warn!("⚠️ Failed to read back spooled body: {}", e);
+                sender.abort();
+                return;
+            }
+        };
+        let rewritten = match encoding.decode(&buffered, max_size) {
+            Ok(decoded) => {
+                let mut injector = Injector::new(snippet);
+                let mut html = Vec::with_capacity(decoded.len() + 256);
+                for piece in injector.feed(&Bytes::from(decoded)) {
+                    html.extend_from_slice(&piece);
+                }
+                if let Some(rest) = injector.finish() {
+                    html.extend_from_slice(&rest);
+                }
+                Bytes::from(encoding.encode(&html))
+            }
+            Err(e) => {
+                debug!(
+                    "Could not decode {:?} body ({}), forwarding as-is",
+                    encoding, e
+                );
+                Bytes::from(buffered)
+            }
+        };
+        if sender.send_data(rewritten).await.is_err() {
+            return;
+        }
+        if let Ok(Some(trailers)) = body.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    out
+}
+
+struct Injector {
+    snippet: Option<Bytes>,
+    carry: Vec<u8>,
+}
+
+impl Injector {
+    fn new(snippet: Bytes) -> Self {
+        Injector {
+            snippet: Some(snippet),
+            carry: Vec::new(),
+        }
+    }
+
+    fn feed(&mut self, chunk: &Bytes) -> Vec<Bytes> {
+        let Some(snippet) = self.snippet.clone() else {
+            return vec![chunk.clone()];
+        };
+        self.carry.extend_from_slice(chunk);
+        let buf = std::mem::take(&mut self.carry);
+
+        if let Some(start) = find_ascii_ci(&buf, b"<body") {
+            if let Some(end) = buf[start..].iter().position(|&b| b == b'>') {
+                let split = start + end + 1;
+                self.snippet = None;
+                return vec![
+                    Bytes::copy_from_slice(&buf[..split]),
+                    snippet,
+                    Bytes::copy_from_slice(&buf[split..]),
+                ];
+            }
+            if buf.len() - start > MAX_TAG_LEN {
+                // Malformed tag; stop trying
+                self.snippet = None;
+                return vec![Bytes::from(buf)];
+            }
+            self.carry = buf[start..].to_vec();
+            return vec![Bytes::copy_from_slice(&buf[..start])];
+        }
+
+        // Keep a tail that could be the beginning of "<body"
+        let keep = buf.len().min(b"<body".len() - 1);
+        let split = buf.len() - keep;
+        self.carry = buf[split..].to_vec();
+        vec![Bytes::copy_from_slice(&buf[..split])]
+    }
+
+    fn finish(&mut self) -> Option<Bytes> {
+        let rest = std::mem::take(&mut self.carry);
+        (!rest.is_empty()).then(|| Bytes::from(rest))
+    }
+}
+
+fn find_ascii_ci(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|w| w.eq_ignore_ascii_case(needle))
+}
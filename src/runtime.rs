@@ -0,0 +1,79 @@
+use clap::Args;
+use serde::Deserialize;
+use std::io;
+use tokio::runtime::{Builder, Runtime};
+use tracing::info;
+
+const MIN_STACK_SIZE: usize = 64 * 1024;
+
+// Threads for the async runtime; tokio's defaults suit a typical server,
+// not a 1-CPU VPS or a 64-core edge box:
+//
+//   [runtime]
+//   worker_threads = 2
+//   max_blocking_threads = 32
+//
+// The same options on the command line win over config.toml.
+#[derive(Debug, Default, Clone, Args, Deserialize)]
+pub struct RuntimeConfig {
+    /// Threads running connections (default: one per CPU core)
+    #[arg(long, global = true, value_parser = clap::value_parser!(u16).range(1..))]
+    pub worker_threads: Option<u16>,
+    /// Most threads for blocking work such as file I/O and LDAP (default: 512)
+    #[arg(long, global = true, value_parser = clap::value_parser!(u16).range(1..))]
+    pub max_blocking_threads: Option<u16>,
+    /// Stack size of each thread in bytes (default: 2 MiB)
+    #[arg(long, global = true)]
+    pub thread_stack_size: Option<usize>,
+}
+
+impl RuntimeConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.worker_threads == Some(0) || self.max_blocking_threads == Some(0) {
+            return Err("runtime: thread counts must be positive".to_string());
+        }
+        match self.thread_stack_size {
+            Some(size) if size < MIN_STACK_SIZE => {
+                Err(format!("runtime.thread_stack_size must be at least {} bytes", MIN_STACK_SIZE))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Each option set here, else the one from `config`.
+    pub fn or(&self, config: Option<&RuntimeConfig>) -> RuntimeConfig {
+        let config = config.cloned().unwrap_or_default();
+        RuntimeConfig {
+            worker_threads: self.worker_threads.or(config.worker_threads),
+            max_blocking_threads: self.max_blocking_threads.or(config.max_blocking_threads),
+            thread_stack_size: self.thread_stack_size.or(config.thread_stack_size),
+        }
+    }
+
+    pub fn build(&self) -> io::Result<Runtime> {
+        self.validate().map_err(io::Error::other)?;
+        let mut builder = Builder::new_multi_thread();
+        builder.enable_all();
+        if let Some(threads) = self.worker_threads {
+            builder.worker_threads(threads as usize);
+        }
+        if let Some(threads) = self.max_blocking_threads {
+            builder.max_blocking_threads(threads as usize);
+        }
+        if let Some(size) = self.thread_stack_size {
+            builder.thread_stack_size(size);
+        }
+        builder.build()
+    }
+
+    // Logged once logging is up, which is after the runtime starts.
+    pub fn log(&self) {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        info!(
+            "⚙️ Runtime: {} worker threads, up to {} blocking threads, {} KiB stacks",
+            self.worker_threads.map_or(cores, |n| n as usize),
+            self.max_blocking_threads.unwrap_or(512),
+            self.thread_stack_size.unwrap_or(2 * 1024 * 1024) / 1024
+        );
+    }
+}
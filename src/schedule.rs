@@ -0,0 +1,170 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::groups::{self, GroupConfig};
+use crate::tz::Zone;
+
+const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+// When some users may browse at all, e.g. office hours or a child's
+// evenings; "@name" refers to [groups]:
+//
+//   [[schedules]]
+//   users = ["@kids"]
+//   timezone = "Europe/Berlin"
+//   allow = ["mon-fri 16:00-19:30", "sat,sun 09:00-20:00"]
+//
+// The first schedule naming the user, one of their groups or "*" applies;
+// users no schedule names are never restricted.
+#[derive(Debug, Deserialize)]
+pub struct Schedule {
+    // User names, "@group" or "*"
+    pub users: Vec<String>,
+    // IANA name from the system tz database, or "UTC"
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    // "days HH:MM-HH:MM"; days are "mon-fri", "sat,sun", "fri-mon" or
+    // "daily", and an end before the start runs past midnight
+    pub allow: Vec<String>,
+    // Shown on the block page instead of the default text
+    pub message: Option<String>,
+    #[serde(skip)]
+    zone: Zone,
+    #[serde(skip)]
+    windows: Vec<Window>,
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+#[derive(Debug)]
+struct Window {
+    // Bit per weekday, Sunday = bit 0
+    days: u8,
+    // Minutes after local midnight
+    start: u32,
+    end: u32,
+}
+
+impl Window {
+    fn parse(rule: &str) -> Option<Window> {
+        let (days, times) = rule.trim().split_once(char::is_whitespace)?;
+        let (start, end) = times.trim().split_once('-')?;
+        let (start, end) = (minutes(start)?, minutes(end)?);
+        (start < 24 * 60 && start != end).then_some(())?;
+        Some(Window {
+            days: weekdays(days)?,
+            start,
+            end,
+        })
+    }
+
+    fn contains(&self, weekday: u32, minute: u32) -> bool {
+        let on = |day: u32| self.days & (1 << day) != 0;
+        match self.start < self.end {
+            true => on(weekday) && (self.start..self.end).contains(&minute),
+            false => (on(weekday) && minute >= self.start) || (on((weekday + 6) % 7) && minute < self.end),
+        }
+    }
+}
+
+// "HH:MM", "24:00" included
+fn minutes(time: &str) -> Option<u32> {
+    let (hours, mins) = time.trim().split_once(':')?;
+    let (hours, mins): (u32, u32) = (hours.parse().ok()?, mins.parse().ok()?);
+    (mins < 60 && (hours < 24 || (hours, mins) == (24, 0))).then_some(hours * 60 + mins)
+}
+
+fn weekdays(days: &str) -> Option<u8> {
+    if days.eq_ignore_ascii_case("daily") {
+        return Some(0x7f);
+    }
+    let day = |name: &str| DAYS.iter().position(|d| d.eq_ignore_ascii_case(name)).map(|i| i as u32);
+    let mut bits = 0;
+    for part in days.split(',') {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (day(first)?, day(last)?),
+            None => (day(part)?, day(part)?),
+        };
+        let mut d = first;
+        loop {
+            bits |= 1 << d;
+            if d == last {
+                break;
+            }
+            d = (d + 1) % 7;
+        }
+    }
+    Some(bits)
+}
+
+// Why a request was refused, for the log and the block page.
+pub struct Blocked<'a> {
+    pub schedule: usize,
+    pub allowed: &'a [String],
+    pub timezone: &'a str,
+    pub message: Option<&'a str>,
+    // Local time of the request, e.g. "Fri 19:42"
+    pub now: String,
+}
+
+// Parses the windows and loads the time zones once at startup.
+pub fn prepare(schedules: &mut [Schedule], groups: &HashMap<String, GroupConfig>) -> Result<(), String> {
+    for (i, schedule) in schedules.iter_mut().enumerate() {
+        if schedule.users.is_empty() {
+            return Err(format!("schedules[{}]: users must not be empty", i));
+        }
+        groups::check_refs(&schedule.users, groups).map_err(|e| format!("schedules[{}]: {}", i, e))?;
+        schedule.zone = Zone::load(&schedule.timezone).map_err(|e| format!("schedules[{}]: {}", i, e))?;
+        schedule.windows = schedule
+            .allow
+            .iter()
+            .map(|rule| Window::parse(rule).ok_or_else(|| format!("schedules[{}]: invalid window '{}'", i, rule)))
+            .collect::<Result<_, _>>()?;
+    }
+    Ok(())
+}
+
+// Err when `user`, a member of `group`, may not browse at `now`.
+pub fn check<'a>(
+    schedules: &'a [Schedule],
+    user: &str,
+    group: Option<&str>,
+    now: SystemTime,
+) -> Result<(), Blocked<'a>> {
+    let Some((i, schedule)) = schedules.iter().enumerate().find(|(_, s)| groups::covers(&s.users, user, group)) else {
+        return Ok(());
+    };
+    let utc = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+    let local = utc + schedule.zone.offset(utc) as i64;
+    let weekday = (local.div_euclid(86_400) + 4).rem_euclid(7) as u32;
+    let minute = (local.rem_euclid(86_400) / 60) as u32;
+    if schedule.windows.iter().any(|w| w.contains(weekday, minute)) {
+        return Ok(());
+    }
+    let day = DAYS[weekday as usize];
+    Err(Blocked {
+        schedule: i,
+        allowed: &schedule.allow,
+        timezone: &schedule.timezone,
+        message: schedule.message.as_deref(),
+        now: format!("{}{} {:02}:{:02}", day[..1].to_uppercase(), &day[1..], minute / 60, minute % 60),
+    })
+}
+
+// The 403 page a blocked user sees in their browser.
+pub fn block_page(blocked: &Blocked) -> String {
+    let escape = |s: &str| s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;");
+    let windows: String = blocked.allowed.iter().map(|w| format!("<li>{}</li>", escape(w))).collect();
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Not available right now</title></head>\n\
+         <body><h1>Not available right now</h1>\n<p>{}</p>\n\
+         <p>It is {} ({}). Browsing is allowed:</p>\n<ul>{}</ul>\n</body></html>\n",
+        escape(blocked.message.unwrap_or("Your account may not browse at this time.")),
+        blocked.now,
+        escape(blocked.timezone),
+        windows
+    )
+}
@@ -0,0 +1,202 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+use crate::gate::sign;
+use crate::users::UserLimits;
+use crate::AppState;
+
+const VERSION: u32 = 1;
+const CONFIG_FILE: &str = "config.toml";
+const SIGNATURE_PREFIX: &str = "signature = \"";
+
+// The configuration file plus what was changed at runtime (bans, feature
+// flags, the user store), for moving an instance to another host. Written
+// as TOML with an HMAC-SHA256 `signature` line in front:
+//
+//   signature = "<hex HMAC of everything after this line>"
+//   version = 1
+//   ...
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    // Unix seconds and host name of the exporting instance
+    created: u64,
+    host: String,
+    // config.toml as it was on disk at export, which may differ from what
+    // the exporting instance loaded if it was edited since
+    config: String,
+    #[serde(default)]
+    flags: BTreeMap<String, bool>,
+    // IP -> ban expiry in Unix seconds, 0 for good
+    #[serde(default)]
+    bans: BTreeMap<String, u64>,
+    // Only with a user store; [users] are part of `config`
+    #[serde(default)]
+    users: Vec<User>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct User {
+    name: String,
+    // As stored, i.e. normally a hash
+    password: String,
+    max_connections: Option<u64>,
+    bandwidth: Option<u64>,
+    quota_bytes: Option<u64>,
+}
+
+pub enum Error {
+    // Bad signature or content; nothing was changed
+    Invalid(String),
+    // Applying failed part way
+    Failed(String),
+}
+
+pub fn export(state: &AppState, key: &str) -> String {
+    let users = state
+        .users
+        .db()
+        .map(|db| {
+            db.list()
+                .into_iter()
+                .filter_map(|record| {
+                    Some(User {
+                        password: db.password(&record.name)?,
+                        max_connections: record.limits.max_connections.map(|n| n as u64),
+                        bandwidth: record.limits.bandwidth,
+                        quota_bytes: record.limits.quota_bytes,
+                        name: record.name,
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let snapshot = Snapshot {
+        version: VERSION,
+        created: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        host: crate::loops::hostname(),
+        // Read again rather than kept in memory, as it holds passwords
+        config: fs::read_to_string(CONFIG_FILE).unwrap_or_default(),
+        flags: state.flags.snapshot().into_iter().map(|(name, on)| (name.to_string(), on)).collect(),
+        bans: state.bans.list().into_iter().map(|(ip, until)| (ip.to_string(), until)).collect(),
+        users,
+    };
+    // Only fails for types TOML cannot represent, which Snapshot has none of
+    let body = toml::to_string(&snapshot).unwrap_or_default();
+    format!("{}{}\"\n{}", SIGNATURE_PREFIX, sign(key, &body).unwrap_or_default(), body)
+}
+
+// Check the signature, then write config.toml (the old file is kept as
+// config.toml.bak) and add the bans, flags and users. The config takes
+// effect on restart; the rest right away. Returns a JSON summary.
+pub fn restore(state: &AppState, key: &str, document: &[u8], actor: &str) -> Result<String, Error> {
+    let invalid = |message: &str| Error::Invalid(message.to_string());
+    let document = std::str::from_utf8(document).map_err(|_| invalid("snapshot is not UTF-8"))?;
+    let (first, body) = document.split_once('\n').ok_or_else(|| invalid("snapshot is empty"))?;
+    let signature = first
+        .trim_end()
+        .strip_prefix(SIGNATURE_PREFIX)
+        .and_then(|rest| rest.strip_suffix('"'))
+        .ok_or_else(|| invalid("snapshot does not start with a signature line"))?;
+    let expected = sign(key, body).ok_or_else(|| Error::Failed("cannot compute HMAC".to_string()))?;
+    if expected.len() != signature.len() || !openssl::memcmp::eq(expected.as_bytes(), signature.as_bytes()) {
+        return Err(invalid("bad snapshot signature"));
+    }
+    let snapshot: Snapshot = toml::from_str(body).map_err(|e| Error::Invalid(format!("snapshot: {}", e)))?;
+    if snapshot.version != VERSION {
+        return Err(Error::Invalid(format!("unsupported snapshot version {}", snapshot.version)));
+    }
+
+    // Everything is checked before anything is applied
+    if !snapshot.config.is_empty() {
+        crate::Config::parse(&snapshot.config).map_err(|e| Error::Invalid(format!("snapshot config: {}", e)))?;
+    }
+    let bans = snapshot
+        .bans
+        .iter()
+        .map(|(ip, until)| Ok((ip.parse::<IpAddr>()?, *until)))
+        .collect::<Result<Vec<_>, std::net::AddrParseError>>()
+        .map_err(|e| Error::Invalid(format!("snapshot bans: {}", e)))?;
+    if let Some(name) = snapshot.flags.keys().find(|name| !crate::flags::ALL.contains(&name.as_str())) {
+        return Err(Error::Invalid(format!("unknown feature flag '{}'", name)));
+    }
+    if let Some(user) = snapshot.users.iter().find(|u| !crate::users::valid_name(&u.name)) {
+        return Err(Error::Invalid(format!("invalid user name '{}'", user.name)));
+    }
+    let db = state.users.db();
+    if db.is_none() && !snapshot.users.is_empty() {
+        return Err(invalid("snapshot has store users but this instance has no user_store"));
+    }
+
+    let failed = |e: std::io::Error| Error::Failed(format!("{}: {}", CONFIG_FILE, e));
+    if !snapshot.config.is_empty() {
+        // The file holds credentials: unreadable to others until it has
+        // the same permissions as the one it replaces
+        let tmp = format!("{}.tmp", CONFIG_FILE);
+        let _ = fs::remove_file(&tmp);
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)
+            .and_then(|mut file| file.write_all(snapshot.config.as_bytes()))
+            .map_err(failed)?;
+        match fs::metadata(CONFIG_FILE) {
+            Ok(old) => fs::set_permissions(&tmp, old.permissions()).map_err(failed)?,
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(failed(e)),
+            Err(_) => {}
+        }
+        match fs::copy(CONFIG_FILE, format!("{}.bak", CONFIG_FILE)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(failed(e)),
+            _ => {}
+        }
+        fs::rename(&tmp, CONFIG_FILE).map_err(failed)?;
+    }
+    for (ip, until) in &bans {
+        state.bans.insert(*ip, *until);
+    }
+    for (name, on) in snapshot.flags.iter().filter(|(name, on)| state.flags.enabled(name) != **on) {
+        state.flags.set(name, *on, actor);
+    }
+    if let Some(db) = db {
+        for user in &snapshot.users {
+            let limits = UserLimits {
+                max_connections: user.max_connections.map(|n| n as usize),
+                bandwidth: user.bandwidth,
+                quota_bytes: user.quota_bytes,
+            };
+            let result = db
+                .insert(&user.name, &user.password)
+                .and_then(|added| match added {
+                    true => Ok(true),
+                    false => db.set_password(&user.name, &user.password),
+                })
+                .and_then(|_| db.set_limits(&user.name, &limits));
+            result.map_err(|e| Error::Failed(format!("user '{}': {}", user.name, e)))?;
+        }
+        state.auth_cache.clear();
+    }
+    info!(
+        target: "audit",
+        actor = %actor,
+        source_host = %snapshot.host,
+        created = snapshot.created,
+        bans = bans.len(),
+        flags = snapshot.flags.len(),
+        users = snapshot.users.len(),
+        "configuration snapshot restored"
+    );
+    Ok(format!(
+        "{{\"bans\":{},\"flags\":{},\"users\":{},\"restart_required\":{}}}",
+        bans.len(),
+        snapshot.flags.len(),
+        snapshot.users.len(),
+        !snapshot.config.is_empty()
+    ))
+}
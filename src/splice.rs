@@ -0,0 +1,115 @@
+use hyper::body::Bytes;
+use hyper::server::conn::AddrStream;
+use hyper::upgrade::Upgraded;
+use socket2::SockRef;
+use std::io;
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, RawFd};
+use tokio::io::{AsyncWriteExt, Interest};
+use tokio::net::TcpStream;
+
+use crate::reaper::Activity;
+use crate::timeouts::Timed;
+
+// Bytes moved per splice call; also the default pipe capacity
+const CHUNK: usize = 64 * 1024;
+
+// Zero-copy tunnels for Linux: splice(2) moves bytes from one socket into a
+// pipe and from the pipe into the other socket without them ever being
+// copied into the proxy's memory. Only plain TCP clients qualify; TLS
+// listeners and throttled tunnels need the bytes in hand.
+
+// The client's socket and whatever it sent ahead of the 200 response, if
+// the tunnel came in on a plain listener.
+pub fn client_socket(upgraded: Upgraded) -> Result<(TcpStream, Bytes), Upgraded> {
+    let parts = upgraded.downcast::<Timed<AddrStream>>()?;
+    Ok((parts.io.into_inner().into_inner(), parts.read_buf))
+}
+
+// Like tokio::io::copy_bidirectional, recording into `activity` as the
+// reaper's Tracked streams do. Returns (bytes from client, bytes from server).
+pub async fn copy_bidirectional(
+    client: TcpStream,
+    server: &mut TcpStream,
+    early: Bytes,
+    activity: &Activity,
+) -> io::Result<(u64, u64)> {
+    if !early.is_empty() {
+        activity.record(true, early.len());
+        server.write_all(&early).await?;
+    }
+    let server = &*server;
+    let (from_client, from_server) =
+        tokio::try_join!(relay(&client, server, activity, true), relay(server, &client, activity, false))?;
+    Ok((from_client + early.len() as u64, from_server))
+}
+
+// One direction, until `from` sends EOF, which is passed on to `to`.
+async fn relay(from: &TcpStream, to: &TcpStream, activity: &Activity, client: bool) -> io::Result<u64> {
+    let pipe = Pipe::new()?;
+    let mut total = 0u64;
+    loop {
+        if activity.closing() {
+            return std::future::pending().await;
+        }
+        from.readable().await?;
+        // The pipe is always empty here, so EAGAIN means the socket is
+        let n = match from.try_io(Interest::READABLE, || splice(from.as_raw_fd(), pipe.write, CHUNK)) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        };
+        activity.record(client, n);
+        if n == 0 {
+            // The other side may be gone already, which is no error here
+            let _ = SockRef::from(to).shutdown(Shutdown::Write);
+            return Ok(total);
+        }
+        let mut pending = n;
+        while pending > 0 {
+            to.writable().await?;
+            match to.try_io(Interest::WRITABLE, || splice(pipe.read, to.as_raw_fd(), pending)) {
+                Ok(written) => pending -= written,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        total += n as u64;
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    let n = unsafe { libc::splice(from, std::ptr::null_mut(), to, std::ptr::null_mut(), len, flags) };
+    match n {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+struct Pipe {
+    read: RawFd,
+    write: RawFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Pipe {
+            read: fds[0],
+            write: fds[1],
+        })
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use hyper::body::{Bytes, Sender};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::debug;
+
+use crate::metrics::Metrics;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// Body buffer for content inspection: held in memory up to `threshold`
+// bytes, then spilled to an anonymous temp file. The file is unlinked as
+// soon as it is created, so it disappears when the spool is dropped even
+// if the task is cancelled or the process dies.
+pub struct Spool {
+    memory: Vec<u8>,
+    file: Option<File>,
+    len: usize,
+    threshold: usize,
+    dir: PathBuf,
+}
+
+impl Spool {
+    pub fn new(threshold: usize, dir: Option<&Path>) -> Self {
+        Spool {
+            memory: Vec::new(),
+            file: None,
+            len: 0,
+            threshold,
+            dir: dir
+                .map(Path::to_path_buf)
+                .unwrap_or_else(std::env::temp_dir),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub async fn write(&mut self, data: &[u8], metrics: &Metrics) -> io::Result<()> {
+        self.len += data.len();
+        if self.file.is_none() && self.len > self.threshold {
+            let mut file = self.create_file().await?;
+            file.write_all(&self.memory).await?;
+            self.memory = Vec::new();
+            self.file = Some(file);
+            metrics.body_spills.fetch_add(1, Ordering::Relaxed);
+            debug!("Spooled body over {} bytes to disk", self.threshold);
+        }
+        match &mut self.file {
+            Some(file) => {
+                metrics
+                    .body_spill_bytes
+                    .fetch_add(data.len() as u64, Ordering::Relaxed);
+                file.write_all(data).await
+            }
+            None => {
+                self.memory.extend_from_slice(data);
+                Ok(())
+            }
+        }
+    }
+
+    async fn create_file(&self) -> io::Result<File> {
+        let path = self.dir.join(format!(
+            "secure-proxy-spool-{}-{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut options = tokio::fs::OpenOptions::new();
+        options.read(true).write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let file = options.open(&path).await?;
+        // Keep only the open handle; the kernel reclaims the space on close
+        tokio::fs::remove_file(&path).await?;
+        Ok(file)
+    }
+
+    // Read the whole body back into memory.
+    pub async fn into_bytes(self) -> io::Result<Vec<u8>> {
+        match self.file {
+            None => Ok(self.memory),
+            Some(mut file) => {
+                let mut data = Vec::with_capacity(self.len);
+                file.seek(io::SeekFrom::Start(0)).await?;
+                file.read_to_end(&mut data).await?;
+                Ok(data)
+            }
+        }
+    }
+
+    // Stream the buffered body into `sender` without loading it whole.
+    pub async fn send_to(self, sender: &mut Sender) -> io::Result<()> {
+        let closed = || io::Error::new(io::ErrorKind::BrokenPipe, "client went away");
+        match self.file {
+            None => {
+                if !self.memory.is_empty() {
+                    sender
+                        .send_data(Bytes::from(self.memory))
+                        .await
+                        .map_err(|_| closed())?;
+                }
+            }
+            Some(mut file) => {
+                file.seek(io::SeekFrom::Start(0)).await?;
+                let mut buf = vec![0u8; 64 * 1024];
+                loop {
+                    let n = file.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    sender
+                        .send_data(Bytes::copy_from_slice(&buf[..n]))
+                        .await
+                        .map_err(|_| closed())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
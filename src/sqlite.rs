@@ -0,0 +1,226 @@
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_uchar, c_void};
+use std::path::Path;
+
+// Minimal bindings to the system libsqlite3: prepared statements with
+// integer, text and null values, which is all the user store needs.
+#[allow(non_camel_case_types)]
+mod ffi {
+    use super::*;
+
+    pub enum sqlite3 {}
+    pub enum sqlite3_stmt {}
+
+    pub const OK: c_int = 0;
+    pub const ROW: c_int = 100;
+    pub const DONE: c_int = 101;
+    pub const OPEN_READWRITE: c_int = 0x02;
+    pub const OPEN_CREATE: c_int = 0x04;
+    pub const INTEGER: c_int = 1;
+    pub const NULL: c_int = 5;
+    // SQLITE_TRANSIENT: sqlite copies bound text before returning
+    pub const TRANSIENT: isize = -1;
+
+    #[link(name = "sqlite3")]
+    extern "C" {
+        pub fn sqlite3_open_v2(filename: *const c_char, db: *mut *mut sqlite3, flags: c_int, vfs: *const c_char) -> c_int;
+        pub fn sqlite3_close(db: *mut sqlite3) -> c_int;
+        pub fn sqlite3_errmsg(db: *mut sqlite3) -> *const c_char;
+        pub fn sqlite3_busy_timeout(db: *mut sqlite3, ms: c_int) -> c_int;
+        pub fn sqlite3_exec(
+            db: *mut sqlite3,
+            sql: *const c_char,
+            callback: *const c_void,
+            arg: *mut c_void,
+            errmsg: *mut *mut c_char,
+        ) -> c_int;
+        pub fn sqlite3_changes(db: *mut sqlite3) -> c_int;
+        pub fn sqlite3_prepare_v2(
+            db: *mut sqlite3,
+            sql: *const c_char,
+            len: c_int,
+            stmt: *mut *mut sqlite3_stmt,
+            tail: *mut *const c_char,
+        ) -> c_int;
+        pub fn sqlite3_bind_text(stmt: *mut sqlite3_stmt, index: c_int, text: *const c_char, len: c_int, destructor: isize) -> c_int;
+        pub fn sqlite3_bind_int64(stmt: *mut sqlite3_stmt, index: c_int, value: i64) -> c_int;
+        pub fn sqlite3_bind_null(stmt: *mut sqlite3_stmt, index: c_int) -> c_int;
+        pub fn sqlite3_step(stmt: *mut sqlite3_stmt) -> c_int;
+        pub fn sqlite3_column_count(stmt: *mut sqlite3_stmt) -> c_int;
+        pub fn sqlite3_column_type(stmt: *mut sqlite3_stmt, col: c_int) -> c_int;
+        pub fn sqlite3_column_int64(stmt: *mut sqlite3_stmt, col: c_int) -> i64;
+        pub fn sqlite3_column_text(stmt: *mut sqlite3_stmt, col: c_int) -> *const c_uchar;
+        pub fn sqlite3_finalize(stmt: *mut sqlite3_stmt) -> c_int;
+        pub fn sqlite3_free(ptr: *mut c_void);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Int(i64),
+    Text(String),
+}
+
+impl Value {
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Text(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Text(s.to_string())
+    }
+}
+
+impl From<Option<i64>> for Value {
+    fn from(n: Option<i64>) -> Self {
+        n.map_or(Value::Null, Value::Int)
+    }
+}
+
+// One connection; not shareable between threads without a lock.
+pub struct Connection {
+    db: *mut ffi::sqlite3,
+}
+
+unsafe impl Send for Connection {}
+
+impl Connection {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let c_path = CString::new(path.to_string_lossy().as_bytes()).map_err(|e| e.to_string())?;
+        let mut db = std::ptr::null_mut();
+        let rc = unsafe {
+            ffi::sqlite3_open_v2(c_path.as_ptr(), &mut db, ffi::OPEN_READWRITE | ffi::OPEN_CREATE, std::ptr::null())
+        };
+        // A handle comes back even on failure, to report the error and close
+        let conn = Connection { db };
+        if rc != ffi::OK {
+            return Err(format!("{}: {}", path.display(), conn.error()));
+        }
+        // The CLI, the admin API and other instances may write concurrently
+        unsafe { ffi::sqlite3_busy_timeout(db, 5000) };
+        Ok(conn)
+    }
+
+    fn error(&self) -> String {
+        if self.db.is_null() {
+            return "out of memory".to_string();
+        }
+        unsafe { CStr::from_ptr(ffi::sqlite3_errmsg(self.db)) }.to_string_lossy().into_owned()
+    }
+
+    // Statements separated by semicolons, without parameters.
+    pub fn execute_batch(&self, sql: &str) -> Result<(), String> {
+        let c_sql = CString::new(sql).map_err(|e| e.to_string())?;
+        let mut errmsg = std::ptr::null_mut();
+        let rc = unsafe { ffi::sqlite3_exec(self.db, c_sql.as_ptr(), std::ptr::null(), std::ptr::null_mut(), &mut errmsg) };
+        if rc == ffi::OK {
+            return Ok(());
+        }
+        if errmsg.is_null() {
+            return Err(self.error());
+        }
+        let message = unsafe { CStr::from_ptr(errmsg) }.to_string_lossy().into_owned();
+        unsafe { ffi::sqlite3_free(errmsg as *mut c_void) };
+        Err(message)
+    }
+
+    // Run one statement; returns the number of rows changed.
+    pub fn execute(&self, sql: &str, params: &[Value]) -> Result<usize, String> {
+        self.run(sql, params, |_| {})?;
+        Ok(unsafe { ffi::sqlite3_changes(self.db) } as usize)
+    }
+
+    pub fn query(&self, sql: &str, params: &[Value]) -> Result<Vec<Vec<Value>>, String> {
+        let mut rows = Vec::new();
+        self.run(sql, params, |row| rows.push(row))?;
+        Ok(rows)
+    }
+
+    fn run(&self, sql: &str, params: &[Value], mut on_row: impl FnMut(Vec<Value>)) -> Result<(), String> {
+        let stmt = self.prepare(sql)?;
+        for (i, param) in params.iter().enumerate() {
+            let index = i as c_int + 1;
+            let rc = match param {
+                Value::Null => unsafe { ffi::sqlite3_bind_null(stmt.0, index) },
+                Value::Int(n) => unsafe { ffi::sqlite3_bind_int64(stmt.0, index, *n) },
+                Value::Text(s) => unsafe {
+                    ffi::sqlite3_bind_text(stmt.0, index, s.as_ptr() as *const c_char, s.len() as c_int, ffi::TRANSIENT)
+                },
+            };
+            if rc != ffi::OK {
+                return Err(self.error());
+            }
+        }
+        loop {
+            match unsafe { ffi::sqlite3_step(stmt.0) } {
+                ffi::ROW => on_row(stmt.row()),
+                ffi::DONE => return Ok(()),
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    fn prepare(&self, sql: &str) -> Result<Statement, String> {
+        let mut stmt = std::ptr::null_mut();
+        let rc = unsafe {
+            ffi::sqlite3_prepare_v2(
+                self.db,
+                sql.as_ptr() as *const c_char,
+                sql.len() as c_int,
+                &mut stmt,
+                std::ptr::null_mut(),
+            )
+        };
+        if rc != ffi::OK {
+            return Err(self.error());
+        }
+        Ok(Statement(stmt))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe { ffi::sqlite3_close(self.db) };
+    }
+}
+
+struct Statement(*mut ffi::sqlite3_stmt);
+
+impl Statement {
+    fn row(&self) -> Vec<Value> {
+        let columns = unsafe { ffi::sqlite3_column_count(self.0) };
+        (0..columns)
+            .map(|col| match unsafe { ffi::sqlite3_column_type(self.0, col) } {
+                ffi::NULL => Value::Null,
+                ffi::INTEGER => Value::Int(unsafe { ffi::sqlite3_column_int64(self.0, col) }),
+                _ => {
+                    let text = unsafe { ffi::sqlite3_column_text(self.0, col) };
+                    if text.is_null() {
+                        Value::Null
+                    } else {
+                        Value::Text(unsafe { CStr::from_ptr(text as *const c_char) }.to_string_lossy().into_owned())
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+impl Drop for Statement {
+    fn drop(&mut self) {
+        unsafe { ffi::sqlite3_finalize(self.0) };
+    }
+}
@@ -0,0 +1,109 @@
+use openssl::hash::{hash, MessageDigest};
+use tracing::info;
+
+use crate::{AppState, Config};
+
+// SHA-256 of the config file as read, lowercase hex; the same as
+// `sha256sum config.toml` prints.
+pub fn config_hash(contents: &str) -> String {
+    hash(MessageDigest::sha256(), contents.as_bytes())
+        .map(|digest| digest.iter().map(|b| format!("{:02x}", b)).collect())
+        .unwrap_or_default()
+}
+
+// The one event logged once the listeners are up, so fleet tooling can
+// check what each instance runs. Lists are comma-separated, e.g.
+//   features="admin,metrics,ldap" listeners="public=0.0.0.0:8080/http"
+//   limits="max_connections_per_user=100,max_tunnels=2000"
+pub fn announce(state: &AppState) {
+    let config = &state.config;
+    let listeners: Vec<String> = state
+        .listeners()
+        .iter()
+        .map(|l| format!("{}={}/{}", l.name, l.addr, if l.tls { "tls" } else { "http" }))
+        .collect();
+    let flags: Vec<&str> = state.flags.snapshot().into_iter().filter(|(_, on)| *on).map(|(name, _)| name).collect();
+    info!(
+        target: "startup",
+        version = env!("CARGO_PKG_VERSION"),
+        config_hash = %config.hash,
+        features = %features(config).join(","),
+        flags = %flags.join(","),
+        listeners = %listeners.join(","),
+        users = %match &config.user_store {
+            Some(_) => "user_store".to_string(),
+            None => state.users.len().to_string(),
+        },
+        limits = %limits(config).join(","),
+        "🚀 Secure proxy {} started",
+        env!("CARGO_PKG_VERSION")
+    );
+}
+
+// Optional sections that are switched on, in config file order.
+fn features(config: &Config) -> Vec<&'static str> {
+    [
+        ("user_store", config.user_store.is_some()),
+        ("ldap", config.ldap.url.is_some()),
+        ("totp", !config.totp.secrets.is_empty()),
+        ("jwt", config.jwt.enabled()),
+        ("oidc", config.oidc.enabled()),
+        ("kerberos", config.kerberos.enabled()),
+        ("api_keys", config.api_keys.enabled()),
+        ("auth_cache", config.auth_cache.ttl > 0),
+        ("groups", !config.groups.is_empty()),
+        ("acl", config.acl.enabled()),
+        ("schedules", !config.schedules.is_empty()),
+        ("blocklists", !config.blocklists.is_empty()),
+        ("user_headers", !config.user_headers.is_empty()),
+        ("deprecations", !config.deprecations.is_empty()),
+        ("headers", !config.headers.is_empty()),
+        ("mirror", !config.mirror.rules.is_empty()),
+        ("privacy", config.privacy.enabled),
+        ("icap", config.icap.enabled()),
+        ("har", config.har.dir.is_some()),
+        ("error_pages", config.error_pages.enabled()),
+        ("cache", config.cache.enabled),
+        ("banners", !config.banners.is_empty()),
+        ("compression", config.compression.enabled),
+        ("metrics", config.metrics.enabled),
+        ("routes", !config.routes.is_empty()),
+        ("admin", config.admin.enabled),
+        ("watchdog", config.watchdog.enabled),
+        ("circuit_breaker", config.circuit_breaker.enabled),
+        ("gate", config.gate.enabled),
+        ("egress_ips", config.egress_ips.enabled()),
+        ("dns_hosts", !config.dns.hosts.is_empty()),
+        ("geoip", config.geoip.database.is_some() || config.geoip.asn_database.is_some()),
+        ("retry", config.retry.attempts > 0),
+        ("honeypot", !config.honeypot.users.is_empty()),
+        ("billing", config.billing.enabled),
+        ("access_log", config.access_log.format.is_some() || config.access_log.parquet_dir.is_some()),
+        ("anomaly", config.anomaly.enabled),
+        ("cert_watch", !config.cert_watch.domains.is_empty()),
+        ("reaper", config.reaper.enabled()),
+    ]
+    .into_iter()
+    .filter(|(_, on)| *on)
+    .map(|(name, _)| name)
+    .collect()
+}
+
+// Only the limits that are set; anything missing is unlimited.
+fn limits(config: &Config) -> Vec<String> {
+    let limits = &config.limits;
+    let bandwidth = &config.bandwidth;
+    [
+        ("max_connections_per_user", limits.max_connections_per_user.map(|n| n as u64)),
+        ("max_client_connections", limits.max_client_connections.map(|n| n as u64)),
+        ("max_tunnels", limits.max_tunnels.map(|n| n as u64)),
+        ("max_request_body", limits.max_request_body),
+        ("max_response_body", limits.max_response_body),
+        ("max_tunnel_bytes", limits.max_tunnel_bytes),
+        ("bandwidth_per_connection", bandwidth.per_connection),
+        ("bandwidth_per_user", bandwidth.per_user),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some(format!("{}={}", name, value?)))
+    .collect()
+}
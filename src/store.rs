@@ -0,0 +1,62 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+const STATE_FILE: &str = "state.toml";
+
+// Small persistent key-value store for runtime state that operators change
+// (feature flags and the like). The whole map is rewritten on every change
+// via a temp file and rename, so the file on disk is always complete.
+pub struct Store {
+    path: PathBuf,
+    entries: Mutex<BTreeMap<String, String>>,
+}
+
+impl Store {
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(STATE_FILE);
+        let entries = match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        info!("🗄️ Loaded {} persisted state entr(ies) from {}", entries.len(), path.display());
+        Ok(Store {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    // Keys under `prefix`, with the prefix stripped.
+    pub fn scan(&self, prefix: &str) -> Vec<(String, String)> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(k, v)| Some((k.strip_prefix(prefix)?.to_string(), v.clone())))
+            .collect()
+    }
+
+    pub fn set(&self, key: &str, value: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(key.to_string(), value.to_string());
+        self.save(&entries);
+    }
+
+    // A failed write keeps the in-memory value; it is retried with the next change.
+    fn save(&self, entries: &BTreeMap<String, String>) {
+        let result = toml::to_string(entries).map_err(io::Error::other).and_then(|contents| {
+            let tmp = self.path.with_extension("toml.tmp");
+            fs::write(&tmp, contents)?;
+            fs::rename(&tmp, &self.path)
+        });
+        if let Err(e) = result {
+            warn!("⚠️ Failed to persist state to {}: {}", self.path.display(), e);
+        }
+    }
+}
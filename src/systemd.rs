@@ -0,0 +1,86 @@
+use std::net::TcpListener;
+use std::os::fd::FromRawFd;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr as UnixAddr, UnixDatagram};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::AppState;
+
+// First descriptor passed by systemd (SD_LISTEN_FDS_START)
+const LISTEN_FDS_START: i32 = 3;
+
+// Sockets handed over by systemd socket activation (LISTEN_FDS), if they
+// are meant for this process. Taken once; the variables are cleared so
+// child processes do not pick them up.
+pub fn listen_fds() -> Vec<TcpListener> {
+    let pid = std::env::var("LISTEN_PID").ok().and_then(|p| p.parse::<u32>().ok());
+    let count = std::env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<i32>().ok());
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    let (Some(pid), Some(count)) = (pid, count) else {
+        return Vec::new();
+    };
+    if pid != std::process::id() {
+        return Vec::new();
+    }
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .filter_map(|fd| {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            match listener.local_addr() {
+                Ok(addr) => {
+                    info!("📥 Received socket {} from systemd", addr);
+                    Some(listener)
+                }
+                Err(e) => {
+                    warn!("⚠️ Ignoring inherited fd {}: not a TCP listener ({})", fd, e);
+                    // Not ours to close
+                    std::mem::forget(listener);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+// sd_notify(3): tell the service manager about state changes, e.g.
+// "READY=1". A no-op when not started by systemd with NotifyAccess.
+pub fn notify(message: &str) {
+    let Ok(path) = std::env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    let addr = match path.strip_prefix('@') {
+        Some(name) => UnixAddr::from_abstract_name(name.as_bytes()),
+        None => UnixAddr::from_pathname(&path),
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| socket.send_to_addr(message.as_bytes(), &addr?));
+    match sent {
+        Ok(_) => debug!("sd_notify: {}", message.replace('\n', " ")),
+        Err(e) => warn!("⚠️ sd_notify to {} failed: {}", path, e),
+    }
+}
+
+// With WatchdogSec= set, ping systemd at half the interval for as long as
+// the proxy is ready. A stalled runtime or a problem found by the internal
+// watchdog stops the pings and systemd restarts the service.
+pub fn spawn_watchdog(state: Arc<AppState>) {
+    let usec = std::env::var("WATCHDOG_USEC").ok().and_then(|u| u.parse::<u64>().ok());
+    let pid = std::env::var("WATCHDOG_PID").ok().and_then(|p| p.parse::<u32>().ok());
+    let Some(usec) = usec.filter(|_| pid.is_none_or(|pid| pid == std::process::id())) else {
+        return;
+    };
+    let interval = Duration::from_micros(usec / 2);
+    info!("🐕 Pinging the systemd watchdog every {:?}", interval);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if state.watchdog.is_ready() {
+                notify("WATCHDOG=1");
+            }
+        }
+    });
+}
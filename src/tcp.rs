@@ -0,0 +1,166 @@
+use hyper::server::conn::AddrIncoming;
+use serde::Deserialize;
+use socket2::{SockRef, TcpKeepalive};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::AsFd;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
+use tracing::debug;
+
+static CONFIG: OnceLock<TcpConfig> = OnceLock::new();
+
+// Socket options for connections from clients and to destinations and
+// parents:
+//
+//   [tcp.client]
+//   keepalive = 30
+//
+//   [tcp.upstream]
+//   recv_buffer = 4194304
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct TcpConfig {
+    #[serde(default)]
+    pub client: SocketOptions,
+    #[serde(default)]
+    pub upstream: SocketOptions,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SocketOptions {
+    // Send small writes at once rather than batching them (Nagle off)
+    #[serde(default = "default_true")]
+    pub nodelay: bool,
+    // Seconds idle before the first keepalive probe; 0 turns keepalives off
+    #[serde(default = "default_keepalive")]
+    pub keepalive: u64,
+    // Seconds between unanswered probes, and how many before the
+    // connection counts as dead
+    #[serde(default = "default_keepalive_interval")]
+    pub keepalive_interval: u64,
+    #[serde(default = "default_keepalive_count")]
+    pub keepalive_count: u32,
+    // Kernel buffer sizes in bytes; 0 leaves the system default
+    #[serde(default)]
+    pub send_buffer: usize,
+    #[serde(default)]
+    pub recv_buffer: usize,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: true,
+            keepalive: default_keepalive(),
+            keepalive_interval: default_keepalive_interval(),
+            keepalive_count: default_keepalive_count(),
+            send_buffer: 0,
+            recv_buffer: 0,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+// Well inside the few minutes after which NAT gateways and firewalls
+// commonly forget idle flows
+fn default_keepalive() -> u64 {
+    60
+}
+
+fn default_keepalive_interval() -> u64 {
+    10
+}
+
+fn default_keepalive_count() -> u32 {
+    5
+}
+
+impl TcpConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for (side, options) in [("client", &self.client), ("upstream", &self.upstream)] {
+            if options.keepalive > 0 && (options.keepalive_interval == 0 || options.keepalive_count == 0) {
+                return Err(format!("tcp.{}: keepalive_interval and keepalive_count must be positive", side));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SocketOptions {
+    // Buffer sizes go on a socket before it connects or listens, so the
+    // window scale negotiated in the handshake can make use of them;
+    // accepted connections inherit them from the listening socket.
+    pub fn size_buffers<S: AsFd>(&self, socket: &S) -> io::Result<()> {
+        let socket = SockRef::from(socket);
+        if self.send_buffer > 0 {
+            socket.set_send_buffer_size(self.send_buffer)?;
+        }
+        if self.recv_buffer > 0 {
+            socket.set_recv_buffer_size(self.recv_buffer)?;
+        }
+        Ok(())
+    }
+
+    // Per-connection options. A platform without some of them still gets
+    // the connection, so failures are only logged.
+    pub fn apply<S: AsFd>(&self, stream: &S) {
+        let socket = SockRef::from(stream);
+        if let Err(e) = socket.set_nodelay(self.nodelay) {
+            debug!("Could not set TCP_NODELAY: {}", e);
+        }
+        let keepalive = match self.keepalive {
+            0 => socket.set_keepalive(false),
+            idle => socket.set_tcp_keepalive(
+                &TcpKeepalive::new()
+                    .with_time(Duration::from_secs(idle))
+                    .with_interval(Duration::from_secs(self.keepalive_interval))
+                    .with_retries(self.keepalive_count),
+            ),
+        };
+        if let Err(e) = keepalive {
+            debug!("Could not set TCP keepalive: {}", e);
+        }
+    }
+
+    // hyper accepts plain HTTP connections itself and applies the same
+    // options through its own setters.
+    pub fn apply_incoming(&self, incoming: &mut AddrIncoming) {
+        let keepalive = (self.keepalive > 0).then(|| Duration::from_secs(self.keepalive));
+        incoming
+            .set_nodelay(self.nodelay)
+            .set_keepalive(keepalive)
+            .set_keepalive_interval(Some(Duration::from_secs(self.keepalive_interval)))
+            .set_keepalive_retries(Some(self.keepalive_count));
+    }
+}
+
+// Set once at startup, before the first connection in either direction.
+pub fn configure(config: &TcpConfig) {
+    let _ = CONFIG.set(config.clone());
+}
+
+pub fn config() -> &'static TcpConfig {
+    CONFIG.get_or_init(TcpConfig::default)
+}
+
+// A socket for a connection to `addr`, ready for binding and connecting.
+pub fn upstream_socket(addr: SocketAddr) -> io::Result<TcpSocket> {
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    config().upstream.size_buffers(&socket)?;
+    Ok(socket)
+}
+
+// Connect to a destination or parent, from `source` if given.
+pub async fn connect(addr: SocketAddr, source: Option<IpAddr>) -> io::Result<TcpStream> {
+    let socket = upstream_socket(addr)?;
+    if let Some(source) = source {
+        socket.bind(SocketAddr::new(source, 0))?;
+    }
+    let stream = socket.connect(addr).await?;
+    config().upstream.apply(&stream);
+    Ok(stream)
+}
@@ -0,0 +1,219 @@
+use hyper::server::accept::Accept;
+use std::io;
+use std::net::SocketAddr;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+use tracing::debug;
+
+use crate::limits::LimitsConfig;
+use crate::listener::RemoteAddr;
+use crate::metrics::Metrics;
+
+// Closes client connections that hold a worker without making progress
+// (Slowloris): one that takes longer than `header_timeout` to send a
+// request head, or that sits idle between requests for `idle_timeout`.
+// hyper's own header timeout cannot tell the two apart, as it starts as
+// soon as a keep-alive connection waits for its next request.
+#[derive(Clone, Copy)]
+pub struct Timeouts {
+    header: Option<Duration>,
+    idle: Option<Duration>,
+}
+
+impl Timeouts {
+    pub fn new(config: &LimitsConfig) -> Self {
+        let seconds = |s: u64| (s > 0).then(|| Duration::from_secs(s));
+        Timeouts {
+            header: seconds(config.header_timeout),
+            idle: seconds(config.idle_timeout),
+        }
+    }
+}
+
+// Where one connection is, shared between its stream and its service.
+pub struct Clock {
+    state: Mutex<ClockState>,
+}
+
+struct ClockState {
+    // Bytes last moved either way
+    last: Instant,
+    // First byte of a request head that is not complete yet
+    head_since: Option<Instant>,
+    // Requests the service is still answering
+    in_flight: usize,
+    // Handed over to a tunnel, which the reaper looks after
+    upgraded: bool,
+}
+
+impl Clock {
+    fn new() -> Self {
+        Clock {
+            state: Mutex::new(ClockState {
+                last: Instant::now(),
+                head_since: None,
+                in_flight: 0,
+                upgraded: false,
+            }),
+        }
+    }
+
+    // A request head is complete and the service has it.
+    pub fn begin(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight += 1;
+        state.head_since = None;
+    }
+
+    // The service has answered; `upgraded` if the connection is now a tunnel.
+    pub fn end(&self, upgraded: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        state.last = Instant::now();
+        state.upgraded |= upgraded;
+    }
+
+    fn read(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.last = Instant::now();
+        // Bytes while no request is being answered start the next head
+        if state.in_flight == 0 && state.head_since.is_none() {
+            state.head_since = Some(state.last);
+        }
+    }
+
+    fn wrote(&self) {
+        self.state.lock().unwrap().last = Instant::now();
+    }
+
+    // When the connection is due to be closed, and why.
+    fn deadline(&self, timeouts: Timeouts) -> Option<(Instant, &'static str)> {
+        let state = self.state.lock().unwrap();
+        if state.upgraded || state.in_flight > 0 {
+            return None;
+        }
+        match state.head_since {
+            Some(since) => timeouts.header.map(|t| (since + t, "header")),
+            None => timeouts.idle.map(|t| (state.last + t, "idle")),
+        }
+    }
+}
+
+// A client connection that errors out of reads once it is overdue, which
+// makes hyper close it.
+pub struct Timed<S> {
+    inner: S,
+    clock: Arc<Clock>,
+    timeouts: Timeouts,
+    sleep: Pin<Box<Sleep>>,
+    metrics: Arc<Metrics>,
+}
+
+impl<S> Timed<S> {
+    pub fn clock(&self) -> Arc<Clock> {
+        self.clock.clone()
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Timed<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) if buf.filled().len() > before => {
+                this.clock.read();
+                Poll::Ready(Ok(()))
+            }
+            Poll::Pending => {
+                let Some((deadline, reason)) = this.clock.deadline(this.timeouts) else {
+                    return Poll::Pending;
+                };
+                this.sleep.as_mut().reset(deadline);
+                if this.sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                debug!("Closing client connection: {} timeout", reason);
+                this.metrics.count_client_timeout(reason);
+                Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, format!("client {} timeout", reason))))
+            }
+            result => result,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Timed<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            if n > 0 {
+                this.clock.wrote();
+            }
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: RemoteAddr> RemoteAddr for Timed<S> {
+    fn remote_addr(&self) -> SocketAddr {
+        self.inner.remote_addr()
+    }
+
+    fn original_dst(&self) -> Option<SocketAddr> {
+        self.inner.original_dst()
+    }
+}
+
+// Wraps each accepted connection of `I` in Timed.
+pub struct TimedIncoming<I> {
+    inner: I,
+    timeouts: Timeouts,
+    metrics: Arc<Metrics>,
+}
+
+impl<I> TimedIncoming<I> {
+    pub fn new(inner: I, timeouts: Timeouts, metrics: Arc<Metrics>) -> Self {
+        TimedIncoming {
+            inner,
+            timeouts,
+            metrics,
+        }
+    }
+}
+
+impl<I: Accept + Unpin> Accept for TimedIncoming<I> {
+    type Conn = Timed<I::Conn>;
+    type Error = I::Error;
+
+    fn poll_accept(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner).poll_accept(cx).map(|conn| {
+            conn.map(|conn| {
+                conn.map(|inner| Timed {
+                    inner,
+                    clock: Arc::new(Clock::new()),
+                    timeouts: this.timeouts,
+                    sleep: Box::pin(tokio::time::sleep(Duration::ZERO)),
+                    metrics: this.metrics.clone(),
+                })
+            })
+        })
+    }
+}
@@ -0,0 +1,205 @@
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const STEP: u64 = 30;
+const DIGITS: usize = 6;
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// RFC 6238 codes as a second factor for selected users, who then log in
+// with "password:123456":
+//
+//   [totp]
+//   window = 1
+//
+//   [totp.secrets]
+//   alice = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP"
+#[derive(Debug, Deserialize)]
+pub struct TotpConfig {
+    // 30 second steps accepted either side of now, for clock drift
+    #[serde(default = "default_window")]
+    pub window: u64,
+    // Seconds the exact Proxy-Authorization value a code was verified in
+    // keeps being accepted. Clients send the same credentials with every
+    // request, after the code in them has changed and cannot be used again.
+    #[serde(default = "default_session")]
+    pub session: u64,
+    // user -> base32 secret, as shown by authenticator apps
+    #[serde(default)]
+    pub secrets: HashMap<String, String>,
+}
+
+impl Default for TotpConfig {
+    fn default() -> Self {
+        TotpConfig {
+            window: default_window(),
+            session: default_session(),
+            secrets: HashMap::new(),
+        }
+    }
+}
+
+fn default_window() -> u64 {
+    1
+}
+
+fn default_session() -> u64 {
+    300
+}
+
+impl TotpConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.window > 10 {
+            return Err("totp.window must be at most 10 steps".to_string());
+        }
+        // Anyone who captures the header can use it for this long
+        if self.session > 3600 {
+            return Err("totp.session must be at most 3600 seconds".to_string());
+        }
+        for (user, secret) in &self.secrets {
+            match decode_base32(secret) {
+                Some(key) if key.len() >= 10 => {}
+                _ => return Err(format!("totp: secret for '{}' must be base32 of at least 80 bits", user)),
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Totp {
+    secrets: RwLock<HashMap<String, Vec<u8>>>,
+    // user -> time step of the last code accepted; codes from that step or
+    // earlier are refused, so a code works once (RFC 6238 section 5.2)
+    used: Mutex<HashMap<String, u64>>,
+    // authcache::key of the header a code came in -> (user, when verified)
+    sessions: Mutex<HashMap<Vec<u8>, (String, Instant)>>,
+}
+
+impl Totp {
+    pub fn new(config: &TotpConfig) -> Self {
+        let totp = Totp {
+            secrets: RwLock::default(),
+            used: Mutex::default(),
+            sessions: Mutex::default(),
+        };
+        totp.replace(config);
+        totp
+    }
+
+    // SIGHUP: secrets added or removed in the config file
+    pub fn replace(&self, config: &TotpConfig) {
+        let secrets = config
+            .secrets
+            .iter()
+            .filter_map(|(user, secret)| Some((user.clone(), decode_base32(secret)?)))
+            .collect();
+        *self.secrets.write().unwrap() = secrets;
+        self.sessions.lock().unwrap().clear();
+    }
+
+    pub fn enrolled(&self, user: &str) -> bool {
+        self.secrets.read().unwrap().contains_key(user)
+    }
+
+    // `header` is the Proxy-Authorization value the code came in. On
+    // failure, why, for the log.
+    pub fn verify(&self, config: &TotpConfig, user: &str, code: &str, header: &[u8]) -> Result<(), &'static str> {
+        let session = Duration::from_secs(config.session);
+        let key = crate::authcache::key(header).ok_or("one-time code could not be checked")?;
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, (_, since)| since.elapsed() < session);
+        if sessions.get(&key).is_some_and(|(verified, _)| verified == user) {
+            return Ok(());
+        }
+        let Some(secret) = self.secrets.read().unwrap().get(user).cloned() else {
+            return Err("wrong one-time code");
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / STEP;
+        let step = (now.saturating_sub(config.window)..=now + config.window)
+            .find(|step| hotp(&secret, *step).is_some_and(|expected| openssl::memcmp::eq(expected.as_bytes(), code.as_bytes())))
+            .ok_or("wrong one-time code")?;
+        let mut used = self.used.lock().unwrap();
+        if used.get(user).is_some_and(|last| step <= *last) {
+            return Err("one-time code already used");
+        }
+        used.insert(user.to_string(), step);
+        if config.session > 0 {
+            sessions.insert(key, (user.to_string(), Instant::now()));
+        }
+        Ok(())
+    }
+}
+
+// "password:123456" -> ("password", "123456")
+pub fn split(given: &str) -> Option<(&str, &str)> {
+    let (password, code) = given.rsplit_once(':')?;
+    (code.len() == DIGITS && code.bytes().all(|b| b.is_ascii_digit())).then_some((password, code))
+}
+
+// RFC 4226 HOTP with HMAC-SHA1, as authenticator apps use by default.
+fn hotp(secret: &[u8], counter: u64) -> Option<String> {
+    let key = PKey::hmac(secret).ok()?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key).ok()?;
+    signer.update(&counter.to_be_bytes()).ok()?;
+    let mac = signer.sign_to_vec().ok()?;
+    let offset = (mac[mac.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([mac[offset] & 0x7f, mac[offset + 1], mac[offset + 2], mac[offset + 3]]);
+    Some(format!("{:0width$}", value % 10u32.pow(DIGITS as u32), width = DIGITS))
+}
+
+// RFC 4648 base32; case, spaces and padding are ignored.
+fn decode_base32(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace() && *c != b'=') {
+        let value = BASE32.iter().position(|b| *b == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+fn encode_base32(data: &[u8]) -> String {
+    let mut out = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[(buffer >> bits) as usize & 31] as char);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        out.push(BASE32[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    out
+}
+
+// A new 160-bit secret, base32 encoded.
+pub fn new_secret() -> Result<String, String> {
+    let mut secret = [0u8; 20];
+    openssl::rand::rand_bytes(&mut secret).map_err(|e| e.to_string())?;
+    Ok(encode_base32(&secret))
+}
+
+// For a QR code authenticator apps can scan.
+pub fn provisioning_uri(user: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/secure-proxy:{}?secret={}&issuer=secure-proxy&digits={}&period={}",
+        crate::oidc::percent_encode(user),
+        secret,
+        DIGITS,
+        STEP
+    )
+}
@@ -0,0 +1,421 @@
+use hyper::server::accept::Accept;
+use hyper::{Body, Request, Uri};
+use serde::Deserialize;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::AsRawFd;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+// Clients that speak first (HTTP, TLS) are given this long to do so before
+// the connection is relayed as it is.
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(1);
+// Enough for a TLS ClientHello with a long extension list
+const SNIFF_LEN: usize = 4096;
+// Response heads of synthetic CONNECTs are dropped, up to this size
+const MAX_SWALLOW: usize = 8 * 1024;
+
+// How connections reach a transparent listener, set by the firewall rule
+// that sends them there:
+//   iptables -t nat -A PREROUTING -p tcp --dport 80 -j REDIRECT --to-ports 3129
+//   iptables -t mangle -A PREROUTING -p tcp --dport 443 -j TPROXY --on-port 3129 ...
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    // NAT: the destination is read back with SO_ORIGINAL_DST
+    Redirect,
+    // No NAT: the socket's own local address is the destination; needs
+    // IP_TRANSPARENT on the listening socket, and so CAP_NET_ADMIN
+    Tproxy,
+    // No firewall: DNS or a port forward sends TLS clients here, and the
+    // ClientHello's server name, at the listener's own port, is the
+    // destination. Clients without one are closed.
+    Sni,
+}
+
+// A transparent listener's settings, as seen by request handling.
+pub struct Transparent {
+    pub mode: Mode,
+    // Client networks and their users, most specific first
+    clients: Vec<((IpAddr, u8), String)>,
+}
+
+impl Transparent {
+    pub fn new(mode: Mode, clients: &std::collections::BTreeMap<String, String>) -> Self {
+        let mut clients: Vec<_> = clients
+            .iter()
+            .filter_map(|(network, user)| Some((crate::egress::parse_network(network)?, user.clone())))
+            .collect();
+        clients.sort_by_key(|((_, prefix), _)| std::cmp::Reverse(*prefix));
+        Transparent { mode, clients }
+    }
+
+    // The user traffic from `ip` is accounted to: "-" when no client
+    // networks are configured, None when `ip` is not in any of them.
+    pub fn user(&self, ip: IpAddr) -> Option<&str> {
+        if self.clients.is_empty() {
+            return Some("-");
+        }
+        let ip = canonical(ip);
+        self.clients
+            .iter()
+            .find(|((network, prefix), _)| crate::egress::in_network(ip, *network, *prefix))
+            .map(|(_, user)| user.as_str())
+    }
+}
+
+// IPv4 clients of a dual-stack socket show up as ::ffff:a.b.c.d.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+// Lets a TPROXY listener accept connections for addresses that are not its
+// own. Must be set before binding.
+pub fn set_ip_transparent(socket: &impl AsRawFd, ipv6: bool) -> io::Result<()> {
+    let (level, name) = match ipv6 {
+        false => (libc::SOL_IP, libc::IP_TRANSPARENT),
+        true => (libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+    };
+    let on: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &on as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+// Where the client meant to connect before the firewall sent it here.
+fn original_destination(stream: &TcpStream, mode: Mode) -> io::Result<SocketAddr> {
+    let local = stream.local_addr()?;
+    if mode != Mode::Redirect {
+        return Ok(local);
+    }
+    // SO_ORIGINAL_DST and IP6T_SO_ORIGINAL_DST share the number
+    const SO_ORIGINAL_DST: libc::c_int = 80;
+    let fd = stream.as_raw_fd();
+    match canonical(local.ip()) {
+        IpAddr::V4(_) => {
+            let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+            let mut len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            let result = unsafe {
+                libc::getsockopt(fd, libc::SOL_IP, SO_ORIGINAL_DST, &mut addr as *mut _ as *mut libc::c_void, &mut len)
+            };
+            if result != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Ok(SocketAddr::new(IpAddr::V4(ip), u16::from_be(addr.sin_port)))
+        }
+        IpAddr::V6(_) => {
+            let mut addr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+            let mut len = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+            let result = unsafe {
+                libc::getsockopt(fd, libc::SOL_IPV6, SO_ORIGINAL_DST, &mut addr as *mut _ as *mut libc::c_void, &mut len)
+            };
+            if result != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Ok(SocketAddr::new(IpAddr::V6(ip), u16::from_be(addr.sin6_port)))
+        }
+    }
+}
+
+// Accepts redirected connections, works out where each was going and
+// yields it once it is known how to handle it. Plain HTTP is served as it
+// comes, with its requests made absolute (see absolute()). Anything else,
+// TLS above all, is relayed by putting a CONNECT in front of it, so it goes
+// through the same checks, limits and tunnels as a proxy client's would.
+pub struct TransparentIncoming {
+    streams: mpsc::Receiver<TransparentStream>,
+}
+
+impl TransparentIncoming {
+    pub fn new(listener: TcpListener, transparent: Arc<Transparent>) -> Self {
+        let (tx, rx) = mpsc::channel(64);
+        let own = listener.local_addr().ok();
+        tokio::spawn(async move {
+            loop {
+                let (tcp, remote) = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            debug!("Transparent listener accept failed: {}", e);
+                            tokio::time::sleep(crate::listener::ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                    },
+                    _ = tx.closed() => return,
+                };
+                if transparent.user(remote.ip()).is_none() {
+                    warn!("🚫 Refusing transparent connection from {}: not a configured client", remote);
+                    continue;
+                }
+                crate::tcp::config().client.apply(&tcp);
+                let original = match original_destination(&tcp, transparent.mode) {
+                    Ok(original) if Some(original) != own || transparent.mode == Mode::Sni => original,
+                    Ok(_) => {
+                        debug!("Connection from {} was not redirected, closing it", remote);
+                        continue;
+                    }
+                    Err(e) => {
+                        debug!("No original destination for {}: {}", remote, e);
+                        continue;
+                    }
+                };
+                let tx = tx.clone();
+                let mode = transparent.mode;
+                tokio::spawn(async move {
+                    if let Some(stream) = TransparentStream::sniff(tcp, remote, original, mode).await {
+                        let _ = tx.send(stream).await;
+                    }
+                });
+            }
+        });
+        TransparentIncoming { streams: rx }
+    }
+}
+
+impl Accept for TransparentIncoming {
+    type Conn = TransparentStream;
+    type Error = io::Error;
+
+    fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<TransparentStream>>> {
+        self.streams.poll_recv(cx).map(|stream| stream.map(Ok))
+    }
+}
+
+pub struct TransparentStream {
+    stream: TcpStream,
+    remote: SocketAddr,
+    original: SocketAddr,
+    // Synthetic CONNECT head still to be read by hyper
+    prefix: Vec<u8>,
+    read: usize,
+    // Response head to that CONNECT, which the client must not see
+    swallow: Option<Swallow>,
+}
+
+enum Swallow {
+    Head(Vec<u8>),
+    // The CONNECT was refused: drop the rest, hyper closes the connection
+    All,
+}
+
+impl TransparentStream {
+    // None when the connection cannot be relayed and has been dropped.
+    async fn sniff(stream: TcpStream, remote: SocketAddr, original: SocketAddr, mode: Mode) -> Option<Self> {
+        let mut buf = vec![0; SNIFF_LEN];
+        let mut len = 0;
+        let deadline = tokio::time::Instant::now() + SNIFF_TIMEOUT;
+        // peek() returns as soon as anything is there; a ClientHello may
+        // take a few segments
+        while let Ok(Ok(n)) = tokio::time::timeout_at(deadline, stream.peek(&mut buf)).await {
+            if n == 0 {
+                break;
+            }
+            len = n;
+            if !wants_more(&buf[..len]) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let head = &buf[..len];
+        let mut conn = TransparentStream {
+            stream,
+            remote,
+            original,
+            prefix: Vec::new(),
+            read: 0,
+            swallow: None,
+        };
+        if is_http(head) && mode != Mode::Sni {
+            return Some(conn);
+        }
+        // The server name stands in for the address, so domain ACLs and
+        // routes apply as they would to a proxy client's CONNECT. The TLS
+        // itself is relayed untouched.
+        let target = match (server_name(head), mode) {
+            (Some(name), _) => format!("{}:{}", name, original.port()),
+            (None, Mode::Sni) => {
+                debug!("No server name from {}, closing the connection", remote);
+                return None;
+            }
+            (None, _) => original.to_string(),
+        };
+        debug!("Relaying transparent connection from {} to {}", remote, target);
+        conn.prefix = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target).into_bytes();
+        conn.swallow = Some(Swallow::Head(Vec::new()));
+        Some(conn)
+    }
+}
+
+impl crate::listener::RemoteAddr for TransparentStream {
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote
+    }
+
+    fn original_dst(&self) -> Option<SocketAddr> {
+        Some(self.original)
+    }
+}
+
+impl AsyncRead for TransparentStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.read < self.prefix.len() {
+            let n = buf.remaining().min(self.prefix.len() - self.read);
+            let start = self.read;
+            buf.put_slice(&self.prefix[start..start + n]);
+            self.read += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TransparentStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match &mut self.swallow {
+            None => Pin::new(&mut self.stream).poll_write(cx, buf),
+            Some(Swallow::All) => Poll::Ready(Ok(buf.len())),
+            Some(Swallow::Head(head)) => {
+                let before = head.len();
+                head.extend_from_slice(buf);
+                let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4) else {
+                    if head.len() > MAX_SWALLOW {
+                        self.swallow = Some(Swallow::All);
+                    }
+                    return Poll::Ready(Ok(buf.len()));
+                };
+                // Only the head is ours to drop; what follows a 2xx
+                // belongs to the tunnel
+                let established = head.starts_with(b"HTTP/1.1 2") || head.starts_with(b"HTTP/1.0 2");
+                self.swallow = if established { None } else { Some(Swallow::All) };
+                Poll::Ready(Ok(end - before))
+            }
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+// Origin-form requests ("GET /path") as browsers send them to what they
+// think is the server, turned into the absolute form proxy handling works
+// on. The Host header names the site; without one, the original
+// destination does.
+pub fn absolute(req: &mut Request<Body>, original: SocketAddr) {
+    if req.uri().authority().is_some() || req.method() == hyper::Method::CONNECT {
+        return;
+    }
+    let host = req
+        .headers()
+        .get(hyper::header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(|| original.to_string());
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    if let Ok(uri) = format!("http://{}{}", host, path).parse::<Uri>() {
+        *req.uri_mut() = uri;
+    }
+}
+
+// An HTTP/1 request line starts with an upper-case method and a space.
+fn is_http(head: &[u8]) -> bool {
+    let method = head.iter().take_while(|b| b.is_ascii_uppercase()).count();
+    (1..=16).contains(&method) && head.get(method) == Some(&b' ')
+}
+
+// A TLS record that has not fully arrived yet.
+fn wants_more(head: &[u8]) -> bool {
+    match head {
+        [0x16, _, _, hi, lo, rest @ ..] => rest.len() < (usize::from(*hi) << 8 | usize::from(*lo)).min(SNIFF_LEN - 5),
+        [0x16, ..] => true,
+        _ => false,
+    }
+}
+
+// The server_name extension of a TLS ClientHello, if `head` starts with one.
+fn server_name(head: &[u8]) -> Option<String> {
+    let mut r = Reader(head);
+    if r.u8()? != 0x16 {
+        return None;
+    }
+    r.skip(4)?;
+    if r.u8()? != 1 {
+        return None;
+    }
+    // Length, version, random
+    r.skip(3 + 2 + 32)?;
+    let session = r.u8()?;
+    r.skip(session.into())?;
+    let suites = r.u16()?;
+    r.skip(suites.into())?;
+    let compression = r.u8()?;
+    r.skip(compression.into())?;
+    let len = r.u16()?;
+    let mut extensions = Reader(r.take(len.into())?);
+    while let (Some(kind), Some(len)) = (extensions.u16(), extensions.u16()) {
+        let data = extensions.take(len.into())?;
+        if kind != 0 {
+            continue;
+        }
+        let mut names = Reader(data);
+        names.skip(2)?;
+        while let Some(name_type) = names.u8() {
+            let len = names.u16()?;
+            let name = names.take(len.into())?;
+            if name_type == 0 {
+                let name = std::str::from_utf8(name).ok()?;
+                let valid = !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"-._".contains(&b));
+                return valid.then(|| name.to_ascii_lowercase());
+            }
+        }
+        return None;
+    }
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = (self.0.get(..n)?, self.0.get(n..)?);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
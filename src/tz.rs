@@ -0,0 +1,210 @@
+use std::fs;
+use std::path::PathBuf;
+
+use crate::logging::days_from_civil;
+
+// UTC offsets of an IANA time zone ("Europe/Berlin"), read from the
+// system's compiled tz database (TZif files, RFC 8536). Times past the
+// file's last transition follow its POSIX TZ footer, e.g.
+// "CET-1CEST,M3.5.0,M10.5.0/3".
+#[derive(Debug, Default)]
+pub struct Zone {
+    // Transition instants (Unix seconds), ascending
+    transitions: Vec<i64>,
+    // Offset in effect from each transition on
+    offsets: Vec<i32>,
+    // Offset before the first transition
+    initial: i32,
+    rule: Option<Rule>,
+}
+
+impl Zone {
+    pub fn load(name: &str) -> Result<Zone, String> {
+        if name == "UTC" {
+            return Ok(Zone::default());
+        }
+        if name.is_empty() || name.starts_with('/') || name.split('/').any(|part| part == "..") {
+            return Err(format!("invalid time zone '{}'", name));
+        }
+        let dir = std::env::var_os("TZDIR").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("/usr/share/zoneinfo"));
+        let data = fs::read(dir.join(name)).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => format!("unknown time zone '{}'", name),
+            _ => format!("time zone '{}': {}", name, e),
+        })?;
+        parse(&data).ok_or_else(|| format!("time zone '{}': not a valid TZif file", name))
+    }
+
+    // Seconds to add to UTC for local time at Unix time `t`.
+    pub fn offset(&self, t: i64) -> i32 {
+        match self.transitions.partition_point(|&at| at <= t) {
+            0 => self.initial,
+            i if i == self.transitions.len() => match &self.rule {
+                Some(rule) => rule.offset(t),
+                None => self.offsets[i - 1],
+            },
+            i => self.offsets[i - 1],
+        }
+    }
+}
+
+fn be32(data: &[u8], at: usize) -> Option<i64> {
+    Some(i32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?) as i64)
+}
+
+fn be64(data: &[u8], at: usize) -> Option<i64> {
+    Some(i64::from_be_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+fn parse(data: &[u8]) -> Option<Zone> {
+    if !data.starts_with(b"TZif") {
+        return None;
+    }
+    // Version 2+ files repeat the data with 64-bit times after the 32-bit
+    // block, then the footer; version 1 has only the 32-bit block.
+    let v1 = block_len(data, 0, 4)?;
+    let (start, size) = match data[4] {
+        0 => (0, 4),
+        _ => (44 + v1, 8),
+    };
+    let counts: Vec<usize> = (0..6).map(|i| be32(data, start + 20 + i * 4).map(|n| n as usize)).collect::<Option<_>>()?;
+    let (timecnt, typecnt) = (counts[3], counts[4]);
+    let mut at = start + 44;
+    let transitions: Vec<i64> = (0..timecnt)
+        .map(|i| if size == 8 { be64(data, at + i * 8) } else { be32(data, at + i * 4) })
+        .collect::<Option<_>>()?;
+    at += timecnt * size;
+    let indices = data.get(at..at + timecnt)?;
+    at += timecnt;
+    let types: Vec<i32> = (0..typecnt).map(|i| be32(data, at + i * 6).map(|o| o as i32)).collect::<Option<_>>()?;
+    if types.is_empty() {
+        return None;
+    }
+    let offsets = indices.iter().map(|&i| types.get(i as usize).copied()).collect::<Option<_>>()?;
+    let rule = match size {
+        8 => {
+            let footer = data.get(start + 44 + block_len(data, start, 8)?..)?;
+            std::str::from_utf8(footer).ok().and_then(|s| Rule::parse(s.trim_matches('\n')))
+        }
+        _ => None,
+    };
+    Some(Zone {
+        transitions,
+        offsets,
+        initial: types[0],
+        rule,
+    })
+}
+
+// Length of the data block after the header at `start`.
+fn block_len(data: &[u8], start: usize, size: usize) -> Option<usize> {
+    let n = |i: usize| be32(data, start + 20 + i * 4).map(|n| n as usize);
+    let (isutcnt, isstdcnt, leapcnt, timecnt, typecnt, charcnt) = (n(0)?, n(1)?, n(2)?, n(3)?, n(4)?, n(5)?);
+    Some(timecnt * (size + 1) + typecnt * 6 + charcnt + leapcnt * (size + 4) + isstdcnt + isutcnt)
+}
+
+// A POSIX TZ string with "Mm.w.d" dates, the form tzdata footers use.
+#[derive(Debug)]
+struct Rule {
+    std: i32,
+    // Daylight offset and its start and end, each (month, week, weekday,
+    // seconds after local midnight); None for zones without DST
+    dst: Option<(i32, Change, Change)>,
+}
+
+type Change = (u32, u32, u32, i64);
+
+impl Rule {
+    fn parse(s: &str) -> Option<Rule> {
+        let mut rest = s;
+        skip_name(&mut rest)?;
+        // POSIX offsets count west of Greenwich as positive
+        let std = -(signed_time(&mut rest)? as i32);
+        if rest.is_empty() {
+            return Some(Rule { std, dst: None });
+        }
+        skip_name(&mut rest)?;
+        let dst = match rest.starts_with(',') {
+            true => std + 3600,
+            false => -(signed_time(&mut rest)? as i32),
+        };
+        let (start, end) = rest.strip_prefix(',')?.split_once(',')?;
+        Some(Rule {
+            std,
+            dst: Some((dst, change(start)?, change(end)?)),
+        })
+    }
+
+    fn offset(&self, t: i64) -> i32 {
+        let Some((dst, start, end)) = self.dst else {
+            return self.std;
+        };
+        let (year, _, _) = crate::logging::civil_from_days((t + self.std as i64).div_euclid(86_400));
+        // Start is given in standard time, end in daylight time
+        let start = at(year, start) - self.std as i64;
+        let end = at(year, end) - dst as i64;
+        let daylight = match start < end {
+            true => start <= t && t < end,
+            false => !(end <= t && t < start),
+        };
+        if daylight {
+            dst
+        } else {
+            self.std
+        }
+    }
+}
+
+// "CET" or "<+03>"
+fn skip_name(s: &mut &str) -> Option<()> {
+    let len = match s.strip_prefix('<') {
+        Some(rest) => rest.find('>')? + 2,
+        None => s.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(s.len()),
+    };
+    (len >= 3).then(|| *s = &s[len..])
+}
+
+// "[+-]hh[:mm[:ss]]" in seconds, consumed from the front of `s`
+fn signed_time(s: &mut &str) -> Option<i64> {
+    let end = s.find(|c: char| !(c.is_ascii_digit() || matches!(c, ':' | '+' | '-'))).unwrap_or(s.len());
+    let (text, rest) = s.split_at(end);
+    *s = rest;
+    let (sign, text) = match text.strip_prefix('-') {
+        Some(text) => (-1, text),
+        None => (1, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let mut secs = 0;
+    for (i, part) in text.split(':').enumerate() {
+        if i > 2 || part.is_empty() {
+            return None;
+        }
+        secs += part.parse::<i64>().ok()? * [3600, 60, 1][i];
+    }
+    Some(sign * secs)
+}
+
+// "M3.5.0" or "M10.5.0/3"
+fn change(s: &str) -> Option<Change> {
+    let (date, time) = match s.split_once('/') {
+        Some((date, mut time)) => (date, signed_time(&mut time)?),
+        None => (s, 7200),
+    };
+    let mut parts = date.strip_prefix('M')?.split('.').map(|p| p.parse::<u32>().ok());
+    let (month, week, weekday) = (parts.next()??, parts.next()??, parts.next()??);
+    ((1..=12).contains(&month) && (1..=5).contains(&week) && weekday <= 6).then_some((month, week, weekday, time))
+}
+
+// Unix time of a change in `year`, as local wall-clock seconds
+fn at(year: i64, (month, week, weekday, time): Change) -> i64 {
+    let first = days_from_civil(year, month, 1);
+    let next = match month {
+        12 => days_from_civil(year + 1, 1, 1),
+        _ => days_from_civil(year, month + 1, 1),
+    };
+    // 1970-01-01 was a Thursday; weekdays count from Sunday = 0
+    let first_weekday = (first + 4).rem_euclid(7);
+    let mut day = first + (weekday as i64 - first_weekday).rem_euclid(7) + (week as i64 - 1) * 7;
+    while day >= next {
+        day -= 7;
+    }
+    day * 86_400 + time
+}
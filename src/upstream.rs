@@ -0,0 +1,677 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use hyper::Uri;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tracing::{debug, info, warn};
+
+use crate::egress::{self, Interface, InterfaceConfig, IpFamily, Outbound};
+use crate::pattern::{host_matches, strip_port};
+
+// A named parent proxy, e.g.
+//
+//   [upstreams.intranet]
+//   type = "http"
+//   address = "proxy.corp.example:3128"
+#[derive(Debug, Clone, Deserialize)]
+pub struct ParentProxy {
+    #[serde(rename = "type", default)]
+    pub kind: UpstreamKind,
+    pub address: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // Seconds to wait for the TCP connection to the parent
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+}
+
+fn default_connect_timeout() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamKind {
+    #[default]
+    Http,
+    // SOCKS5 (RFC 1928), with optional username/password auth (RFC 1929)
+    Socks5,
+}
+
+// Destination pattern -> egress path. `via` is "direct", an upstream name,
+// a pool name or an interface name.
+#[derive(Debug, Deserialize)]
+pub struct RouteRule {
+    pub host: String,
+    pub via: String,
+    // Where to go instead while `via` is unreachable ("direct", an upstream
+    // or a pool)
+    pub fallback: Option<String>,
+    // Address family for direct and interface connections; overrides
+    // [server] ip_family
+    pub ip_family: Option<IpFamily>,
+}
+
+pub const DIRECT: &str = "direct";
+
+// A set of upstreams sharing the load, e.g.
+//
+//   [pools.scrape]
+//   members = ["exit1", "exit2", "exit3"]
+//   strategy = "least_connections"
+#[derive(Debug, Deserialize)]
+pub struct PoolConfig {
+    pub members: Vec<String>,
+    #[serde(default)]
+    pub strategy: Strategy,
+    // Seconds between TCP health checks of each member
+    #[serde(default = "default_health_check_interval")]
+    pub health_check_interval: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+}
+
+fn default_health_check_interval() -> u64 {
+    10
+}
+
+// Runtime view of one configured upstream.
+#[derive(Debug)]
+pub struct Upstream {
+    pub name: String,
+    pub proxy: ParentProxy,
+    healthy: AtomicBool,
+    active: AtomicUsize,
+    // How often the health check probes this upstream
+    interval: Duration,
+}
+
+impl Upstream {
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    fn set_healthy(&self, healthy: bool) {
+        if self.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            if healthy {
+                info!("✅ Upstream '{}' ({}) is back", self.name, self.proxy.address);
+            } else {
+                warn!("⚠️ Upstream '{}' ({}) is down", self.name, self.proxy.address);
+            }
+        }
+    }
+}
+
+struct Pool {
+    members: Vec<Arc<Upstream>>,
+    strategy: Strategy,
+    next: AtomicUsize,
+}
+
+impl Pool {
+    // Dead members are skipped; if every member is down, all are candidates
+    // so the failure is reported by the connection attempt itself.
+    fn select(&self) -> Arc<Upstream> {
+        let healthy: Vec<&Arc<Upstream>> = self.members.iter().filter(|m| m.is_healthy()).collect();
+        let candidates = if healthy.is_empty() {
+            self.members.iter().collect()
+        } else {
+            healthy
+        };
+        let chosen = match self.strategy {
+            Strategy::RoundRobin => {
+                let n = self.next.fetch_add(1, Ordering::Relaxed);
+                candidates[n % candidates.len()]
+            }
+            Strategy::LeastConnections => candidates
+                .iter()
+                .min_by_key(|m| m.active.load(Ordering::Relaxed))
+                .copied()
+                .unwrap(),
+        };
+        chosen.clone()
+    }
+}
+
+pub struct Upstreams {
+    by_name: HashMap<String, Arc<Upstream>>,
+    pools: HashMap<String, Pool>,
+    interfaces: HashMap<String, Arc<Interface>>,
+    // Interfaces with networks, consulted by direct connections
+    split: Arc<[Arc<Interface>]>,
+    // Address family unless a route says otherwise
+    family: IpFamily,
+}
+
+impl Upstreams {
+    pub fn new(
+        upstreams: &HashMap<String, ParentProxy>,
+        pools: &HashMap<String, PoolConfig>,
+        interfaces: &HashMap<String, InterfaceConfig>,
+        family: IpFamily,
+    ) -> Self {
+        let by_name: HashMap<String, Arc<Upstream>> = upstreams
+            .iter()
+            .map(|(name, proxy)| {
+                // Pool members follow their pool's (shortest) check interval
+                let interval = pools
+                    .values()
+                    .filter(|pool| pool.members.contains(name))
+                    .map(|pool| pool.health_check_interval)
+                    .min()
+                    .unwrap_or_else(default_health_check_interval);
+                let upstream = Upstream {
+                    name: name.clone(),
+                    proxy: proxy.clone(),
+                    healthy: AtomicBool::new(true),
+                    active: AtomicUsize::new(0),
+                    interval: Duration::from_secs(interval.max(1)),
+                };
+                (name.clone(), Arc::new(upstream))
+            })
+            .collect();
+        let pools = pools
+            .iter()
+            .map(|(name, pool)| {
+                let pool = Pool {
+                    members: pool.members.iter().filter_map(|m| by_name.get(m).cloned()).collect(),
+                    strategy: pool.strategy,
+                    next: AtomicUsize::new(0),
+                };
+                (name.clone(), pool)
+            })
+            .collect();
+        let interfaces: HashMap<String, Arc<Interface>> = interfaces
+            .iter()
+            .map(|(name, config)| (name.clone(), Arc::new(Interface::new(name, config))))
+            .collect();
+        let split = interfaces.values().filter(|i| i.has_networks()).cloned().collect();
+        Upstreams {
+            by_name,
+            pools,
+            interfaces,
+            split,
+            family,
+        }
+    }
+
+    pub fn direct(&self) -> Route {
+        Route::Direct(self.split.clone(), self.family)
+    }
+
+    // First matching rule wins; unmatched destinations go direct. While the
+    // chosen upstream is marked down, a rule's fallback is used instead. An
+    // interface that is down falls back too, or goes direct without one.
+    pub fn route(&self, rules: &[RouteRule], host: &str) -> Route {
+        let Some(rule) = rules.iter().find(|r| host_matches(&r.host, host)) else {
+            return self.direct();
+        };
+        let family = rule.ip_family.unwrap_or(self.family);
+        let route = self.resolve(&rule.via, host, family);
+        match (&route, &rule.fallback) {
+            (Route::Parent(upstream), Some(fallback)) if !upstream.is_healthy() => {
+                debug!("Upstream '{}' is down, using fallback '{}' for {}", upstream.name, fallback, host);
+                self.resolve(fallback, host, family)
+            }
+            (Route::Interface(interface, _), fallback) if !interface.is_up() => {
+                let fallback = fallback.as_deref().unwrap_or(DIRECT);
+                debug!("Interface '{}' is down, using '{}' for {}", interface.name, fallback, host);
+                self.resolve(fallback, host, family)
+            }
+            _ => route,
+        }
+    }
+
+    // A route chosen by name rather than by rule; an interface that is down
+    // goes direct.
+    pub fn via(&self, via: &str, host: &str) -> Route {
+        match self.resolve(via, host, self.family) {
+            Route::Interface(interface, _) if !interface.is_up() => self.direct(),
+            route => route,
+        }
+    }
+
+    // The upstream whose address is `address` ("host:port"), for parents
+    // named by address in a PAC file; by name if several share it.
+    pub fn by_address(&self, address: &str) -> Option<&Arc<Upstream>> {
+        self.by_name
+            .values()
+            .filter(|upstream| upstream.proxy.address.eq_ignore_ascii_case(address))
+            .min_by(|a, b| a.name.cmp(&b.name))
+    }
+
+    // The fallback for `host`, to retry through when its route fails.
+    pub fn fallback(&self, rules: &[RouteRule], host: &str) -> Option<Route> {
+        let rule = rules.iter().find(|r| host_matches(&r.host, host))?;
+        let family = rule.ip_family.unwrap_or(self.family);
+        rule.fallback.as_deref().map(|via| self.resolve(via, host, family))
+    }
+
+    fn resolve(&self, via: &str, host: &str, family: IpFamily) -> Route {
+        if let Some(pool) = self.pools.get(via) {
+            let upstream = pool.select();
+            debug!("Pool '{}' selected upstream '{}' for {}", via, upstream.name, host);
+            return Route::Parent(upstream);
+        }
+        if let Some(interface) = self.interfaces.get(via) {
+            return Route::Interface(interface.clone(), family);
+        }
+        match self.by_name.get(via) {
+            Some(upstream) => Route::Parent(upstream.clone()),
+            None => Route::Direct(self.split.clone(), family),
+        }
+    }
+
+    // Periodically probe every upstream with a TCP connect. Unreachable ones
+    // are taken out of rotation (and their routes fail over) until they
+    // answer again.
+    pub fn spawn_health_checks(&self) {
+        for upstream in self.by_name.values() {
+            let upstream = upstream.clone();
+            debug!("Health-checking upstream '{}' every {:?}", upstream.name, upstream.interval);
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(upstream.interval);
+                loop {
+                    ticker.tick().await;
+                    let probe = connect_address(&upstream.proxy.address);
+                    let healthy = matches!(
+                        tokio::time::timeout(HEALTH_CHECK_TIMEOUT, probe).await,
+                        Ok(Ok(_))
+                    );
+                    upstream.set_healthy(healthy);
+                }
+            });
+        }
+    }
+}
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub enum Route {
+    // Carries the interfaces whose networks are split off (split horizon)
+    Direct(Arc<[Arc<Interface>]>, IpFamily),
+    // A parent resolves the destination itself, so has no family
+    Parent(Arc<Upstream>),
+    Interface(Arc<Interface>, IpFamily),
+}
+
+impl Route {
+    pub fn name(&self) -> &str {
+        match self {
+            Route::Direct(..) => DIRECT,
+            Route::Parent(upstream) => &upstream.name,
+            Route::Interface(interface, _) => &interface.name,
+        }
+    }
+
+    // Counts towards the upstream's active connections (least_connections)
+    // until dropped.
+    pub fn lease(&self) -> Option<Lease> {
+        match self {
+            Route::Direct(..) | Route::Interface(..) => None,
+            Route::Parent(upstream) => {
+                upstream.active.fetch_add(1, Ordering::Relaxed);
+                Some(Lease(upstream.clone()))
+            }
+        }
+    }
+}
+
+pub struct Lease(Arc<Upstream>);
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn validate(
+    rules: &[RouteRule],
+    upstreams: &HashMap<String, ParentProxy>,
+    pools: &HashMap<String, PoolConfig>,
+    interfaces: &HashMap<String, InterfaceConfig>,
+) -> Result<(), String> {
+    for (name, interface) in interfaces {
+        if upstreams.contains_key(name) || pools.contains_key(name) || name == DIRECT {
+            return Err(format!("interface '{}' clashes with an upstream or pool name", name));
+        }
+        interface.validate(name)?;
+    }
+    for (name, pool) in pools {
+        if upstreams.contains_key(name) || name == DIRECT {
+            return Err(format!("pool '{}' clashes with an upstream name", name));
+        }
+        if pool.members.is_empty() {
+            return Err(format!("pool '{}' has no members", name));
+        }
+        if let Some(member) = pool.members.iter().find(|m| !upstreams.contains_key(*m)) {
+            return Err(format!("pool '{}' uses unknown upstream '{}'", name, member));
+        }
+    }
+    for rule in rules {
+        for via in std::iter::once(&rule.via).chain(&rule.fallback) {
+            if !known_via(via, upstreams, pools, interfaces) {
+                return Err(format!(
+                    "route for '{}' uses unknown upstream '{}'",
+                    rule.host, via
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// "direct", or the name of an upstream, pool or interface.
+pub fn known_via(
+    via: &str,
+    upstreams: &HashMap<String, ParentProxy>,
+    pools: &HashMap<String, PoolConfig>,
+    interfaces: &HashMap<String, InterfaceConfig>,
+) -> bool {
+    via == DIRECT || upstreams.contains_key(via) || pools.contains_key(via) || interfaces.contains_key(via)
+}
+
+impl ParentProxy {
+    // Proxy-Authorization value for this parent, if it needs credentials.
+    pub fn proxy_authorization(&self) -> Option<String> {
+        let user = self.username.as_deref()?;
+        let pass = self.password.as_deref().unwrap_or_default();
+        Some(format!("Basic {}", BASE64.encode(format!("{}:{}", user, pass))))
+    }
+}
+
+// TCP connection to a parent. Failing to reach it marks the upstream down
+// until the next successful health check.
+async fn connect_parent(upstream: &Upstream) -> io::Result<TcpStream> {
+    let timeout = Duration::from_secs(upstream.proxy.connect_timeout);
+    let result = match tokio::time::timeout(timeout, connect_address(&upstream.proxy.address)).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("connecting to upstream {} timed out", upstream.proxy.address),
+        )),
+    };
+    if result.is_err() {
+        upstream.set_healthy(false);
+    }
+    result
+}
+
+// A parent's "host:port", its name resolved like destinations are.
+async fn connect_address(address: &str) -> io::Result<TcpStream> {
+    let (host, port) = split_target(address);
+    egress::race(crate::dns::lookup(host, port).await?, |addr| crate::tcp::connect(addr, None)).await
+}
+
+// Open a TCP stream to `target` ("host:port") along the given route. `via`
+// names us to HTTP parents so they can spot loops. `outbound` applies where
+// we connect ourselves, not to parents.
+pub async fn connect(route: &Route, target: &str, via: &str, outbound: &Outbound) -> io::Result<TcpStream> {
+    match route {
+        Route::Direct(split, family) => {
+            let (host, port) = split_target(target);
+            egress::connect_direct(split, host, port, *family, outbound).await
+        }
+        Route::Interface(interface, family) => {
+            let (host, port) = split_target(target);
+            debug!("Tunnelling to {} via interface '{}'", target, interface.name);
+            interface.connect(host, port, *family, outbound.guard.as_deref()).await
+        }
+        Route::Parent(upstream) => {
+            let parent = &upstream.proxy;
+            debug!("Tunnelling to {} via upstream '{}'", target, upstream.name);
+            let mut stream = connect_parent(upstream).await?;
+            match parent.kind {
+                UpstreamKind::Http => http_connect(&mut stream, parent, target, via).await?,
+                UpstreamKind::Socks5 => {
+                    let (host, port) = split_target(target);
+                    socks5_connect(&mut stream, parent, host, port).await?
+                }
+            }
+            Ok(stream)
+        }
+    }
+}
+
+// "host:port" (or "[v6]:port") -> bare host and port, 443 if missing.
+fn split_target(target: &str) -> (&str, u16) {
+    let port = target
+        .rsplit_once(':')
+        .and_then(|(_, p)| p.parse().ok())
+        .unwrap_or(443);
+    (strip_port(target), port)
+}
+
+// Issue CONNECT on an HTTP parent and wait for its 2xx.
+async fn http_connect(stream: &mut TcpStream, parent: &ParentProxy, target: &str, via: &str) -> io::Result<()> {
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\nVia: 1.1 {1}\r\n", target, via);
+    if let Some(auth) = parent.proxy_authorization() {
+        request.push_str(&format!("Proxy-Authorization: {}\r\n", auth));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read the response head byte-wise so no tunnel payload is consumed
+    let mut head = Vec::with_capacity(256);
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > 8 * 1024 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "upstream response head too large"));
+        }
+        let byte = stream.read_u8().await?;
+        head.push(byte);
+    }
+    let status_line = String::from_utf8_lossy(&head);
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .unwrap_or(0);
+    if !(200..300).contains(&status) {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("upstream {} refused CONNECT with status {}", parent.address, status),
+        ));
+    }
+    Ok(())
+}
+
+fn socks_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, message)
+}
+
+// SOCKS5 client handshake: method negotiation, optional username/password
+// sub-negotiation, then CONNECT. Hostnames are resolved by the upstream.
+async fn socks5_connect(
+    stream: &mut TcpStream,
+    parent: &ParentProxy,
+    host: &str,
+    port: u16,
+) -> io::Result<()> {
+    let credentials = parent.username.as_deref().map(|user| {
+        (user, parent.password.as_deref().unwrap_or_default())
+    });
+    let greeting: &[u8] = if credentials.is_some() {
+        &[0x05, 0x02, 0x00, 0x02]
+    } else {
+        &[0x05, 0x01, 0x00]
+    };
+    stream.write_all(greeting).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != 0x05 {
+        return Err(socks_error(format!("{} is not a SOCKS5 server", parent.address)));
+    }
+    match (choice[1], credentials) {
+        (0x00, _) => {}
+        (0x02, Some((user, pass))) => {
+            if user.len() > 255 || pass.len() > 255 {
+                return Err(socks_error("SOCKS5 credentials too long".to_string()));
+            }
+            let mut auth = vec![0x01, user.len() as u8];
+            auth.extend_from_slice(user.as_bytes());
+            auth.push(pass.len() as u8);
+            auth.extend_from_slice(pass.as_bytes());
+            stream.write_all(&auth).await?;
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0x00 {
+                return Err(socks_error(format!(
+                    "SOCKS5 upstream {} rejected credentials",
+                    parent.address
+                )));
+            }
+        }
+        _ => {
+            return Err(socks_error(format!(
+                "SOCKS5 upstream {} offers no acceptable auth method",
+                parent.address
+            )))
+        }
+    }
+
+    let mut request = vec![0x05, 0x01, 0x00];
+    match host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(ip)) => {
+            request.push(0x01);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(std::net::IpAddr::V6(ip)) => {
+            request.push(0x04);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(socks_error("SOCKS5 hostname too long".to_string()));
+            }
+            request.push(0x03);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0x00 {
+        return Err(socks_error(format!(
+            "SOCKS5 upstream {} refused {}:{} (reply {})",
+            parent.address, host, port, reply[1]
+        )));
+    }
+    // Skip the bound address and port
+    let addr_len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => stream.read_u8().await? as usize,
+        other => return Err(socks_error(format!("SOCKS5 reply has bad address type {}", other))),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+// hyper connector that sends plain HTTP requests along a route. Requests
+// through an HTTP parent keep their absolute-form URI; a SOCKS5 parent just
+// carries the connection, so requests go out in origin-form.
+#[derive(Clone)]
+pub struct RouteConnector {
+    route: Route,
+    outbound: Outbound,
+}
+
+impl RouteConnector {
+    pub fn new(route: Route, outbound: Outbound) -> Self {
+        RouteConnector { route, outbound }
+    }
+}
+
+impl Service<Uri> for RouteConnector {
+    type Response = RoutedStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<RoutedStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let route = self.route.clone();
+        let outbound = self.outbound.clone();
+        Box::pin(async move {
+            let host = uri
+                .host()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "URI has no host"))?;
+            let port = uri.port_u16().unwrap_or(80);
+            let (stream, proxied) = match &route {
+                Route::Direct(split, family) => {
+                    let host = host.trim_start_matches('[').trim_end_matches(']');
+                    (egress::connect_direct(split, host, port, *family, &outbound).await?, false)
+                }
+                Route::Interface(interface, family) => {
+                    let host = host.trim_start_matches('[').trim_end_matches(']');
+                    (interface.connect(host, port, *family, outbound.guard.as_deref()).await?, false)
+                }
+                Route::Parent(upstream) => match upstream.proxy.kind {
+                    UpstreamKind::Http => (connect_parent(upstream).await?, true),
+                    UpstreamKind::Socks5 => {
+                        let mut stream = connect_parent(upstream).await?;
+                        let host = host.trim_start_matches('[').trim_end_matches(']');
+                        socks5_connect(&mut stream, &upstream.proxy, host, port).await?;
+                        (stream, false)
+                    }
+                },
+            };
+            Ok(RoutedStream { stream, proxied })
+        })
+    }
+}
+
+pub struct RoutedStream {
+    stream: TcpStream,
+    proxied: bool,
+}
+
+impl Connection for RoutedStream {
+    fn connected(&self) -> Connected {
+        Connected::new().proxy(self.proxied)
+    }
+}
+
+impl AsyncRead for RoutedStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for RoutedStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,234 @@
+use hyper::Uri;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::pattern::{glob_matches, host_matches};
+
+// Rules on the path and query of plain HTTP requests, checked in order
+// before the host-level [acl] and blocklists (which then see any rewritten
+// destination); the first match decides:
+//
+//   [[url_filters]]
+//   host = "*.example.com"
+//   path = "/ads/*"
+//   action = "block"
+//
+//   [[url_filters]]
+//   host = "api.example.com"
+//   regex = "^/v1/(.*)$"
+//   action = "rewrite"
+//   rewrite = "/v2/$1"
+//
+//   [[url_filters]]
+//   host = "wiki.corp.example"
+//   regex = "^/old/(.*)$"
+//   action = "redirect"
+//   redirect = "https://docs.corp.example/$1"
+//   status = 301
+//
+// Tunnels carry no URL the proxy can see, so CONNECT is not filtered.
+#[derive(Debug, Deserialize)]
+pub struct UrlFilter {
+    #[serde(default = "default_host")]
+    pub host: String,
+    // Shell-style pattern over path and query, e.g. "/ads/*" or "*utm_source=*"
+    pub path: Option<String>,
+    // Regular expression over path and query; `rewrite` may use its groups
+    pub regex: Option<String>,
+    pub action: Action,
+    // New path and query for action = "rewrite": replaces the part `regex`
+    // matched, or the whole of it for `path`. An http:// URL sends the
+    // request to another host.
+    pub rewrite: Option<String>,
+    // Location for action = "redirect", a URL or a path on the same host,
+    // substituted like `rewrite`
+    pub redirect: Option<String>,
+    // 301, 302, 303, 307 or 308; default 302
+    pub status: Option<u16>,
+    #[serde(skip)]
+    compiled: Option<Regex>,
+}
+
+fn default_host() -> String {
+    "*".to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    // Let the request through, skipping the rules after this one
+    Allow,
+    Block,
+    Rewrite,
+    // Answer with a 3xx to `redirect` instead of forwarding
+    Redirect,
+}
+
+impl UrlFilter {
+    // How the rule is named in logs and on error pages.
+    pub fn describe(&self) -> String {
+        let pattern = self.path.as_deref().or(self.regex.as_deref()).unwrap_or("*");
+        format!("{} {}", self.host, pattern)
+    }
+
+    fn matches(&self, host: &str, path: &str) -> bool {
+        if !host_matches(&self.host, host) {
+            return false;
+        }
+        match (&self.path, &self.compiled) {
+            (Some(pattern), _) => glob_matches(pattern, path),
+            (None, Some(regex)) => regex.is_match(path),
+            (None, None) => true,
+        }
+    }
+
+    // `template` with the regex's groups filled in from `path`, or as is.
+    fn substitute(&self, template: Option<&str>, path: &str) -> String {
+        let template = template.unwrap_or(path);
+        match &self.compiled {
+            Some(regex) if template.starts_with('/') => regex.replace(path, template).into_owned(),
+            // A whole URL: only what the regex matched is of any use
+            Some(regex) => match regex.captures(path) {
+                Some(captures) => {
+                    let mut url = String::new();
+                    captures.expand(template, &mut url);
+                    url
+                }
+                None => template.to_string(),
+            },
+            None => template.to_string(),
+        }
+    }
+}
+
+pub enum Verdict<'a> {
+    Pass,
+    Block(&'a UrlFilter),
+    // The new path and query, or an http:// URL
+    Rewrite(&'a UrlFilter, String),
+    // Location and status
+    Redirect(&'a UrlFilter, String, u16),
+}
+
+// Compiles each rule's regex once, at config load.
+pub fn prepare(filters: &mut [UrlFilter]) -> Result<(), String> {
+    for (i, filter) in filters.iter_mut().enumerate() {
+        if filter.path.is_some() && filter.regex.is_some() {
+            return Err(format!("url_filters[{}]: set path or regex, not both", i));
+        }
+        if let Some(regex) = &filter.regex {
+            filter.compiled = Some(Regex::new(regex).map_err(|e| format!("url_filters[{}]: {}", i, e))?);
+        }
+        match (filter.action, &filter.rewrite) {
+            (Action::Rewrite, None) => return Err(format!("url_filters[{}]: action \"rewrite\" needs rewrite", i)),
+            (Action::Rewrite, Some(rewrite)) if !rewrite.starts_with('/') && !rewrite.starts_with("http://") => {
+                return Err(format!("url_filters[{}]: rewrite must be a path starting with '/' or an http:// URL", i));
+            }
+            (Action::Allow | Action::Block | Action::Redirect, Some(_)) => {
+                return Err(format!("url_filters[{}]: rewrite is only for action \"rewrite\"", i));
+            }
+            _ => {}
+        }
+        match (filter.action, &filter.redirect) {
+            (Action::Redirect, None) => return Err(format!("url_filters[{}]: action \"redirect\" needs redirect", i)),
+            (Action::Redirect, Some(_)) => {}
+            (_, Some(_)) => return Err(format!("url_filters[{}]: redirect is only for action \"redirect\"", i)),
+            (_, None) => {}
+        }
+        match (filter.action, filter.status) {
+            (Action::Redirect, Some(301 | 302 | 303 | 307 | 308) | None) => {}
+            (Action::Redirect, Some(status)) => {
+                return Err(format!("url_filters[{}]: {} is not a redirect status", i, status));
+            }
+            (_, Some(_)) => return Err(format!("url_filters[{}]: status is only for action \"redirect\"", i)),
+            (_, None) => {}
+        }
+    }
+    Ok(())
+}
+
+// `path` is the request's path and query, e.g. "/search?q=x". Rules see it
+// normalized, so "/a/../ads//%78" is checked as "/ads/x".
+pub fn check<'a>(filters: &'a [UrlFilter], host: &str, path: &str) -> Verdict<'a> {
+    let path = &normalize(path);
+    let Some(filter) = filters.iter().find(|f| f.matches(host, path)) else {
+        return Verdict::Pass;
+    };
+    match filter.action {
+        Action::Allow => Verdict::Pass,
+        Action::Block => Verdict::Block(filter),
+        Action::Rewrite => Verdict::Rewrite(filter, filter.substitute(filter.rewrite.as_deref(), path)),
+        Action::Redirect => {
+            let location = filter.substitute(filter.redirect.as_deref(), path);
+            Verdict::Redirect(filter, location, filter.status.unwrap_or(302))
+        }
+    }
+}
+
+// The path as an origin would read it: escaped unreserved characters
+// decoded, other escapes in upper case, repeated slashes collapsed and "."
+// and ".." segments resolved (RFC 3986 6.2.2). The query is left alone.
+fn normalize(path_and_query: &str) -> String {
+    let (path, query) = match path_and_query.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path_and_query, None),
+    };
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes.get(i + 1..i + 3).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit));
+        match (bytes[i], escape) {
+            (b'%', Some(hex)) => {
+                let hex = std::str::from_utf8(hex).unwrap_or_default();
+                let byte = u8::from_str_radix(hex, 16).unwrap_or_default();
+                match byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                    true => decoded.push(byte),
+                    false => decoded.extend_from_slice(format!("%{:02X}", byte).as_bytes()),
+                }
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    // Only ASCII was decoded, so this stays valid UTF-8
+    let decoded = String::from_utf8_lossy(&decoded);
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/').filter(|s| !s.is_empty()) {
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    // "/dir/", "/dir/." and "/dir/x/.." all name the directory
+    if !segments.is_empty() && (decoded.ends_with('/') || decoded.ends_with("/.") || decoded.ends_with("/..")) {
+        normalized.push('/');
+    }
+    if let Some(query) = query {
+        normalized.push('?');
+        normalized.push_str(query);
+    }
+    normalized
+}
+
+// `uri` with its path and query replaced, or the whole of it for an
+// http:// URL.
+pub fn rewritten(uri: &Uri, to: &str) -> Result<Uri, String> {
+    if !to.starts_with('/') {
+        let uri: Uri = to.parse().map_err(|e| format!("'{}': {}", to, e))?;
+        return match (uri.scheme_str(), uri.host()) {
+            (Some("http"), Some(_)) => Ok(uri),
+            _ => Err(format!("'{}' is not an http:// URL", to)),
+        };
+    }
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(to.parse().map_err(|e| format!("'{}': {}", to, e))?);
+    Uri::from_parts(parts).map_err(|e| e.to_string())
+}
@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::sqlite::{Connection, Value};
+use crate::users::UserLimits;
+use crate::AppState;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    name TEXT PRIMARY KEY,
+    password TEXT NOT NULL,
+    max_connections INTEGER,
+    bandwidth INTEGER,
+    quota_bytes INTEGER,
+    requests INTEGER NOT NULL DEFAULT 0,
+    bytes INTEGER NOT NULL DEFAULT 0
+);
+";
+
+// `user_store = "sqlite://users.db"` (relative) or "sqlite:///var/lib/proxy/users.db".
+pub fn store_path(user_store: &str) -> Result<PathBuf, String> {
+    match user_store.strip_prefix("sqlite://") {
+        Some(path) if !path.is_empty() => Ok(PathBuf::from(path)),
+        _ => Err(format!("user_store '{}' is not a sqlite:// URL", user_store)),
+    }
+}
+
+pub struct UserRecord {
+    pub name: String,
+    pub hashed: bool,
+    pub limits: UserLimits,
+    pub requests: u64,
+    pub bytes: u64,
+}
+
+// What requests look up, copied from the database.
+struct Account {
+    password: String,
+    limits: UserLimits,
+    bytes: u64,
+}
+
+// Users, their limits and usage counters in SQLite, shared with the CLI
+// and other instances. Usage is added up in memory and written every few
+// seconds rather than on each request. Requests never wait on SQLite:
+// logins, limits and quotas are served from a copy of the table that is
+// reloaded with each flush and updated on each change made here.
+pub struct UserDb {
+    conn: Mutex<Connection>,
+    // user -> (requests, bytes) not yet written
+    pending: Mutex<HashMap<String, (u64, u64)>>,
+    accounts: RwLock<HashMap<String, Account>>,
+}
+
+impl UserDb {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        let db = UserDb {
+            conn: Mutex::new(conn),
+            pending: Mutex::default(),
+            accounts: RwLock::default(),
+        };
+        db.load()?;
+        Ok(db)
+    }
+
+    // Replaces the in-memory copy; on failure the old one stays.
+    fn load(&self) -> Result<(), String> {
+        let accounts = self.accounts_where("", &[])?.collect();
+        *self.accounts.write().unwrap() = accounts;
+        Ok(())
+    }
+
+    // Refreshes one user's copy after a change, so it shows at once.
+    fn load_one(&self, name: &str) {
+        let found = match self.accounts_where("WHERE name = ?", &[name.into()]) {
+            Ok(mut found) => found.next(),
+            Err(e) => {
+                warn!("⚠️ Failed to reload '{}' from the user store: {}", name, e);
+                return;
+            }
+        };
+        let mut accounts = self.accounts.write().unwrap();
+        match found {
+            Some((name, account)) => accounts.insert(name, account),
+            None => accounts.remove(name),
+        };
+    }
+
+    fn accounts_where(&self, clause: &str, params: &[Value]) -> Result<impl Iterator<Item = (String, Account)>, String> {
+        let sql = format!("SELECT name, password, max_connections, bandwidth, quota_bytes, bytes FROM users {}", clause);
+        Ok(self.query(&sql, params)?.into_iter().filter_map(|row| {
+            let account = Account {
+                password: row[1].as_str()?.to_string(),
+                limits: limits(&row[2..5]),
+                bytes: number(&row[5]).unwrap_or(0),
+            };
+            Some((row[0].as_str()?.to_string(), account))
+        }))
+    }
+
+    fn query(&self, sql: &str, params: &[Value]) -> Result<Vec<Vec<Value>>, String> {
+        self.conn.lock().unwrap().query(sql, params)
+    }
+
+    // Changes the user `name`; false if there was nothing to change.
+    fn execute(&self, name: &str, sql: &str, params: &[Value]) -> Result<bool, String> {
+        let changed = self.conn.lock().unwrap().execute(sql, params)? > 0;
+        self.load_one(name);
+        Ok(changed)
+    }
+
+    pub fn password(&self, name: &str) -> Option<String> {
+        self.accounts.read().unwrap().get(name).map(|account| account.password.clone())
+    }
+
+    pub fn limits(&self, name: &str) -> UserLimits {
+        self.accounts.read().unwrap().get(name).map(|account| account.limits.clone()).unwrap_or_default()
+    }
+
+    // Total bytes so far, including usage not yet written. `default` is the
+    // quota for users without one of their own, e.g. their group's.
+    pub fn over_quota(&self, name: &str, default: Option<u64>) -> bool {
+        let accounts = self.accounts.read().unwrap();
+        let Some(account) = accounts.get(name) else {
+            return false;
+        };
+        let pending = self.pending.lock().unwrap().get(name).map_or(0, |usage| usage.1);
+        account.limits.quota_bytes.or(default).is_some_and(|quota| account.bytes + pending >= quota)
+    }
+
+    pub fn list(&self) -> Vec<UserRecord> {
+        let sql = "SELECT name, password, max_connections, bandwidth, quota_bytes, requests, bytes FROM users ORDER BY name";
+        let rows = match self.query(sql, &[]) {
+            Ok(rows) => rows,
+            Err(e) => {
+                warn!("⚠️ User store query failed: {}", e);
+                return Vec::new();
+            }
+        };
+        let pending = self.pending.lock().unwrap();
+        rows.into_iter()
+            .filter_map(|row| {
+                let name = row.first()?.as_str()?.to_string();
+                let (requests, bytes) = pending.get(&name).copied().unwrap_or_default();
+                Some(UserRecord {
+                    hashed: row[1].as_str().is_some_and(crate::users::is_hashed),
+                    limits: limits(&row[2..5]),
+                    requests: number(&row[5]).unwrap_or(0) + requests,
+                    bytes: number(&row[6]).unwrap_or(0) + bytes,
+                    name,
+                })
+            })
+            .collect()
+    }
+
+    pub fn count(&self) -> usize {
+        self.accounts.read().unwrap().len()
+    }
+
+    // False if the user already exists.
+    pub fn insert(&self, name: &str, password_hash: &str) -> Result<bool, String> {
+        self.execute(
+            name,
+            "INSERT OR IGNORE INTO users (name, password) VALUES (?, ?)",
+            &[name.into(), password_hash.into()],
+        )
+    }
+
+    // The update functions return false if there is no such user.
+    pub fn set_password(&self, name: &str, password_hash: &str) -> Result<bool, String> {
+        self.execute(name, "UPDATE users SET password = ? WHERE name = ?", &[password_hash.into(), name.into()])
+    }
+
+    pub fn set_limits(&self, name: &str, limits: &UserLimits) -> Result<bool, String> {
+        let int = |n: Option<u64>| Value::from(n.map(|n| n.min(i64::MAX as u64) as i64));
+        self.execute(
+            name,
+            "UPDATE users SET max_connections = ?, bandwidth = ?, quota_bytes = ? WHERE name = ?",
+            &[
+                int(limits.max_connections.map(|n| n as u64)),
+                int(limits.bandwidth),
+                int(limits.quota_bytes),
+                name.into(),
+            ],
+        )
+    }
+
+    pub fn remove(&self, name: &str) -> Result<bool, String> {
+        self.pending.lock().unwrap().remove(name);
+        self.execute(name, "DELETE FROM users WHERE name = ?", &[name.into()])
+    }
+
+    pub fn reset_usage(&self, name: &str) -> Result<bool, String> {
+        self.pending.lock().unwrap().remove(name);
+        self.execute(name, "UPDATE users SET requests = 0, bytes = 0 WHERE name = ?", &[name.into()])
+    }
+
+    pub fn record(&self, name: &str, bytes: u64) {
+        let mut pending = self.pending.lock().unwrap();
+        let entry = pending.entry(name.to_string()).or_default();
+        entry.0 += 1;
+        entry.1 += bytes;
+    }
+
+    // Write pending usage; kept in memory for the next try on failure.
+    // Then reload, which also picks up changes made by the CLI or other
+    // instances.
+    pub fn flush(&self) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        let mut failed = Vec::new();
+        let conn = self.conn.lock().unwrap();
+        for (name, (requests, bytes)) in pending {
+            let result = conn.execute(
+                "UPDATE users SET requests = requests + ?, bytes = bytes + ? WHERE name = ?",
+                &[Value::Int(requests as i64), Value::Int(bytes as i64), name.as_str().into()],
+            );
+            if let Err(e) = result {
+                warn!("⚠️ Failed to store usage for '{}': {}", name, e);
+                failed.push((name, (requests, bytes)));
+            }
+        }
+        drop(conn);
+        let mut pending = self.pending.lock().unwrap();
+        for (name, (requests, bytes)) in failed {
+            let entry = pending.entry(name).or_default();
+            entry.0 += requests;
+            entry.1 += bytes;
+        }
+        drop(pending);
+        if let Err(e) = self.load() {
+            warn!("⚠️ Failed to reload the user store: {}", e);
+        }
+    }
+}
+
+fn number(value: &Value) -> Option<u64> {
+    value.as_i64().and_then(|n| u64::try_from(n).ok())
+}
+
+// max_connections, bandwidth and quota_bytes columns.
+fn limits(row: &[Value]) -> UserLimits {
+    UserLimits {
+        max_connections: number(&row[0]).map(|n| n as usize),
+        bandwidth: number(&row[1]),
+        quota_bytes: number(&row[2]),
+    }
+}
+
+pub fn spawn(state: Arc<AppState>) {
+    if state.users.db().is_none() {
+        return;
+    }
+    info!("🗄️ Using the SQLite user store");
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let state = state.clone();
+            let _ = tokio::task::spawn_blocking(move || state.users.flush()).await;
+        }
+    });
+}
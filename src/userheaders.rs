@@ -0,0 +1,89 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::groups::{self, GroupConfig};
+use crate::pattern::host_matches;
+
+// Headers the proxy manages itself or that would break the request
+pub const RESERVED: &[&str] = &[
+    "proxy-authorization",
+    "host",
+    "connection",
+    "content-length",
+    "transfer-encoding",
+    "upgrade",
+    "te",
+    "trailer",
+    "via",
+    "max-forwards",
+];
+
+// Headers added to some users' plain HTTP requests, e.g. a partner's key:
+//
+//   [[user_headers]]
+//   users = ["partner-bot", "@partners"]
+//   host = "api.partner.example"
+//   set = { "X-Api-Key" = "k-8c1f…", "X-Team" = "integrations" }
+//
+// Every matching rule applies, in order, so a later one can override an
+// earlier one's value.
+#[derive(Debug, Deserialize)]
+pub struct HeaderRule {
+    // User names, "@group" or "*"
+    pub users: Vec<String>,
+    #[serde(default = "any_host")]
+    pub host: String,
+    pub set: BTreeMap<String, String>,
+}
+
+fn any_host() -> String {
+    "*".to_string()
+}
+
+pub fn validate(rules: &[HeaderRule], groups: &HashMap<String, GroupConfig>) -> Result<(), String> {
+    for (i, rule) in rules.iter().enumerate() {
+        groups::check_refs(&rule.users, groups).map_err(|e| format!("user_headers[{}]: {}", i, e))?;
+        for (name, value) in &rule.set {
+            let header = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("user_headers[{}]: '{}' is not a valid header name", i, name))?;
+            if RESERVED.contains(&header.as_str()) {
+                return Err(format!("user_headers[{}]: '{}' cannot be set", i, name));
+            }
+            // The value itself stays out of the message, like out of logs
+            HeaderValue::from_str(value)
+                .map_err(|_| format!("user_headers[{}]: value for '{}' is not a valid header value", i, name))?;
+        }
+    }
+    Ok(())
+}
+
+// Sets the headers of every rule matching `user` and `host`, replacing any
+// the client sent. Returns the names set, for logging; values are marked
+// sensitive so header dumps leave them out.
+pub fn apply(
+    rules: &[HeaderRule],
+    user: &str,
+    group: Option<&str>,
+    host: &str,
+    headers: &mut HeaderMap,
+) -> Vec<String> {
+    let mut set = Vec::new();
+    for rule in rules {
+        if !groups::covers(&rule.users, user, group) || !host_matches(&rule.host, host) {
+            continue;
+        }
+        for (name, value) in &rule.set {
+            let (Ok(name), Ok(mut value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value))
+            else {
+                continue;
+            };
+            value.set_sensitive(true);
+            if !set.contains(&name.to_string()) {
+                set.push(name.to_string());
+            }
+            headers.insert(name, value);
+        }
+    }
+    set
+}
@@ -0,0 +1,499 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use clap::Subcommand;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{OnceLock, RwLock};
+use toml_edit::{DocumentMut, Item, Table};
+
+use crate::userdb::{self, UserDb};
+
+const SCHEME: &str = "pbkdf2-sha256$";
+const ITERATIONS: u32 = 100_000;
+// Plain-text passwords from the config file as held in memory
+const SEALED: &str = "sealed$";
+
+// Proxy users, name -> password. Passwords are either plain text (as
+// written by hand) or "pbkdf2-sha256$<iterations>$<salt>$<hash>" as written
+// by `secure-proxy user`. Plain-text ones are sealed as they are loaded, so
+// they are not kept in memory. Replaced on SIGHUP. With `user_store` set,
+// the database is used instead and [users] is ignored.
+pub struct Users {
+    users: RwLock<HashMap<String, String>>,
+    db: Option<UserDb>,
+}
+
+// Per-user overrides from the user store; unset means the config applies.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct UserLimits {
+    pub max_connections: Option<usize>,
+    pub bandwidth: Option<u64>,
+    pub quota_bytes: Option<u64>,
+}
+
+impl Users {
+    pub fn new(users: HashMap<String, String>, db: Option<UserDb>) -> Self {
+        Users {
+            users: RwLock::new(seal_all(users)),
+            db,
+        }
+    }
+
+    pub fn db(&self) -> Option<&UserDb> {
+        self.db.as_ref()
+    }
+
+    pub fn password(&self, name: &str) -> Option<String> {
+        match &self.db {
+            Some(db) => db.password(name),
+            None => self.users.read().unwrap().get(name).cloned(),
+        }
+    }
+
+    pub fn limits(&self, name: &str) -> UserLimits {
+        self.db.as_ref().map(|db| db.limits(name)).unwrap_or_default()
+    }
+
+    pub fn over_quota(&self, name: &str, default: Option<u64>) -> bool {
+        self.db.as_ref().is_some_and(|db| db.over_quota(name, default))
+    }
+
+    pub fn record(&self, name: &str, bytes: u64) {
+        if let Some(db) = &self.db {
+            db.record(name, bytes);
+        }
+    }
+
+    pub fn flush(&self) {
+        if let Some(db) = &self.db {
+            db.flush();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.db {
+            Some(db) => db.count(),
+            None => self.users.read().unwrap().len(),
+        }
+    }
+
+    pub fn replace(&self, users: HashMap<String, String>) {
+        *self.users.write().unwrap() = seal_all(users);
+    }
+}
+
+fn seal_all(users: HashMap<String, String>) -> HashMap<String, String> {
+    users
+        .into_iter()
+        .map(|(name, password)| match is_hashed(&password) {
+            true => (name, password),
+            // Should sealing ever fail, the empty seal matches nothing
+            false => (name, format!("{}{}", SEALED, BASE64.encode(seal(&password).unwrap_or_default()))),
+        })
+        .collect()
+}
+
+// HMAC-SHA256 under a key made at startup. Cheap enough for every request,
+// unlike PBKDF2, and the same length whatever the password, so comparing
+// two seals reveals nothing about either password's length.
+fn seal(password: &str) -> Option<Vec<u8>> {
+    static KEY: OnceLock<Option<Vec<u8>>> = OnceLock::new();
+    let key = KEY.get_or_init(|| {
+        let mut key = vec![0u8; 32];
+        openssl::rand::rand_bytes(&mut key).ok()?;
+        Some(key)
+    });
+    let key = PKey::hmac(key.as_ref()?).ok()?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).ok()?;
+    signer.update(password.as_bytes()).ok()?;
+    signer.sign_to_vec().ok()
+}
+
+pub fn validate(users: &HashMap<String, String>) -> Result<(), String> {
+    match users.iter().find(|(_, p)| p.starts_with(SCHEME) && parse(p).is_none()) {
+        Some((name, _)) => Err(format!("user '{}' has a malformed password hash", name)),
+        None => Ok(()),
+    }
+}
+
+pub fn is_hashed(stored: &str) -> bool {
+    stored.starts_with(SCHEME)
+}
+
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(':') && !name.chars().any(char::is_control)
+}
+
+pub fn hash(password: &str) -> Result<String, openssl::error::ErrorStack> {
+    let mut salt = [0u8; 16];
+    openssl::rand::rand_bytes(&mut salt)?;
+    let mut key = [0u8; 32];
+    openssl::pkcs5::pbkdf2_hmac(password.as_bytes(), &salt, ITERATIONS as usize, MessageDigest::sha256(), &mut key)?;
+    Ok(format!("{}{}${}${}", SCHEME, ITERATIONS, BASE64.encode(salt), BASE64.encode(key)))
+}
+
+fn parse(stored: &str) -> Option<(usize, Vec<u8>, Vec<u8>)> {
+    let mut parts = stored.strip_prefix(SCHEME)?.split('$');
+    let iterations = parts.next()?.parse().ok().filter(|n| *n > 0)?;
+    let salt = BASE64.decode(parts.next()?).ok()?;
+    let key = BASE64.decode(parts.next()?).ok()?;
+    (parts.next().is_none() && !key.is_empty()).then_some((iterations, salt, key))
+}
+
+// `verify` off the async workers: PBKDF2 takes tens of milliseconds, and
+// failures are never cached, so a client guessing passwords would otherwise
+// stall other traffic. At most one check per CPU runs at a time.
+pub async fn check(stored: String, given: String) -> bool {
+    static HASHING: OnceLock<tokio::sync::Semaphore> = OnceLock::new();
+    let hashing = HASHING.get_or_init(|| {
+        tokio::sync::Semaphore::new(std::thread::available_parallelism().map_or(1, |n| n.get()))
+    });
+    let Ok(_permit) = hashing.acquire().await else {
+        return false;
+    };
+    tokio::task::spawn_blocking(move || verify(&stored, &given)).await.unwrap_or(false)
+}
+
+pub fn verify(stored: &str, given: &str) -> bool {
+    if let Some(sealed) = stored.strip_prefix(SEALED) {
+        let expected = BASE64.decode(sealed).unwrap_or_default();
+        return match seal(given) {
+            Some(given) => expected.len() == given.len() && openssl::memcmp::eq(&expected, &given),
+            None => false,
+        };
+    }
+    // Plain text from the user store
+    if !stored.starts_with(SCHEME) {
+        return match (seal(stored), seal(given)) {
+            (Some(stored), Some(given)) => openssl::memcmp::eq(&stored, &given),
+            _ => false,
+        };
+    }
+    let Some((iterations, salt, key)) = parse(stored) else {
+        return false;
+    };
+    let mut derived = vec![0u8; key.len()];
+    if openssl::pkcs5::pbkdf2_hmac(given.as_bytes(), &salt, iterations, MessageDigest::sha256(), &mut derived).is_err() {
+        return false;
+    }
+    openssl::memcmp::eq(&derived, &key)
+}
+
+#[derive(Subcommand)]
+pub enum Action {
+    /// Add a user; the password is prompted for, or read from stdin when piped
+    Add { name: String },
+    /// Set a new password for a user
+    Passwd { name: String },
+    /// Remove a user
+    Rm { name: String },
+    /// List users
+    List,
+    /// Set per-user limits in the user store; "none" falls back to the config
+    Limits {
+        name: String,
+        #[arg(long)]
+        max_connections: Option<Limit>,
+        /// Bytes per second, shared by the user's connections
+        #[arg(long)]
+        bandwidth: Option<Limit>,
+        /// Total bytes until the usage counters are reset
+        #[arg(long)]
+        quota_bytes: Option<Limit>,
+    },
+    /// Zero a user's usage counters in the user store
+    ResetUsage { name: String },
+    /// Copy [users] from the config file into the user store
+    Import,
+    /// Give a user a new TOTP secret; they then log in with "password:123456"
+    Totp {
+        name: String,
+        /// Remove the secret instead, so the password alone is enough again
+        #[arg(long)]
+        remove: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Limit {
+    None,
+    Value(u64),
+}
+
+impl Limit {
+    pub fn value(self) -> Option<u64> {
+        match self {
+            Limit::None => None,
+            Limit::Value(n) => Some(n),
+        }
+    }
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Limit::None),
+            _ => s.parse().map(Limit::Value).map_err(|_| format!("'{}' is not a number or \"none\"", s)),
+        }
+    }
+}
+
+// `secure-proxy user ...`: edit [users] in the config file in place,
+// keeping its comments and layout, or the user store when one is
+// configured. A running proxy picks config file changes up on SIGHUP and
+// user store changes immediately.
+pub fn run(path: &Path, action: Action) -> Result<(), String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut doc: DocumentMut = contents.parse().map_err(|e| format!("{}: {}", path.display(), e))?;
+    // Secrets stay in the config file even with a user store
+    if let Action::Totp { name, remove } = &action {
+        return run_totp(path, doc, name, *remove);
+    }
+    if let Some(store) = doc.get("user_store").and_then(Item::as_str) {
+        let db = UserDb::open(&userdb::store_path(store)?)?;
+        let imported = doc.get("users").and_then(Item::as_table).map(|users| {
+            users
+                .iter()
+                .filter_map(|(name, value)| Some((name.to_string(), value.as_str()?.to_string())))
+                .collect()
+        });
+        return run_store(&db, action, imported.unwrap_or_default());
+    }
+    if !doc.contains_key("users") {
+        doc.insert("users", Item::Table(Table::new()));
+    }
+    let users = doc["users"]
+        .as_table_mut()
+        .ok_or_else(|| format!("{}: [users] is not a table", path.display()))?;
+
+    match &action {
+        Action::List => {
+            let mut names: Vec<(&str, bool)> = users
+                .iter()
+                .map(|(name, value)| (name, value.as_str().is_some_and(is_hashed)))
+                .collect();
+            names.sort();
+            for (name, hashed) in names {
+                println!("{}{}", name, if hashed { "" } else { "  (plain-text password)" });
+            }
+            return Ok(());
+        }
+        Action::Add { name } => {
+            if !valid_name(name) {
+                return Err(format!("invalid user name '{}'", name));
+            }
+            if users.contains_key(name) {
+                return Err(format!("user '{}' already exists; use `user passwd` to change the password", name));
+            }
+            users.insert(name, toml_edit::value(new_password()?));
+        }
+        Action::Passwd { name } => {
+            if !users.contains_key(name) {
+                return Err(format!("no such user '{}'", name));
+            }
+            users.insert(name, toml_edit::value(new_password()?));
+        }
+        Action::Rm { name } => {
+            if users.remove(name).is_none() {
+                return Err(format!("no such user '{}'", name));
+            }
+        }
+        Action::Limits { .. } | Action::ResetUsage { .. } | Action::Import => {
+            return Err("this command needs a user_store in the config".to_string());
+        }
+        Action::Totp { .. } => unreachable!("handled by run_totp"),
+    }
+
+    save(path, &doc)?;
+    match action {
+        Action::Rm { name } => println!("Removed user '{}'", name),
+        Action::Add { name } | Action::Passwd { name } => println!("Saved password for '{}'", name),
+        _ => {}
+    }
+    println!("Send SIGHUP to a running proxy to apply: kill -HUP $(pidof secure-proxy)");
+    Ok(())
+}
+
+fn run_totp(path: &Path, mut doc: DocumentMut, name: &str, remove: bool) -> Result<(), String> {
+    if !valid_name(name) {
+        return Err(format!("invalid user name '{}'", name));
+    }
+    let mut implicit = Table::new();
+    implicit.set_implicit(true);
+    let totp = doc
+        .entry("totp")
+        .or_insert(Item::Table(implicit))
+        .as_table_mut()
+        .ok_or_else(|| format!("{}: [totp] is not a table", path.display()))?;
+    let secrets = totp
+        .entry("secrets")
+        .or_insert(Item::Table(Table::new()))
+        .as_table_mut()
+        .ok_or_else(|| format!("{}: [totp.secrets] is not a table", path.display()))?;
+    if remove {
+        if secrets.remove(name).is_none() {
+            return Err(format!("user '{}' has no TOTP secret", name));
+        }
+        save(path, &doc)?;
+        println!("Removed the TOTP secret of '{}'", name);
+    } else {
+        let secret = crate::totp::new_secret()?;
+        secrets.insert(name, toml_edit::value(&secret));
+        save(path, &doc)?;
+        println!("Secret for '{}': {}", name, secret);
+        println!("Authenticator app URI: {}", crate::totp::provisioning_uri(name, &secret));
+        println!("The password is now entered as \"password:<code>\"");
+    }
+    println!("Send SIGHUP to a running proxy to apply: kill -HUP $(pidof secure-proxy)");
+    Ok(())
+}
+
+fn save(path: &Path, doc: &DocumentMut) -> Result<(), String> {
+    let updated = doc.to_string();
+    toml::from_str::<crate::Config>(&updated).map_err(|e| format!("refusing to write an invalid config: {}", e))?;
+    // Same permissions as before; the file holds credentials
+    let tmp = path.with_extension("toml.tmp");
+    fs::write(&tmp, updated)
+        .and_then(|_| fs::set_permissions(&tmp, fs::metadata(path)?.permissions()))
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn run_store(db: &UserDb, action: Action, config_users: Vec<(String, String)>) -> Result<(), String> {
+    let no_such_user = |name: &str| format!("no such user '{}'", name);
+    match action {
+        Action::List => {
+            for user in db.list() {
+                let limit = |n: Option<u64>| n.map_or("-".to_string(), |n| n.to_string());
+                println!(
+                    "{}  max_connections={} bandwidth={} quota_bytes={} requests={} bytes={}{}",
+                    user.name,
+                    limit(user.limits.max_connections.map(|n| n as u64)),
+                    limit(user.limits.bandwidth),
+                    limit(user.limits.quota_bytes),
+                    user.requests,
+                    user.bytes,
+                    if user.hashed { "" } else { "  (plain-text password)" }
+                );
+            }
+        }
+        Action::Add { name } => {
+            if !valid_name(&name) {
+                return Err(format!("invalid user name '{}'", name));
+            }
+            if db.password(&name).is_some() {
+                return Err(format!("user '{}' already exists; use `user passwd` to change the password", name));
+            }
+            if !db.insert(&name, &new_password()?)? {
+                return Err(format!("user '{}' already exists", name));
+            }
+            println!("Saved password for '{}'", name);
+        }
+        Action::Passwd { name } => {
+            if db.password(&name).is_none() {
+                return Err(no_such_user(&name));
+            }
+            if !db.set_password(&name, &new_password()?)? {
+                return Err(no_such_user(&name));
+            }
+            println!("Saved password for '{}'", name);
+        }
+        Action::Rm { name } => {
+            if !db.remove(&name)? {
+                return Err(no_such_user(&name));
+            }
+            println!("Removed user '{}'", name);
+        }
+        Action::Limits {
+            name,
+            max_connections,
+            bandwidth,
+            quota_bytes,
+        } => {
+            if db.password(&name).is_none() {
+                return Err(no_such_user(&name));
+            }
+            let mut limits = db.limits(&name);
+            if let Some(limit) = max_connections {
+                limits.max_connections = limit.value().map(|n| n as usize);
+            }
+            if let Some(limit) = bandwidth {
+                limits.bandwidth = limit.value();
+            }
+            if let Some(limit) = quota_bytes {
+                limits.quota_bytes = limit.value();
+            }
+            db.set_limits(&name, &limits)?;
+            println!("Saved limits for '{}'", name);
+        }
+        Action::ResetUsage { name } => {
+            if !db.reset_usage(&name)? {
+                return Err(no_such_user(&name));
+            }
+            println!("Reset usage for '{}'", name);
+        }
+        Action::Import => {
+            let mut imported = 0;
+            for (name, password) in config_users {
+                let stored = match is_hashed(&password) {
+                    true => password,
+                    false => hash(&password).map_err(|e| format!("hashing failed: {}", e))?,
+                };
+                match db.insert(&name, &stored)? {
+                    true => imported += 1,
+                    false => println!("Skipped '{}': already in the user store", name),
+                }
+            }
+            println!("Imported {} user(s); [users] can now be removed from the config", imported);
+        }
+        Action::Totp { .. } => unreachable!("handled by run_totp"),
+    }
+    Ok(())
+}
+
+fn new_password() -> Result<String, String> {
+    let password = if io::stdin().is_terminal() {
+        let first = prompt("New password: ").map_err(|e| e.to_string())?;
+        if prompt("Retype password: ").map_err(|e| e.to_string())? != first {
+            return Err("passwords do not match".to_string());
+        }
+        first
+    } else {
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line).map_err(|e| e.to_string())?;
+        line.trim_end_matches(['\r', '\n']).to_string()
+    };
+    if password.is_empty() {
+        return Err("empty password".to_string());
+    }
+    hash(&password).map_err(|e| format!("hashing failed: {}", e))
+}
+
+// Read a line from the terminal with echo turned off.
+fn prompt(label: &str) -> io::Result<String> {
+    eprint!("{}", label);
+    io::stderr().flush()?;
+    let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+    let have_termios = unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } == 0;
+    if have_termios {
+        let mut quiet = saved;
+        quiet.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &quiet) };
+    }
+    let mut line = String::new();
+    let result = io::stdin().lock().read_line(&mut line);
+    if have_termios {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &saved) };
+    }
+    eprintln!();
+    result?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
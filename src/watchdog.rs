@@ -0,0 +1,224 @@
+use serde::Deserialize;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::AppState;
+
+#[derive(Debug, Deserialize)]
+pub struct WatchdogConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Seconds between checks
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    // Event-loop heartbeat lag that counts as a stall
+    #[serde(default = "default_stall_threshold_ms")]
+    pub stall_threshold_ms: u64,
+    // Resident memory ceiling in MiB
+    pub max_rss_mb: Option<u64>,
+    // Exit (for the supervisor to restart us) after this many failed checks in a row
+    #[serde(default)]
+    pub restart: bool,
+    #[serde(default = "default_failures_before_restart")]
+    pub failures_before_restart: u32,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            enabled: false,
+            interval: default_interval(),
+            stall_threshold_ms: default_stall_threshold_ms(),
+            max_rss_mb: None,
+            restart: false,
+            failures_before_restart: default_failures_before_restart(),
+        }
+    }
+}
+
+fn default_interval() -> u64 {
+    10
+}
+
+fn default_stall_threshold_ms() -> u64 {
+    2000
+}
+
+fn default_failures_before_restart() -> u32 {
+    3
+}
+
+const HEARTBEAT: Duration = Duration::from_millis(250);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+// Exit status for a watchdog-initiated restart (EX_SOFTWARE)
+const RESTART_EXIT_CODE: i32 = 70;
+
+// Shared between the heartbeat task, the watchdog thread and /ready.
+pub struct Watchdog {
+    started: Instant,
+    heartbeat_ms: AtomicU64,
+    ready: AtomicBool,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Watchdog {
+            started: Instant::now(),
+            heartbeat_ms: AtomicU64::new(0),
+            ready: AtomicBool::new(true),
+        }
+    }
+}
+
+impl Watchdog {
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+}
+
+// Start the heartbeat task on the runtime and the checker on its own OS
+// thread, so a wedged runtime cannot also wedge the watchdog.
+pub fn spawn(state: Arc<AppState>) {
+    let config = &state.config.watchdog;
+    if !config.enabled {
+        return;
+    }
+    info!(
+        "🐕 Watchdog enabled (every {}s, stall threshold {}ms)",
+        config.interval, config.stall_threshold_ms
+    );
+
+    let heartbeat = state.clone();
+    tokio::spawn(async move {
+        loop {
+            let dog = &heartbeat.watchdog;
+            dog.heartbeat_ms.store(dog.now_ms(), Ordering::Relaxed);
+            tokio::time::sleep(HEARTBEAT).await;
+        }
+    });
+
+    let spawned = std::thread::Builder::new()
+        .name("watchdog".to_string())
+        .spawn(move || run(state));
+    if let Err(e) = spawned {
+        error!("❌ Failed to start watchdog thread: {}", e);
+    }
+}
+
+fn run(state: Arc<AppState>) {
+    let config = &state.config.watchdog;
+    let dog = &state.watchdog;
+    let mut failures = 0u32;
+    loop {
+        std::thread::sleep(Duration::from_secs(config.interval.max(1)));
+
+        let problems = check(&state);
+        if problems.is_empty() {
+            if failures > 0 {
+                info!("✅ Watchdog checks passing again, marking ready");
+            }
+            failures = 0;
+            dog.ready.store(true, Ordering::Relaxed);
+            continue;
+        }
+
+        failures += 1;
+        dog.ready.store(false, Ordering::Relaxed);
+        warn!(
+            "🐕 Watchdog check failed ({} in a row): {} | client connections: {}, tunnels: {}, rss: {}",
+            failures,
+            problems.join("; "),
+            state.client_slots.active(),
+            state.tunnel_slots.active(),
+            rss_bytes()
+                .map(|b| format!("{} MiB", b / (1024 * 1024)))
+                .unwrap_or_else(|| "unknown".to_string())
+        );
+        if config.restart && failures >= config.failures_before_restart {
+            error!("❌ Watchdog giving up after {} failed checks, exiting for restart", failures);
+            std::process::exit(RESTART_EXIT_CODE);
+        }
+    }
+}
+
+fn check(state: &AppState) -> Vec<String> {
+    let config = &state.config.watchdog;
+    let dog = &state.watchdog;
+    let mut problems = Vec::new();
+
+    let lag = dog.now_ms().saturating_sub(dog.heartbeat_ms.load(Ordering::Relaxed));
+    if lag > config.stall_threshold_ms {
+        problems.push(format!("event loop stalled for {}ms", lag));
+    }
+
+    if let (Some(max), Some(rss)) = (config.max_rss_mb, rss_bytes()) {
+        if rss > max * 1024 * 1024 {
+            problems.push(format!("rss {} MiB over {} MiB", rss / (1024 * 1024), max));
+        }
+    }
+
+    // The probe speaks plain HTTP, so TLS listeners are skipped, and so are
+    // transparent ones, which close connections the firewall did not send,
+    // and PROXY protocol ones that do not take headers from loopback
+    let probed = |l: &&Arc<crate::listener::Listener>| {
+        !l.tls && l.transparent.is_none() && l.proxy_protocol.as_ref().is_none_or(|t| t.contains(loopback(l.addr).ip()))
+    };
+    for listener in state.listeners().iter().filter(probed) {
+        if let Err(e) = probe(listener.addr, listener.proxy_protocol.is_some()) {
+            problems.push(format!("listener {} not answering: {}", listener.addr, e));
+        }
+    }
+    problems
+}
+
+// A full request through the accept loop; a bare TCP connect would succeed
+// from the kernel backlog even if nothing is accepting.
+// Behind a load balancer the probe introduces itself as its own client.
+fn probe(addr: SocketAddr, proxy_protocol: bool) -> std::io::Result<()> {
+    let addr = loopback(addr);
+    let mut stream = TcpStream::connect_timeout(&addr, PROBE_TIMEOUT)?;
+    stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+    stream.set_write_timeout(Some(PROBE_TIMEOUT))?;
+    if proxy_protocol {
+        stream.write_all(b"PROXY UNKNOWN\r\n")?;
+    }
+    stream.write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+    let mut status = [0u8; 12];
+    stream.read_exact(&mut status)?;
+    if &status[9..12] != b"200" {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "unexpected health check status",
+        ));
+    }
+    Ok(())
+}
+
+// Where the probe connects: the listener's address, or loopback for a
+// wildcard one.
+fn loopback(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    addr
+}
+
+// Resident set size from /proc (Linux only).
+fn rss_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // SAFETY: sysconf has no preconditions
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * page_size.max(0) as u64)
+}
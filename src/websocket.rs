@@ -0,0 +1,66 @@
+use hyper::header::{HeaderMap, CONNECTION, UPGRADE};
+use hyper::{Body, Request, Response, StatusCode};
+use std::future::Future;
+use std::sync::Arc;
+use tracing::debug;
+
+use crate::limits::SlotGuard;
+
+// Protocol upgrades (WebSocket above all) on plain HTTP requests. The
+// request goes out with its Upgrade and Sec-WebSocket-* headers as they
+// are, so the client and server negotiate subprotocols and extensions
+// between themselves. Once the server answers 101, the two connections are
+// joined and bytes are relayed as they come, frames and all, with no
+// message ever held whole. Each side's EOF is passed on to the other, so
+// a half-closed connection stays open the other way.
+
+// Whether the request asks to switch protocols.
+pub fn requested(headers: &HeaderMap) -> bool {
+    let connection_upgrade = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    connection_upgrade && headers.contains_key(UPGRADE)
+}
+
+// Sends `req` with `send` and, if the server switches protocols, relays
+// between the client and server until both are done. `closed` gets the
+// bytes moved (from client, from server) when that happens.
+pub async fn relay<F, Fut>(
+    mut req: Request<Body>,
+    send: F,
+    closed: impl FnOnce(u64, u64) + Send + 'static,
+) -> hyper::Result<Response<Body>>
+where
+    F: FnOnce(Request<Body>) -> Fut,
+    Fut: Future<Output = hyper::Result<Response<Body>>>,
+{
+    let client = hyper::upgrade::on(&mut req);
+    // The connection outlives the service, like a CONNECT tunnel
+    let slot = req.extensions_mut().remove::<Arc<SlotGuard>>();
+    let mut response = send(req).await?;
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Ok(response);
+    }
+    let server = hyper::upgrade::on(&mut response);
+    tokio::spawn(async move {
+        let _slot = slot;
+        let (mut client, mut server) = match tokio::try_join!(client, server) {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                debug!("Protocol upgrade failed: {}", e);
+                return;
+            }
+        };
+        match tokio::io::copy_bidirectional(&mut client, &mut server).await {
+            Ok((from_client, from_server)) => {
+                debug!("Upgraded connection closed: {} bytes from client, {} from server", from_client, from_server);
+                closed(from_client, from_server);
+            }
+            Err(e) => debug!("Upgraded connection failed: {}", e),
+        }
+    });
+    Ok(response)
+}